@@ -0,0 +1,95 @@
+// =============================================================================
+// APRK OS - Console Output Sinks
+// =============================================================================
+// Kernel output (`print!`/`println!`) fans out to every registered sink.
+// The UART is always present; other outputs such as a framebuffer text
+// console register themselves once their hardware is up.
+// =============================================================================
+
+use core::fmt;
+use spin::Mutex;
+
+/// An output device for kernel console text.
+///
+/// Implementations do their own locking. `write_str` is called with IRQs
+/// masked and may run in interrupt context, so it must not block.
+pub trait ConsoleSink: Sync {
+    /// Short name for diagnostics
+    fn name(&self) -> &str;
+
+    /// Write text to the device.
+    fn write_str(&self, s: &str) -> fmt::Result;
+
+    /// Push out any buffered output.
+    fn flush(&self) {}
+}
+
+/// Maximum number of registered sinks
+const MAX_SINKS: usize = 4;
+
+/// Registered sinks. Only locked with IRQs masked.
+static SINKS: Mutex<[Option<&'static dyn ConsoleSink>; MAX_SINKS]> =
+    Mutex::new([Some(&crate::uart::UART_SINK), None, None, None]);
+
+/// Register an additional console sink.
+///
+/// Returns `false` if the sink table is full.
+pub fn register(sink: &'static dyn ConsoleSink) -> bool {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let ok = match SINKS.lock().iter_mut().find(|s| s.is_none()) {
+        Some(slot) => {
+            *slot = Some(sink);
+            true
+        }
+        None => false,
+    };
+    crate::cpu::restore_interrupts(daif);
+    ok
+}
+
+/// Snapshot of the sink table, so sinks run without the table locked.
+fn sinks() -> [Option<&'static dyn ConsoleSink>; MAX_SINKS] {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let sinks = *SINKS.lock();
+    crate::cpu::restore_interrupts(daif);
+    sinks
+}
+
+/// Write text to every sink.
+///
+/// A sink reporting an error is skipped for this write; it never stops
+/// the text from reaching the others.
+pub(crate) fn write_all(s: &str) {
+    for sink in sinks().iter().flatten() {
+        let _ = sink.write_str(s);
+    }
+}
+
+/// Write text to every sink without recording it in the kernel log: input
+/// echo, which belongs on whichever console the user is typing at.
+pub fn echo(s: &str) {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    write_all(s);
+    crate::cpu::restore_interrupts(daif);
+}
+
+/// Flush every sink. Used on panic and poweroff paths.
+///
+/// Never waits for a lock: if the sink table is held (a panic while
+/// registering a sink), only the UART is flushed.
+pub fn flush() {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let sinks = SINKS.try_lock().map(|sinks| *sinks);
+    crate::cpu::restore_interrupts(daif);
+    match sinks {
+        Some(sinks) => sinks.iter().flatten().for_each(|sink| sink.flush()),
+        None => crate::uart::UART_SINK.flush(),
+    }
+}
+
+/// Call `f` with the name of each registered sink.
+pub fn for_each_sink(mut f: impl FnMut(&str)) {
+    for sink in sinks().iter().flatten() {
+        f(sink.name());
+    }
+}
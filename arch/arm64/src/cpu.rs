@@ -20,6 +20,45 @@ pub fn halt() -> ! {
     }
 }
 
+/// PSCI function IDs (SMC32 calling convention)
+const PSCI_SYSTEM_OFF: u64 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u64 = 0x8400_0009;
+
+/// Issue a PSCI call through the conduit the device tree's `/psci` node
+/// gives as its `method`: SMC, or HVC (what QEMU virt uses without EL2)
+/// otherwise.
+fn psci_call(function: u64) {
+    let smc = crate::dtb::get()
+        .and_then(|dtb| dtb.find_path("/psci"))
+        .and_then(|psci| psci.prop_str("method"))
+        == Some("smc");
+    unsafe {
+        if smc {
+            core::arch::asm!("smc #0", inout("x0") function => _, options(nomem, nostack));
+        } else {
+            core::arch::asm!("hvc #0", inout("x0") function => _, options(nomem, nostack));
+        }
+    }
+}
+
+/// Power off the machine.
+///
+/// Flushes pending console output first. Halts if PSCI is unavailable.
+pub fn poweroff() -> ! {
+    crate::console::flush();
+    psci_call(PSCI_SYSTEM_OFF);
+    halt()
+}
+
+/// Reset the machine.
+///
+/// Flushes pending console output first. Halts if PSCI is unavailable.
+pub fn reboot() -> ! {
+    crate::console::flush();
+    psci_call(PSCI_SYSTEM_RESET);
+    halt()
+}
+
 /// Enable interrupts.
 /// 
 /// # Safety
@@ -37,6 +76,28 @@ pub fn disable_interrupts() {
     }
 }
 
+/// Disable interrupts, returning the previous DAIF state.
+///
+/// Pair with `restore_interrupts` to build short critical sections that are
+/// safe to nest and to enter with interrupts already masked.
+#[inline(always)]
+pub fn save_and_disable_interrupts() -> u64 {
+    let daif: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, daif", out(reg) daif);
+        core::arch::asm!("msr daifset, #2"); // Set IRQ mask
+    }
+    daif
+}
+
+/// Restore the DAIF state returned by `save_and_disable_interrupts`.
+#[inline(always)]
+pub fn restore_interrupts(daif: u64) {
+    unsafe {
+        core::arch::asm!("msr daif, {}", in(reg) daif);
+    }
+}
+
 /// Get the current exception level (0-3).
 #[inline(always)]
 pub fn current_el() -> u8 {
@@ -84,3 +145,55 @@ pub unsafe fn clean_dcache_range(start: usize, len: usize) {
     
     core::arch::asm!("dsb ish");
 }
+
+/// Clean and invalidate Data Cache by MVA to Point of Coherency.
+/// Writes back dirty lines and drops the range from the cache, e.g.
+/// before the memory is accessed through a non-cacheable mapping.
+///
+/// # Safety
+/// `[start, start + len)` must be mapped. Whole cache lines are written
+/// back, so no device may be writing memory that shares a line with
+/// either end of the range.
+pub unsafe fn clean_invalidate_dcache_range(start: usize, len: usize) {
+    let line_size = 64;
+    let end = start + len;
+    let mut addr = start & !(line_size - 1);
+
+    while addr < end {
+        core::arch::asm!("dc civac, {}", in(reg) addr);
+        addr += line_size;
+    }
+
+    core::arch::asm!("dsb sy");
+}
+
+/// Most frames `backtrace` walks
+const MAX_FRAMES: usize = 16;
+
+/// Call `frame` with the return address of each caller on the current
+/// stack, innermost first, by following the frame-pointer (x29) chain.
+///
+/// Stops at the first frame record that isn't mapped kernel memory, so a
+/// corrupted chain ends the walk instead of faulting.
+#[inline(never)]
+pub fn backtrace(mut frame: impl FnMut(usize)) {
+    let mut fp: usize;
+    unsafe {
+        core::arch::asm!("mov {}, x29", out(reg) fp);
+    }
+    for _ in 0..MAX_FRAMES {
+        if fp < crate::mmu::KERNEL_OFFSET || !fp.is_multiple_of(16) || crate::mmu::translate(fp).is_none() {
+            break;
+        }
+        // A frame record is the caller's x29, then the return address
+        let [next, lr] = unsafe { *(fp as *const [usize; 2]) };
+        if lr == 0 {
+            break;
+        }
+        frame(lr);
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+}
@@ -0,0 +1,279 @@
+// =============================================================================
+// APRK OS - Flattened Device Tree (DTB) Parser
+// =============================================================================
+// Minimal read-only parser for the device tree blob QEMU hands to the
+// kernel. For bare-metal ELF images QEMU places the blob at the start of
+// RAM, below the kernel image.
+//
+// Only what the kernel needs is supported: walking nodes, reading
+// properties, and decoding `reg` / `interrupts` for devices on the root bus.
+//
+// Reference: Devicetree Specification v0.4, chapter 5
+// =============================================================================
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Where QEMU places the DTB for bare-metal images (start of RAM)
+pub const DEFAULT_DTB_ADDR: usize = 0x4000_0000;
+
+/// FDT header magic
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Address of the validated DTB (0 = none)
+static DTB_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Read a big-endian value made of `cells` 32-bit cells.
+fn read_cells(bytes: &[u8], cells: u32) -> Option<u64> {
+    let mut value = 0u64;
+    for i in 0..cells as usize {
+        value = (value << 32) | be32(bytes, i * 4)? as u64;
+    }
+    Some(value)
+}
+
+/// Read a NUL-terminated string starting at `offset`.
+fn cstr(bytes: &[u8], offset: usize) -> Option<&str> {
+    let rest = bytes.get(offset..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
+/// A parsed device tree blob.
+#[derive(Clone, Copy)]
+pub struct Dtb {
+    data: &'static [u8],
+    struct_off: usize,
+    strings_off: usize,
+}
+
+impl Dtb {
+    /// Validate and wrap the blob at `addr`.
+    ///
+    /// # Safety
+    /// `addr` must be mapped and readable for the blob's total size.
+    pub unsafe fn from_addr(addr: usize) -> Option<Self> {
+        let header = core::slice::from_raw_parts(addr as *const u8, 40);
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total = be32(header, 4)? as usize;
+        let data = core::slice::from_raw_parts(addr as *const u8, total);
+        Some(Self {
+            data,
+            struct_off: be32(header, 8)? as usize,
+            strings_off: be32(header, 12)? as usize,
+        })
+    }
+
+    /// Total size of the blob in bytes.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Start address of the blob.
+    pub fn addr(&self) -> usize {
+        self.data.as_ptr() as usize
+    }
+
+    /// Call `f` for every node, in document order.
+    ///
+    /// Stops early and returns the node if `f` returns `true`.
+    pub fn find_node(&self, mut f: impl FnMut(&Node) -> bool) -> Option<Node> {
+        let mut pos = self.struct_off;
+        let mut depth = 0usize;
+        loop {
+            let token = be32(self.data, pos)?;
+            pos += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(self.data, pos)?;
+                    let node = Node { dtb: *self, name, depth, props: pos + align4(name.len() + 1) };
+                    if f(&node) {
+                        return Some(node);
+                    }
+                    pos += align4(name.len() + 1);
+                    depth += 1;
+                }
+                FDT_END_NODE => depth = depth.checked_sub(1)?,
+                FDT_PROP => {
+                    let len = be32(self.data, pos)? as usize;
+                    pos += 8 + align4(len);
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                _ => return None,
+            }
+        }
+    }
+
+    /// Find a node by its full path (e.g. "/chosen", "/pl011@9000000").
+    ///
+    /// Only root-level paths are supported.
+    pub fn find_path(&self, path: &str) -> Option<Node> {
+        if path == "/" {
+            return self.find_node(|n| n.depth == 0);
+        }
+        let name = path.strip_prefix('/')?;
+        self.find_node(|n| n.depth == 1 && n.name == name)
+    }
+
+    /// Find the `index`-th node whose `compatible` list contains `compat`.
+    pub fn find_compatible(&self, compat: &str, index: usize) -> Option<Node> {
+        let mut seen = 0;
+        self.find_node(|n| {
+            if n.is_compatible(compat) {
+                seen += 1;
+                seen > index
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Find the node with the given `phandle`.
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node> {
+        self.find_node(|n| n.prop_u32("phandle") == Some(phandle))
+    }
+
+    /// Base and size of the first RAM bank, from the `/memory` node.
+    pub fn memory(&self) -> Option<(u64, u64)> {
+        self.find_node(|n| {
+            n.depth == 1 && (n.name == "memory" || n.name.starts_with("memory@")
+                || n.prop_str("device_type") == Some("memory"))
+        })?.reg(0)
+    }
+
+    /// Start and end of the initial ramdisk a bootloader loaded, from
+    /// `/chosen`. Either property may be 32 or 64 bits wide.
+    pub fn initrd(&self) -> Option<(u64, u64)> {
+        let chosen = self.find_path("/chosen")?;
+        let cell = |name| chosen.prop(name).and_then(|v| read_cells(v, (v.len() / 4) as u32));
+        let (start, end) = (cell("linux,initrd-start")?, cell("linux,initrd-end")?);
+        (end > start).then_some((start, end))
+    }
+
+    /// `#address-cells` and `#size-cells` of the root node.
+    fn root_cells(&self) -> (u32, u32) {
+        let root = self.find_path("/");
+        let addr = root.and_then(|r| r.prop_u32("#address-cells")).unwrap_or(2);
+        let size = root.and_then(|r| r.prop_u32("#size-cells")).unwrap_or(1);
+        (addr, size)
+    }
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// A node in the device tree.
+#[derive(Clone, Copy)]
+pub struct Node {
+    dtb: Dtb,
+    /// Node name including unit address, e.g. "pl011@9000000"
+    pub name: &'static str,
+    /// Nesting depth (root = 0)
+    pub depth: usize,
+    /// Offset of the first token after the node name
+    props: usize,
+}
+
+impl Node {
+    /// Raw value of a property of this node.
+    pub fn prop(&self, name: &str) -> Option<&'static [u8]> {
+        let data = self.dtb.data;
+        let mut pos = self.props;
+        loop {
+            match be32(data, pos)? {
+                FDT_PROP => {
+                    let len = be32(data, pos + 4)? as usize;
+                    let nameoff = be32(data, pos + 8)? as usize;
+                    let value = data.get(pos + 12..pos + 12 + len)?;
+                    if cstr(data, self.dtb.strings_off + nameoff)? == name {
+                        return Some(value);
+                    }
+                    pos += 12 + align4(len);
+                }
+                FDT_NOP => pos += 4,
+                // Properties precede child nodes, so we're done
+                _ => return None,
+            }
+        }
+    }
+
+    /// A property holding a single 32-bit cell.
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        be32(self.prop(name)?, 0)
+    }
+
+    /// A string property (first string of a list).
+    pub fn prop_str(&self, name: &str) -> Option<&'static str> {
+        cstr(self.prop(name)?, 0)
+    }
+
+    /// Check the `compatible` string list.
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.prop("compatible").is_some_and(|list| {
+            list.split(|&b| b == 0).any(|s| s == compat.as_bytes())
+        })
+    }
+
+    /// The `index`-th (address, size) pair of `reg`, for root-level nodes.
+    pub fn reg(&self, index: usize) -> Option<(u64, u64)> {
+        let (addr_cells, size_cells) = self.dtb.root_cells();
+        let entry = ((addr_cells + size_cells) * 4) as usize;
+        let reg = self.prop("reg")?.get(index * entry..(index + 1) * entry)?;
+        Some((read_cells(reg, addr_cells)?, read_cells(&reg[addr_cells as usize * 4..], size_cells)?))
+    }
+
+    /// GIC interrupt ID of the `index`-th entry of `interrupts`.
+    ///
+    /// Assumes the three-cell GIC binding: (type, number, flags), where
+    /// type 0 is an SPI (ID = number + 32) and 1 is a PPI (ID = number + 16).
+    pub fn irq(&self, index: usize) -> Option<u32> {
+        let cells = self.prop("interrupts")?.get(index * 12..(index + 1) * 12)?;
+        let kind = be32(cells, 0)?;
+        let number = be32(cells, 4)?;
+        match kind {
+            0 => Some(number + 32),
+            1 => Some(number + 16),
+            _ => None,
+        }
+    }
+
+    /// Frequency of the first clock referenced by `clocks`.
+    pub fn clock_frequency(&self) -> Option<u32> {
+        let phandle = be32(self.prop("clocks")?, 0)?;
+        self.dtb.find_phandle(phandle)?.prop_u32("clock-frequency")
+    }
+}
+
+/// Look for a DTB at `addr` and remember it if valid.
+///
+/// # Safety
+/// `addr` must be mapped; only the header is read before validation.
+pub unsafe fn init(addr: usize) -> Option<Dtb> {
+    let dtb = Dtb::from_addr(addr)?;
+    DTB_ADDR.store(addr, Ordering::Release);
+    Some(dtb)
+}
+
+/// The DTB found at boot, if any.
+pub fn get() -> Option<Dtb> {
+    match DTB_ADDR.load(Ordering::Acquire) {
+        0 => None,
+        // SAFETY: Validated by `init`
+        addr => unsafe { Dtb::from_addr(addr) },
+    }
+}
@@ -2,17 +2,295 @@
 // APRK OS - Exception Handling (Rust)
 // =============================================================================
 // Rust handlers for the exceptions defined in exception.S
+//
+// Every exception is counted. Interrupts are also counted per ID, and an
+// ID that fires without a handler is reported at most once per second and
+// masked if it storms.
+//
+// Latency is measured in counter ticks with one CNTVCT read at IRQ entry
+// and one after the handler: timer ticks against their programmed
+// deadline, device interrupts by handler run time.
 // =============================================================================
 
 use crate::println;
-use crate::gic::Gic;
-use crate::timer::Timer;
+use crate::gic::{self, Gic, MAX_IRQS};
+use crate::timer::{self, Timer, TICK_MS};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use core::time::Duration;
 
 extern "C" {
     fn kernel_syscall_handler(id: u64, arg0: u64, arg1: u64, arg2: u64) -> u64;
 }
 
+/// GICv2 spurious interrupt ID
+const SPURIOUS_IRQ: u32 = 1023;
+
+/// Default number of unhandled interrupts from one ID within a second
+/// after which the line is masked
+const DEFAULT_STORM_THRESHOLD: u32 = 10_000;
+
+// =============================================================================
+// Exception Statistics
+// =============================================================================
+
+/// Exception counts since boot.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExceptionStats {
+    /// Synchronous exceptions other than SVC (faults)
+    pub sync: u64,
+    pub svc: u64,
+    /// IRQs acknowledged with a valid ID
+    pub irq: u64,
+    /// Spurious IRQs (ID 1023)
+    pub spurious: u64,
+}
+
+static SYNC_COUNT: AtomicU64 = AtomicU64::new(0);
+static SVC_COUNT: AtomicU64 = AtomicU64::new(0);
+static IRQ_COUNT: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Handlers running longer than this are reported (once per ID)
+const SLOW_HANDLER: Duration = Duration::from_millis(1);
+
+/// Upper bounds of the per-interrupt latency histogram buckets, in
+/// microseconds; a last bucket catches everything slower
+pub const LATENCY_BUCKETS_US: [u64; 7] = [10, 50, 100, 500, 1_000, 5_000, 10_000];
+
+/// Min / average / max of a series of counter tick measurements.
+struct Latency {
+    samples: AtomicU64,
+    total: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Latency {
+    const fn new() -> Self {
+        Self {
+            samples: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    /// Add a sample. Only called with IRQs masked.
+    #[inline(always)]
+    fn record(&self, ticks: u64) {
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(ticks, Ordering::Relaxed);
+        self.min.fetch_min(ticks, Ordering::Relaxed);
+        self.max.fetch_max(ticks, Ordering::Relaxed);
+    }
+
+    fn summary(&self) -> LatencySummary {
+        let samples = self.samples.load(Ordering::Relaxed);
+        let avg = self.total.load(Ordering::Relaxed).checked_div(samples).unwrap_or(0);
+        let min = if samples == 0 { 0 } else { self.min.load(Ordering::Relaxed) };
+        LatencySummary {
+            samples,
+            min: Timer::ticks_to_duration(min),
+            avg: Timer::ticks_to_duration(avg),
+            max: Timer::ticks_to_duration(self.max.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Latency figures for display.
+#[derive(Debug, Clone, Copy)]
+pub struct LatencySummary {
+    pub samples: u64,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+}
+
+/// Delay from the programmed deadline to timer IRQ entry
+static TIMER_LATENCY: Latency = Latency::new();
+
+/// Per-ID interrupt counters.
+struct IrqCounter {
+    count: AtomicU64,
+    /// Handler run time
+    handler_time: Latency,
+    /// Latency samples per `LATENCY_BUCKETS_US` bucket: handler run times,
+    /// or for the timer the tick latency
+    histogram: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    /// A slow handler run was already reported
+    slow_reported: AtomicBool,
+    unhandled: AtomicU64,
+    /// Start of the current one-second storm window, in ms of uptime
+    window_start_ms: AtomicU64,
+    /// Unhandled interrupts within the current window
+    window_count: AtomicU32,
+    /// When "unhandled" was last reported, in ms of uptime (0 = never)
+    last_report_ms: AtomicU64,
+    /// Line was disabled because it stormed
+    masked: AtomicBool,
+}
+
+impl IrqCounter {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            handler_time: Latency::new(),
+            histogram: [const { AtomicU64::new(0) }; LATENCY_BUCKETS_US.len() + 1],
+            slow_reported: AtomicBool::new(false),
+            unhandled: AtomicU64::new(0),
+            window_start_ms: AtomicU64::new(0),
+            window_count: AtomicU32::new(0),
+            last_report_ms: AtomicU64::new(0),
+            masked: AtomicBool::new(false),
+        }
+    }
+}
+
+static IRQ_COUNTERS: [IrqCounter; MAX_IRQS] = [const { IrqCounter::new() }; MAX_IRQS];
+
+static STORM_THRESHOLD: AtomicU32 = AtomicU32::new(DEFAULT_STORM_THRESHOLD);
+
+/// Counts for one interrupt ID.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqStats {
+    pub irq: u32,
+    pub count: u64,
+    /// Occurrences with no registered handler
+    pub unhandled: u64,
+    /// Line was masked after an unhandled-interrupt storm
+    pub masked: bool,
+    pub has_handler: bool,
+    /// Handler run time (no samples for the timer, whose handler may
+    /// switch tasks; an SGI handler that switches counts until it's
+    /// switched back)
+    pub handler_time: LatencySummary,
+    /// Counts per `LATENCY_BUCKETS_US` bucket, plus a final overflow
+    /// bucket: of handler run times, or for the timer of tick latencies
+    pub histogram: [u64; LATENCY_BUCKETS_US.len() + 1],
+}
+
+/// Exception counts since boot.
+pub fn stats() -> ExceptionStats {
+    ExceptionStats {
+        sync: SYNC_COUNT.load(Ordering::Relaxed),
+        svc: SVC_COUNT.load(Ordering::Relaxed),
+        irq: IRQ_COUNT.load(Ordering::Relaxed),
+        spurious: SPURIOUS_COUNT.load(Ordering::Relaxed),
+    }
+}
+
+/// Call `f` for every interrupt ID that has fired at least once.
+pub fn for_each_irq_stat(mut f: impl FnMut(IrqStats)) {
+    for (irq, counter) in IRQ_COUNTERS.iter().enumerate() {
+        let count = counter.count.load(Ordering::Relaxed);
+        if count == 0 {
+            continue;
+        }
+        f(IrqStats {
+            irq: irq as u32,
+            count,
+            unhandled: counter.unhandled.load(Ordering::Relaxed),
+            masked: counter.masked.load(Ordering::Relaxed),
+            has_handler: gic::has_handler(irq as u32),
+            handler_time: counter.handler_time.summary(),
+            histogram: core::array::from_fn(|i| counter.histogram[i].load(Ordering::Relaxed)),
+        });
+    }
+}
+
+/// Delay between the timer deadline and the tick IRQ being taken, which
+/// includes any time IRQs were masked.
+pub fn timer_latency() -> LatencySummary {
+    TIMER_LATENCY.summary()
+}
+
+/// Count a latency sample of `ticks` in interrupt `irq`'s histogram.
+#[inline(always)]
+fn record_histogram(irq: u32, ticks: u64) {
+    let Some(counter) = IRQ_COUNTERS.get(irq as usize) else { return };
+    let us = ticks * 1_000_000 / Timer::frequency();
+    let bucket = LATENCY_BUCKETS_US.iter().position(|&limit| us < limit)
+        .unwrap_or(LATENCY_BUCKETS_US.len());
+    counter.histogram[bucket].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Account for timer interrupt `irq` taken `ticks` after its deadline.
+#[inline(always)]
+fn record_timer_latency(irq: u32, ticks: u64) {
+    TIMER_LATENCY.record(ticks);
+    record_histogram(irq, ticks);
+}
+
+/// Account for a handler run of `ticks`, reporting the first slow one.
+#[inline(always)]
+fn record_handler_time(irq: u32, ticks: u64) {
+    let Some(counter) = IRQ_COUNTERS.get(irq as usize) else { return };
+    counter.handler_time.record(ticks);
+    record_histogram(irq, ticks);
+    if Timer::ticks_to_duration(ticks) > SLOW_HANDLER
+        && !counter.slow_reported.swap(true, Ordering::Relaxed)
+    {
+        crate::log_warn!("irq", "Handler for interrupt {} took {} us (> {} us)",
+            irq, Timer::ticks_to_duration(ticks).as_micros(), SLOW_HANDLER.as_micros());
+    }
+}
+
+/// Set how many unhandled interrupts from one ID within a second cause
+/// the line to be masked (0 disables masking).
+pub fn set_storm_threshold(count: u32) {
+    STORM_THRESHOLD.store(count, Ordering::Relaxed);
+}
+
+/// Current storm threshold (see `set_storm_threshold`).
+pub fn storm_threshold() -> u32 {
+    STORM_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Run the handler registered for `irq`, timing it, or account for it
+/// being unhandled.
+#[inline(always)]
+fn dispatch(irq: u32) {
+    let start = Timer::counter();
+    if gic::dispatch(irq) {
+        record_handler_time(irq, Timer::counter() - start);
+    } else {
+        note_unhandled(irq);
+    }
+}
+
+/// Account for an interrupt that has no handler: report it at most once
+/// per second, and mask the line if it storms.
+fn note_unhandled(irq: u32) {
+    let Some(counter) = IRQ_COUNTERS.get(irq as usize) else {
+        crate::log_warn!("irq", "Unhandled interrupt ID: {}", irq);
+        return;
+    };
+    let total = counter.unhandled.fetch_add(1, Ordering::Relaxed) + 1;
+    let now_ms = Timer::uptime().as_millis() as u64;
+
+    // IRQs are masked while we run, so plain load/store sequences are safe
+    let in_window = now_ms.saturating_sub(counter.window_start_ms.load(Ordering::Relaxed)) < 1000;
+    let window = if in_window {
+        counter.window_count.load(Ordering::Relaxed) + 1
+    } else {
+        counter.window_start_ms.store(now_ms, Ordering::Relaxed);
+        1
+    };
+    counter.window_count.store(window, Ordering::Relaxed);
+
+    let last = counter.last_report_ms.load(Ordering::Relaxed);
+    if last == 0 || now_ms.saturating_sub(last) >= 1000 {
+        counter.last_report_ms.store(now_ms.max(1), Ordering::Relaxed);
+        crate::log_warn!("irq", "Unhandled interrupt ID: {} ({} so far)", irq, total);
+    }
+
+    let threshold = storm_threshold();
+    if threshold != 0 && window >= threshold && !counter.masked.swap(true, Ordering::Relaxed) {
+        gic::disable_irq(irq);
+        crate::log_warn!("irq", "Interrupt {} stormed ({} unhandled in 1s), line masked", irq, window);
+    }
+}
+
 /// Initialize exceptions.
 /// Sets the VBAR_EL1 register to point to our vector table.
 pub unsafe fn init() {
@@ -51,9 +329,9 @@ pub struct TrapFrame {
 
 /// Handler for Synchronous Exceptions (SVC, Data Abort, etc.).
 /// 
-/// `trap_frame` points to the saved register context on the stack.
+/// `trap_frame` is the saved register context on the stack.
 #[no_mangle]
-pub extern "C" fn handle_sync_exception(trap_frame: *mut TrapFrame) {
+pub extern "C" fn handle_sync_exception(trap_frame: &mut TrapFrame) {
     let esr: u64;
     
     unsafe {
@@ -62,17 +340,60 @@ pub extern "C" fn handle_sync_exception(trap_frame: *mut TrapFrame) {
     
     let ec = (esr >> 26) & 0x3F;
 
-    let tf_debug = unsafe { &*trap_frame };
+    // Translation fault on a user address, from EL0 or from a syscall
+    // touching a user buffer: the kernel may demand-page it, in which case
+    // returning retries the access
+    if (ec == 0x24 || ec == 0x25) && esr & 0x3C == 0x04 {
+        extern "Rust" { fn kernel_user_fault(addr: usize) -> bool; }
+        let far: u64;
+        unsafe { core::arch::asm!("mrs {}, far_el1", out(reg) far); }
+        if (far as usize) < crate::mmu::VA_LIMIT && unsafe { kernel_user_fault(far as usize) } {
+            return;
+        }
+    }
+
+    // Write permission fault on a user address: the page may be shared
+    // copy-on-write, in which case the kernel gives the writer its own copy
+    // and returning retries the write
+    if (ec == 0x24 || ec == 0x25) && esr & 0x3C == 0x0C && esr & (1 << 6) != 0 {
+        extern "Rust" { fn kernel_cow_fault(addr: usize) -> bool; }
+        let far: u64;
+        unsafe { core::arch::asm!("mrs {}, far_el1", out(reg) far); }
+        if (far as usize) < crate::mmu::VA_LIMIT && unsafe { kernel_cow_fault(far as usize) } {
+            return;
+        }
+    }
+
+    // Data/instruction abort at EL1 inside `mmu::probe_*` or a user copy
+    // that the kernel couldn't resolve above: resume at its fixup
+    if ec == 0x25 || ec == 0x21 {
+        let fixup = crate::mmu::FAULT_FIXUP.load(Ordering::Relaxed);
+        if fixup != 0 {
+            trap_frame.elr = fixup as u64;
+            return;
+        }
+    }
+
+    // Data abort from EL0 or EL1 in a stack guard page: the kernel reports
+    // the overflow and kills the task, so this doesn't return if it was one
+    if ec == 0x24 || ec == 0x25 {
+        extern "Rust" { fn kernel_stack_fault(addr: usize) -> bool; }
+        let far: u64;
+        unsafe { core::arch::asm!("mrs {}, far_el1", out(reg) far); }
+        unsafe { kernel_stack_fault(far as usize); }
+    }
+
     if ec != 0x15 {
-         crate::println!("[except] SYNC EC={:#x} ELR={:#x}", ec, tf_debug.elr);
+         crate::log_error!("except", "SYNC EC={:#x} ELR={:#x}", ec, trap_frame.elr);
     } else {
-         // crate::println!("[except] SVC at ELR={:#x}", tf_debug.elr);
+         // crate::println!("[except] SVC at ELR={:#x}", trap_frame.elr);
     }
 
     // EC = 0x15 is SVC (System Call) from AArch64
     if ec == 0x15 {
+        SVC_COUNT.fetch_add(1, Ordering::Relaxed);
         // Read syscall arguments from the saved trap frame
-        let tf = unsafe { &mut *trap_frame };
+        let tf = &mut *trap_frame;
         let id = tf.x8;    // Syscall number in x8
         let arg0 = tf.x0;  // First argument in x0
         let arg1 = tf.x1;  // Second argument in x1
@@ -90,18 +411,28 @@ pub extern "C" fn handle_sync_exception(trap_frame: *mut TrapFrame) {
         return; // Return to user
     }
     
+    SYNC_COUNT.fetch_add(1, Ordering::Relaxed);
+
     let elr: u64;
     let far: u64;
     unsafe {
         core::arch::asm!("mrs {}, elr_el1", out(reg) elr);
         core::arch::asm!("mrs {}, far_el1", out(reg) far);
     }
+
+    // Anything else from EL0 (SPSR.M = EL0t) is the task's own fault: the
+    // kernel kills it and carries on
+    if trap_frame.spsr & 0xF == 0 {
+        extern "Rust" { fn kernel_user_exception(esr: u64, elr: u64, far: u64) -> !; }
+        unsafe { kernel_user_exception(esr, elr, far) }
+    }
     
     println!("\n!!! SYNCHRONOUS EXCEPTION !!!");
     println!("ESR_EL1: {:#018x}", esr);
     println!("ELR_EL1: {:#018x}", elr);
     println!("FAR_EL1: {:#018x}", far);
     println!("System halted.");
+    crate::console::flush();
     
     loop { core::hint::spin_loop(); }
 }
@@ -109,34 +440,45 @@ pub extern "C" fn handle_sync_exception(trap_frame: *mut TrapFrame) {
 /// Handler for IRQ Exceptions (Hardware Interrupts).
 #[no_mangle]
 pub extern "C" fn handle_irq_exception() {
+    let entry = Timer::counter();
+
     // 1. Acknowledge interrupt from GIC
     let iar = Gic::acknowledge();
     let irq_id = iar & 0x3FF; // Lower 10 bits are the ID
 
+    if irq_id == SPURIOUS_IRQ {
+        SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+        return; // Don't EOI spurious
+    }
+    IRQ_COUNT.fetch_add(1, Ordering::Relaxed);
+    if let Some(counter) = IRQ_COUNTERS.get(irq_id as usize) {
+        counter.count.fetch_add(1, Ordering::Relaxed);
+    }
+
     // 2. Handle the interrupt
     match irq_id {
-        27 | 30 => {
+        timer::VIRT_TIMER_IRQ | timer::PHYS_TIMER_IRQ => {
             // Timer Interrupt
+            record_timer_latency(irq_id, entry.saturating_sub(Timer::deadline()));
+
             // CRITICAL: Rearm timer and EOI BEFORE kernel_tick because 
             // kernel_tick may context switch and never return!
-            Timer::set_next_tick(Duration::from_millis(50)); // 50ms timer tick
+            Timer::set_next_tick(Duration::from_millis(TICK_MS));
             Gic::end_interrupt(iar);
             
             extern "Rust" { fn kernel_tick(); }
             unsafe { kernel_tick(); }
             return; // EOI already done above
         }
-        33 => {
-            // UART Interrupt
-            crate::uart::handle_irq();
-        }
-        1023 => {
-            // Spurious - ignore
-            return; // Don't EOI spurious
-        }
-        _ => {
-            println!("[IRQ] Unknown interrupt ID: {}", irq_id);
+        // SGIs are edge-triggered, so they can be EOI'd before the handler
+        // runs. This lets e.g. the reschedule handler switch tasks.
+        0..=15 => {
+            Gic::end_interrupt(iar);
+            dispatch(irq_id);
+            return; // EOI already done above
         }
+        // Device interrupts go to the handler their driver registered
+        _ => dispatch(irq_id),
     }
 
     // 3. Signal End Of Interrupt to GIC
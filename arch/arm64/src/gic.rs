@@ -6,18 +6,102 @@
 // The GIC consists of:
 // - Distributor: Prioritizes and routes interrupts to CPUs.
 // - CPU Interface: Handles interrupt masking and acknowledgement for a specific CPU.
+//
+// Drivers claim their interrupts with `register_handler` and `enable_irq`;
+// the IRQ exception handler dispatches through the handler table. A line
+// can also be masked temporarily (`mask_irq`, `IrqMaskGuard`) without
+// affecting whether its driver has it enabled.
 // =============================================================================
 
 use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+/// Number of interrupt IDs with a handler slot (SGIs, PPIs and SPIs)
+pub const MAX_IRQS: usize = 256;
+
+/// Interrupt handler, called with the interrupt ID
+pub type IrqHandler = fn(u32);
+
+/// Registered handlers, indexed by interrupt ID. Read-only once booted
+/// (see `mmu::protect_tables`); only updated with IRQs masked.
+#[link_section = ".data.ro_after_init"]
+static mut HANDLERS: Handlers = Handlers([None; MAX_IRQS]);
+
+/// The handler table, padded to whole pages so it can be protected alone
+#[repr(C, align(4096))]
+struct Handlers([Option<IrqHandler>; MAX_IRQS]);
+
+fn handlers() -> &'static [Option<IrqHandler>; MAX_IRQS] {
+    unsafe { &(*core::ptr::addr_of!(HANDLERS)).0 }
+}
+
+/// Which CPUs receive a software-generated interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SgiTarget {
+    /// CPU interfaces in the mask (bit n = CPU n)
+    Cpus(u8),
+    /// Every CPU except the sender
+    Others,
+    /// Only the sending CPU
+    Current,
+}
+
+/// Trigger mode of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Asserted while the device holds the line (e.g. PL011)
+    Level,
+    /// Asserted once per rising edge (e.g. virtio-mmio)
+    Edge,
+}
+
+/// Lines their driver has enabled (bitmap), restored by `unmask_irq`
+static WANTED: [AtomicU32; MAX_IRQS / 32] = [const { AtomicU32::new(0) }; MAX_IRQS / 32];
+
+/// Outstanding `mask_irq` calls per line
+static MASK_DEPTH: [AtomicU8; MAX_IRQS] = [const { AtomicU8::new(0) }; MAX_IRQS];
+
+/// Trigger mode each driver declared (0 = none, 1 = level, 2 = edge),
+/// checked against the hardware by `dump`
+static DECLARED: [AtomicU8; MAX_IRQS] = [const { AtomicU8::new(0) }; MAX_IRQS];
+
 
 // QEMU virt machine GICv2 base addresses
-const GICD_BASE: usize = 0x0800_0000;
-const GICC_BASE: usize = 0x0801_0000;
+const GICD_PHYS: usize = 0x0800_0000;
+const GICC_PHYS: usize = 0x0801_0000;
+const GICD_BASE: usize = crate::mmu::phys_to_virt(GICD_PHYS);
+const GICC_BASE: usize = crate::mmu::phys_to_virt(GICC_PHYS);
+
+/// Physical MMIO regions of the distributor and CPU interface
+pub const MMIO_REGIONS: [(usize, usize); 2] = [(GICD_PHYS, 0x1_0000), (GICC_PHYS, 0x1_0000)];
 
 // Distributor Registers
 const GICD_CTLR: usize = 0x000;       // Control Register
+const GICD_TYPER: usize = 0x004;      // Interrupt Controller Type Register
 const GICD_ISENABLER: usize = 0x100;  // Interrupt Set-Enable Registers
+const GICD_ICENABLER: usize = 0x180;  // Interrupt Clear-Enable Registers
+const GICD_ISPENDR: usize = 0x200;    // Interrupt Set-Pending Registers
+const GICD_IPRIORITYR: usize = 0x400; // Interrupt Priority Registers
 const GICD_ITARGETSR: usize = 0x800;  // Interrupt Processor Targets Registers
+const GICD_ICFGR: usize = 0xC00;      // Interrupt Configuration Registers
+const GICD_SGIR: usize = 0xF00;       // Software Generated Interrupt Register
+
+/// Reserved SGI IDs (inter-processor interrupts)
+/// Ask the target CPU to run the scheduler
+pub const SGI_RESCHEDULE: u32 = 0;
+/// Wake the target CPU from `wfi`/`wfe`
+pub const SGI_WAKEUP: u32 = 1;
+/// Debug / diagnostics requests
+pub const SGI_DEBUG: u32 = 2;
+
+/// First Private Peripheral Interrupt (IDs 0-15 are SGIs)
+pub const PPI_BASE: u32 = 16;
+/// First Shared Peripheral Interrupt
+pub const SPI_BASE: u32 = 32;
+
+/// Priority given to device interrupts unless a driver asks otherwise
+/// (lower value = higher priority)
+pub const DEFAULT_PRIORITY: u8 = 0xA0;
 
 // CPU Interface Registers
 const GICC_CTLR: usize = 0x0000;      // Control Register
@@ -39,40 +123,8 @@ impl Gic {
         // Enable the distributor
         write_gicd(GICD_CTLR, 1);
 
-        // Enable the timer interrupt (ID 27 for virtual timer)
-        let timer_irq = 27;
-        let reg_offset = (timer_irq / 32) * 4;
-        let bit = 1 << (timer_irq % 32);
-        
-        // Read-Modify-Write
-        let mut current_enable = read_gicd(GICD_ISENABLER + reg_offset);
-        current_enable |= bit;
-        write_gicd(GICD_ISENABLER + reg_offset, current_enable);
-
-        // Enable UART Interrupt (ID 33)
-        // ID 33 is likely in ISENABLER1 (32-63)
-        let uart_irq = 33;
-        let reg_offset_u = (uart_irq / 32) * 4;
-        let bit_u = 1 << (uart_irq % 32);
-        
-        let mut current_enable_u = read_gicd(GICD_ISENABLER + reg_offset_u);
-        current_enable_u |= bit_u;
-        write_gicd(GICD_ISENABLER + reg_offset_u, current_enable_u);
-
-        // Route UART Interrupt (SPI 33) to CPU 0
-        // ITARGETSR registers are byte-accessible or word-accessible.
-        // Each interrupt has an 8-bit field.
-        // Offset for ID 33: 0x800 + 33 -> But let's align to 4 bytes.
-        // ID 32-35 are in ITARGETSR8 (Offset 0x820).
-        let target_reg_offset = (uart_irq / 4) * 4; // 33/4 = 8 * 4 = 32 (0x20)
-        let target_shift = (uart_irq % 4) * 8;      // 33%4 = 1 * 8 = 8
-        
-        // Read-Modify-Write target register
-        let mut current_target = read_gicd(GICD_ITARGETSR + target_reg_offset);
-        // Set target for ID 33 to CPU 0 (Map: 0x01) or All (0xFF)
-        // We'll trust CPU 0 is bit 0.
-        current_target |= 0x01 << target_shift; // CPU Interface 0
-        write_gicd(GICD_ITARGETSR + target_reg_offset, current_target);
+        // Interrupt lines (timer, UART, ...) are enabled by their drivers
+        // through `enable_irq`, using IDs discovered from the device tree.
 
         // ---------------------------------------------------------------------
         // 2. CPU Interface Initialization
@@ -97,6 +149,357 @@ impl Gic {
     }
 }
 
+/// Install the handler for an interrupt ID, replacing any previous one.
+///
+/// Returns `false` if `irq` is out of range. The interrupt still has to be
+/// enabled with `enable_irq`.
+pub fn register_handler(irq: u32, handler: IrqHandler) -> bool {
+    let index = irq as usize;
+    if index >= MAX_IRQS {
+        return false;
+    }
+    // Mask IRQs so the dispatcher never sees a half-written entry
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let table = core::ptr::addr_of_mut!(HANDLERS);
+    let len = core::mem::size_of::<Handlers>();
+    let locked = crate::mmu::unlock(table as *const u8, len);
+    unsafe { (*table).0[index] = Some(handler); }
+    if locked {
+        crate::mmu::relock(table as *const u8, len);
+    }
+    crate::cpu::restore_interrupts(daif);
+    true
+}
+
+/// Number of interrupt IDs implemented by the distributor.
+pub fn num_irqs() -> u32 {
+    let it_lines = unsafe { read_gicd(GICD_TYPER) } & 0x1F;
+    (it_lines + 1) * 32
+}
+
+/// Check that `irq` is an implemented interrupt ID.
+fn valid(irq: u32) -> bool {
+    irq < num_irqs()
+}
+
+/// Enable an interrupt in the distributor. SPIs are also routed to CPU 0.
+///
+/// The GIC must be initialized, and the driver should have registered a
+/// handler for `irq` first. If the line is currently masked (see
+/// `mask_irq`), it is enabled once fully unmasked. Returns `false` for an
+/// invalid ID.
+pub fn enable_irq(irq: u32) -> bool {
+    if !valid(irq) {
+        return false;
+    }
+    // SGIs/PPIs are banked per CPU and always target it
+    if irq >= SPI_BASE && target(irq) == 0 {
+        set_target(irq, 0x01);
+    }
+    if let Some(wanted) = WANTED.get(irq as usize / 32) {
+        wanted.fetch_or(bit(irq), Ordering::Relaxed);
+    }
+    if mask_depth(irq) == 0 {
+        hw_enable(irq);
+    }
+    true
+}
+
+/// Disable an interrupt in the distributor. It stays disabled across
+/// `unmask_irq` until enabled again. Returns `false` for an invalid ID.
+pub fn disable_irq(irq: u32) -> bool {
+    if !valid(irq) {
+        return false;
+    }
+    if let Some(wanted) = WANTED.get(irq as usize / 32) {
+        wanted.fetch_and(!bit(irq), Ordering::Relaxed);
+    }
+    hw_disable(irq);
+    true
+}
+
+/// Temporarily silence an interrupt line without changing whether its
+/// driver has it enabled. Masks nest; each needs a matching `unmask_irq`.
+///
+/// Prefer `IrqMaskGuard`, which unmasks on drop. Returns `false` for an
+/// invalid ID.
+pub fn mask_irq(irq: u32) -> bool {
+    let Some(depth) = MASK_DEPTH.get(irq as usize).filter(|_| valid(irq)) else {
+        return false;
+    };
+    // Masked before the count is visible, so unmask can't race ahead
+    hw_disable(irq);
+    depth.fetch_add(1, Ordering::AcqRel);
+    true
+}
+
+/// Undo one `mask_irq`. The line is re-enabled when the last mask is
+/// removed, if its driver still has it enabled.
+///
+/// Edges that arrive while masked stay pending in the distributor; use
+/// `pending` to check for them. Returns `false` if the line wasn't masked.
+pub fn unmask_irq(irq: u32) -> bool {
+    let Some(depth) = MASK_DEPTH.get(irq as usize) else {
+        return false;
+    };
+    if depth.load(Ordering::Acquire) == 0 {
+        return false;
+    }
+    if depth.fetch_sub(1, Ordering::AcqRel) == 1
+        && WANTED[irq as usize / 32].load(Ordering::Relaxed) & bit(irq) != 0
+    {
+        hw_enable(irq);
+    }
+    true
+}
+
+/// Number of outstanding `mask_irq` calls on a line.
+pub fn mask_depth(irq: u32) -> u8 {
+    MASK_DEPTH.get(irq as usize).map_or(0, |d| d.load(Ordering::Acquire))
+}
+
+/// Masks an interrupt line for as long as it lives (see `mask_irq`).
+pub struct IrqMaskGuard {
+    irq: u32,
+    masked: bool,
+}
+
+impl IrqMaskGuard {
+    /// Mask `irq` until the guard is dropped.
+    pub fn new(irq: u32) -> Self {
+        Self { irq, masked: mask_irq(irq) }
+    }
+}
+
+impl Drop for IrqMaskGuard {
+    fn drop(&mut self) {
+        if self.masked {
+            unmask_irq(self.irq);
+        }
+    }
+}
+
+fn hw_enable(irq: u32) {
+    // Write-1-to-set: other bits are unaffected
+    unsafe { write_gicd(GICD_ISENABLER + bank(irq), bit(irq)); }
+}
+
+fn hw_disable(irq: u32) {
+    // Write-1-to-clear: other bits are unaffected
+    unsafe { write_gicd(GICD_ICENABLER + bank(irq), bit(irq)); }
+}
+
+/// Whether an interrupt is enabled in the distributor.
+pub fn is_enabled(irq: u32) -> bool {
+    valid(irq) && unsafe { read_gicd(GICD_ISENABLER + bank(irq)) } & bit(irq) != 0
+}
+
+/// Whether an interrupt is pending in the distributor, e.g. an edge that
+/// arrived while the line was masked.
+pub fn pending(irq: u32) -> bool {
+    valid(irq) && unsafe { read_gicd(GICD_ISPENDR + bank(irq)) } & bit(irq) != 0
+}
+
+/// Set the priority of an interrupt (0 = highest). The GIC may ignore
+/// low-order bits. Returns `false` for an invalid ID.
+pub fn set_priority(irq: u32, priority: u8) -> bool {
+    if !valid(irq) {
+        return false;
+    }
+    write_byte_field(GICD_IPRIORITYR, irq, priority);
+    true
+}
+
+/// Priority of an interrupt.
+pub fn priority(irq: u32) -> u8 {
+    read_byte_field(GICD_IPRIORITYR, irq)
+}
+
+/// Route an SPI to the CPUs in `cpu_mask` (bit n = CPU interface n).
+///
+/// Returns `false` for SGIs and PPIs, whose targets are fixed, and for
+/// invalid IDs.
+pub fn set_target(irq: u32, cpu_mask: u8) -> bool {
+    if irq < SPI_BASE || !valid(irq) {
+        return false;
+    }
+    write_byte_field(GICD_ITARGETSR, irq, cpu_mask);
+    true
+}
+
+/// CPU mask an interrupt is routed to.
+pub fn target(irq: u32) -> u8 {
+    read_byte_field(GICD_ITARGETSR, irq)
+}
+
+/// Send software-generated interrupt `sgi` (0-15).
+///
+/// SGIs are dispatched through the handler table like any other
+/// interrupt, but are EOI'd before their handler runs so it may switch
+/// tasks. Returns `false` for an invalid SGI ID.
+pub fn send_sgi(sgi: u32, target: SgiTarget) -> bool {
+    if sgi >= PPI_BASE {
+        return false;
+    }
+    let (filter, cpus) = match target {
+        SgiTarget::Cpus(mask) => (0b00, mask),
+        SgiTarget::Others => (0b01, 0),
+        SgiTarget::Current => (0b10, 0),
+    };
+    unsafe { write_gicd(GICD_SGIR, (filter << 24) | ((cpus as u32) << 16) | sgi); }
+    true
+}
+
+/// Configure an interrupt as level- or edge-triggered.
+///
+/// SGIs are always edge-triggered and PPIs are fixed on QEMU's GIC, so for
+/// those this only checks that the line already has the requested mode.
+/// Returns `false` if the mode can't be applied or the ID is invalid.
+pub fn set_trigger(irq: u32, mode: TriggerMode) -> bool {
+    if !valid(irq) {
+        return false;
+    }
+    if let Some(slot) = DECLARED.get(irq as usize) {
+        slot.store(mode as u8 + 1, Ordering::Relaxed);
+    }
+    if irq < SPI_BASE {
+        return trigger(irq) == mode;
+    }
+
+    // Changing the mode of an enabled interrupt is UNPREDICTABLE
+    let enabled = is_enabled(irq);
+    if enabled {
+        hw_disable(irq);
+    }
+    let offset = GICD_ICFGR + (irq as usize / 16) * 4;
+    let edge_bit = 1 << ((irq % 16) * 2 + 1);
+    unsafe {
+        let current = read_gicd(offset);
+        let value = match mode {
+            TriggerMode::Edge => current | edge_bit,
+            TriggerMode::Level => current & !edge_bit,
+        };
+        write_gicd(offset, value);
+    }
+    if enabled {
+        hw_enable(irq);
+    }
+    trigger(irq) == mode
+}
+
+/// Trigger mode configured in the distributor.
+pub fn trigger(irq: u32) -> TriggerMode {
+    let offset = GICD_ICFGR + (irq as usize / 16) * 4;
+    let edge_bit = 1 << ((irq % 16) * 2 + 1);
+    if unsafe { read_gicd(offset) } & edge_bit != 0 {
+        TriggerMode::Edge
+    } else {
+        TriggerMode::Level
+    }
+}
+
+/// Trigger mode a driver declared with `set_trigger`, if any.
+fn declared_trigger(irq: u32) -> Option<TriggerMode> {
+    match DECLARED.get(irq as usize)?.load(Ordering::Relaxed) {
+        1 => Some(TriggerMode::Level),
+        2 => Some(TriggerMode::Edge),
+        _ => None,
+    }
+}
+
+/// Print enable, pending, priority, target and trigger state of every
+/// interrupt that is enabled, pending or has a handler.
+///
+/// The trigger column is flagged with `!` if it differs from what the
+/// driver declared, or `?` for an enabled line with no declared mode.
+pub fn dump() {
+    let handlers = {
+        let daif = crate::cpu::save_and_disable_interrupts();
+        let handlers = *handlers();
+        crate::cpu::restore_interrupts(daif);
+        handlers
+    };
+
+    crate::println!("GICv2: {} interrupt IDs", num_irqs());
+    crate::println!("  IRQ  TYPE  EN  PEND  PRIO  TARGET  TRIGGER  HANDLER");
+    for irq in 0..num_irqs() {
+        let handler = handlers.get(irq as usize).copied().flatten().is_some();
+        let (enabled, pending) = (is_enabled(irq), pending(irq));
+        if !enabled && !pending && !handler {
+            continue;
+        }
+        let kind = match irq {
+            0..=15 => "SGI",
+            16..=31 => "PPI",
+            _ => "SPI",
+        };
+        let yes_no = |b: bool| if b { "yes" } else { "no" };
+        let mode = trigger(irq);
+        let check = match declared_trigger(irq) {
+            Some(declared) if declared != mode => "!",
+            None if enabled => "?",
+            _ => "",
+        };
+        let mode = match mode {
+            TriggerMode::Level => "level",
+            TriggerMode::Edge => "edge",
+        };
+        crate::println!("  {:>3}  {:<4}  {:<3} {:<4}  {:#04x}  {:#04x}    {:<5}{:<2}  {}",
+            irq, kind, yes_no(enabled), yes_no(pending), priority(irq), target(irq),
+            mode, check, yes_no(handler));
+    }
+}
+
+/// Run the handler registered for `irq`. Called from the IRQ exception
+/// handler before EOI.
+///
+/// Returns `false` if no handler is registered; the caller accounts for
+/// unhandled interrupts.
+pub fn dispatch(irq: u32) -> bool {
+    let handler = handlers().get(irq as usize).copied().flatten();
+    match handler {
+        Some(handler) => {
+            handler(irq);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Whether a handler is registered for `irq`.
+pub fn has_handler(irq: u32) -> bool {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let registered = handlers().get(irq as usize).copied().flatten().is_some();
+    crate::cpu::restore_interrupts(daif);
+    registered
+}
+
+/// Offset of the 32-bit register holding `irq`'s bit in a bitmap bank.
+fn bank(irq: u32) -> usize {
+    (irq as usize / 32) * 4
+}
+
+fn bit(irq: u32) -> u32 {
+    1 << (irq % 32)
+}
+
+/// Read `irq`'s byte in a byte-per-interrupt register bank.
+fn read_byte_field(base: usize, irq: u32) -> u8 {
+    let offset = base + (irq as usize / 4) * 4;
+    let shift = (irq % 4) * 8;
+    (unsafe { read_gicd(offset) } >> shift) as u8
+}
+
+/// Update `irq`'s byte in a byte-per-interrupt register bank.
+fn write_byte_field(base: usize, irq: u32, value: u8) {
+    let offset = base + (irq as usize / 4) * 4;
+    let shift = (irq % 4) * 8;
+    unsafe {
+        let current = read_gicd(offset) & !(0xFF << shift);
+        write_gicd(offset, current | ((value as u32) << shift));
+    }
+}
+
 // Helper to read distributor register
 unsafe fn read_gicd(offset: usize) -> u32 {
     ptr::read_volatile((GICD_BASE + offset) as *const u32)
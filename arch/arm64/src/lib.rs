@@ -2,8 +2,10 @@
 // APRK OS - ARM64 Architecture Module
 // =============================================================================
 // This module contains all ARM64-specific code:
-// - UART driver for console output
+// - UART driver and console sinks for output
+// - Kernel log buffer
 // - Boot initialization
+// - Device tree parsing
 // - CPU utilities
 // - Exception handling
 // - Interrupt Controller
@@ -16,6 +18,9 @@
 #![no_std]
 
 pub mod uart;
+pub mod console;
+pub mod dtb;
+pub mod log;
 pub mod cpu;
 pub mod exception;
 pub mod gic;
@@ -23,30 +28,93 @@ pub mod timer;
 pub mod mmu;
 pub mod context;
 
-/// Initialize the ARM64 hardware for kernel operation.
+/// PL031 real-time clock on the QEMU virt machine
+const RTC_BASE: usize = 0x0901_0000;
+/// The two 64MB NOR flash banks on the QEMU virt machine
+const FLASH_BASE: usize = 0x0000_0000;
+const FLASH_SIZE: usize = 0x0800_0000;
+
+/// Map the MMIO regions of the devices the kernel touches. Everything else
+/// in the device space stays unmapped, so stray accesses fault.
+fn map_devices(ports: &[Option<uart::PortInfo>]) {
+    let uarts = ports.iter().flatten().map(|port| ("uart", port.base, 0x1000));
+    let gic = gic::MMIO_REGIONS.iter().map(|&(base, len)| ("gic", base, len));
+    let others = [("rtc", RTC_BASE, 0x1000), ("flash", FLASH_BASE, FLASH_SIZE)];
+    for (name, base, len) in uarts.chain(gic).chain(others) {
+        if !mmu::map_device(base, len) {
+            log_error!("mmu", "Failed to map {} at {:#x}", name, base);
+        }
+    }
+}
+
+/// RAM mapped by the boot page tables (boot.S): the first 1GB
+const BOOT_RAM: core::ops::Range<usize> = mmu::DEFAULT_RAM_BASE..mmu::DEFAULT_RAM_BASE + (1 << 30);
+
+/// Find the device tree: at `dtb_phys` (passed in x0 by the boot
+/// loader), or else where QEMU puts it for bare-metal images.
+fn find_dtb(dtb_phys: usize) -> Option<dtb::Dtb> {
+    [dtb_phys, dtb::DEFAULT_DTB_ADDR].into_iter()
+        .filter(|addr| BOOT_RAM.contains(addr))
+        // SAFETY: The boot tables map all of `BOOT_RAM`
+        .find_map(|addr| unsafe { dtb::init(mmu::phys_to_virt(addr)) })
+}
+
+/// Initialize the ARM64 hardware for kernel operation. `dtb_phys` is the
+/// device tree address the boot loader passed, or 0.
 /// 
 /// This function is called early in the boot process to set up
 /// essential hardware before the kernel can run properly.
 /// 
 /// # Safety
 /// This function must only be called once during boot.
-pub fn init() {
-    // 1. Initialize UART (for debug output)
-    uart::init();
+pub fn init(dtb_phys: usize) {
+    // 1. Find the UARTs in the device tree (or use the QEMU virt defaults)
+    //    and initialize the console UART (for debug output)
+    let dtb = find_dtb(dtb_phys);
+    let ports = uart::discover(dtb.as_ref());
+    if let Some(console) = ports[0] {
+        uart::init(console);
+    }
+    for (id, info) in ports.iter().enumerate().skip(1) {
+        uart::register_port(id, *info);
+    }
+    match dtb {
+        Some(dtb) => log_info!("dtb", "Device tree at {:#x} ({} bytes)", dtb.addr(), dtb.size()),
+        None => log_warn!("dtb", "No device tree found, using QEMU virt defaults"),
+    }
     
-    // 2. Initialize MMU (enable virtual memory & caches)
+    // 2. Initialize MMU (enable virtual memory & caches), mapping the RAM
+    //    the device tree describes
+    let (ram_base, ram_size) = match dtb.and_then(|dtb| dtb.memory()) {
+        Some((base, size)) => (base as usize, size as usize),
+        None => {
+            log_warn!("mmu", "No memory node, assuming {} MB", mmu::DEFAULT_RAM_SIZE >> 20);
+            (mmu::DEFAULT_RAM_BASE, mmu::DEFAULT_RAM_SIZE)
+        }
+    };
     // SAFETY: We trust our page table setup is correct
-    unsafe { mmu::init(); }
+    unsafe { mmu::init(ram_base, ram_size); }
+    map_devices(&ports);
+    let (ram_base, mapped) = mmu::ram();
+    if mapped < ram_size {
+        log_warn!("mmu", "Only {} of {} MB RAM mapped", mapped >> 20, ram_size >> 20);
+    }
+    log_info!("mmu", "RAM {:#x}-{:#x} ({} MB)", ram_base, ram_base + mapped, mapped >> 20);
     
     // 3. Initialize Exception Vectors
     unsafe { exception::init(); }
     
-    // 4. Initialize GIC (Interrupt Controller)
+    // 4. Initialize GIC (Interrupt Controller) and enable the console
+    //    UART's interrupt
     unsafe { gic::Gic::init(); }
+    uart::enable_irq(0);
     
     // 5. Initialize Timer
     timer::Timer::init();
     
     // 6. Enable Interrupts (CPU level)
     unsafe { cpu::enable_interrupts(); }
+
+    // 7. Switch console output to interrupt-driven transmit
+    uart::enable_tx_interrupts();
 }
@@ -0,0 +1,335 @@
+// =============================================================================
+// APRK OS - Kernel Log Buffer
+// =============================================================================
+// Every line printed by the kernel is also recorded here, so boot messages
+// can be reviewed (`dmesg`) after they have scrolled off the console.
+//
+// The buffer holds a fixed number of fixed-size lines. When it wraps, the
+// oldest lines are overwritten; sequence numbers keep increasing, so
+// readers can tell how many lines they missed.
+//
+// Subsystems log through the `log_*!` macros, which filter by level using
+// a global threshold plus optional per-tag overrides.
+// =============================================================================
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use crate::timer::Timer;
+
+/// Number of lines kept in the buffer
+const LOG_LINES: usize = 512;
+
+/// Maximum stored length of a single line (longer lines are truncated)
+const LINE_LEN: usize = 120;
+
+/// A single recorded log line.
+#[derive(Clone, Copy)]
+pub struct LogLine {
+    /// Sequence number, starting at 0 and never reused
+    pub seq: u64,
+    /// Uptime when the line was started
+    pub timestamp: Duration,
+    len: u8,
+    text: [u8; LINE_LEN],
+}
+
+impl LogLine {
+    const EMPTY: Self = Self { seq: 0, timestamp: Duration::ZERO, len: 0, text: [0; LINE_LEN] };
+
+    /// Line contents, without the trailing newline.
+    pub fn text(&self) -> &str {
+        let bytes = &self.text[..self.len as usize];
+        match core::str::from_utf8(bytes) {
+            Ok(s) => s,
+            // Truncation may have split a multi-byte character
+            Err(e) => unsafe { core::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+        }
+    }
+}
+
+struct KernelLog {
+    lines: [LogLine; LOG_LINES],
+    /// Sequence number of the next completed line
+    next_seq: u64,
+    /// Line being assembled until its newline arrives
+    current: LogLine,
+    /// `current` has at least one byte (or was started)
+    in_line: bool,
+}
+
+impl KernelLog {
+    const fn new() -> Self {
+        Self {
+            lines: [LogLine::EMPTY; LOG_LINES],
+            next_seq: 0,
+            current: LogLine::EMPTY,
+            in_line: false,
+        }
+    }
+
+    fn write(&mut self, s: &str) {
+        for byte in s.bytes() {
+            if !self.in_line {
+                self.in_line = true;
+                self.current.len = 0;
+                self.current.timestamp = Timer::uptime();
+            }
+            match byte {
+                b'\n' => self.commit(),
+                b'\r' => {}
+                _ => {
+                    let len = self.current.len as usize;
+                    if len < LINE_LEN {
+                        self.current.text[len] = byte;
+                        self.current.len += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn commit(&mut self) {
+        self.current.seq = self.next_seq;
+        self.lines[(self.next_seq % LOG_LINES as u64) as usize] = self.current;
+        self.next_seq += 1;
+        self.in_line = false;
+    }
+
+    fn oldest_seq(&self) -> u64 {
+        self.next_seq.saturating_sub(LOG_LINES as u64)
+    }
+}
+
+/// The kernel log. Only locked with IRQs masked (see `record`).
+static LOG: Mutex<KernelLog> = Mutex::new(KernelLog::new());
+
+/// Append printed text to the log.
+///
+/// Called from `_print` with IRQs already masked, so it is safe from
+/// interrupt handlers.
+pub(crate) fn record(s: &str) {
+    LOG.lock().write(s);
+}
+
+/// Return the oldest line with sequence number `seq` or later.
+///
+/// Returns `None` once the reader has caught up. If `seq` has already been
+/// overwritten, the returned line's `seq` is larger than requested.
+pub fn read_since(seq: u64) -> Option<LogLine> {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let result = {
+        let log = LOG.lock();
+        let seq = seq.max(log.oldest_seq());
+        if seq < log.next_seq {
+            Some(log.lines[(seq % LOG_LINES as u64) as usize])
+        } else {
+            None
+        }
+    };
+    crate::cpu::restore_interrupts(daif);
+    result
+}
+
+/// Sequence number the next completed line will get.
+pub fn next_seq() -> u64 {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let seq = LOG.lock().next_seq;
+    crate::cpu::restore_interrupts(daif);
+    seq
+}
+
+/// Number of lines lost because the buffer wrapped.
+pub fn lost() -> u64 {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let lost = LOG.lock().oldest_seq();
+    crate::cpu::restore_interrupts(daif);
+    lost
+}
+
+// =============================================================================
+// Log Levels
+// =============================================================================
+
+/// Message severity, most severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+
+    /// Parse a level name ("error", "warn", "info", "debug").
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+/// Maximum number of per-tag level overrides
+const MAX_OVERRIDES: usize = 16;
+
+/// Maximum length of a subsystem tag
+const TAG_LEN: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Override {
+    tag: [u8; TAG_LEN],
+    tag_len: usize,
+    level: Level,
+}
+
+impl Override {
+    fn tag(&self) -> &str {
+        // Only ever filled from a &str, cut at a char boundary
+        unsafe { core::str::from_utf8_unchecked(&self.tag[..self.tag_len]) }
+    }
+}
+
+/// Level used for tags without an override
+static GLOBAL_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Per-tag overrides. Only locked with IRQs masked.
+static OVERRIDES: Mutex<[Option<Override>; MAX_OVERRIDES]> = Mutex::new([None; MAX_OVERRIDES]);
+
+/// Global log level.
+pub fn level() -> Level {
+    Level::from_u8(GLOBAL_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Set the global log level.
+pub fn set_level(level: Level) {
+    GLOBAL_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Override the level for one subsystem tag, or clear it with `None`.
+///
+/// Returns `false` if the override table is full or the tag is too long.
+pub fn set_tag_level(tag: &str, level: Option<Level>) -> bool {
+    if tag.len() > TAG_LEN {
+        return false;
+    }
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let ok = {
+        let mut overrides = OVERRIDES.lock();
+        let existing = overrides.iter().position(|o| o.is_some_and(|o| o.tag() == tag));
+        match (existing, level) {
+            (Some(i), None) => {
+                overrides[i] = None;
+                true
+            }
+            (Some(i), Some(level)) => {
+                if let Some(o) = overrides[i].as_mut() {
+                    o.level = level;
+                }
+                true
+            }
+            (None, None) => true,
+            (None, Some(level)) => match overrides.iter_mut().find(|o| o.is_none()) {
+                Some(slot) => {
+                    let mut o = Override { tag: [0; TAG_LEN], tag_len: tag.len(), level };
+                    o.tag[..tag.len()].copy_from_slice(tag.as_bytes());
+                    *slot = Some(o);
+                    true
+                }
+                None => false,
+            },
+        }
+    };
+    crate::cpu::restore_interrupts(daif);
+    ok
+}
+
+/// Call `f` for each per-tag override.
+pub fn for_each_override(mut f: impl FnMut(&str, Level)) {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let overrides = *OVERRIDES.lock();
+    crate::cpu::restore_interrupts(daif);
+    for o in overrides.iter().flatten() {
+        f(o.tag(), o.level);
+    }
+}
+
+/// Check whether a message for `tag` at `level` should be printed.
+pub fn enabled(tag: &str, level: Level) -> bool {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let threshold = OVERRIDES.lock().iter()
+        .flatten()
+        .find(|o| o.tag() == tag)
+        .map(|o| o.level);
+    crate::cpu::restore_interrupts(daif);
+    level <= threshold.unwrap_or_else(self::level)
+}
+
+/// Print a tagged message if its level is enabled. Used by the `log_*!` macros.
+pub fn _log(level: Level, tag: &str, args: fmt::Arguments) {
+    if !enabled(tag, level) {
+        return;
+    }
+    match level {
+        Level::Error => crate::uart::_print(format_args!("[{}] ERROR: {}\n", tag, args)),
+        Level::Warn => crate::uart::_print(format_args!("[{}] WARNING: {}\n", tag, args)),
+        _ => crate::uart::_print(format_args!("[{}] {}\n", tag, args)),
+    }
+}
+
+/// Log an error for a subsystem tag.
+#[macro_export]
+macro_rules! log_error {
+    ($tag:expr, $($arg:tt)*) => {
+        $crate::log::_log($crate::log::Level::Error, $tag, format_args!($($arg)*))
+    };
+}
+
+/// Log a warning for a subsystem tag.
+#[macro_export]
+macro_rules! log_warn {
+    ($tag:expr, $($arg:tt)*) => {
+        $crate::log::_log($crate::log::Level::Warn, $tag, format_args!($($arg)*))
+    };
+}
+
+/// Log an informational message for a subsystem tag.
+#[macro_export]
+macro_rules! log_info {
+    ($tag:expr, $($arg:tt)*) => {
+        $crate::log::_log($crate::log::Level::Info, $tag, format_args!($($arg)*))
+    };
+}
+
+/// Log a debug message for a subsystem tag.
+///
+/// Compiled out of release builds, arguments and format string included.
+#[macro_export]
+macro_rules! log_debug {
+    ($tag:expr, $($arg:tt)*) => {{
+        #[cfg(debug_assertions)]
+        $crate::log::_log($crate::log::Level::Debug, $tag, format_args!($($arg)*));
+    }};
+}
@@ -2,10 +2,46 @@
 // APRK OS - Memory Management Unit (MMU)
 // =============================================================================
 // Handles virtual memory setup for ARM64.
-// For Phase 2, we implement a simple identity mapping (VA=PA).
+//
+// The address space is split in two 39-bit halves. The kernel lives in the
+// upper half (TTBR1), where physical memory is mapped linearly at
+// `KERNEL_OFFSET`: the RAM found at boot, plus only the MMIO regions
+// drivers ask for with `map_device`, so a stray access to any other
+// device address faults instead of hanging the bus.
+// The lower half (TTBR0) belongs to user tasks and holds nothing of the
+// kernel's, so EL0 can't even address kernel memory.
+//
+// W^X is enforced: the kernel image is mapped with 4KB pages (text RX,
+// rodata RO, data/bss/stack RW), everything else is never-execute, and
+// SCTLR_EL1.WXN makes any writable page non-executable.
+//
+// User mappings are non-global and tagged with the ASID of the
+// `AddressSpace` they belong to, so switching TTBR0 between address
+// spaces needs no TLB flush.
+//
+// On top of the boot-time block mappings, `map_page` / `unmap_page` manage
+// individual 4KB pages. Intermediate tables come from a pool in the
+// kernel image; a 1GB or 2MB block covering the page is split into a
+// next-level table with the same attributes first. `map_range` /
+// `unmap_range` do the same for whole ranges, using 2MB blocks wherever
+// both addresses are 2MB-aligned and demoting a block to a table when
+// only part of it is unmapped.
+//
+// Live leaf entries are only ever changed with break-before-make (invalid
+// entry, TLB invalidate, new entry), which `remap` exposes; debug builds
+// assert that nothing else overwrites one. Blocks are split into tables
+// the same way. TLB maintenance goes through `tlbi_va` / `tlbi_asid` /
+// `tlbi_all`, except inside the block split.
+//
+// Once booted, the translation tables (except the kernel image's L3
+// tables, which control the protection) and the `.data.ro_after_init`
+// statics are mapped read-only. Legitimate updates unlock the one page
+// they write and lock it again straight after.
 // =============================================================================
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
 
 // Number of entries in a page table
 const ENTRIES_COUNT: usize = 512;
@@ -28,6 +64,125 @@ const AP_RW_EL1_EL0: u64 = 1 << 6; // Read-Write EL1 & EL0
 // Shareability
 const SH_INNER: u64 = 3 << 8;
 
+// Descriptor types (bits [1:0])
+const DESC_VALID: u64 = 1 << 0;
+const DESC_TABLE: u64 = 0b11;     // L1/L2 table descriptor
+const DESC_PAGE: u64 = 0b11;      // L3 page descriptor
+const DESC_BLOCK: u64 = 0b01;     // L1/L2 block descriptor
+const DESC_TYPE_MASK: u64 = 0b11;
+
+const AP_RO: u64 = 1 << 7;        // Read-only (AP[2])
+const NG: u64 = 1 << 11;          // Not global: tagged with the current ASID
+const PXN: u64 = 1 << 53;         // Privileged Execute Never
+const UXN: u64 = 1 << 54;         // Unprivileged Execute Never
+const SW_COW: u64 = 1 << 55;      // Software bit: read-only copy-on-write page
+
+/// Output address bits of a descriptor
+const ADDR_MASK: u64 = 0x0000_FFFF_FFFF_F000;
+/// Attribute bits of a block/page descriptor (all but address and type)
+const ATTR_MASK: u64 = !(ADDR_MASK | DESC_TYPE_MASK);
+
+/// Size of a small page
+pub const PAGE_SIZE: usize = 4096;
+
+// VA bits translated at each level (4KB granule, 39-bit VA starting at L1)
+const L1_SHIFT: u32 = 30;
+const L2_SHIFT: u32 = 21;
+const L3_SHIFT: u32 = 12;
+
+/// Size of the TTBR0 (user) address space (T0SZ = 25)
+pub const VA_LIMIT: usize = 1 << 39;
+
+/// Start of the TTBR1 (kernel) address space (T1SZ = 25). Physical memory
+/// is mapped from here on: VA = PA + `KERNEL_OFFSET`.
+pub const KERNEL_OFFSET: usize = 0xFFFF_FF80_0000_0000;
+
+/// Kernel VA of a physical address, through the linear map.
+pub const fn phys_to_virt(pa: usize) -> usize {
+    pa + KERNEL_OFFSET
+}
+
+/// Physical address of a kernel VA in the linear map (the kernel image,
+/// heap and page tables). Use `translate` for other mappings.
+pub const fn virt_to_phys(va: usize) -> usize {
+    va - KERNEL_OFFSET
+}
+
+/// Memory type of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Write-back cacheable RAM
+    Normal,
+    /// Uncached RAM (e.g. buffers shared with devices)
+    NonCacheable,
+    /// Device-nGnRnE MMIO
+    Device,
+}
+
+/// Permissions and attributes of a 4KB mapping. Pages are always readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFlags {
+    pub write: bool,
+    pub execute: bool,
+    /// Accessible from EL0
+    pub user: bool,
+    pub memory: MemoryType,
+}
+
+impl PageFlags {
+    /// Kernel read-write data
+    pub const KERNEL_RW: Self = Self { write: true, execute: false, user: false, memory: MemoryType::Normal };
+    /// Kernel read-only data
+    pub const KERNEL_RO: Self = Self { write: false, execute: false, user: false, memory: MemoryType::Normal };
+    /// Kernel code
+    pub const KERNEL_RX: Self = Self { write: false, execute: true, user: false, memory: MemoryType::Normal };
+    /// Device registers
+    pub const DEVICE: Self = Self { write: true, execute: false, user: false, memory: MemoryType::Device };
+    /// User read-write data
+    pub const USER_RW: Self = Self { write: true, execute: false, user: true, memory: MemoryType::Normal };
+    /// User read-only data
+    pub const USER_RO: Self = Self { write: false, execute: false, user: true, memory: MemoryType::Normal };
+    /// User code
+    pub const USER_RX: Self = Self { write: false, execute: true, user: true, memory: MemoryType::Normal };
+
+    /// Decode the attribute bits of a block or page descriptor.
+    fn from_bits(desc: u64) -> Self {
+        let memory = match (desc >> 2) & 0b111 {
+            MT_DEVICE_NGNRNE => MemoryType::Device,
+            MT_NORMAL_NC => MemoryType::NonCacheable,
+            _ => MemoryType::Normal,
+        };
+        let user = desc & AP_RW_EL1_EL0 != 0;
+        let execute = if user { desc & UXN == 0 } else { desc & PXN == 0 };
+        Self { write: desc & AP_RO == 0, execute, user, memory }
+    }
+
+    /// Descriptor attribute bits for these flags.
+    fn bits(&self) -> u64 {
+        let attr = match self.memory {
+            MemoryType::Normal => (MT_NORMAL << 2) | SH_INNER,
+            MemoryType::NonCacheable => (MT_NORMAL_NC << 2) | SH_INNER,
+            MemoryType::Device => MT_DEVICE_NGNRNE << 2,
+        };
+        let ap = match (self.user, self.write) {
+            (false, true) => AP_RW_EL1,
+            (true, true) => AP_RW_EL1_EL0,
+            (false, false) => AP_RO,
+            (true, false) => AP_RO | AP_RW_EL1_EL0,
+        };
+        // Only the owning exception level may execute, and only if asked
+        let xn = match (self.user, self.execute) {
+            (_, false) => PXN | UXN,
+            (false, true) => UXN,
+            (true, true) => PXN,
+        };
+        attr | ap | xn | AF
+    }
+}
+
+/// Serializes page table updates
+static MAP_LOCK: Mutex<()> = Mutex::new(());
+
 /// A translation table (4KB).
 #[repr(C, align(4096))]
 struct Table {
@@ -35,17 +190,99 @@ struct Table {
 }
 
 // Statically allocate page tables.
+
+/// Root of the kernel half (TTBR1)
 #[no_mangle]
-static mut L1_TABLE: Table = Table { entries: [0; ENTRIES_COUNT] };
+#[link_section = ".bss.pgtables"]
+static mut KERNEL_L1: Table = Table { entries: [0; ENTRIES_COUNT] };
+
+/// Root of the user half (TTBR0), shared by all user tasks
+#[no_mangle]
+#[link_section = ".bss.pgtables"]
+static mut USER_L1: Table = Table { entries: [0; ENTRIES_COUNT] };
+
+/// Start of RAM on the QEMU virt machine, used if the device tree
+/// doesn't say
+pub const DEFAULT_RAM_BASE: usize = 0x4000_0000;
+/// RAM size assumed without a device tree (matches `make run`)
+pub const DEFAULT_RAM_SIZE: usize = 512 * 1024 * 1024;
+
+/// Most RAM the boot identity map can cover (one L2 table per GB)
+pub const MAX_RAM_SIZE: usize = 4 << 30;
+const RAM_L2_TABLES: usize = MAX_RAM_SIZE >> L1_SHIFT;
 
+/// L2 tables for the RAM identity map, one per 1GB of address space
 #[no_mangle]
-static mut L2_TABLE: Table = Table { entries: [0; ENTRIES_COUNT] };
+#[link_section = ".bss.pgtables"]
+static mut RAM_L2: [Table; RAM_L2_TABLES] =
+    [const { Table { entries: [0; ENTRIES_COUNT] } }; RAM_L2_TABLES];
+
+/// RAM covered by the identity map, set by `init`
+static RAM_BASE: AtomicUsize = AtomicUsize::new(0);
+static RAM_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Size of an L2 block
+const BLOCK_SIZE: usize = 1 << L2_SHIFT;
+
+/// L3 tables for the 2MB blocks holding the kernel image (which the
+/// linker script limits to 16MB from 0x4008_0000). They stay writable:
+/// read-only protection is switched through them.
+const KERNEL_L3_TABLES: usize = 9;
+static mut KERNEL_L3: [Table; KERNEL_L3_TABLES] =
+    [const { Table { entries: [0; ENTRIES_COUNT] } }; KERNEL_L3_TABLES];
+
+// Kernel image layout, from the linker script
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __kernel_end: u8;
+    static __pgtables_start: u8;
+    static __pgtables_end: u8;
+    static __ro_after_init_start: u8;
+    static __ro_after_init_end: u8;
+}
+
+/// Permissions for a page of the RAM blocks that hold the kernel image.
+///
+/// `addr` is the page's kernel VA.
+unsafe fn kernel_image_flags(addr: usize) -> PageFlags {
+    let sym = |s: &u8| s as *const u8 as usize;
+    if addr >= sym(&__text_start) && addr < sym(&__text_end) {
+        PageFlags::KERNEL_RX
+    } else if addr < sym(&__text_start)
+        || (addr >= sym(&__rodata_start) && addr < sym(&__rodata_end))
+    {
+        // Below the kernel is the device tree, only ever read
+        PageFlags::KERNEL_RO
+    } else {
+        // data, bss and the boot stack, then RAM past the image
+        PageFlags::KERNEL_RW
+    }
+}
+
+/// Base and size of the RAM identity-mapped by `init`.
+///
+/// This may be less than the machine has: the size is rounded down to
+/// 2MB and capped at `MAX_RAM_SIZE`.
+pub fn ram() -> (usize, usize) {
+    (RAM_BASE.load(Ordering::Relaxed), RAM_SIZE.load(Ordering::Relaxed))
+}
 
-/// Initialize the MMU.
+/// Initialize the MMU: build the kernel's linear map of exactly the RAM
+/// at `[ram_base, ram_base + ram_size)`, and give TTBR0 an empty user
+/// table. No MMIO is mapped: call `map_device` for each device before
+/// touching it (the console UART included, before the next print).
+///
+/// `ram_base` must be 2MB-aligned and contain the kernel image. This
+/// replaces the boot tables set up in boot.S, which already run the
+/// kernel from the upper half.
 /// 
 /// # Safety
 /// Must only be called during boot. Changes memory view globally.
-pub unsafe fn init() {
+pub unsafe fn init(ram_base: usize, ram_size: usize) {
     // -------------------------------------------------------------------------
     // 1. Setup MAIR_EL1 (Memory Attribute Indirection Register)
     // -------------------------------------------------------------------------
@@ -55,74 +292,100 @@ pub unsafe fn init() {
     asm!("msr mair_el1, {}", in(reg) mair_val);
 
     // -------------------------------------------------------------------------
-    // 2. Setup Page Tables (Identity Map 0-2GB)
+    // 2. Setup Page Tables (Linear Map of RAM in the kernel half; MMIO is
+    //    added region by region with `map_device`)
     // -------------------------------------------------------------------------
-    let l1_table_ptr = core::ptr::addr_of_mut!(L1_TABLE);
-
-    // Entry 0: 0-1GB (Devices / MMIO)
-    (*l1_table_ptr).entries[0] = 
-        0x0000_0000 | 
-        PROT_VALID | 
-        PROT_BLOCK | 
-        (MT_DEVICE_NGNRNE << 2) | 
-        AP_RW_EL1 |
-        AF;
-
-    let l2_table_ptr = core::ptr::addr_of_mut!(L2_TABLE);
-
-    // Entry 1: 1GB-2GB (RAM at 0x4000_0000) - Point to L2 Table
-    // Descriptor: 0x4000_0000 (ignored for Table?) -> Addr of L2 Table | Valid | Table
-    (*l1_table_ptr).entries[1] = 
-        (l2_table_ptr as u64) | // Point to next level table
-        PROT_VALID | 
-        (1 << 1); // 1 = Table Descriptor (for L0/L1/L2), 0 = Block
-
-    // Populate L2 Table (512 entries, each 2MB)
-    // Covers 0x4000_0000 to 0x7FFF_FFFF (1GB)
-    for i in 0..ENTRIES_COUNT {
-        let addr = 0x4000_0000 + (i as u64 * 0x200000); // 2MB = 0x200000
-        
-        // Permissions:
-        // First 1 entry (2MB) -> Kernel Code/Data -> EL1 Only
-        // Rest (User Code + Heap) -> EL0 Accessible
-        let ap = if i < 1 { AP_RW_EL1 } else { AP_RW_EL1_EL0 };
+    // The L1 index is VA bits [38:30] in both halves, so kernel VA
+    // `phys_to_virt(pa)` uses the same slots an identity map of `pa` would.
+    // Descriptors hold physical addresses of the tables they point to.
+    let l1_table_ptr = core::ptr::addr_of_mut!(KERNEL_L1);
+
+    // RAM above the last L2 table can't be mapped
+    let first_gb = ram_base >> L1_SHIFT;
+    let ram_limit = ((first_gb + RAM_L2_TABLES) << L1_SHIFT) - ram_base;
+    let ram_size = ram_size.min(ram_limit) & !(BLOCK_SIZE - 1);
+    RAM_BASE.store(ram_base, Ordering::Relaxed);
+    RAM_SIZE.store(ram_size, Ordering::Relaxed);
+
+    // RAM: one L1 entry per GB touched, each pointing to an L2 table of
+    // 2MB blocks. Entries past the end of RAM stay invalid.
+    let ram_end = ram_base + ram_size;
+    for gb in first_gb..ram_end.div_ceil(1 << L1_SHIFT) {
+        let l2 = core::ptr::addr_of_mut!(RAM_L2[gb - first_gb]);
+        (*l1_table_ptr).entries[gb] = virt_to_phys(l2 as usize) as u64 | DESC_TABLE;
+    }
+
+    let kernel_end = virt_to_phys(core::ptr::addr_of!(__kernel_end) as usize);
+    let kernel_blocks = (kernel_end - ram_base).div_ceil(BLOCK_SIZE);
+    for i in 0..ram_size / BLOCK_SIZE {
+        let addr = (ram_base + i * BLOCK_SIZE) as u64;
+        let l2_table_ptr = core::ptr::addr_of_mut!(RAM_L2[(addr as usize >> L1_SHIFT) - first_gb]);
+        let slot = index(addr as usize, L2_SHIFT);
+
+        // Blocks holding the kernel image get 4KB pages with per-section
+        // permissions
+        if i < kernel_blocks.min(KERNEL_L3_TABLES) {
+            // `i` is below `KERNEL_L3_TABLES`, so in bounds
+            let l3 = core::ptr::addr_of_mut!(KERNEL_L3).cast::<Table>().add(i);
+            for (j, entry) in (*l3).entries.iter_mut().enumerate() {
+                let page = addr as usize + j * PAGE_SIZE;
+                *entry = page as u64 | kernel_image_flags(phys_to_virt(page)).bits() | DESC_PAGE;
+            }
+            (*l2_table_ptr).entries[slot] = virt_to_phys(l3 as usize) as u64 | DESC_TABLE;
+            continue;
+        }
         
-        (*l2_table_ptr).entries[i] = 
+        // Permissions: EL1 only, never executable (code must be mapped
+        // explicitly, see `map_page`)
+        (*l2_table_ptr).entries[slot] = 
             addr |
             PROT_VALID | 
             PROT_BLOCK | // L2 Block = 2MB
             (MT_NORMAL << 2) | 
-            ap |
+            AP_RW_EL1 |
             SH_INNER | 
+            PXN | UXN |
             AF;
     }
 
     // -------------------------------------------------------------------------
     // 3. Setup TCR_EL1 (Translation Control Register)
     // -------------------------------------------------------------------------
-    // T0SZ = 25 (39-bit VA)
-    // TG0 = 0 (4KB granule)
-    // SH0 = 3 (Inner Shareable)
-    // ORGN0/IRGN0 = 1 (Normal WB Write-Back Cacheable)
+    // T0SZ/T1SZ = 25 (39-bit VA in each half)
+    // A1 = 0 (TTBR0 holds the ASID), AS = 0 (8-bit ASIDs)
+    // TG0 = 0, TG1 = 2 (4KB granule)
+    // SH0/SH1 = 3 (Inner Shareable)
+    // ORGNn/IRGNn = 1 (Normal WB Write-Back Cacheable)
+    // Same as boot.S sets up; rewritten here so the two can't drift
     let tcr_val: u64 = (25 << 0)  | // T0SZ
                        (3 << 12) | // SH0
                        (1 << 10) | // ORGN0
                        (1 << 8)  | // IRGN0
                        (0 << 14) | // TG0 (4KB)
+                       (25 << 16) | // T1SZ
+                       (1 << 24) | // IRGN1
+                       (1 << 26) | // ORGN1
+                       (3 << 28) | // SH1
+                       (2 << 30) | // TG1 (4KB)
                        (2 << 32);  // IPS (40-bit PA)
     asm!("msr tcr_el1, {}", in(reg) tcr_val);
 
     // -------------------------------------------------------------------------
-    // 4. Invalidate TLBs to ensure no stale mappings
+    // 4. Switch TTBR1_EL1 to the kernel tables, TTBR0_EL1 to the (empty)
+    //    user tables, and drop the boot mappings from the TLBs. The boot
+    //    tables map the kernel at the same addresses, so we keep running.
     // -------------------------------------------------------------------------
-    asm!("tlbi vmalle1is", "dsb sy", "isb");
+    let ttbr1 = virt_to_phys(l1_table_ptr as usize) as u64;
+    let ttbr0 = boot_ttbr();
+    asm!("dsb ishst");
+    asm!("msr ttbr1_el1, {}", in(reg) ttbr1);
+    asm!("msr ttbr0_el1, {}", in(reg) ttbr0);
+    asm!("isb");
+    tlbi_all();
 
     // -------------------------------------------------------------------------
-    // 5. Set TTBR0_EL1 and Enable MMU
+    // 5. Enable MMU features (translation itself is already on)
     // -------------------------------------------------------------------------
-    let ttbr0 = l1_table_ptr as u64;
-    asm!("msr ttbr0_el1, {}", in(reg) ttbr0);
-    asm!("isb");
 
     let mut sctlr: u64;
     asm!("mrs {}, sctlr_el1", out(reg) sctlr);
@@ -130,9 +393,1233 @@ pub unsafe fn init() {
     // crate::println!("[mmu] SCTLR before: {:#x}", sctlr);
     
     sctlr |= 1 | (1 << 2) | (1 << 12); // M, C, I bits
-    sctlr &= !(1 << 19); // Clear WXN (Write Execute Never) to allow Executing RW pages (Phase 2 MVP)
+    sctlr |= 1 << 19; // WXN: writable pages are never executable
     
     asm!("msr sctlr_el1, {}", in(reg) sctlr);
     
     asm!("isb");
 }
+
+// =============================================================================
+// 4KB Page Mapping
+// =============================================================================
+
+fn index(va: usize, shift: u32) -> usize {
+    (va >> shift) & (ENTRIES_COUNT - 1)
+}
+
+/// Table referenced by a table descriptor, through the linear map.
+fn table_ptr(desc: u64) -> *mut Table {
+    phys_to_virt((desc & ADDR_MASK) as usize) as *mut Table
+}
+
+/// Root table of the half `va` is in, or `None` for a non-canonical VA.
+/// User VAs resolve in the active address space.
+fn root(va: usize) -> Option<*mut Table> {
+    if va >= KERNEL_OFFSET {
+        Some(core::ptr::addr_of_mut!(KERNEL_L1))
+    } else if va < VA_LIMIT {
+        Some(table_ptr(read_ttbr0()))
+    } else {
+        None
+    }
+}
+
+/// Get the next-level table below `entry`, a descriptor at the level that
+/// translates `shift` bits, on the way to `va`.
+///
+/// An empty entry gets a fresh zeroed table; a block is split into a
+/// table of smaller blocks (or pages) with identical attributes, with
+/// break-before-make. Returns
+/// the table and whether a live block was split, or `None` if no memory
+/// is available for the table.
+///
+/// # Safety
+/// `entry` must point into a live translation table; `MAP_LOCK` held.
+unsafe fn next_table(entry: *mut u64, shift: u32, va: usize) -> Option<(*mut Table, bool)> {
+    let desc = *entry;
+    if desc & DESC_TYPE_MASK == DESC_TABLE {
+        return Some((table_ptr(desc), false));
+    }
+
+    let table = alloc_table()?;
+    let split = desc & DESC_VALID != 0;
+    if split {
+        let child_shift = shift - 9;
+        let child_type = if child_shift == L3_SHIFT { DESC_PAGE } else { DESC_BLOCK };
+        let base = desc & ADDR_MASK & !((1u64 << shift) - 1);
+        let attrs = desc & ATTR_MASK;
+        with_writable(table as usize, || {
+            for (i, child) in (*table).entries.iter_mut().enumerate() {
+                *child = (base + ((i as u64) << child_shift)) | attrs | child_type;
+            }
+        });
+    }
+
+    // The table must be visible to the walker before it is linked in
+    asm!("dsb ishst");
+    let table_desc = virt_to_phys(table as usize) as u64 | DESC_TABLE;
+    if split {
+        // Break the block (invalid entry, TLB invalidate by VA for every
+        // ASID, since these tables may not be active), then link the
+        // table. One asm block, so nothing touches memory, the stack
+        // included, while the block is unmapped: it may hold the stack
+        let operand = ((va >> 12) & 0xFFF_FFFF_FFFF) as u64;
+        with_writable(entry as usize, || asm!(
+            "str xzr, [{entry}]",
+            "dsb ishst",
+            "tlbi vaae1is, {operand}",
+            "dsb ish",
+            "str {desc}, [{entry}]",
+            "dsb ishst",
+            "isb",
+            entry = in(reg) entry,
+            operand = in(reg) operand,
+            desc = in(reg) table_desc,
+            options(nostack),
+        ));
+    } else {
+        write_desc(entry, table_desc);
+    }
+    Some((table, split))
+}
+
+/// Find (creating or splitting a 1GB block as needed) the L2 entry for
+/// `va`.
+///
+/// Returns the entry and whether a block was split on the way.
+///
+/// # Safety
+/// `MAP_LOCK` must be held.
+unsafe fn l2_entry(va: usize) -> Option<(*mut u64, bool)> {
+    let l1 = root(va)?;
+    let (l2, split) = next_table(&mut (*l1).entries[index(va, L1_SHIFT)], L1_SHIFT, va)?;
+    Some((&mut (*l2).entries[index(va, L2_SHIFT)], split))
+}
+
+/// Find (creating or splitting as needed) the L3 entry for `va`.
+///
+/// Returns the entry and whether a block was split on the way.
+///
+/// # Safety
+/// `MAP_LOCK` must be held.
+unsafe fn l3_entry(va: usize) -> Option<(*mut u64, bool)> {
+    l3_entry_in(root(va)?, va)
+}
+
+/// `l3_entry` in the tables under root `l1`, which need not be active.
+///
+/// # Safety
+/// `l1` must be a live root table; `MAP_LOCK` held.
+unsafe fn l3_entry_in(l1: *mut Table, va: usize) -> Option<(*mut u64, bool)> {
+    let (l2, split1) = next_table(&mut (*l1).entries[index(va, L1_SHIFT)], L1_SHIFT, va)?;
+    let (l3, split2) = next_table(&mut (*l2).entries[index(va, L2_SHIFT)], L2_SHIFT, va)?;
+    Some((&mut (*l3).entries[index(va, L3_SHIFT)], split1 || split2))
+}
+
+// -----------------------------------------------------------------------------
+// TLB maintenance. Each helper orders earlier table writes before the
+// invalidation (DSB ISHST), waits for it to complete on all CPUs (DSB ISH)
+// and resynchronizes this CPU's instruction stream (ISB).
+// -----------------------------------------------------------------------------
+
+/// Invalidate the translations of the page at `va` tagged with `asid`, and
+/// any global (kernel) translation of it, including cached walks.
+pub fn tlbi_va(va: usize, asid: u16) {
+    // ASID in [63:48], VA[55:12] in [43:0]
+    let operand = (asid as u64) << TTBR_ASID_SHIFT | ((va >> 12) & 0xFFF_FFFF_FFFF) as u64;
+    unsafe { asm!("dsb ishst", "tlbi vae1is, {}", "dsb ish", "isb", in(reg) operand); }
+}
+
+/// Invalidate every non-global translation tagged with `asid`.
+pub fn tlbi_asid(asid: u16) {
+    let operand = (asid as u64) << TTBR_ASID_SHIFT;
+    unsafe { asm!("dsb ishst", "tlbi aside1is, {}", "dsb ish", "isb", in(reg) operand); }
+}
+
+/// Invalidate all translations, of every ASID.
+pub fn tlbi_all() {
+    unsafe { asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb"); }
+}
+
+/// The ASID translations of `va` are tagged with: the active address
+/// space's for a user VA. Kernel mappings are global, so any will do.
+fn asid_of(va: usize) -> u16 {
+    if va < VA_LIMIT { (read_ttbr0() >> TTBR_ASID_SHIFT) as u16 } else { 0 }
+}
+
+/// Panic when a live leaf entry is overwritten with a different live one,
+/// i.e. without break-before-make. On in debug builds.
+static BBM_ASSERT: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+
+/// Turn the break-before-make assertion on or off.
+pub fn set_bbm_assert(on: bool) {
+    BBM_ASSERT.store(on, Ordering::Relaxed);
+}
+
+/// Store a page or block descriptor.
+///
+/// # Safety
+/// `entry` must point into a live translation table; `MAP_LOCK` held.
+unsafe fn write_leaf(entry: *mut u64, desc: u64) {
+    if BBM_ASSERT.load(Ordering::Relaxed) {
+        let old = core::ptr::read_volatile(entry);
+        assert!(old & DESC_VALID == 0 || desc & DESC_VALID == 0 || old == desc,
+            "mmu: live entry {:#x} replaced by {:#x} without break-before-make", old, desc);
+    }
+    write_desc(entry, desc);
+}
+
+/// Replace the page or block descriptor at `entry`, which translates `va`
+/// under `asid`, with `desc`. A live entry is broken first: invalidated
+/// and flushed from the TLB, so the old and new translations can never
+/// both be cached.
+///
+/// # Safety
+/// `entry` must point into a live translation table; `MAP_LOCK` held.
+unsafe fn replace_leaf(entry: *mut u64, va: usize, asid: u16, desc: u64) {
+    let old = core::ptr::read_volatile(entry);
+    if old == desc {
+        return;
+    }
+    if old & DESC_VALID != 0 {
+        write_leaf(entry, 0);
+        tlbi_va(va, asid);
+    }
+    write_leaf(entry, desc);
+    asm!("dsb ishst", "isb");
+}
+
+/// Map the 4KB page at `va` to physical page `pa`, replacing any existing
+/// mapping of that page.
+///
+/// `va` selects the half: user mappings are only allowed below
+/// `VA_LIMIT`. Returns `false` if an address is misaligned or out of
+/// range, or no memory is available for a page table.
+pub fn map_page(va: usize, pa: usize, flags: PageFlags) -> bool {
+    if !va.is_multiple_of(PAGE_SIZE) || !pa.is_multiple_of(PAGE_SIZE) || root(va).is_none()
+        || (flags.user && va >= KERNEL_OFFSET)
+    {
+        return false;
+    }
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let _guard = MAP_LOCK.lock();
+    let ok = unsafe {
+        match l3_entry(va) {
+            Some((entry, split)) => {
+                if split {
+                    // The block the page was part of may still be cached
+                    tlbi_all();
+                }
+                let ng = if va < VA_LIMIT { NG } else { 0 };
+                replace_leaf(entry, va, asid_of(va), pa as u64 | flags.bits() | ng | DESC_PAGE);
+                true
+            }
+            None => false,
+        }
+    };
+    drop(_guard);
+    crate::cpu::restore_interrupts(daif);
+    ok
+}
+
+/// Remove the mapping of the 4KB page at `va`. Accesses to it fault
+/// afterwards.
+///
+/// Returns `false` if the page wasn't mapped (or a block covering it
+/// couldn't be split).
+pub fn unmap_page(va: usize) -> bool {
+    let Some(l1) = root(va) else {
+        return false;
+    };
+    if !va.is_multiple_of(PAGE_SIZE) {
+        return false;
+    }
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let _guard = MAP_LOCK.lock();
+    let ok = unsafe {
+        let l1_desc = (*l1).entries[index(va, L1_SHIFT)];
+        let mapped = l1_desc & DESC_VALID != 0 && (l1_desc & DESC_TYPE_MASK == DESC_BLOCK || {
+            let l2_desc = (*table_ptr(l1_desc)).entries[index(va, L2_SHIFT)];
+            l2_desc & DESC_VALID != 0 && (l2_desc & DESC_TYPE_MASK == DESC_BLOCK
+                || (*table_ptr(l2_desc)).entries[index(va, L3_SHIFT)] & DESC_VALID != 0)
+        });
+        match mapped.then(|| l3_entry(va)).flatten() {
+            Some((entry, split)) => {
+                write_leaf(entry, 0);
+                if split { tlbi_all() } else { tlbi_va(va, asid_of(va)) }
+                true
+            }
+            None => false,
+        }
+    };
+    drop(_guard);
+    crate::cpu::restore_interrupts(daif);
+    ok
+}
+
+/// Point the live 4KB mapping at `va` to `new_pa` with `new_flags`, using
+/// break-before-make: the old entry is invalidated and flushed from the
+/// TLB before the new one is written. Accesses to the page in between
+/// fault, so the caller must not rely on it meanwhile.
+///
+/// Returns `false` if `va` isn't mapped, is part of a larger block, or an
+/// address is misaligned.
+pub fn remap(va: usize, new_pa: usize, new_flags: PageFlags) -> bool {
+    if !va.is_multiple_of(PAGE_SIZE) || !new_pa.is_multiple_of(PAGE_SIZE) || root(va).is_none()
+        || (new_flags.user && va >= KERNEL_OFFSET)
+    {
+        return false;
+    }
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let _guard = MAP_LOCK.lock();
+    let entry = lookup(va);
+    let ok = entry.desc.is_some() && entry.size == PAGE_SIZE && unsafe {
+        // Already a page, so nothing gets split
+        l3_entry(va).is_some_and(|(entry, _)| {
+            let ng = if va < VA_LIMIT { NG } else { 0 };
+            replace_leaf(entry, va, asid_of(va), new_pa as u64 | new_flags.bits() | ng | DESC_PAGE);
+            true
+        })
+    };
+    drop(_guard);
+    crate::cpu::restore_interrupts(daif);
+    ok
+}
+
+// =============================================================================
+// Range Mapping
+// =============================================================================
+
+/// Map `[va, va + len)` to the physical range at `pa`, replacing existing
+/// mappings. Wherever both addresses are 2MB-aligned with at least 2MB
+/// left, a single 2MB block is used; the edges get 4KB pages.
+///
+/// Returns `false` if an address or the length isn't page-aligned, the
+/// range crosses out of its half, or a page table couldn't be allocated
+/// (some of the range may be mapped by then).
+pub fn map_range(va: usize, pa: usize, len: usize, flags: PageFlags) -> bool {
+    let Some(end) = va.checked_add(len) else {
+        return false;
+    };
+    if !va.is_multiple_of(PAGE_SIZE) || !pa.is_multiple_of(PAGE_SIZE) || !len.is_multiple_of(PAGE_SIZE)
+        || root(va).is_none() || (len != 0 && root(end - 1).is_none())
+        || (flags.user && va >= KERNEL_OFFSET)
+    {
+        return false;
+    }
+    let ng = if va < VA_LIMIT { NG } else { 0 };
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let _guard = MAP_LOCK.lock();
+    let mut offset = 0;
+    let mut ok = true;
+    while offset < len {
+        let (v, p) = (va + offset, pa + offset);
+        if v % BLOCK_SIZE == 0 && p % BLOCK_SIZE == 0 && len - offset >= BLOCK_SIZE {
+            ok = unsafe { map_block(v, p as u64 | flags.bits() | ng | DESC_BLOCK) };
+            offset += BLOCK_SIZE;
+        } else {
+            ok = unsafe { l3_entry(v) }.is_some_and(|(entry, split)| unsafe {
+                if split {
+                    tlbi_all();
+                }
+                replace_leaf(entry, v, asid_of(v), p as u64 | flags.bits() | ng | DESC_PAGE);
+                true
+            });
+            offset += PAGE_SIZE;
+        }
+        if !ok {
+            break;
+        }
+    }
+    drop(_guard);
+    crate::cpu::restore_interrupts(daif);
+    ok
+}
+
+/// Install a 2MB block descriptor for `va`, freeing any L3 table that
+/// mapped the block before.
+///
+/// # Safety
+/// `MAP_LOCK` must be held; the caller flushes the TLB.
+unsafe fn map_block(va: usize, desc: u64) -> bool {
+    let Some((entry, _)) = l2_entry(va) else {
+        return false;
+    };
+    let old = *entry;
+    if old & DESC_VALID != 0 {
+        // Break before make: the old entry must be gone from the TLB
+        // (and any walk caches) before a different-sized entry appears
+        write_leaf(entry, 0);
+        tlbi_all();
+        if old & DESC_TYPE_MASK == DESC_TABLE {
+            free_table(table_ptr(old));
+        }
+    }
+    write_leaf(entry, desc);
+    asm!("dsb ishst", "isb");
+    true
+}
+
+/// Remove every mapping in `[va, va + len)`. 2MB blocks lying wholly
+/// inside the range are dropped at once; a block only partly covered is
+/// demoted to a table of pages first, so the rest of it stays mapped.
+///
+/// Returns `false` if an address or the length isn't page-aligned, or a
+/// table for a demotion couldn't be allocated.
+pub fn unmap_range(va: usize, len: usize) -> bool {
+    let Some(end) = va.checked_add(len) else {
+        return false;
+    };
+    if !va.is_multiple_of(PAGE_SIZE) || !len.is_multiple_of(PAGE_SIZE) || root(va).is_none()
+        || (len != 0 && root(end - 1).is_none())
+    {
+        return false;
+    }
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let _guard = MAP_LOCK.lock();
+    let mut v = va;
+    let mut ok = true;
+    while v < end {
+        let entry = lookup(v);
+        if entry.desc.is_none() {
+            // Nothing mapped here: skip the whole hole
+            v = entry.va.saturating_add(entry.size).min(end);
+            continue;
+        }
+        if entry.size == BLOCK_SIZE && v == entry.va && end - v >= BLOCK_SIZE {
+            ok = unsafe { l2_entry(v) }.is_some_and(|(e, _)| {
+                unsafe { write_leaf(e, 0) };
+                true
+            });
+            v += BLOCK_SIZE;
+        } else {
+            ok = unsafe { l3_entry(v) }.is_some_and(|(e, _)| {
+                unsafe { write_leaf(e, 0) };
+                true
+            });
+            v += PAGE_SIZE;
+        }
+        if !ok {
+            break;
+        }
+    }
+    tlbi_all();
+    drop(_guard);
+    crate::cpu::restore_interrupts(daif);
+    ok
+}
+
+// =============================================================================
+// Table Pool and Read-Only Protection
+// =============================================================================
+
+/// Translation tables available to `map_page` and `AddressSpace` (2MB)
+const POOL_TABLES: usize = 512;
+
+#[link_section = ".bss.pgtables"]
+static mut TABLE_POOL: [Table; POOL_TABLES] =
+    [const { Table { entries: [0; ENTRIES_COUNT] } }; POOL_TABLES];
+
+/// Pool tables in use, one bit each; guarded by `MAP_LOCK`
+static mut POOL_MAP: [u64; POOL_TABLES / 64] = [0; POOL_TABLES / 64];
+
+/// Set once `protect_tables` has made the tables read-only
+static TABLES_RO: AtomicBool = AtomicBool::new(false);
+
+/// Take a zeroed table from the pool.
+///
+/// # Safety
+/// `MAP_LOCK` must be held.
+unsafe fn alloc_table() -> Option<*mut Table> {
+    let map = &mut *core::ptr::addr_of_mut!(POOL_MAP);
+    let i = (0..POOL_TABLES).find(|&i| map[i / 64] & (1 << (i % 64)) == 0)?;
+    map[i / 64] |= 1 << (i % 64);
+    let table = core::ptr::addr_of_mut!(TABLE_POOL[i]);
+    with_writable(table as usize, || (*table).entries = [0; ENTRIES_COUNT]);
+    Some(table)
+}
+
+/// Return a table to the pool.
+///
+/// # Safety
+/// `MAP_LOCK` must be held and nothing may reference the table any more.
+unsafe fn free_table(table: *mut Table) {
+    // Static tables (e.g. the kernel image's L3 tables) aren't pooled
+    let Some(offset) = (table as usize).checked_sub(core::ptr::addr_of!(TABLE_POOL) as usize) else {
+        return;
+    };
+    let i = offset / PAGE_SIZE;
+    if i < POOL_TABLES {
+        (*core::ptr::addr_of_mut!(POOL_MAP))[i / 64] &= !(1 << (i % 64));
+    }
+}
+
+/// Number of pool tables in use, and the pool size.
+pub fn table_pool_usage() -> (usize, usize) {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let _guard = MAP_LOCK.lock();
+    let used = unsafe { (*core::ptr::addr_of!(POOL_MAP)).iter().map(|w| w.count_ones() as usize).sum() };
+    drop(_guard);
+    crate::cpu::restore_interrupts(daif);
+    (used, POOL_TABLES)
+}
+
+/// The kernel image L3 entry mapping the image page at `va`, if any.
+fn image_pte(va: usize) -> Option<*mut u64> {
+    // KERNEL_L3[i] maps the i-th 2MB block of RAM
+    let ram_base = phys_to_virt(RAM_BASE.load(Ordering::Relaxed));
+    let block = va.checked_sub(ram_base)? / BLOCK_SIZE;
+    if block >= KERNEL_L3_TABLES || va >= core::ptr::addr_of!(__kernel_end) as usize {
+        return None;
+    }
+    Some(unsafe { core::ptr::addr_of_mut!(KERNEL_L3[block].entries[index(va, L3_SHIFT)]) })
+}
+
+/// Make the image page at `va` read-only or writable again.
+///
+/// # Safety
+/// `va` must be a page of the kernel image whose protection may change.
+unsafe fn set_page_readonly(va: usize, readonly: bool) {
+    if let Some(pte) = image_pte(va) {
+        let desc = core::ptr::read_volatile(pte);
+        let desc = if readonly { desc | AP_RO } else { desc & !AP_RO };
+        // Only the permission changes, which needs no break-before-make
+        core::ptr::write_volatile(pte, desc);
+        tlbi_va(va, 0);
+    }
+}
+
+fn in_pgtables(va: usize) -> bool {
+    let start = core::ptr::addr_of!(__pgtables_start) as usize;
+    let end = core::ptr::addr_of!(__pgtables_end) as usize;
+    (start..end).contains(&va)
+}
+
+/// Run `f`, which writes the table page at `page`, with that page
+/// unlocked.
+///
+/// # Safety
+/// `MAP_LOCK` must be held (or the tables not yet protected).
+unsafe fn with_writable<R>(page: usize, f: impl FnOnce() -> R) -> R {
+    let page = page & !(PAGE_SIZE - 1);
+    let locked = TABLES_RO.load(Ordering::Relaxed) && in_pgtables(page);
+    if locked {
+        set_page_readonly(page, false);
+    }
+    let r = f();
+    if locked {
+        set_page_readonly(page, true);
+    }
+    r
+}
+
+/// Store a descriptor, unlocking its table page for the write.
+///
+/// # Safety
+/// `entry` must point into a live translation table; `MAP_LOCK` held.
+unsafe fn write_desc(entry: *mut u64, desc: u64) {
+    with_writable(entry as usize, || core::ptr::write_volatile(entry, desc));
+}
+
+/// Map `[ptr, ptr + len)` read-only. The range must be page-aligned and
+/// lie within the kernel image.
+///
+/// Returns `false` (changing nothing) otherwise.
+pub fn protect_readonly(ptr: *const u8, len: usize) -> bool {
+    set_range_readonly(ptr, len, true)
+}
+
+/// Temporarily make a range protected with `protect_readonly` writable
+/// again, for a legitimate update.
+///
+/// Returns `true` if the range was protected, in which case it must be
+/// protected again with `relock` after the update.
+pub fn unlock(ptr: *const u8, len: usize) -> bool {
+    let protected = image_pte(ptr as usize)
+        .is_some_and(|pte| unsafe { core::ptr::read_volatile(pte) } & AP_RO != 0);
+    protected && set_range_readonly(ptr, len, false)
+}
+
+/// Protect a range again after `unlock`.
+pub fn relock(ptr: *const u8, len: usize) -> bool {
+    set_range_readonly(ptr, len, true)
+}
+
+fn set_range_readonly(ptr: *const u8, len: usize, readonly: bool) -> bool {
+    let start = ptr as usize;
+    let Some(end) = start.checked_add(len) else {
+        return false;
+    };
+    if !start.is_multiple_of(PAGE_SIZE) || (start..end).step_by(PAGE_SIZE).any(|va| image_pte(va).is_none()) {
+        return false;
+    }
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let _guard = MAP_LOCK.lock();
+    for va in (start..end).step_by(PAGE_SIZE) {
+        unsafe { set_page_readonly(va, readonly); }
+    }
+    drop(_guard);
+    crate::cpu::restore_interrupts(daif);
+    true
+}
+
+/// Map the translation tables and the `.data.ro_after_init` statics
+/// read-only. Call once boot-time setup (including interrupt handler
+/// registration) is done.
+pub fn protect_tables() {
+    let range = |start: &u8, end: &u8| (start as *const u8, end as *const u8 as usize - start as *const u8 as usize);
+    let (tables, tables_len) = unsafe { range(&__pgtables_start, &__pgtables_end) };
+    let (statics, statics_len) = unsafe { range(&__ro_after_init_start, &__ro_after_init_end) };
+    // Hold the map lock across both so no update sees half the tables
+    // locked without `TABLES_RO` set
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let _guard = MAP_LOCK.lock();
+    for va in (tables as usize..tables as usize + tables_len).step_by(PAGE_SIZE) {
+        unsafe { set_page_readonly(va, true); }
+    }
+    TABLES_RO.store(true, Ordering::Relaxed);
+    drop(_guard);
+    crate::cpu::restore_interrupts(daif);
+    protect_readonly(statics, statics_len);
+}
+
+/// Try to write the kernel's root table in place (storing back the byte
+/// already there), reporting whether the write went through. Once
+/// `protect_tables` has run it must fault.
+pub fn probe_table_write() -> bool {
+    let l1 = core::ptr::addr_of_mut!(KERNEL_L1) as *mut u8;
+    unsafe { probe_write(l1, core::ptr::read_volatile(l1)) }
+}
+
+// =============================================================================
+// Device Regions
+// =============================================================================
+
+/// Map the MMIO registers at `[pa, pa + len)` at their linear-map address
+/// `phys_to_virt(pa)`, as Device-nGnRnE, kernel read-write and never
+/// executable. The range is widened to whole pages.
+///
+/// Mapping a region twice is harmless. Returns `false` if the range
+/// overlaps RAM or a page table couldn't be allocated.
+pub fn map_device(pa: usize, len: usize) -> bool {
+    let start = pa & !(PAGE_SIZE - 1);
+    let end = (pa + len).next_multiple_of(PAGE_SIZE);
+    let (ram_base, ram_size) = ram();
+    if start < ram_base + ram_size && ram_base < end {
+        return false;
+    }
+    map_range(phys_to_virt(start), start, end - start, PageFlags::DEVICE)
+}
+
+/// VA window for `map_device_region` mappings (64GB into the kernel half,
+/// above the linear map of all RAM)
+const IO_WINDOW_BASE: usize = KERNEL_OFFSET + 0x10_0000_0000;
+const IO_WINDOW_SIZE: usize = 1 << 30;
+
+/// Next free VA in the I/O window
+static IO_WINDOW_NEXT: AtomicUsize = AtomicUsize::new(IO_WINDOW_BASE);
+
+/// Map `len` bytes of physical memory at `pa` into the I/O window with
+/// the given memory type, kernel read-write and never executable.
+///
+/// Regions are separated by an unmapped page. Regions of 2MB or more
+/// are placed at the same offset within a 2MB block as `pa`, so they can
+/// be mapped with 2MB blocks where the physical range allows. Returns the
+/// VA corresponding to `pa`, or `None` if the window is exhausted or a
+/// page table couldn't be allocated.
+pub fn map_device_region(pa: usize, len: usize, memory: MemoryType) -> Option<usize> {
+    let offset = pa % PAGE_SIZE;
+    let pa_base = pa - offset;
+    let map_len = (offset + len).next_multiple_of(PAGE_SIZE);
+    let align_slack = if map_len >= BLOCK_SIZE { BLOCK_SIZE } else { 0 };
+    let size = map_len + PAGE_SIZE + align_slack;
+    let base = IO_WINDOW_NEXT.fetch_add(size, Ordering::Relaxed);
+    if base + size > IO_WINDOW_BASE + IO_WINDOW_SIZE {
+        return None;
+    }
+    let va = match align_slack {
+        0 => base,
+        _ => base + (pa_base.wrapping_sub(base) % BLOCK_SIZE),
+    };
+
+    let flags = PageFlags { write: true, execute: false, user: false, memory };
+    if !map_range(va, pa_base, map_len, flags) {
+        unmap_range(va, map_len);
+        return None;
+    }
+    Some(va + offset)
+}
+
+// =============================================================================
+// Address Spaces and ASIDs
+// =============================================================================
+
+/// Position of the ASID in TTBR0_EL1
+const TTBR_ASID_SHIFT: u32 = 48;
+/// 8-bit ASIDs (TCR_EL1.AS = 0)
+const NUM_ASIDS: usize = 256;
+/// ASID of the user address space set up by `init` (0 is never used)
+const BOOT_ASID: u16 = 1;
+
+/// ASIDs allocated to a live address space, and ASIDs that have been
+/// used since they were last flushed from the TLB
+struct AsidMap {
+    live: [u64; NUM_ASIDS / 64],
+    dirty: [u64; NUM_ASIDS / 64],
+    /// Where the next search starts, so ASIDs are recycled as late as
+    /// possible
+    next: usize,
+}
+
+static ASIDS: Mutex<AsidMap> = Mutex::new(AsidMap {
+    live: [0b11, 0, 0, 0],
+    dirty: [0b11, 0, 0, 0],
+    next: BOOT_ASID as usize + 1,
+});
+
+/// When set, every address space switch also flushes the whole TLB, as
+/// if there were no ASIDs (for comparing switch costs)
+static FLUSH_ON_SWITCH: AtomicBool = AtomicBool::new(false);
+
+/// TTBR0 value for the boot address space
+fn boot_ttbr() -> u64 {
+    virt_to_phys(core::ptr::addr_of!(USER_L1) as usize) as u64 | (BOOT_ASID as u64) << TTBR_ASID_SHIFT
+}
+
+fn read_ttbr0() -> u64 {
+    let ttbr0: u64;
+    unsafe { asm!("mrs {}, ttbr0_el1", out(reg) ttbr0); }
+    ttbr0
+}
+
+/// Allocate an ASID. One that was used before is flushed from the TLB
+/// first, so no stale translations of its previous owner survive.
+fn alloc_asid() -> Option<u16> {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let mut map = ASIDS.lock();
+    let found = (0..NUM_ASIDS).map(|i| (map.next + i) % NUM_ASIDS)
+        .find(|&asid| map.live[asid / 64] & (1 << (asid % 64)) == 0);
+    if let Some(asid) = found {
+        let bit = 1u64 << (asid % 64);
+        map.live[asid / 64] |= bit;
+        if map.dirty[asid / 64] & bit != 0 {
+            tlbi_asid(asid as u16);
+        }
+        map.dirty[asid / 64] |= bit;
+        map.next = (asid + 1) % NUM_ASIDS;
+    }
+    drop(map);
+    crate::cpu::restore_interrupts(daif);
+    found.map(|asid| asid as u16)
+}
+
+fn free_asid(asid: u16) {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    ASIDS.lock().live[asid as usize / 64] &= !(1 << (asid % 64));
+    crate::cpu::restore_interrupts(daif);
+}
+
+/// A user (TTBR0) address space: a root table and the ASID its
+/// translations are tagged with.
+///
+/// The tables below the root are freed with it, but not the pages they
+/// map, which belong to whoever mapped them.
+pub struct AddressSpace {
+    /// Physical address of the L1 table
+    root: usize,
+    asid: u16,
+    /// The root is the static boot table, never freed
+    boot: bool,
+}
+
+impl AddressSpace {
+    /// Create an empty address space.
+    ///
+    /// Returns `None` if there is no memory for the root table or all
+    /// ASIDs are in use.
+    pub fn new() -> Option<Self> {
+        let asid = alloc_asid()?;
+        let daif = crate::cpu::save_and_disable_interrupts();
+        let table = {
+            let _guard = MAP_LOCK.lock();
+            unsafe { alloc_table() }
+        };
+        crate::cpu::restore_interrupts(daif);
+        let Some(table) = table else {
+            free_asid(asid);
+            return None;
+        };
+        Some(Self { root: virt_to_phys(table as usize), asid, boot: false })
+    }
+
+    /// The address space `init` installed in TTBR0.
+    ///
+    /// # Safety
+    /// Must only be called once: the returned value owns that space.
+    pub unsafe fn boot() -> Self {
+        Self {
+            root: virt_to_phys(core::ptr::addr_of!(USER_L1) as usize),
+            asid: BOOT_ASID,
+            boot: true,
+        }
+    }
+
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    fn ttbr(&self) -> u64 {
+        self.root as u64 | (self.asid as u64) << TTBR_ASID_SHIFT
+    }
+
+    /// Make this the address space user VAs translate in.
+    ///
+    /// Translations of other address spaces stay cached under their own
+    /// ASIDs, so no TLB maintenance is needed.
+    pub fn activate(&self) {
+        let ttbr = self.ttbr();
+        if read_ttbr0() == ttbr {
+            return;
+        }
+        unsafe {
+            asm!("msr ttbr0_el1, {}", "isb", in(reg) ttbr);
+            if FLUSH_ON_SWITCH.load(Ordering::Relaxed) {
+                tlbi_all();
+            }
+        }
+    }
+
+    /// Whether this address space is the one in TTBR0.
+    pub fn is_active(&self) -> bool {
+        read_ttbr0() == self.ttbr()
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.boot {
+            return;
+        }
+        // Never leave TTBR0 pointing at freed tables
+        if self.is_active() {
+            unsafe { asm!("msr ttbr0_el1, {}", "isb", in(reg) boot_ttbr()); }
+        }
+        let daif = crate::cpu::save_and_disable_interrupts();
+        let guard = MAP_LOCK.lock();
+        unsafe {
+            let l1 = phys_to_virt(self.root) as *mut Table;
+            for &l1_desc in (*l1).entries.iter() {
+                if l1_desc & DESC_TYPE_MASK != DESC_TABLE {
+                    continue;
+                }
+                for &l2_desc in (*table_ptr(l1_desc)).entries.iter() {
+                    if l2_desc & DESC_TYPE_MASK == DESC_TABLE {
+                        free_table(table_ptr(l2_desc));
+                    }
+                }
+                free_table(table_ptr(l1_desc));
+            }
+            free_table(l1);
+        }
+        drop(guard);
+        crate::cpu::restore_interrupts(daif);
+        free_asid(self.asid);
+    }
+}
+
+// =============================================================================
+// Copy-on-Write
+// =============================================================================
+
+/// Share the 4KB pages mapped in `range` of `src` with `dst`, copy-on-write:
+/// both spaces end up mapping the same physical pages read-only, marked so
+/// that `cow_page` recognizes them when a write faults. `shared` is called
+/// with the physical address of each page shared, for reference counting.
+///
+/// Unmapped pages are skipped. Returns `false` if `range` isn't
+/// page-aligned user space, a page is mapped by a block, or `dst` ran out
+/// of page tables (some pages may be shared by then).
+pub fn share_cow(
+    dst: &AddressSpace,
+    src: &AddressSpace,
+    range: core::ops::Range<usize>,
+    mut shared: impl FnMut(usize),
+) -> bool {
+    if !range.start.is_multiple_of(PAGE_SIZE) || !range.end.is_multiple_of(PAGE_SIZE) || range.end > VA_LIMIT {
+        return false;
+    }
+    let src_l1 = phys_to_virt(src.root) as *mut Table;
+    let dst_l1 = phys_to_virt(dst.root) as *mut Table;
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let _guard = MAP_LOCK.lock();
+    let ok = range.step_by(PAGE_SIZE).all(|va| unsafe {
+        let l1_desc = (*src_l1).entries[index(va, L1_SHIFT)];
+        if l1_desc & DESC_VALID == 0 {
+            return true;
+        }
+        if l1_desc & DESC_TYPE_MASK != DESC_TABLE {
+            return false;
+        }
+        let l2_desc = (*table_ptr(l1_desc)).entries[index(va, L2_SHIFT)];
+        if l2_desc & DESC_VALID == 0 {
+            return true;
+        }
+        if l2_desc & DESC_TYPE_MASK != DESC_TABLE {
+            return false;
+        }
+        let entry: *mut u64 = &mut (*table_ptr(l2_desc)).entries[index(va, L3_SHIFT)];
+        let desc = *entry;
+        if desc & DESC_VALID == 0 {
+            return true;
+        }
+        let Some((dst_entry, _)) = l3_entry_in(dst_l1, va) else {
+            return false;
+        };
+        let cow = desc | AP_RO | SW_COW;
+        replace_leaf(entry, va, src.asid, cow);
+        replace_leaf(dst_entry, va, dst.asid, cow);
+        shared((desc & ADDR_MASK) as usize);
+        true
+    });
+    drop(_guard);
+    crate::cpu::restore_interrupts(daif);
+    ok
+}
+
+/// If `va` is on a copy-on-write page in the active address space, the
+/// physical page behind it.
+pub fn cow_page(va: usize) -> Option<usize> {
+    if va >= VA_LIMIT {
+        return None;
+    }
+    let entry = lookup(va);
+    let desc = entry.desc.filter(|desc| desc & SW_COW != 0 && entry.size == PAGE_SIZE)?;
+    Some((desc & ADDR_MASK) as usize)
+}
+
+/// Make every address space switch flush the whole TLB too (`true`), or
+/// rely on ASIDs (`false`, the default). Only useful for benchmarking.
+pub fn set_flush_on_switch(flush: bool) {
+    FLUSH_ON_SWITCH.store(flush, Ordering::Relaxed);
+}
+
+// =============================================================================
+// Translation and Introspection
+// =============================================================================
+
+/// The translation table entry covering a VA.
+struct Lookup {
+    /// Start of the region the entry maps
+    va: usize,
+    /// Region size: 1GB, 2MB or 4KB
+    size: usize,
+    /// Block or page descriptor, `None` if unmapped
+    desc: Option<u64>,
+}
+
+/// Walk the tables in software to find the entry covering `va`.
+///
+/// `va` must be in one of the two halves.
+fn lookup(va: usize) -> Lookup {
+    let mut table = root(va).expect("non-canonical VA") as *const Table;
+    for shift in [L1_SHIFT, L2_SHIFT, L3_SHIFT] {
+        let size = 1 << shift;
+        let region = va & !(size - 1);
+        let desc = unsafe { (*table).entries[index(va, shift)] };
+        if desc & DESC_VALID == 0 {
+            return Lookup { va: region, size, desc: None };
+        }
+        if shift == L3_SHIFT || desc & DESC_TYPE_MASK == DESC_BLOCK {
+            return Lookup { va: region, size, desc: Some(desc) };
+        }
+        table = table_ptr(desc);
+    }
+    unreachable!()
+}
+
+/// Translate a virtual address to its physical address and mapping flags.
+///
+/// Returns `None` if `va` is not mapped.
+pub fn translate(va: usize) -> Option<(usize, PageFlags)> {
+    root(va)?;
+    let entry = lookup(va);
+    let desc = entry.desc?;
+    let pa = (desc & ADDR_MASK) as usize & !(entry.size - 1);
+    Some((pa + (va - entry.va), PageFlags::from_bits(desc)))
+}
+
+/// Print the mappings in `[start, end)`, which must lie within one half.
+///
+/// Neighbouring entries of the same size whose attributes match and whose
+/// physical addresses are contiguous are shown as one line.
+pub fn dump_range(start: usize, end: usize) {
+    /// A run of similar entries being accumulated
+    struct Run {
+        va: usize,
+        pa: usize,
+        len: usize,
+        granule: usize,
+        attrs: u64,
+    }
+
+    fn print_run(run: &Run) {
+        let flags = PageFlags::from_bits(run.attrs);
+        let memory = match flags.memory {
+            MemoryType::Normal => "normal",
+            MemoryType::NonCacheable => "uncached",
+            MemoryType::Device => "device",
+        };
+        let (len, len_unit) = size_unit(run.len);
+        let (granule, granule_unit) = size_unit(run.granule);
+        crate::println!("  {:#018x}-{:#018x} -> {:#012x}  {:>6}{}  {:>3}{}  r{}{} {}  {}",
+            run.va, run.va + run.len, run.pa, len, len_unit, granule, granule_unit,
+            if flags.write { 'w' } else { '-' },
+            if flags.execute { 'x' } else { '-' },
+            if flags.user { "user  " } else { "kernel" },
+            memory);
+    }
+
+    crate::println!("  {:<37}    {:<12}  {:>7}  {:>4}  {:<10}  {}", "VIRTUAL", "PHYSICAL", "SIZE", "PAGE", "ACCESS", "TYPE");
+    let mut va = start & !(PAGE_SIZE - 1);
+    let mut run: Option<Run> = None;
+    while va < end && root(va).is_some() {
+        let entry = lookup(va);
+        match entry.desc {
+            Some(desc) => {
+                let pa = (desc & ADDR_MASK) as usize & !(entry.size - 1);
+                let attrs = desc & ATTR_MASK;
+                match &mut run {
+                    Some(r) if r.granule == entry.size && r.attrs == attrs
+                        && r.va + r.len == entry.va && r.pa + r.len == pa => r.len += entry.size,
+                    _ => {
+                        if let Some(r) = run.take() {
+                            print_run(&r);
+                        }
+                        run = Some(Run { va: entry.va, pa, len: entry.size, granule: entry.size, attrs });
+                    }
+                }
+            }
+            None => {
+                if let Some(r) = run.take() {
+                    print_run(&r);
+                }
+            }
+        }
+        // The last entry of the kernel half ends at the top of the space
+        match entry.va.checked_add(entry.size) {
+            Some(next) => va = next,
+            None => break,
+        }
+    }
+    if let Some(r) = run {
+        print_run(&r);
+    }
+}
+
+/// Attributes of a block or page mapping, decoded from its descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PteAttrs {
+    pub memory: MemoryType,
+    /// AP[2:1]: bit 1 set = read-only, bit 0 set = EL0 accessible
+    pub ap: u8,
+    /// Unprivileged (EL0) execute never
+    pub uxn: bool,
+    /// Privileged (EL1) execute never
+    pub pxn: bool,
+}
+
+impl PteAttrs {
+    fn from_desc(desc: u64) -> Self {
+        Self {
+            memory: PageFlags::from_bits(desc).memory,
+            ap: ((desc >> 6) & 0b11) as u8,
+            uxn: desc & UXN != 0,
+            pxn: desc & PXN != 0,
+        }
+    }
+
+    pub fn writable(&self) -> bool {
+        self.ap & 0b10 == 0
+    }
+
+    /// Accessible from EL0
+    pub fn user(&self) -> bool {
+        self.ap & 0b01 != 0
+    }
+
+    /// Executable at some exception level. EL0 can only execute what it
+    /// can access.
+    pub fn executable(&self) -> bool {
+        !self.pxn || (self.user() && !self.uxn)
+    }
+}
+
+/// Call `f(va, pa, size, attrs)` for every block and page mapping in the
+/// active translation tables (TTBR0, then TTBR1), in address order.
+///
+/// `size` is 1GB, 2MB or 4KB. The tables are read without taking the
+/// map lock, so concurrent updates may or may not be seen.
+pub fn walk(mut f: impl FnMut(usize, usize, usize, PteAttrs)) {
+    let ttbr0: u64;
+    let ttbr1: u64;
+    unsafe {
+        asm!("mrs {}, ttbr0_el1", out(reg) ttbr0);
+        asm!("mrs {}, ttbr1_el1", out(reg) ttbr1);
+    }
+    for (base, ttbr) in [(0, ttbr0), (KERNEL_OFFSET, ttbr1)] {
+        unsafe { walk_table(table_ptr(ttbr), base, L1_SHIFT, &mut f); }
+    }
+}
+
+/// Walk the table at the level translating `shift` bits, which maps the
+/// VAs from `base`.
+///
+/// # Safety
+/// `table` must be a live translation table.
+unsafe fn walk_table(table: *const Table, base: usize, shift: u32,
+                     f: &mut impl FnMut(usize, usize, usize, PteAttrs)) {
+    let size = 1usize << shift;
+    for (i, &desc) in (*table).entries.iter().enumerate() {
+        let va = base + i * size;
+        match (desc & DESC_TYPE_MASK, shift) {
+            (DESC_TABLE, L1_SHIFT | L2_SHIFT) => walk_table(table_ptr(desc), va, shift - 9, f),
+            (DESC_PAGE, L3_SHIFT) | (DESC_BLOCK, L1_SHIFT | L2_SHIFT) => {
+                let pa = (desc & ADDR_MASK) as usize & !(size - 1);
+                f(va, pa, size, PteAttrs::from_desc(desc));
+            }
+            // Invalid, or a block type that is reserved at L3
+            _ => {}
+        }
+    }
+}
+
+/// Split a byte count into a value and binary unit for printing.
+pub fn size_unit(bytes: usize) -> (usize, &'static str) {
+    match bytes {
+        n if n >= 1 << 30 && n % (1 << 30) == 0 => (n >> 30, "G"),
+        n if n >= 1 << 20 && n % (1 << 20) == 0 => (n >> 20, "M"),
+        n => (n >> 10, "K"),
+    }
+}
+
+// =============================================================================
+// Fault Probing
+// =============================================================================
+
+/// Where an abort taken inside `probe_write` / `probe_exec` or a user copy
+/// resumes (0 = not probing)
+pub(crate) static FAULT_FIXUP: AtomicUsize = AtomicUsize::new(0);
+
+/// Try to store `value` at `addr`, reporting a data abort instead of
+/// treating it as fatal. Used by self-tests to check page permissions.
+///
+/// Returns `true` if the write succeeded.
+///
+/// # Safety
+/// A successful write must not corrupt anything.
+pub unsafe fn probe_write(addr: *mut u8, value: u8) -> bool {
+    let ok: u64;
+    let daif = crate::cpu::save_and_disable_interrupts();
+    asm!(
+        "adr {tmp}, 2f",
+        "str {tmp}, [{fixup}]",
+        "mov {ok}, #0",
+        "strb {value:w}, [{addr}]",
+        "mov {ok}, #1",
+        "2:",
+        "str xzr, [{fixup}]",
+        tmp = out(reg) _,
+        ok = out(reg) ok,
+        fixup = in(reg) FAULT_FIXUP.as_ptr(),
+        value = in(reg) value as u32,
+        addr = in(reg) addr,
+    );
+    crate::cpu::restore_interrupts(daif);
+    ok != 0
+}
+
+/// Copy `len` bytes from `src` to `dst` a byte at a time, with IRQs masked
+/// so nothing else runs while `FAULT_FIXUP` is set. Returns `false` if an
+/// access faulted (and wasn't a page the kernel could fault in).
+///
+/// # Safety
+/// Whatever the copy manages to write must not corrupt anything.
+unsafe fn copy_with_fixup(dst: *mut u8, src: *const u8, len: usize) -> bool {
+    let ok: u64;
+    let daif = crate::cpu::save_and_disable_interrupts();
+    asm!(
+        "adr {tmp}, 2f",
+        "str {tmp}, [{fixup}]",
+        "mov {ok}, #0",
+        "cbz {len}, 1f",
+        "0:",
+        "ldrb {tmp:w}, [{src}], #1",
+        "strb {tmp:w}, [{dst}], #1",
+        "subs {len}, {len}, #1",
+        "b.ne 0b",
+        "1:",
+        "mov {ok}, #1",
+        "2:",
+        "str xzr, [{fixup}]",
+        tmp = out(reg) _,
+        ok = out(reg) ok,
+        fixup = in(reg) FAULT_FIXUP.as_ptr(),
+        src = inout(reg) src => _,
+        dst = inout(reg) dst => _,
+        len = inout(reg) len => _,
+    );
+    crate::cpu::restore_interrupts(daif);
+    ok != 0
+}
+
+/// Whether the `len` bytes at `addr` lie entirely in the user half.
+pub fn user_range_ok(addr: usize, len: usize) -> bool {
+    addr.checked_add(len).is_some_and(|end| end <= VA_LIMIT)
+}
+
+/// Copy user memory at `src`, in the current task's address space, into
+/// `dst`. Returns `false` if the range isn't in the user half or any of
+/// it isn't mapped readable, rather than faulting the kernel.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> bool {
+    // SAFETY: the writes go to `dst`, which is ours
+    user_range_ok(src, dst.len()) && unsafe { copy_with_fixup(dst.as_mut_ptr(), src as *const u8, dst.len()) }
+}
+
+/// Copy `src` into user memory at `dst`, in the current task's address
+/// space. Returns `false` if the range isn't in the user half or any of it
+/// isn't mapped writable (copy-on-write pages are copied first), rather
+/// than faulting the kernel; the part before the fault may be written.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> bool {
+    // SAFETY: the writes only reach the user half, which the kernel keeps
+    // nothing of its own in
+    user_range_ok(dst, src.len()) && unsafe { copy_with_fixup(dst as *mut u8, src.as_ptr(), src.len()) }
+}
+
+/// Try to call the code at `addr`, reporting an instruction abort instead
+/// of treating it as fatal. Used by self-tests to check W^X.
+///
+/// Returns `true` if the code ran (and returned).
+///
+/// # Safety
+/// If `addr` is executable it must hold a function that just returns.
+pub unsafe fn probe_exec(addr: usize) -> bool {
+    let ok: u64;
+    let daif = crate::cpu::save_and_disable_interrupts();
+    asm!(
+        "adr {tmp}, 2f",
+        "str {tmp}, [{fixup}]",
+        "mov {ok}, #0",
+        "blr {addr}",
+        "mov {ok}, #1",
+        "2:",
+        "str xzr, [{fixup}]",
+        tmp = out(reg) _,
+        ok = out(reg) ok,
+        fixup = in(reg) FAULT_FIXUP.as_ptr(),
+        addr = in(reg) addr,
+        out("x30") _,
+    );
+    crate::cpu::restore_interrupts(daif);
+    ok != 0
+}
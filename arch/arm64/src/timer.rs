@@ -6,13 +6,26 @@
 // =============================================================================
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::time::Duration;
 
+/// Scheduler tick interval in milliseconds.
+pub const TICK_MS: u64 = 50;
+
+/// Virtual timer interrupt (PPI 11)
+pub const VIRT_TIMER_IRQ: u32 = 27;
+
+/// Non-secure physical timer interrupt (PPI 14)
+pub const PHYS_TIMER_IRQ: u32 = 30;
+
+/// Counter value at which the next tick is due
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+
 pub struct Timer;
 
 impl Timer {
     /// Initialize the timer.
-    /// Sets it to fire periodically.
+    /// Sets it to fire periodically. The GIC must be initialized.
     pub fn init() {
         // disable timer first
         unsafe {
@@ -28,6 +41,45 @@ impl Timer {
         unsafe {
             asm!("msr cntv_ctl_el0, {}", in(reg) 1_u64);
         }
+
+        // The timer PPI is level-sensitive (fixed by the GIC, so this only
+        // checks it). The tick drives scheduling, so it outranks devices.
+        if !crate::gic::set_trigger(VIRT_TIMER_IRQ, crate::gic::TriggerMode::Level) {
+            crate::log_warn!("timer", "Timer interrupt is not level-triggered");
+        }
+        crate::gic::set_priority(VIRT_TIMER_IRQ, 0x80);
+        crate::gic::enable_irq(VIRT_TIMER_IRQ);
+    }
+
+    /// Raw virtual counter value (CNTVCT_EL0).
+    #[inline(always)]
+    pub fn counter() -> u64 {
+        let count: u64;
+        unsafe { asm!("mrs {}, cntvct_el0", out(reg) count); }
+        count
+    }
+
+    /// Counter frequency in Hz (CNTFRQ_EL0).
+    #[inline(always)]
+    pub fn frequency() -> u64 {
+        let freq: u64;
+        unsafe { asm!("mrs {}, cntfrq_el0", out(reg) freq); }
+        freq
+    }
+
+    /// Convert a number of counter ticks to a duration.
+    pub fn ticks_to_duration(ticks: u64) -> Duration {
+        Duration::from_nanos(((ticks as u128 * 1_000_000_000) / Self::frequency() as u128) as u64)
+    }
+
+    /// Counter value the current tick was programmed to fire at.
+    pub fn deadline() -> u64 {
+        DEADLINE.load(Ordering::Relaxed)
+    }
+
+    /// Time elapsed since the counter started (roughly, since boot).
+    pub fn uptime() -> Duration {
+        Self::ticks_to_duration(Self::counter())
     }
 
     /// Set the next timer interrupt.
@@ -47,5 +99,6 @@ impl Timer {
         unsafe {
             asm!("msr cntv_tval_el0, {}", in(reg) ticks);
         }
+        DEADLINE.store(Self::counter() + ticks, Ordering::Relaxed);
     }
 }
@@ -5,26 +5,58 @@
 // This is the primary serial console used by QEMU's virt machine.
 //
 // The PL011 is a fully-featured UART with FIFOs and modem control signals.
-// For v0.0.1, we only implement basic transmit functionality.
+// Transmit and receive are interrupt-driven through ring buffers, with a
+// synchronous fallback for early boot and IRQ-masked contexts.
+//
+// Ports are indexed by id (0 = UART0). Each has its own buffers and IRQ;
+// kernel output goes to the console port, which defaults to UART0.
 //
 // Reference: ARM PrimeCell UART (PL011) Technical Reference Manual
 // =============================================================================
 
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use spin::Mutex;
+use crate::console::ConsoleSink;
+use crate::dtb::{self, Dtb};
 
 // =============================================================================
 // PL011 Register Definitions
 // =============================================================================
 
-/// Base address of UART0 on QEMU virt machine
-const UART0_BASE: usize = 0x0900_0000;
+/// Number of UART ports supported
+pub const MAX_PORTS: usize = 2;
+
+/// UARTCLK frequency on QEMU virt machine
+const UART_CLOCK_HZ: u32 = 24_000_000;
+
+/// Where and how to reach one PL011.
+#[derive(Debug, Clone, Copy)]
+pub struct PortInfo {
+    pub base: usize,
+    pub clock_hz: u32,
+    /// GIC interrupt ID
+    pub irq: u32,
+}
+
+/// PL011 ports on the QEMU virt machine, used when there is no device
+/// tree. UART1 only exists on newer machine revisions.
+const DEFAULT_PORTS: [PortInfo; MAX_PORTS] = [
+    PortInfo { base: 0x0900_0000, clock_hz: UART_CLOCK_HZ, irq: 33 },
+    PortInfo { base: 0x0904_0000, clock_hz: UART_CLOCK_HZ, irq: 40 },
+];
+
+/// Default line settings: 115200 baud, 8N1
+const DEFAULT_BAUD: u32 = 115_200;
 
 /// UART Register Offsets from base address
 mod regs {
     /// Data Register - read/write data here
     pub const DR: usize = 0x00;
     
+    /// Receive Status Register / Error Clear Register
+    pub const RSR: usize = 0x04;
+
     /// Flag Register - contains UART status flags
     pub const FR: usize = 0x18;
     
@@ -43,28 +75,95 @@ mod regs {
     /// Interrupt Mask Set/Clear Register
     pub const IMSC: usize = 0x38;
 
+    /// Masked Interrupt Status Register
+    pub const MIS: usize = 0x40;
+
     /// Interrupt Clear Register
     pub const ICR: usize = 0x44;
 }
 
+/// Interrupt Mask Set/Clear Register bits
+mod imsc {
+    /// Receive interrupt mask
+    pub const RXIM: u32 = 1 << 4;
+
+    /// Transmit interrupt mask
+    pub const TXIM: u32 = 1 << 5;
+
+    /// Receive timeout interrupt mask
+    pub const RTIM: u32 = 1 << 6;
+}
+
+/// Interrupt Clear Register bits
+mod icr {
+    /// Receive interrupt clear
+    pub const RXIC: u32 = 1 << 4;
+
+    /// Transmit interrupt clear
+    pub const TXIC: u32 = 1 << 5;
+
+    /// Receive timeout interrupt clear
+    pub const RTIC: u32 = 1 << 6;
+
+    /// Clear all interrupts
+    pub const ALL: u32 = 0x7FF;
+}
+
 /// Flag Register bits
 mod flags {
+    /// UART busy transmitting
+    pub const BUSY: u32 = 1 << 3;
+
     /// Transmit FIFO full
     pub const TXFF: u32 = 1 << 5;
     
     /// Receive FIFO empty
     pub const RXFE: u32 = 1 << 4;
+
+    /// Receive FIFO full
+    pub const RXFF: u32 = 1 << 6;
+
+    /// Transmit FIFO empty
+    pub const TXFE: u32 = 1 << 7;
+}
+
+/// Receive error bits, as found in RSR and bits 8-11 of DR
+mod rsr {
+    /// Framing error
+    pub const FE: u32 = 1 << 0;
+
+    /// Parity error
+    pub const PE: u32 = 1 << 1;
+
+    /// Break condition
+    pub const BE: u32 = 1 << 2;
+
+    /// Overrun error (FIFO was full when a byte arrived)
+    pub const OE: u32 = 1 << 3;
+
+    /// Position of the error bits in DR
+    pub const DR_SHIFT: u32 = 8;
 }
 
 
 
 /// Line Control Register bits
 mod lcr {
+    /// Parity enable
+    pub const PEN: u32 = 1 << 1;
+
+    /// Even parity select
+    pub const EPS: u32 = 1 << 2;
+
+    /// Two stop bits
+    pub const STP2: u32 = 1 << 3;
+
     /// Enable FIFOs
     pub const FEN: u32 = 1 << 4;
-    
-    /// Word length: 8 bits (bits 5-6 = 0b11)
-    pub const WLEN_8: u32 = 0b11 << 5;
+
+    /// Word length field (bits 5-6, value = data bits - 5)
+    pub const WLEN_SHIFT: u32 = 5;
+    pub const WLEN_MASK: u32 = 0b11 << WLEN_SHIFT;
 }
 
 /// Control Register bits
@@ -83,21 +182,62 @@ mod cr {
 // UART Driver Implementation
 // =============================================================================
 
+/// Parity setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Line settings of a UART, as read back from the hardware.
+#[derive(Debug, Clone, Copy)]
+pub struct UartConfig {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+    pub clock_hz: u32,
+}
+
 /// PL011 UART driver
 pub struct Uart {
+    id: usize,
     base: usize,
+    clock_hz: u32,
 }
 
 impl Uart {
-    /// Create a new UART driver instance.
-    /// 
+    /// Create a driver instance for port `id`.
+    ///
     /// # Arguments
+    /// * `id` - Port index, selecting the ring buffers used
     /// * `base` - Base address of the UART registers
-    /// 
+    ///
     /// # Safety
     /// The caller must ensure the base address points to valid UART hardware.
-    pub const fn new(base: usize) -> Self {
-        Self { base }
+    pub const fn new(id: usize, base: usize) -> Self {
+        Self { id, base, clock_hz: UART_CLOCK_HZ }
+    }
+
+    /// Driver for one of the known ports.
+    fn port(id: usize) -> Self {
+        let state = &PORTS[id];
+        Self {
+            id,
+            base: crate::mmu::phys_to_virt(state.base.load(Ordering::Relaxed)),
+            clock_hz: state.clock_hz.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Buffers and counters belonging to this port.
+    fn state(&self) -> &'static PortState {
+        &PORTS[self.id]
+    }
+
+    /// Set the UARTCLK frequency used for baud rate calculations.
+    pub fn set_clock(&mut self, clock_hz: u32) {
+        self.clock_hz = clock_hz;
     }
 
     /// Read a register at the given offset
@@ -113,7 +253,6 @@ impl Uart {
         // SAFETY: We trust that self.base points to valid UART registers
         unsafe { core::ptr::write_volatile(addr, value) }
     }
-
     /// Initialize the UART.
     /// 
     /// Configures the UART for 8-N-1 operation (8 data bits, no parity, 1 stop bit).
@@ -124,25 +263,121 @@ impl Uart {
 
         // Clear all pending interrupts
         self.write_reg(regs::IMSC, 0);
-        self.write_reg(regs::ICR, 0x7FF); // Clear all interrupts
-
-        // Enable Receive Interrupt (RXIM) and Receive Timeout (RTIM)
-        // self.write_reg(regs::IMSC, imsc::RXIM | imsc::RTIM);
+        self.write_reg(regs::ICR, icr::ALL);
 
-        // Set baud rate (115200 with 24MHz clock)
-        // Divider = 24000000 / (16 * 115200) = 13.0208
-        // Integer part = 13
-        // Fractional part = 0.0208 * 64 = 1.33 ≈ 1
-        self.write_reg(regs::IBRD, 13);
-        self.write_reg(regs::FBRD, 1);
+        // Set baud rate and line control: 115200 8N1, FIFO enabled
+        self.program_line(DEFAULT_BAUD, 8, Parity::None, 1);
 
-        // Configure line control: 8 bits, FIFO enabled
-        self.write_reg(regs::LCR_H, lcr::WLEN_8 | lcr::FEN);
+        // Enable Receive Interrupt (RXIM) and Receive Timeout (RTIM)
+        // The timeout interrupt fires when bytes sit in the FIFO below the
+        // trigger level, so single keystrokes are delivered immediately.
+        self.write_reg(regs::IMSC, imsc::RXIM | imsc::RTIM);
 
         // Enable UART, TX, and RX
         self.write_reg(regs::CR, cr::UARTEN | cr::TXE | cr::RXE);
     }
 
+    /// Change the line settings.
+    ///
+    /// Drains pending output and discards unread input first, so no byte
+    /// is sent or received with half-applied settings. Returns `false`
+    /// (leaving the UART untouched) if the settings can't be represented.
+    pub fn configure(&self, baud: u32, data_bits: u8, parity: Parity, stop_bits: u8) -> bool {
+        if !(5..=8).contains(&data_bits) || !(1..=2).contains(&stop_bits) {
+            return false;
+        }
+        if Self::divisor(self.clock_hz, baud).is_none() {
+            return false;
+        }
+
+        // Quiesce: finish transmitting, then stop the UART
+        self.drain_tx();
+        let cr = self.read_reg(regs::CR);
+        self.write_reg(regs::CR, 0);
+
+        // Flush the RX FIFO and any bytes already buffered
+        while self.read_reg(regs::FR) & flags::RXFE == 0 {
+            let _ = self.read_reg(regs::DR);
+        }
+        self.write_reg(regs::RSR, 0);
+        {
+            let mut rx = self.state().rx.lock();
+            while rx.pop().is_some() {}
+        }
+
+        self.program_line(baud, data_bits, parity, stop_bits);
+        self.write_reg(regs::CR, cr);
+        true
+    }
+
+    /// Read back the current line settings.
+    pub fn config(&self) -> UartConfig {
+        let divisor = (self.read_reg(regs::IBRD) << 6) | (self.read_reg(regs::FBRD) & 0x3F);
+        let lcr_h = self.read_reg(regs::LCR_H);
+        let parity = match (lcr_h & lcr::PEN != 0, lcr_h & lcr::EPS != 0) {
+            (false, _) => Parity::None,
+            (true, true) => Parity::Even,
+            (true, false) => Parity::Odd,
+        };
+        UartConfig {
+            baud: if divisor == 0 { 0 } else { ((self.clock_hz as u64 * 4) / divisor as u64) as u32 },
+            data_bits: 5 + ((lcr_h & lcr::WLEN_MASK) >> lcr::WLEN_SHIFT) as u8,
+            parity,
+            stop_bits: if lcr_h & lcr::STP2 != 0 { 2 } else { 1 },
+            clock_hz: self.clock_hz,
+        }
+    }
+
+    /// Baud rate divisor in 1/64 units (IBRD << 6 | FBRD), if in range.
+    ///
+    /// Divisor = UARTCLK / (16 * baud), so in 1/64ths it's
+    /// UARTCLK * 4 / baud, rounded to nearest.
+    fn divisor(clock_hz: u32, baud: u32) -> Option<u32> {
+        if baud == 0 {
+            return None;
+        }
+        let div = ((clock_hz as u64 * 4 + baud as u64 / 2) / baud as u64) as u32;
+        let ibrd = div >> 6;
+        if (1..=0xFFFF).contains(&ibrd) { Some(div) } else { None }
+    }
+
+    /// Program the divisor and LCR_H. The UART must be disabled.
+    fn program_line(&self, baud: u32, data_bits: u8, parity: Parity, stop_bits: u8) {
+        let div = Self::divisor(self.clock_hz, baud).unwrap_or(0);
+        self.write_reg(regs::IBRD, div >> 6);
+        self.write_reg(regs::FBRD, div & 0x3F);
+
+        // LCR_H must be written after the divisor to latch it
+        let mut lcr_h = lcr::FEN | (((data_bits - 5) as u32) << lcr::WLEN_SHIFT);
+        match parity {
+            Parity::None => {}
+            Parity::Even => lcr_h |= lcr::PEN | lcr::EPS,
+            Parity::Odd => lcr_h |= lcr::PEN,
+        }
+        if stop_bits == 2 {
+            lcr_h |= lcr::STP2;
+        }
+        self.write_reg(regs::LCR_H, lcr_h);
+    }
+
+    /// Send everything in the TX buffer synchronously and wait for the
+    /// last byte to leave the shift register.
+    ///
+    /// Never waits for the buffer's lock: it's only taken with IRQs
+    /// masked, so if it's held here it's by the code this interrupted (a
+    /// panic in the middle of a write), and the buffer is left as it is.
+    fn drain_tx(&self) {
+        if let Some(mut tx) = self.state().tx.try_lock() {
+            while let Some(byte) = tx.pop() {
+                self.putc(byte);
+            }
+            self.set_tx_irq(false);
+        }
+        while self.read_reg(regs::FR) & flags::BUSY != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
     /// Transmit a single byte.
     /// 
     /// Blocks until the transmit FIFO has space.
@@ -156,9 +391,14 @@ impl Uart {
         self.write_reg(regs::DR, c as u32);
     }
 
-    /// Transmit a string.
+    /// Transmit a string synchronously.
     pub fn puts(&self, s: &str) {
-        for byte in s.bytes() {
+        self.put_bytes(s.as_bytes());
+    }
+
+    /// Transmit bytes synchronously.
+    fn put_bytes(&self, bytes: &[u8]) {
+        for &byte in bytes {
             // Convert newlines to CRLF for proper terminal output
             if byte == b'\n' {
                 self.putc(b'\r');
@@ -166,45 +406,458 @@ impl Uart {
             self.putc(byte);
         }
     }
+
+    /// Enable or disable the transmit interrupt.
+    fn set_tx_irq(&self, enabled: bool) {
+        let imsc = self.read_reg(regs::IMSC);
+        if enabled {
+            self.write_reg(regs::IMSC, imsc | imsc::TXIM);
+        } else {
+            self.write_reg(regs::IMSC, imsc & !imsc::TXIM);
+        }
+    }
+
+    /// Move bytes from the TX ring buffer into the FIFO while it has space.
+    fn fill_tx_fifo(&self, tx: &mut RingBuffer<TX_BUFFER_SIZE>) {
+        while self.read_reg(regs::FR) & flags::TXFF == 0 {
+            match tx.pop() {
+                Some(byte) => self.write_reg(regs::DR, byte as u32),
+                None => break,
+            }
+        }
+    }
+
+    /// Queue bytes for interrupt-driven transmission.
+    fn puts_buffered(&self, bytes: &[u8]) {
+        let mut tx = self.state().tx.lock();
+        for &byte in bytes {
+            if byte == b'\n' {
+                self.queue_byte(&mut tx, b'\r');
+            }
+            self.queue_byte(&mut tx, byte);
+        }
+
+        // Prime the FIFO ourselves; the TX interrupt takes over from here
+        // whenever the FIFO drains below its trigger level.
+        self.fill_tx_fifo(&mut tx);
+        self.set_tx_irq(!tx.is_empty());
+    }
+
+    /// Queue one byte, making room synchronously if the buffer is full.
+    ///
+    /// Falling back to polling keeps output flowing when the caller runs
+    /// with IRQs masked (exception handlers) and the buffer can't drain.
+    fn queue_byte(&self, tx: &mut RingBuffer<TX_BUFFER_SIZE>, byte: u8) {
+        while !tx.push(byte) {
+            if let Some(old) = tx.pop() {
+                self.putc(old);
+            }
+        }
+    }
+
+    /// Transmit bytes, buffered once interrupts are up. Newlines become CRLF.
+    fn write_bytes(&self, bytes: &[u8]) {
+        if TX_IRQ_ENABLED.load(Ordering::Acquire) {
+            self.puts_buffered(bytes);
+        } else {
+            self.put_bytes(bytes);
+        }
+    }
 }
 
 impl Write for Uart {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.puts(s);
+        self.write_bytes(s.as_bytes());
         Ok(())
     }
 }
 
 // =============================================================================
-// Global UART Instance
+// Ring Buffer
+// =============================================================================
+
+/// Size of the receive ring buffer in bytes
+const RX_BUFFER_SIZE: usize = 4096;
+
+/// RX fill level above which XOFF is sent (when flow control is on)
+const RX_HIGH_WATER: usize = RX_BUFFER_SIZE * 3 / 4;
+
+/// RX fill level below which XON is sent again
+const RX_LOW_WATER: usize = RX_BUFFER_SIZE / 4;
+
+/// Software flow control characters
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+/// Size of the transmit ring buffer in bytes
+const TX_BUFFER_SIZE: usize = 4096;
+
+struct RingBuffer<const N: usize> {
+    data: [u8; N],
+    head: usize,
+    tail: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        Self { data: [0; N], head: 0, tail: 0 }
+    }
+
+    /// Push a byte. Returns `false` if the buffer is full.
+    fn push(&mut self, byte: u8) -> bool {
+        let next = (self.head + 1) % N;
+        if next == self.tail {
+            return false;
+        }
+        self.data[self.head] = byte;
+        self.head = next;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.head == self.tail {
+            return None;
+        }
+        let byte = self.data[self.tail];
+        self.tail = (self.tail + 1) % N;
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    fn len(&self) -> usize {
+        (self.head + N - self.tail) % N
+    }
+}
+
+/// Per-port buffers and counters.
+///
+/// The ring buffers are only ever locked from IRQ context or with IRQs
+/// masked, so the handler can never spin on a lock held by the code it
+/// interrupted.
+struct PortState {
+    /// Driver instance, shared by all writers
+    uart: Mutex<Uart>,
+    /// Register base, clock and interrupt (see `PortInfo`)
+    base: AtomicUsize,
+    clock_hz: AtomicU32,
+    irq: AtomicU32,
+    /// Hardware is believed to exist (from the device tree or defaults)
+    known: AtomicBool,
+    /// Receive buffer filled by the interrupt handler
+    rx: Mutex<RingBuffer<RX_BUFFER_SIZE>>,
+    /// Transmit buffer drained into the FIFO by the interrupt handler
+    tx: Mutex<RingBuffer<TX_BUFFER_SIZE>>,
+    /// Port has been initialized
+    present: AtomicBool,
+    /// Bytes dropped because the RX buffer was full
+    rx_dropped: AtomicUsize,
+    /// Bytes lost because the hardware RX FIFO overflowed
+    rx_overruns: AtomicUsize,
+    /// Bytes received with a framing, parity, or break error
+    rx_line_errors: AtomicUsize,
+    /// Software (XON/XOFF) flow control enabled
+    flow_control: AtomicBool,
+    /// XOFF has been sent and not yet followed by XON
+    xoff_sent: AtomicBool,
+}
+
+impl PortState {
+    const fn new(id: usize) -> Self {
+        let info = DEFAULT_PORTS[id];
+        Self {
+            uart: Mutex::new(Uart::new(id, crate::mmu::phys_to_virt(info.base))),
+            base: AtomicUsize::new(info.base),
+            clock_hz: AtomicU32::new(info.clock_hz),
+            irq: AtomicU32::new(info.irq),
+            known: AtomicBool::new(true),
+            rx: Mutex::new(RingBuffer::new()),
+            tx: Mutex::new(RingBuffer::new()),
+            present: AtomicBool::new(false),
+            rx_dropped: AtomicUsize::new(0),
+            rx_overruns: AtomicUsize::new(0),
+            rx_line_errors: AtomicUsize::new(0),
+            flow_control: AtomicBool::new(false),
+            xoff_sent: AtomicBool::new(false),
+        }
+    }
+}
+
+static PORTS: [PortState; MAX_PORTS] = [PortState::new(0), PortState::new(1)];
+
+/// Port that kernel output (`print!`) and console input use
+static CONSOLE_PORT: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once interrupts are up; before that, output is fully synchronous.
+static TX_IRQ_ENABLED: AtomicBool = AtomicBool::new(false);
+
+extern "Rust" {
+    /// Block the current task until input arrives on a port (kernel hook).
+    /// Called with IRQs masked.
+    fn kernel_input_wait(port: usize);
+
+    /// Wake tasks waiting for input on a port (kernel hook).
+    fn kernel_input_wake(port: usize);
+
+    /// Ctrl-C was typed on a port (kernel hook). Returns `true` if the
+    /// kernel consumed it; otherwise the byte is delivered as normal input.
+    fn kernel_console_interrupt(port: usize) -> bool;
+
+    /// Read an edited console line into a buffer, blocking (kernel hook).
+    /// Returns its length.
+    fn kernel_console_read_line(buf: &mut [u8]) -> usize;
+
+    /// Read an edited console line into a buffer if one is complete
+    /// (kernel hook).
+    fn kernel_console_try_read_line(buf: &mut [u8]) -> Option<usize>;
+}
+
+/// ASCII ETX, sent by Ctrl-C
+const CTRL_C: u8 = 0x03;
+
+// =============================================================================
+// Ports
 // =============================================================================
 
-/// Global UART instance, protected by a spinlock for thread-safety.
-/// 
-/// We use a static Mutex to allow multiple parts of the kernel to print
-/// without stepping on each other's output.
-static UART: Mutex<Uart> = Mutex::new(Uart::new(UART0_BASE));
+/// Find the PL011s in the device tree.
+///
+/// The port named by `/chosen/stdout-path` becomes port 0. Without a
+/// device tree, the QEMU virt defaults are returned. Port 0 always falls
+/// back to the default so there is a console.
+pub fn discover(dtb: Option<&Dtb>) -> [Option<PortInfo>; MAX_PORTS] {
+    let Some(dtb) = dtb else {
+        return DEFAULT_PORTS.map(Some);
+    };
+
+    let info = |node: &dtb::Node| -> Option<PortInfo> {
+        Some(PortInfo {
+            base: node.reg(0)?.0 as usize,
+            clock_hz: node.clock_frequency().unwrap_or(UART_CLOCK_HZ),
+            irq: node.irq(0)?,
+        })
+    };
+
+    let stdout = dtb.find_path("/chosen")
+        .and_then(|chosen| chosen.prop_str("stdout-path"))
+        .and_then(|path| dtb.find_path(path.split(':').next().unwrap_or(path)))
+        .filter(|node| node.is_compatible("arm,pl011"));
+
+    let mut ports = [None; MAX_PORTS];
+    let mut next = 0;
+    if let Some(node) = stdout {
+        ports[0] = info(&node);
+        next = 1;
+    }
+    let mut index = 0;
+    while next < MAX_PORTS {
+        let Some(node) = dtb.find_compatible("arm,pl011", index) else { break };
+        index += 1;
+        if stdout.is_some_and(|s| s.name == node.name) {
+            continue;
+        }
+        ports[next] = info(&node);
+        next += 1;
+    }
+
+    if ports[0].is_none() {
+        ports[0] = Some(DEFAULT_PORTS[0]);
+    }
+    ports
+}
+
+/// Record where a port lives, or mark it absent with `None`.
+///
+/// Takes effect the next time the port is initialized.
+pub fn register_port(id: usize, info: Option<PortInfo>) {
+    let state = &PORTS[id];
+    if let Some(info) = info {
+        state.base.store(info.base, Ordering::Relaxed);
+        state.clock_hz.store(info.clock_hz, Ordering::Relaxed);
+        state.irq.store(info.irq, Ordering::Relaxed);
+    }
+    state.known.store(info.is_some(), Ordering::Release);
+}
+
+/// Initialize the console UART (port 0) at the given location.
+///
+/// Its handler is registered here; the interrupt is enabled once the GIC is
+/// up (see `enable_irq`).
+pub fn init(info: PortInfo) {
+    register_port(0, Some(info));
+    *PORTS[0].uart.lock() = Uart::port(0);
+    PORTS[0].uart.lock().init();
+    PORTS[0].present.store(true, Ordering::Release);
+    crate::gic::register_handler(info.irq, irq_handler);
+}
+
+/// Location of a port.
+pub fn port_info(id: usize) -> PortInfo {
+    let state = &PORTS[id];
+    PortInfo {
+        base: state.base.load(Ordering::Relaxed),
+        clock_hz: state.clock_hz.load(Ordering::Relaxed),
+        irq: state.irq.load(Ordering::Relaxed),
+    }
+}
+
+/// Enable a port's interrupt in the GIC. The PL011 interrupt is
+/// level-sensitive.
+pub fn enable_irq(id: usize) {
+    let irq = PORTS[id].irq.load(Ordering::Relaxed);
+    crate::gic::set_trigger(irq, crate::gic::TriggerMode::Level);
+    crate::gic::set_priority(irq, crate::gic::DEFAULT_PRIORITY);
+    crate::gic::enable_irq(irq);
+}
+
+/// Initialize another UART port and enable its interrupt.
+///
+/// Ports other than UART0 are brought up on first use, as the hardware
+/// may not exist on every machine. Returns `false` for unknown ports.
+pub fn init_port(id: usize) -> bool {
+    if id >= MAX_PORTS || !PORTS[id].known.load(Ordering::Acquire) {
+        return false;
+    }
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let port = &PORTS[id];
+    if !port.present.load(Ordering::Acquire) {
+        *port.uart.lock() = Uart::port(id);
+        port.uart.lock().init();
+        port.present.store(true, Ordering::Release);
+        crate::gic::register_handler(port.irq.load(Ordering::Relaxed), irq_handler);
+        enable_irq(id);
+    }
+    crate::cpu::restore_interrupts(daif);
+    true
+}
+
+/// Check whether a port has been initialized.
+pub fn is_present(id: usize) -> bool {
+    id < MAX_PORTS && PORTS[id].present.load(Ordering::Acquire)
+}
 
-/// Initialize the global UART.
-pub fn init() {
-    UART.lock().init();
+/// Port that owns a GIC interrupt ID, if any.
+pub fn port_for_irq(irq: u32) -> Option<usize> {
+    (0..MAX_PORTS).find(|&id| PORTS[id].irq.load(Ordering::Relaxed) == irq && is_present(id))
 }
 
-/// Print a string to the UART.
+/// Direct kernel console output to another port.
+///
+/// Returns `false` if the port hasn't been initialized.
+pub fn set_console(id: usize) -> bool {
+    if !is_present(id) {
+        return false;
+    }
+    flush();
+    CONSOLE_PORT.store(id, Ordering::Release);
+    true
+}
+
+/// Port currently used for kernel console output.
+pub fn console_port() -> usize {
+    CONSOLE_PORT.load(Ordering::Acquire)
+}
+
+/// Switch console output to interrupt-driven transmission.
+///
+/// Called once the GIC and CPU interrupts are enabled.
+pub fn enable_tx_interrupts() {
+    TX_IRQ_ENABLED.store(true, Ordering::Release);
+}
+
+/// Write bytes to a port. Newlines are sent as CRLF.
+///
+/// Returns `false` if the port isn't present.
+pub fn write_port(id: usize, bytes: &[u8]) -> bool {
+    if !is_present(id) {
+        return false;
+    }
+    let daif = crate::cpu::save_and_disable_interrupts();
+    PORTS[id].uart.lock().write_bytes(bytes);
+    crate::cpu::restore_interrupts(daif);
+    true
+}
+
+/// Print a string to the console UART.
+///
+/// Unlike `print!`, this output is not recorded in the kernel log, which
+/// suits input echo and replaying the log itself.
 pub fn puts(s: &str) {
-    UART.lock().puts(s);
+    write_port(console_port(), s.as_bytes());
 }
 
-/// Print a formatted string to the UART.
+/// Print a formatted string to all console sinks, recording it in the
+/// kernel log.
 pub fn _print(args: fmt::Arguments) {
-    UART.lock().write_fmt(args).unwrap();
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let _ = ConsoleWriter.write_fmt(args);
+    crate::cpu::restore_interrupts(daif);
+}
+
+/// Writer that feeds the kernel log and every console sink.
+struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::log::record(s);
+        crate::console::write_all(s);
+        Ok(())
+    }
+}
+
+/// The console UART as a console sink. Always registered.
+pub(crate) struct UartSink;
+
+pub(crate) static UART_SINK: UartSink = UartSink;
+
+impl ConsoleSink for UartSink {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn write_str(&self, s: &str) -> fmt::Result {
+        PORTS[console_port()].uart.lock().write_str(s)
+    }
+
+    fn flush(&self) {
+        flush();
+    }
+}
+
+/// Writer for the panic path: polls the console UART directly and takes no
+/// locks, so a panic raised while one is held still gets its message out.
+/// Bypasses the kernel log and the other console sinks.
+pub struct PanicWriter;
+
+impl Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Uart::port(console_port()).put_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// A `PanicWriter`, once output already buffered has gone out ahead of it
+/// (if the buffer's lock is free). Call with IRQs masked.
+pub fn panic_writer() -> PanicWriter {
+    Uart::port(console_port()).drain_tx();
+    PanicWriter
+}
+
+/// Block until all buffered console output has left the UART.
+///
+/// Used on panic and poweroff paths so the last messages are never lost.
+pub fn flush() {
+    let daif = crate::cpu::save_and_disable_interrupts();
+    Uart::port(console_port()).drain_tx();
+    crate::cpu::restore_interrupts(daif);
 }
 
 // =============================================================================
 // Print Macros
 // =============================================================================
 
-/// Print to the kernel console.
+/// Print to the kernel console (all registered sinks).
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
@@ -224,81 +877,274 @@ macro_rules! println {
 }
 
 // =============================================================================
-// Input Ring Buffer
+// Interrupt Handling
 // =============================================================================
 
-struct RingBuffer {
-    data: [u8; 128],
-    head: usize,
-    tail: usize,
+/// GIC handler for all UART interrupts.
+fn irq_handler(irq: u32) {
+    if let Some(port) = port_for_irq(irq) {
+        handle_irq(port);
+    }
 }
 
-impl RingBuffer {
-    const fn new() -> Self {
-        Self { data: [0; 128], head: 0, tail: 0 }
+/// Handle a UART interrupt (Rx/Tx) for port `id`.
+/// This is called from the GIC dispatcher (see `irq_handler`).
+///
+/// Drains the RX FIFO into the ring buffer and refills the TX FIFO from
+/// the transmit buffer. Bytes are delivered untouched: echo and newline
+/// translation belong to the kernel's line discipline.
+pub fn handle_irq(id: usize) {
+    let uart = Uart::port(id);
+    let state = uart.state();
+    let mis = uart.read_reg(regs::MIS);
+
+    if mis & (imsc::RXIM | imsc::RTIM) != 0 {
+        // Keep this port's line quiet while the ring buffer is updated, so
+        // the handler can't re-enter itself even if IRQs get unmasked
+        let _mask = crate::gic::IrqMaskGuard::new(state.irq.load(Ordering::Relaxed));
+        let mut rx = state.rx.lock();
+
+        // While RX FIFO is NOT empty...
+        while uart.read_reg(regs::FR) & flags::RXFE == 0 {
+            let data = uart.read_reg(regs::DR);
+            let errors = data >> rsr::DR_SHIFT;
+            if errors & rsr::OE != 0 {
+                state.rx_overruns.fetch_add(1, Ordering::Relaxed);
+            }
+            if errors & (rsr::FE | rsr::PE | rsr::BE) != 0 {
+                state.rx_line_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            let c = (data & 0xFF) as u8;
+            if c == CTRL_C && unsafe { kernel_console_interrupt(id) } {
+                continue;
+            }
+            if !rx.push(c) {
+                state.rx_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // Ask the sender to pause before the buffer overflows. Flow control
+        // characters bypass the TX buffer so they aren't stuck behind output.
+        if state.flow_control.load(Ordering::Relaxed)
+            && rx.len() > RX_HIGH_WATER
+            && !state.xoff_sent.swap(true, Ordering::Relaxed)
+        {
+            uart.putc(XOFF);
+        }
+
+        // Clear RX Interrupt (RXIC) and Timeout (RTIC)
+        uart.write_reg(regs::ICR, icr::RXIC | icr::RTIC);
+
+        let has_input = !rx.is_empty();
+        drop(rx);
+        if has_input {
+            unsafe { kernel_input_wake(id); }
+        }
     }
 
-    fn push(&mut self, byte: u8) {
-        let next = (self.head + 1) % 128;
-        if next != self.tail {
-            self.data[self.head] = byte;
-            self.head = next;
+    if mis & imsc::TXIM != 0 {
+        let mut tx = state.tx.lock();
+        uart.fill_tx_fifo(&mut tx);
+        if tx.is_empty() {
+            uart.set_tx_irq(false);
         }
+        uart.write_reg(regs::ICR, icr::TXIC);
     }
+}
 
+/// Deliver `bytes` received by another device (a virtio console, say) as
+/// input on port `id`, as though they had arrived on its RX line. Ctrl-C
+/// goes to the kernel first, as for the UART. Safe from IRQ context.
+pub fn inject_input(id: usize, bytes: &[u8]) {
+    if !is_present(id) {
+        return;
+    }
+    let state = &PORTS[id];
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let mut delivered = false;
+    {
+        let mut rx = state.rx.lock();
+        for &c in bytes {
+            if c == CTRL_C && unsafe { kernel_console_interrupt(id) } {
+                continue;
+            }
+            if rx.push(c) {
+                delivered = true;
+            } else {
+                state.rx_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    crate::cpu::restore_interrupts(daif);
+    if delivered {
+        unsafe { kernel_input_wake(id); }
+    }
+}
 
+/// Read a line from the console without blocking.
+///
+/// Input goes through the kernel's line discipline, which echoes it and
+/// applies backspace/DEL edits. Returns the line length (without the
+/// terminator) once a full line has been entered, or `None` if the line
+/// is still incomplete. Lines longer than `buf` are truncated.
+pub fn try_read_line(buf: &mut [u8]) -> Option<usize> {
+    unsafe { kernel_console_try_read_line(buf) }
 }
 
-static RX_BUFFER: Mutex<RingBuffer> = Mutex::new(RingBuffer::new());
+/// Read a line from the console, blocking until one is complete.
+///
+/// The calling task sleeps on the console wait queue between keystrokes.
+/// Returns the line length, without the terminator.
+pub fn read_line(buf: &mut [u8]) -> usize {
+    unsafe { kernel_console_read_line(buf) }
+}
 
-/// Handle UART Interrupt (Rx).
-/// This is called from the exception handler.
-pub fn handle_irq() {
-    let uart = Uart::new(UART0_BASE);
-    
-    // Check Flags: RXFE (Receive FIFO Empty)
-    // While RX FIFO is NOT empty...
-    while uart.read_reg(regs::FR) & flags::RXFE == 0 {
-        // Read byte
-        let c = (uart.read_reg(regs::DR) & 0xFF) as u8;
-        
-        // Push to buffer
-        RX_BUFFER.lock().push(c);
-        
-        // Echo back (for CLI feedback)
-        if c == b'\r' {
-            uart.putc(b'\r');
-            uart.putc(b'\n');
-        } else if c == 8 || c == 127 { // Backspace
-            uart.putc(8);
-            uart.putc(b' ');
-            uart.putc(8);
-        } else {
-             uart.putc(c);
+/// Read one byte received on a port (non-blocking).
+pub fn read_byte(id: usize) -> Option<u8> {
+    if !is_present(id) {
+        return None;
+    }
+    // Mask interrupts so the IRQ handler can't deadlock on the RX buffer
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let state = &PORTS[id];
+    let result = {
+        let mut rx = state.rx.lock();
+        let result = rx.pop();
+
+        // Resume the sender once the buffer has drained
+        if rx.len() < RX_LOW_WATER && state.xoff_sent.swap(false, Ordering::Relaxed) {
+            Uart::port(id).putc(XON);
+        }
+        result
+    };
+    crate::cpu::restore_interrupts(daif);
+    result
+}
+
+/// Read received bytes from a port into `buf` (non-blocking).
+///
+/// Returns the number of bytes copied, which may be 0.
+pub fn read_port(id: usize, buf: &mut [u8]) -> usize {
+    let mut n = 0;
+    while n < buf.len() {
+        match read_byte(id) {
+            Some(c) => {
+                buf[n] = c;
+                n += 1;
+            }
+            None => break,
         }
     }
-    
-    // Clear RX Interrupt (RXIC) and Timeout (RTIC)
-    // UARTICR (0x44) bit 4 (RXIC) and bit 6 (RTIC)
-    uart.write_reg(0x44, (1 << 4) | (1 << 6));
+    n
+}
+
+/// Block the current task until input arrives on a port.
+///
+/// Must be called with IRQs masked, after checking for buffered input.
+pub fn wait_for_input(id: usize) {
+    unsafe { kernel_input_wait(id); }
 }
 
-/// Read a character from the serial port (non-blocking).
+/// Read a character from the console port (non-blocking).
+///
+/// Returns the oldest byte received by the interrupt handler, if any.
 pub fn get_char() -> Option<u8> {
-    // DEBUG: Polling Mode (Bypass Interrupts)
-    let uart = Uart::new(UART0_BASE);
-    if uart.read_reg(regs::FR) & flags::RXFE == 0 {
-        let c = (uart.read_reg(regs::DR) & 0xFF) as u8;
-        return Some(c);
-    }
-    None
-
-    /*
-    // Disable interrupts to prevent deadlock with IRQ handler
-    crate::cpu::disable_interrupts();
-    let result = RX_BUFFER.lock().pop();
-    // Re-enable interrupts
-    unsafe { crate::cpu::enable_interrupts(); }
-    result
-    */
+    read_byte(console_port())
+}
+
+/// Number of console input bytes dropped because the ring buffer was full.
+pub fn rx_dropped() -> usize {
+    PORTS[console_port()].rx_dropped.load(Ordering::Relaxed)
+}
+
+// =============================================================================
+// Line Settings and Status
+// =============================================================================
+
+/// Change a port's line settings. See `Uart::configure`.
+pub fn configure(id: usize, baud: u32, data_bits: u8, parity: Parity, stop_bits: u8) -> bool {
+    if !is_present(id) {
+        return false;
+    }
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let ok = PORTS[id].uart.lock().configure(baud, data_bits, parity, stop_bits);
+    crate::cpu::restore_interrupts(daif);
+    ok
+}
+
+/// Current line settings of a port.
+pub fn config(id: usize) -> Option<UartConfig> {
+    if !is_present(id) {
+        return None;
+    }
+    let daif = crate::cpu::save_and_disable_interrupts();
+    let config = PORTS[id].uart.lock().config();
+    crate::cpu::restore_interrupts(daif);
+    Some(config)
+}
+
+/// Snapshot of FIFO state and error counters.
+#[derive(Debug, Clone, Copy)]
+pub struct UartStatus {
+    pub tx_fifo_empty: bool,
+    pub tx_fifo_full: bool,
+    pub rx_fifo_empty: bool,
+    pub rx_fifo_full: bool,
+    pub busy: bool,
+    /// Sticky error bits currently set in RSR
+    pub rsr: u32,
+    pub overruns: usize,
+    pub line_errors: usize,
+    pub dropped: usize,
+    /// Bytes waiting in the RX ring buffer
+    pub rx_buffered: usize,
+    pub flow_control: bool,
+    /// Sender is currently paused by XOFF
+    pub xoff_sent: bool,
+}
+
+/// Read FIFO flags and receive error counters of a port.
+pub fn status(id: usize) -> Option<UartStatus> {
+    if !is_present(id) {
+        return None;
+    }
+    let uart = Uart::port(id);
+    let state = uart.state();
+    let fr = uart.read_reg(regs::FR);
+    Some(UartStatus {
+        tx_fifo_empty: fr & flags::TXFE != 0,
+        tx_fifo_full: fr & flags::TXFF != 0,
+        rx_fifo_empty: fr & flags::RXFE != 0,
+        rx_fifo_full: fr & flags::RXFF != 0,
+        busy: fr & flags::BUSY != 0,
+        rsr: uart.read_reg(regs::RSR) & 0xF,
+        overruns: state.rx_overruns.load(Ordering::Relaxed),
+        line_errors: state.rx_line_errors.load(Ordering::Relaxed),
+        dropped: state.rx_dropped.load(Ordering::Relaxed),
+        rx_buffered: {
+            let daif = crate::cpu::save_and_disable_interrupts();
+            let len = state.rx.lock().len();
+            crate::cpu::restore_interrupts(daif);
+            len
+        },
+        flow_control: state.flow_control.load(Ordering::Relaxed),
+        xoff_sent: state.xoff_sent.load(Ordering::Relaxed),
+    })
+}
+
+/// Enable or disable XON/XOFF flow control on a port (off by default).
+///
+/// Disabling it releases a sender currently paused by XOFF.
+pub fn set_flow_control(id: usize, enabled: bool) -> bool {
+    if !is_present(id) {
+        return false;
+    }
+    let state = &PORTS[id];
+    state.flow_control.store(enabled, Ordering::Relaxed);
+    if !enabled && state.xoff_sent.swap(false, Ordering::Relaxed) {
+        let daif = crate::cpu::save_and_disable_interrupts();
+        Uart::port(id).putc(XON);
+        crate::cpu::restore_interrupts(daif);
+    }
+    true
 }
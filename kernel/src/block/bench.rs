@@ -0,0 +1,126 @@
+// =============================================================================
+// APRK OS - Block Device Benchmark
+// =============================================================================
+// `diskbench`: sequential reads, sequential writes and random 4 KB reads
+// on a registered device, timed with the system counter. Reads go to the
+// device directly, past the block cache. Writes go to a scratch file on
+// the root filesystem (synced, so the time includes writing the cache
+// back), or with `raw` to the device itself: each chunk is read first and
+// written back unchanged, so nothing is lost unless the machine stops
+// partway.
+// =============================================================================
+
+use alloc::vec::Vec;
+use aprk_arch_arm64::timer::Timer;
+use crate::fs::FsError;
+use crate::println;
+use super::{BlockDevice, Device, BLOCK_SIZE};
+
+/// Bytes per sequential request
+const CHUNK: usize = 64 * 1024;
+/// Bytes per random read
+const RANDOM_SIZE: usize = 4096;
+/// Random reads timed
+const RANDOM_READS: usize = 256;
+/// Scratch file for the write test
+const SCRATCH: &str = "/diskbench.tmp";
+
+/// `bytes` moved in `us` microseconds, as "12.34 MB/s".
+fn rate(bytes: usize, us: u128) -> alloc::string::String {
+    let kb_per_s = bytes as u128 * 1_000_000 / 1024 / us.max(1);
+    alloc::format!("{}.{:02} MB/s", kb_per_s / 1024, kb_per_s % 1024 * 100 / 1024)
+}
+
+/// Microseconds since counter value `start`.
+fn since(start: u64) -> u128 {
+    Timer::ticks_to_duration(Timer::counter() - start).as_micros()
+}
+
+/// Benchmark device `name` over its first `kb` kilobytes (rounded down to
+/// 64 KB). `raw` writes to the device instead of a scratch file.
+pub fn run(name: &str, kb: usize, raw: bool) {
+    let Some(dev) = super::get(name) else {
+        println!("[shell] Error: diskbench: No block device {}", name);
+        return;
+    };
+    let size = (kb * 1024).min(dev.size() as usize) / CHUNK * CHUNK;
+    let mut buf = Vec::new();
+    if size == 0 || buf.try_reserve_exact(CHUNK).is_err() {
+        println!("[shell] Error: diskbench: {} is smaller than {} KB, or no memory", name, CHUNK / 1024);
+        return;
+    }
+    buf.resize(CHUNK, 0);
+    let chunk_blocks = (CHUNK / BLOCK_SIZE) as u64;
+    println!("{}: {} KB in {} KB requests", name, size / 1024, CHUNK / 1024);
+
+    let start = Timer::counter();
+    for chunk in 0..(size / CHUNK) as u64 {
+        if let Err(e) = dev.read_blocks(chunk * chunk_blocks, &mut buf) {
+            println!("[shell] Error: diskbench: Read at block {}: {:?}", chunk * chunk_blocks, e);
+            return;
+        }
+    }
+    println!("  sequential read:  {}", rate(size, since(start)));
+
+    let written = if raw { raw_write(&dev, size, &mut buf) } else { file_write(size, &buf) };
+    match written {
+        Ok(us) => println!("  sequential write: {}{}", rate(size, us), if raw { " (raw)" } else { " (file, synced)" }),
+        Err(e) => println!("[shell] Error: diskbench: Write: {}", e),
+    }
+
+    // Fixed-seed xorshift, so runs are comparable
+    let mut seed: u64 = 0x2545_F491_4F6C_DD1D;
+    let slots = (size / RANDOM_SIZE) as u64;
+    let start = Timer::counter();
+    for _ in 0..RANDOM_READS {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let block = seed % slots * (RANDOM_SIZE / BLOCK_SIZE) as u64;
+        if let Err(e) = dev.read_blocks(block, &mut buf[..RANDOM_SIZE]) {
+            println!("[shell] Error: diskbench: Read at block {}: {:?}", block, e);
+            return;
+        }
+    }
+    let us = since(start);
+    println!("  random 4 KB read: {} IOPS ({})", RANDOM_READS as u128 * 1_000_000 / us.max(1),
+        rate(RANDOM_READS * RANDOM_SIZE, us));
+}
+
+/// Write each chunk of the first `size` bytes of `dev` back as read,
+/// timing the writes and a final flush. Microseconds taken.
+fn raw_write(dev: &Device, size: usize, buf: &mut [u8]) -> Result<u128, FsError> {
+    if dev.readonly() {
+        return Err(FsError::ReadOnlyFs);
+    }
+    let chunk_blocks = (CHUNK / BLOCK_SIZE) as u64;
+    let mut ticks = 0;
+    for chunk in 0..(size / CHUNK) as u64 {
+        dev.read_blocks(chunk * chunk_blocks, buf).map_err(|_| FsError::IO)?;
+        let start = Timer::counter();
+        dev.write_blocks(chunk * chunk_blocks, buf).map_err(|_| FsError::Io { block: Some(chunk * chunk_blocks), write: true })?;
+        ticks += Timer::counter() - start;
+    }
+    let start = Timer::counter();
+    dev.flush().map_err(|_| FsError::IO)?;
+    ticks += Timer::counter() - start;
+    Ok(Timer::ticks_to_duration(ticks).as_micros())
+}
+
+/// Write `size` bytes to the scratch file in chunks of `buf` and sync,
+/// then remove it. Microseconds taken.
+fn file_write(size: usize, buf: &[u8]) -> Result<u128, FsError> {
+    let start = Timer::counter();
+    let written = (|| {
+        let mut file = crate::fs::open_write(SCRATCH, true)?;
+        for _ in 0..size / CHUNK {
+            file.write(buf)?;
+        }
+        file.flush()?;
+        drop(file);
+        crate::fs::sync()
+    })();
+    let us = since(start);
+    let _ = crate::fs::remove(SCRATCH);
+    written.map(|()| us)
+}
@@ -0,0 +1,376 @@
+// =============================================================================
+// APRK OS - Block Devices
+// =============================================================================
+// Anything holding numbered blocks implements `BlockDevice`: virtio disks,
+// RAM disks, and partitions (a window onto another device). Devices are
+// registered by name (`blk0`, `blk0p1`, `ram0`) and looked up with `get`;
+// the filesystem layer and `lsblk` only see them through here.
+//
+// Every device knows the whole disk it's on and where it starts there, so
+// the block cache can key a partition's blocks by the disk's: a block read
+// through the partition and through the disk is the same cache entry.
+//
+// Requests through a registered device are checked against its size before
+// they reach the driver, and an `IoError` (the one error that may go away)
+// is retried up to `RETRIES` times, a little later each time. Retries and
+// requests that failed for good are counted per device (`lsblk -s`).
+//
+// For testing error paths, `set_fault_interval(n)` makes every nth request
+// through a registered device fail (`blkfault` in the shell).
+// =============================================================================
+
+pub mod bench;
+pub mod ram;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Bytes per block. The block cache and FAT code assume 512; devices
+/// with other sizes aren't registered.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Extra attempts at a request that failed with `IoError`
+const RETRIES: u32 = 3;
+
+/// Why a block device request failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The device is gone, or was never set up
+    NotReady,
+    /// The device failed the request; trying again may work
+    IoError,
+    /// Blocks past the end of the device
+    OutOfRange,
+    /// A request the device doesn't take, such as a flush or a buffer it
+    /// can't use
+    Unsupported,
+    /// The device refuses writes
+    ReadOnly,
+}
+
+impl BlockError {
+    /// Whether trying the request again may work.
+    pub fn is_transient(self) -> bool {
+        self == BlockError::IoError
+    }
+}
+
+/// A device of fixed-size blocks.
+pub trait BlockDevice: Send + Sync {
+    /// Bytes per block
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn num_blocks(&self) -> u64;
+
+    /// Read `buf.len() / block_size()` blocks from `start` on.
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf` to the blocks from `start` on.
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Commit written blocks to stable storage.
+    fn flush(&self) -> Result<(), BlockError>;
+
+    /// Whether writes are refused.
+    fn readonly(&self) -> bool {
+        false
+    }
+
+    /// Tell the device blocks `start..start + count` hold nothing worth
+    /// keeping, so it can free the space (TRIM).
+    fn discard(&self, _start: u64, _count: u64) -> Result<(), BlockError> {
+        Err(BlockError::Unsupported)
+    }
+
+    /// Whether `discard` does anything.
+    fn can_discard(&self) -> bool {
+        false
+    }
+}
+
+/// Check that `len` bytes from block `start` fit on `device`.
+fn check_range(device: &dyn BlockDevice, start: u64, len: usize) -> Result<(), BlockError> {
+    let blocks = (len / device.block_size()) as u64;
+    match start.checked_add(blocks) {
+        Some(end) if end <= device.num_blocks() => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// A run of blocks of another device, as a device of its own.
+pub struct PartitionDevice {
+    disk: Arc<dyn BlockDevice>,
+    /// First block on `disk`
+    start: u64,
+    blocks: u64,
+}
+
+impl PartitionDevice {
+    pub fn new(disk: Arc<dyn BlockDevice>, start: u64, blocks: u64) -> Self {
+        Self { disk, start, blocks }
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        self.disk.read_blocks(self.start + start, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        self.disk.write_blocks(self.start + start, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+
+    fn readonly(&self) -> bool {
+        self.disk.readonly()
+    }
+
+    fn discard(&self, start: u64, count: u64) -> Result<(), BlockError> {
+        check_range(self, start, count as usize * self.block_size())?;
+        self.disk.discard(self.start + start, count)
+    }
+
+    fn can_discard(&self) -> bool {
+        self.disk.can_discard()
+    }
+}
+
+/// Error counters of a registered device.
+#[derive(Default)]
+struct Counters {
+    /// Requests tried again after an `IoError`
+    retries: AtomicU64,
+    /// Requests that failed for good
+    failures: AtomicU64,
+}
+
+/// Error counters of a registered device, since it was registered.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceStats {
+    pub retries: u64,
+    pub failures: u64,
+}
+
+/// A registered device.
+#[derive(Clone)]
+pub struct Device {
+    /// Number in the registry
+    pub id: usize,
+    pub name: String,
+    /// Registry number of the whole disk it's on: its own, for a disk
+    pub disk: usize,
+    /// First block on that disk
+    pub start: u64,
+    device: Arc<dyn BlockDevice>,
+    counters: Arc<Counters>,
+}
+
+impl Device {
+    /// Whether it's part of another device.
+    pub fn is_partition(&self) -> bool {
+        self.disk != self.id
+    }
+
+    /// Size in bytes.
+    pub fn size(&self) -> u64 {
+        self.num_blocks() * self.block_size() as u64
+    }
+
+    /// Error counters.
+    pub fn stats(&self) -> DeviceStats {
+        DeviceStats {
+            retries: self.counters.retries.load(Ordering::Relaxed),
+            failures: self.counters.failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Run `request` (`what`, at `block`), retrying it while it fails with
+    /// an error that may go away.
+    fn retry(&self, what: &str, block: u64, mut request: impl FnMut() -> Result<(), BlockError>) -> Result<(), BlockError> {
+        let mut tries = 0;
+        loop {
+            let result = inject_fault(self, what, block).and_then(|()| request());
+            match result {
+                Err(e) if e.is_transient() && tries < RETRIES => {
+                    tries += 1;
+                    self.counters.retries.fetch_add(1, Ordering::Relaxed);
+                    crate::log_warn!("block", "{}: {} at block {} failed, retrying ({}/{})",
+                        self.name, what, block, tries, RETRIES);
+                    backoff(tries);
+                }
+                Err(e) => {
+                    self.counters.failures.fetch_add(1, Ordering::Relaxed);
+                    return Err(e);
+                }
+                Ok(()) => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Wait `tries` milliseconds before retrying: letting other tasks run if
+/// the scheduler is up, spinning otherwise.
+fn backoff(tries: u32) {
+    use aprk_arch_arm64::timer::Timer;
+    let until = Timer::uptime() + core::time::Duration::from_millis(tries as u64);
+    while Timer::uptime() < until {
+        if crate::sched::is_enabled() {
+            crate::sched::schedule();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl BlockDevice for Device {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.device.num_blocks()
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        self.retry("Read", start, || self.device.read_blocks(start, buf))
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.readonly() {
+            return Err(BlockError::ReadOnly);
+        }
+        check_range(self, start, buf.len())?;
+        self.retry("Write", start, || self.device.write_blocks(start, buf))
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.retry("Flush", 0, || self.device.flush())
+    }
+
+    fn readonly(&self) -> bool {
+        self.device.readonly()
+    }
+
+    /// A no-op on devices that can't discard: the blocks just keep
+    /// their contents.
+    fn discard(&self, start: u64, count: u64) -> Result<(), BlockError> {
+        if !self.can_discard() {
+            return Ok(());
+        }
+        if self.readonly() {
+            return Err(BlockError::ReadOnly);
+        }
+        check_range(self, start, count as usize * self.block_size())?;
+        match self.retry("Discard", start, || self.device.discard(start, count)) {
+            Err(BlockError::Unsupported) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn can_discard(&self) -> bool {
+        self.device.can_discard()
+    }
+}
+
+/// Fail every `FAULT_INTERVAL`th request, or none if 0
+static FAULT_INTERVAL: AtomicUsize = AtomicUsize::new(0);
+/// Requests since the interval was set
+static FAULT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Make every `n`th request from now on fail with `BlockError::IoError`,
+/// or stop failing them if `n` is 0.
+pub fn set_fault_interval(n: usize) {
+    FAULT_COUNT.store(0, Ordering::Relaxed);
+    FAULT_INTERVAL.store(n, Ordering::Relaxed);
+}
+
+/// The interval set by `set_fault_interval`.
+pub fn fault_interval() -> usize {
+    FAULT_INTERVAL.load(Ordering::Relaxed)
+}
+
+/// Count a request to `dev`, failing it if it's one to inject a fault in.
+fn inject_fault(dev: &Device, what: &str, block: u64) -> Result<(), BlockError> {
+    let n = FAULT_INTERVAL.load(Ordering::Relaxed);
+    if n != 0 && (FAULT_COUNT.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(n) {
+        crate::log_warn!("block", "{}: Injected fault: {} at block {}", dev.name, what, block);
+        return Err(BlockError::IoError);
+    }
+    Ok(())
+}
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+/// Add `device` to the registry as `name`, part of disk `disk` (and
+/// starting at block `start` there) or a disk of its own.
+fn add(name: &str, device: Arc<dyn BlockDevice>, disk: Option<&Device>, start: u64) -> Option<Device> {
+    if device.block_size() != BLOCK_SIZE {
+        crate::log_warn!("block", "{}: {}-byte blocks aren't supported", name, device.block_size());
+        return None;
+    }
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|d| d.name == name) {
+        crate::log_warn!("block", "{} is already registered", name);
+        return None;
+    }
+    let id = devices.len();
+    let (disk, start) = match disk {
+        Some(disk) => (disk.disk, disk.start + start),
+        None => (id, 0),
+    };
+    let entry = Device { id, name: String::from(name), disk, start, device, counters: Arc::default() };
+    devices.push(entry.clone());
+    Some(entry)
+}
+
+/// Register a whole disk as `name`.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> Option<Device> {
+    let entry = add(name, device, None, 0)?;
+    crate::log_info!("block", "{}: {} blocks ({} KB)", name, entry.num_blocks(), entry.size() / 1024);
+    Some(entry)
+}
+
+/// Register `blocks` blocks of `disk` from `start` on as `name`.
+pub fn register_partition(name: &str, disk: &Device, start: u64, blocks: u64) -> Option<Device> {
+    let partition = PartitionDevice::new(disk.device.clone(), start, blocks);
+    add(name, Arc::new(partition), Some(disk), start)
+}
+
+/// The device called `name`.
+pub fn get(name: &str) -> Option<Device> {
+    DEVICES.lock().iter().find(|d| d.name == name).cloned()
+}
+
+/// The device registered as number `id`.
+pub fn by_id(id: usize) -> Option<Device> {
+    DEVICES.lock().get(id).cloned()
+}
+
+/// Every registered device, disks before their partitions.
+pub fn devices() -> Vec<Device> {
+    DEVICES.lock().clone()
+}
+
+/// Every whole disk, in the order registered.
+pub fn disks() -> Vec<Device> {
+    DEVICES.lock().iter().filter(|d| !d.is_partition()).cloned().collect()
+}
@@ -0,0 +1,71 @@
+// =============================================================================
+// APRK OS - RAM Disk
+// =============================================================================
+// A block device held in the kernel heap, for trying filesystems out
+// without touching a real disk. Its contents go with it.
+// =============================================================================
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::{check_range, BlockDevice, BlockError, BLOCK_SIZE};
+
+pub struct RamDisk {
+    data: Mutex<Vec<u8>>,
+    blocks: u64,
+}
+
+impl RamDisk {
+    /// A zeroed disk of `blocks` blocks, or `None` if the heap can't hold it.
+    pub fn new(blocks: u64) -> Option<Self> {
+        let len = blocks as usize * BLOCK_SIZE;
+        let mut data = Vec::new();
+        data.try_reserve_exact(len).ok()?;
+        data.resize(len, 0);
+        Some(Self { data: Mutex::new(data), blocks })
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        let at = start as usize * BLOCK_SIZE;
+        buf.copy_from_slice(&self.data.lock()[at..at + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, start, buf.len())?;
+        let at = start as usize * BLOCK_SIZE;
+        self.data.lock()[at..at + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Zeroes the blocks, as a disk reading discarded blocks back as
+    /// zeroes would.
+    fn discard(&self, start: u64, count: u64) -> Result<(), BlockError> {
+        check_range(self, start, count as usize * BLOCK_SIZE)?;
+        let at = start as usize * BLOCK_SIZE;
+        self.data.lock()[at..at + count as usize * BLOCK_SIZE].fill(0);
+        Ok(())
+    }
+
+    fn can_discard(&self) -> bool {
+        true
+    }
+}
+
+/// Register a new zeroed RAM disk of `kb` kilobytes as the next free
+/// `ramN`.
+pub fn create(kb: u64) -> Option<super::Device> {
+    let disk = RamDisk::new(kb * 1024 / BLOCK_SIZE as u64)?;
+    let n = (0..).find(|n| super::get(&alloc::format!("ram{}", n)).is_none())?;
+    super::register(&alloc::format!("ram{}", n), alloc::sync::Arc::new(disk))
+}
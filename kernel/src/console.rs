@@ -0,0 +1,358 @@
+// =============================================================================
+// APRK OS - Console Key Input
+// =============================================================================
+// Decodes raw UART bytes into keys. Terminals send arrow and navigation
+// keys as ANSI escape sequences (ESC [ A, ESC [ 3 ~, ...), which are
+// collapsed here into a single `Key` so consumers never see the raw bytes.
+//
+// On top of that sits the line discipline. In cooked mode (the default)
+// input is edited a line at a time with echo, backspace and CR -> LF
+// translation; in raw mode bytes reach the reader as typed, unechoed.
+//
+// The console also tracks the foreground task, which receives Ctrl-C.
+// =============================================================================
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use aprk_arch_arm64::timer::Timer;
+use aprk_arch_arm64::console::echo;
+use aprk_arch_arm64::{cpu, uart};
+use crate::sched;
+use spin::Mutex;
+
+/// How long to wait for the rest of an escape sequence before treating
+/// ESC as a key press of its own.
+const ESC_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A decoded key press.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// A plain byte (printable, Enter, Tab, Backspace, bare ESC)
+    Char(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Delete,
+    /// Ctrl + letter, e.g. `Ctrl('c')`
+    Ctrl(char),
+}
+
+/// Escape sequence decoder state
+#[derive(Clone, Copy)]
+enum State {
+    Normal,
+    /// Got ESC at the given uptime
+    Escape(Duration),
+    /// Got ESC [ and an optional numeric parameter
+    Csi(Duration, u8),
+}
+
+struct Decoder {
+    state: State,
+    /// Byte that ended an aborted escape sequence, delivered next
+    pending: Option<u8>,
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder { state: State::Normal, pending: None });
+
+/// Translate a byte outside of an escape sequence.
+fn plain_key(c: u8) -> Key {
+    match c {
+        b'\r' | b'\n' | b'\t' | 0x08 => Key::Char(c),
+        0x01..=0x1A => Key::Ctrl((b'a' + c - 1) as char),
+        _ => Key::Char(c),
+    }
+}
+
+/// Translate the final byte of an `ESC [` sequence.
+fn csi_key(param: u8, c: u8) -> Option<Key> {
+    match (param, c) {
+        (_, b'A') => Some(Key::Up),
+        (_, b'B') => Some(Key::Down),
+        (_, b'C') => Some(Key::Right),
+        (_, b'D') => Some(Key::Left),
+        (_, b'H') | (1, b'~') | (7, b'~') => Some(Key::Home),
+        (_, b'F') | (4, b'~') | (8, b'~') => Some(Key::End),
+        (3, b'~') => Some(Key::Delete),
+        _ => None,
+    }
+}
+
+/// Read a key without blocking.
+///
+/// Returns `None` if no complete key is available yet. A lone ESC is
+/// reported as `Key::Char(0x1b)` once `ESC_TIMEOUT` passes without the
+/// rest of a sequence arriving.
+pub fn read_key() -> Option<Key> {
+    // wait_key takes DECODER with IRQs masked; a task preempted holding
+    // it here would leave that spinning forever
+    let daif = cpu::save_and_disable_interrupts();
+    let key = decode(&mut DECODER.lock());
+    cpu::restore_interrupts(daif);
+    key
+}
+
+/// Decode the next key from the UART bytes available.
+fn decode(decoder: &mut Decoder) -> Option<Key> {
+    if let Some(c) = decoder.pending.take() {
+        return Some(plain_key(c));
+    }
+
+    let state = &mut decoder.state;
+    loop {
+        let Some(c) = uart::get_char() else {
+            if let State::Escape(start) = *state {
+                if Timer::uptime() - start >= ESC_TIMEOUT {
+                    *state = State::Normal;
+                    return Some(Key::Char(0x1b));
+                }
+            }
+            return None;
+        };
+
+        match *state {
+            State::Normal => {
+                if c == 0x1b {
+                    *state = State::Escape(Timer::uptime());
+                } else {
+                    return Some(plain_key(c));
+                }
+            }
+            State::Escape(start) => {
+                if c == b'[' || c == b'O' {
+                    *state = State::Csi(start, 0);
+                } else if c == 0x1b {
+                    // ESC ESC: report the first, keep waiting on the second
+                    *state = State::Escape(Timer::uptime());
+                    return Some(Key::Char(0x1b));
+                } else {
+                    // Not a sequence: deliver ESC now, the byte next time
+                    *state = State::Normal;
+                    decoder.pending = Some(c);
+                    return Some(Key::Char(0x1b));
+                }
+            }
+            State::Csi(start, param) => {
+                if c.is_ascii_digit() {
+                    *state = State::Csi(start, param.saturating_mul(10).saturating_add(c - b'0'));
+                } else {
+                    *state = State::Normal;
+                    // Unknown sequences are dropped rather than leaking bytes
+                    if let Some(key) = csi_key(param, c) {
+                        return Some(key);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Block until a key is available.
+///
+/// Sleeps on the console input queue; while an escape sequence is
+/// incomplete it keeps yielding instead, so a bare ESC times out.
+pub fn wait_key() -> Key {
+    loop {
+        let daif = cpu::save_and_disable_interrupts();
+        if let Some(key) = read_key() {
+            cpu::restore_interrupts(daif);
+            return key;
+        }
+        if matches!(DECODER.lock().state, State::Normal) {
+            uart::wait_for_input(uart::console_port());
+            cpu::restore_interrupts(daif);
+        } else {
+            cpu::restore_interrupts(daif);
+            sched::schedule();
+        }
+    }
+}
+
+// =============================================================================
+// Line Discipline
+// =============================================================================
+
+/// How console input is processed before reaching a reader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Line-buffered with echo and editing
+    Cooked = 0,
+    /// Bytes delivered immediately, no echo or translation. Ctrl-C is
+    /// passed through instead of interrupting the foreground task.
+    Raw = 1,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Cooked as u8);
+
+/// PID of the task that switched to raw mode (0 = none)
+static MODE_OWNER: AtomicUsize = AtomicUsize::new(0);
+
+/// Rest of a cooked line not yet consumed by `read`
+static PENDING: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+/// Current console mode.
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Acquire) {
+        1 => Mode::Raw,
+        _ => Mode::Cooked,
+    }
+}
+
+/// Switch the console mode on behalf of the current task.
+///
+/// Raw mode is undone automatically when that task exits.
+pub fn set_mode(mode: Mode) {
+    let owner = match mode {
+        Mode::Raw => sched::current_task_id(),
+        Mode::Cooked => 0,
+    };
+    MODE_OWNER.store(owner, Ordering::Release);
+    MODE.store(mode as u8, Ordering::Release);
+}
+
+/// A task exited: restore cooked mode if it left the console raw.
+pub fn task_exited(pid: usize) {
+    if MODE_OWNER.compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+        MODE.store(Mode::Cooked as u8, Ordering::Release);
+    }
+}
+
+/// Line being edited in cooked mode, kept between keys so a line started
+/// by `try_read_line` can be finished by a later call
+static EDITING: Mutex<String> = Mutex::new(String::new());
+
+/// Apply `key` to the line being edited, echoing it. Returns the line
+/// once it's complete.
+fn edit(key: Key) -> Option<String> {
+    let mut line = EDITING.lock();
+    match key {
+        Key::Char(b'\r') | Key::Char(b'\n') => {
+            echo("\n");
+            return Some(core::mem::take(&mut *line));
+        }
+        Key::Ctrl('c') => {
+            // Not taken by a foreground task: discard the line
+            echo("^C\n");
+            line.clear();
+            return Some(String::new());
+        }
+        Key::Char(0x08) | Key::Char(127) => {
+            if line.pop().is_some() {
+                echo("\x08 \x08");
+            }
+        }
+        Key::Char(c @ 0x20..=0x7E) => {
+            line.push(c as char);
+            echo(&line[line.len() - 1..]);
+        }
+        // Navigation keys are decoded but not yet used for editing
+        _ => {}
+    }
+    None
+}
+
+/// Read one line in cooked mode, echoing and editing as keys arrive.
+///
+/// Returns the line without its terminator. Ctrl-C discards the line
+/// and returns an empty one.
+pub fn read_line() -> String {
+    loop {
+        if let Some(line) = edit(wait_key()) {
+            return line;
+        }
+    }
+}
+
+/// `read_line` without blocking: applies the keys typed so far, and
+/// returns the line once it's complete.
+pub fn try_read_line() -> Option<String> {
+    while let Some(key) = read_key() {
+        if let Some(line) = edit(key) {
+            return Some(line);
+        }
+    }
+    None
+}
+
+/// Read console input into `buf` according to the current mode,
+/// blocking until at least one byte is available.
+///
+/// Cooked reads return at most one line, terminated by `\n`.
+pub fn read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    if mode() == Mode::Raw {
+        return read_raw(uart::console_port(), buf);
+    }
+
+    let mut pending = PENDING.lock();
+    if pending.is_empty() {
+        // Don't hold the lock while blocked on the keyboard
+        drop(pending);
+        let line = read_line();
+        pending = PENDING.lock();
+        pending.extend(line.bytes());
+        pending.push_back(b'\n');
+    }
+    let n = buf.len().min(pending.len());
+    for (dst, src) in buf.iter_mut().zip(pending.drain(..n)) {
+        *dst = src;
+    }
+    n
+}
+
+/// Read bytes from a port as they arrive, blocking until at least one
+/// is available.
+pub fn read_raw(port: usize, buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    loop {
+        // Check and sleep with IRQs masked so input can't be missed
+        let daif = cpu::save_and_disable_interrupts();
+        let n = uart::read_port(port, buf);
+        if n > 0 {
+            cpu::restore_interrupts(daif);
+            return n;
+        }
+        uart::wait_for_input(port);
+        cpu::restore_interrupts(daif);
+    }
+}
+
+// =============================================================================
+// Foreground Task
+// =============================================================================
+
+/// PID of the task receiving Ctrl-C (0 = none; the idle task never is)
+static FOREGROUND: AtomicUsize = AtomicUsize::new(0);
+
+/// Make `pid` the foreground task, so Ctrl-C interrupts it.
+pub fn set_foreground(pid: usize) {
+    FOREGROUND.store(pid, Ordering::Release);
+}
+
+/// Clear the foreground task. Ctrl-C then reaches the shell as a key.
+pub fn clear_foreground() {
+    FOREGROUND.store(0, Ordering::Release);
+}
+
+/// Handle Ctrl-C from the UART interrupt handler.
+///
+/// Returns `true` if a foreground task was signalled. In raw mode the
+/// byte is left for the reader instead.
+pub fn interrupt_foreground() -> bool {
+    if mode() == Mode::Raw {
+        return false;
+    }
+    match FOREGROUND.load(Ordering::Acquire) {
+        0 => false,
+        pid => sched::send_signal(pid, sched::Signal::Interrupt),
+    }
+}
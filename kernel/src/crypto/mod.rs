@@ -0,0 +1,8 @@
+// =============================================================================
+// APRK OS - Cryptography
+// =============================================================================
+// Hash functions for checking file integrity. Nothing here is meant to
+// keep secrets: there is no constant-time code or key handling.
+// =============================================================================
+
+pub mod sha256;
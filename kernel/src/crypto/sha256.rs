@@ -0,0 +1,180 @@
+// =============================================================================
+// APRK OS - SHA-256
+// =============================================================================
+// FIPS 180-4 SHA-256, fed a piece at a time so large files can be hashed
+// while they're read rather than after loading them whole.
+// =============================================================================
+
+/// Bytes in a digest
+pub const DIGEST_SIZE: usize = 32;
+/// Bytes in a block
+const BLOCK_SIZE: usize = 64;
+
+/// First 32 bits of the fractional parts of the cube roots of the first
+/// 64 primes
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// First 32 bits of the fractional parts of the square roots of the
+/// first 8 primes
+const INITIAL: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// A hash in progress.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    /// Input not yet making up a whole block
+    buf: [u8; BLOCK_SIZE],
+    buf_len: usize,
+    /// Bytes taken in so far
+    len: u64,
+}
+
+impl Sha256 {
+    pub const fn new() -> Self {
+        Self { state: INITIAL, buf: [0; BLOCK_SIZE], buf_len: 0, len: 0 }
+    }
+
+    /// Add `data` to the message.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if self.buf_len > 0 {
+            let n = data.len().min(BLOCK_SIZE - self.buf_len);
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            data = &data[n..];
+            if self.buf_len < BLOCK_SIZE {
+                return;
+            }
+            let block = self.buf;
+            self.compress(&block);
+            self.buf_len = 0;
+        }
+        let (blocks, rest) = data.as_chunks::<BLOCK_SIZE>();
+        for block in blocks {
+            self.compress(block);
+        }
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.buf_len = rest.len();
+    }
+
+    /// Pad the message and return its digest.
+    pub fn finish(mut self) -> [u8; DIGEST_SIZE] {
+        let bits = self.len * 8;
+        // A 1 bit, zeros up to 8 bytes short of a block end, then the length
+        let pad = (BLOCK_SIZE + 55 - self.buf_len) % BLOCK_SIZE + 1;
+        let mut tail = [0u8; BLOCK_SIZE + 8];
+        tail[0] = 0x80;
+        tail[pad..pad + 8].copy_from_slice(&bits.to_be_bytes());
+        self.update(&tail[..pad + 8]);
+        debug_assert_eq!(self.buf_len, 0);
+
+        let mut digest = [0u8; DIGEST_SIZE];
+        for (out, word) in digest.as_chunks_mut::<4>().0.iter_mut().zip(self.state) {
+            *out = word.to_be_bytes();
+        }
+        digest
+    }
+
+    /// Run one block through the compression function.
+    fn compress(&mut self, block: &[u8; BLOCK_SIZE]) {
+        let mut w = [0u32; 64];
+        for (i, &word) in block.as_chunks::<4>().0.iter().enumerate() {
+            w[i] = u32::from_be_bytes(word);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+/// The digest of `data`.
+pub fn digest(data: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finish()
+}
+
+/// `digest` as lowercase hex.
+pub fn to_hex(digest: &[u8; DIGEST_SIZE]) -> alloc::string::String {
+    use core::fmt::Write;
+    let mut hex = alloc::string::String::with_capacity(DIGEST_SIZE * 2);
+    for byte in digest {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Check the FIPS 180-4 example digests, including a million bytes fed in
+/// uneven pieces, printing the result.
+pub fn self_test() {
+    const CASES: &[(&[u8], &str)] = &[
+        (b"", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+        (b"abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+        (b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+    ];
+    const MILLION_A: &str = "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0";
+
+    let mut failed = 0;
+    for &(message, expected) in CASES {
+        if to_hex(&digest(message)) != expected {
+            crate::println!("  FAILED: SHA-256 of {:?}", core::str::from_utf8(message).unwrap_or("?"));
+            failed += 1;
+        }
+    }
+    let mut hash = Sha256::new();
+    let chunk = [b'a'; 1000];
+    let mut left = 1_000_000;
+    // Piece sizes that don't line up with blocks
+    for size in [1, 63, 64, 65, 999].into_iter().cycle() {
+        if left == 0 {
+            break;
+        }
+        let n = size.min(left);
+        hash.update(&chunk[..n]);
+        left -= n;
+    }
+    if to_hex(&hash.finish()) != MILLION_A {
+        crate::println!("  FAILED: SHA-256 of a million 'a's fed in pieces");
+        failed += 1;
+    }
+    if failed == 0 {
+        crate::println!("PASSED: SHA-256 known answers");
+    } else {
+        crate::println!("FAILED: {} of {} SHA-256 known answers", failed, CASES.len() + 1);
+    }
+}
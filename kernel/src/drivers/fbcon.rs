@@ -0,0 +1,278 @@
+// =============================================================================
+// APRK OS - Framebuffer Text Console
+// =============================================================================
+// Kernel console output on the virtio-gpu display: a grid of 8x8 character
+// cells (`font`), written like a terminal. Text wraps at the right edge and
+// the screen scrolls up one text row at the bottom. Tabs stop every eight
+// columns.
+//
+// The escape sequences the kernel prints are understood: SGR colors and
+// attributes (bold, reverse), cursor home/position, and clearing the screen
+// or the rest of a line. Any other CSI sequence is dropped.
+//
+// It takes over the screen from the boot screen once boot is done (`start`),
+// showing the end of the kernel log so far, and then joins the console
+// sinks. It draws into the GPU's back buffer, presented once per write
+// rather than per character. Output arriving while someone else holds the
+// back buffer isn't shown: the console never waits for it.
+// =============================================================================
+
+use aprk_arch_arm64::console::{self, ConsoleSink};
+use aprk_arch_arm64::{cpu, log};
+use core::fmt;
+use spin::Mutex;
+use crate::drivers::{font, gpu::{self, Framebuffer}};
+
+/// Tab stops every this many columns
+const TAB_WIDTH: u32 = 8;
+/// Most parameters kept from one escape sequence
+const MAX_PARAMS: usize = 8;
+/// Default colors (palette indices)
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+
+/// The 16 ANSI colors, as the VGA text mode shows them: normal, then bright
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (170, 0, 0), (0, 170, 0), (170, 85, 0),
+    (0, 0, 170), (170, 0, 170), (0, 170, 170), (170, 170, 170),
+    (85, 85, 85), (255, 85, 85), (85, 255, 85), (255, 255, 85),
+    (85, 85, 255), (255, 85, 255), (85, 255, 255), (255, 255, 255),
+];
+
+/// Where an escape sequence is.
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    /// After ESC
+    Esc,
+    /// After ESC [, collecting parameters; the last of the `count` so far
+    /// is the one being read
+    Csi { params: [u16; MAX_PARAMS], count: usize },
+}
+
+/// The text grid on the framebuffer and the terminal state. Drawn into the
+/// back buffer, which each call that draws is given.
+struct Screen {
+    /// Size in character cells
+    cols: u32,
+    rows: u32,
+    /// Cell the next character goes to
+    col: u32,
+    row: u32,
+    fg: u8,
+    bg: u8,
+    bold: bool,
+    reverse: bool,
+    escape: Escape,
+}
+
+impl Screen {
+    /// Foreground and background of the next character.
+    fn colors(&self) -> ((u8, u8, u8), (u8, u8, u8)) {
+        let fg = if self.bold && self.fg < 8 { self.fg + 8 } else { self.fg };
+        let (fg, bg) = (PALETTE[fg as usize], PALETTE[self.bg as usize]);
+        if self.reverse { (bg, fg) } else { (fg, bg) }
+    }
+
+    /// Fill `cols` cells from (`col`, `row`) on, and `rows` rows down, with
+    /// the background color.
+    fn clear(&mut self, fb: &mut Framebuffer, col: u32, row: u32, cols: u32, rows: u32) {
+        let bg = PALETTE[self.bg as usize];
+        fb.fill_rect((col * font::WIDTH) as i32, (row * font::HEIGHT) as i32,
+            cols * font::WIDTH, rows * font::HEIGHT, bg);
+    }
+
+    /// Move the text up one row, clearing the bottom one.
+    fn scroll(&mut self, fb: &mut Framebuffer) {
+        fb.scroll_up(self.rows * font::HEIGHT, font::HEIGHT);
+        self.clear(fb, 0, self.rows - 1, self.cols, 1);
+    }
+
+    fn newline(&mut self, fb: &mut Framebuffer) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll(fb);
+        }
+    }
+
+    fn put(&mut self, fb: &mut Framebuffer, c: char) {
+        if self.col == self.cols {
+            self.newline(fb);
+        }
+        let (fg, bg) = self.colors();
+        fb.put_char((self.col * font::WIDTH) as i32, (self.row * font::HEIGHT) as i32, c, fg, bg);
+        self.col += 1;
+    }
+
+    fn write(&mut self, fb: &mut Framebuffer, s: &str) {
+        for c in s.chars() {
+            match self.escape {
+                Escape::None => self.control(fb, c),
+                Escape::Esc => {
+                    self.escape = match c {
+                        '[' => Escape::Csi { params: [0; MAX_PARAMS], count: 0 },
+                        _ => Escape::None,
+                    };
+                }
+                Escape::Csi { mut params, mut count } => match c {
+                    '0'..='9' => {
+                        count = count.max(1);
+                        let param = &mut params[count - 1];
+                        *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                        self.escape = Escape::Csi { params, count };
+                    }
+                    ';' => {
+                        // An empty parameter counts as 0
+                        count = (count.max(1) + 1).min(MAX_PARAMS);
+                        self.escape = Escape::Csi { params, count };
+                    }
+                    '?' => {}
+                    _ => {
+                        self.escape = Escape::None;
+                        self.csi(fb, c, &params[..count]);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Handle a character outside any escape sequence.
+    fn control(&mut self, fb: &mut Framebuffer, c: char) {
+        match c {
+            '\n' => self.newline(fb),
+            '\r' => self.col = 0,
+            '\t' => {
+                let stop = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.col < stop.min(self.cols) {
+                    self.put(fb, ' ');
+                }
+            }
+            '\x08' => self.col = self.col.saturating_sub(1),
+            '\x1b' => self.escape = Escape::Esc,
+            c if c.is_control() => {}
+            c => self.put(fb, c),
+        }
+    }
+
+    /// Carry out the CSI sequence ending in `command`.
+    fn csi(&mut self, fb: &mut Framebuffer, command: char, params: &[u16]) {
+        let param = |i: usize| params.get(i).copied().unwrap_or(0);
+        match command {
+            'm' => self.sgr(params),
+            'H' | 'f' => {
+                self.row = (param(0).max(1) as u32 - 1).min(self.rows - 1);
+                self.col = (param(1).max(1) as u32 - 1).min(self.cols - 1);
+            }
+            'J' => match param(0) {
+                0 => {
+                    self.clear(fb, self.col, self.row, self.cols - self.col, 1);
+                    self.clear(fb, 0, self.row + 1, self.cols, self.rows - self.row - 1);
+                }
+                2 | 3 => self.clear(fb, 0, 0, self.cols, self.rows),
+                _ => {}
+            },
+            'K' => match param(0) {
+                0 => self.clear(fb, self.col, self.row, self.cols - self.col, 1),
+                2 => self.clear(fb, 0, self.row, self.cols, 1),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Select Graphic Rendition: colors and attributes.
+    fn sgr(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.sgr(&[0]);
+            return;
+        }
+        for &p in params {
+            match p {
+                0 => {
+                    (self.fg, self.bg) = (DEFAULT_FG, DEFAULT_BG);
+                    (self.bold, self.reverse) = (false, false);
+                }
+                1 => self.bold = true,
+                7 => self.reverse = true,
+                22 => self.bold = false,
+                27 => self.reverse = false,
+                30..=37 => self.fg = (p - 30) as u8,
+                39 => self.fg = DEFAULT_FG,
+                40..=47 => self.bg = (p - 40) as u8,
+                49 => self.bg = DEFAULT_BG,
+                90..=97 => self.fg = (p - 90) as u8 + 8,
+                100..=107 => self.bg = (p - 100) as u8 + 8,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The framebuffer console, as a console sink. The screen is only locked
+/// with IRQs masked.
+pub struct FbConsole {
+    screen: Mutex<Option<Screen>>,
+}
+
+static FB_CONSOLE: FbConsole = FbConsole { screen: Mutex::new(None) };
+
+impl ConsoleSink for FbConsole {
+    fn name(&self) -> &str {
+        "fbcon"
+    }
+
+    fn write_str(&self, s: &str) -> fmt::Result {
+        // Never wait: the locks may be held by the code this interrupted
+        let Some(mut screen) = self.screen.try_lock() else {
+            return Err(fmt::Error);
+        };
+        let Some(mut fb_config) = gpu::FB_CONFIG.try_lock() else {
+            return Err(fmt::Error);
+        };
+        let (Some(text), Some(fb)) = (screen.as_mut(), fb_config.as_mut()) else {
+            return Err(fmt::Error);
+        };
+        text.write(fb, s);
+        drop(fb_config);
+        drop(screen);
+        gpu::present();
+        Ok(())
+    }
+}
+
+/// Take the screen over from the boot screen and show kernel output on it
+/// from now on, starting with the kernel log. Does nothing without a
+/// framebuffer.
+pub fn start() {
+    let mut fb_config = gpu::FB_CONFIG.lock();
+    let Some(fb) = fb_config.as_mut() else {
+        return;
+    };
+    let (cols, rows) = (fb.width() / font::WIDTH, fb.height() / font::HEIGHT);
+    if cols == 0 || rows == 0 {
+        return;
+    }
+    fb.clear(PALETTE[DEFAULT_BG as usize]);
+    let mut screen = Screen {
+        cols, rows, col: 0, row: 0,
+        fg: DEFAULT_FG, bg: DEFAULT_BG, bold: false, reverse: false, escape: Escape::None,
+    };
+    // What fits on the screen, at most: each line past it would only
+    // scroll the framebuffer once more
+    let mut seq = log::next_seq().saturating_sub(rows as u64);
+    while let Some(line) = log::read_since(seq) {
+        screen.write(fb, line.text());
+        screen.write(fb, "\n");
+        seq = line.seq + 1;
+    }
+    drop(fb_config);
+    let daif = cpu::save_and_disable_interrupts();
+    *FB_CONSOLE.screen.lock() = Some(screen);
+    cpu::restore_interrupts(daif);
+    gpu::present();
+    if !console::register(&FB_CONSOLE) {
+        crate::log_warn!("fbcon", "Console sink table full, no output");
+    }
+}
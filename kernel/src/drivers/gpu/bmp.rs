@@ -0,0 +1,179 @@
+// =============================================================================
+// APRK OS - BMP Decoder
+// =============================================================================
+// Windows bitmaps (BITMAPINFOHEADER or later), uncompressed: 24 bits per
+// pixel, or 32 with the alpha byte ignored, or 32 with BI_BITFIELDS masks
+// of 8 bits per channel (alpha used if there's a mask for it). Rows may run
+// bottom-up (positive height, the usual) or top-down (negative height),
+// each padded to 4 bytes.
+// =============================================================================
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Largest width or height accepted
+pub const MAX_SIDE: u32 = 8192;
+
+const FILE_HEADER: usize = 14;
+/// BITMAPINFOHEADER, the smallest info header understood
+const INFO_HEADER: usize = 40;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+/// A decoded image: rows top to bottom, pixels as `framebuffer::pixel`
+/// packs them, with alpha in the top byte.
+pub struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u32>,
+}
+
+impl Image {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [u32] {
+        &mut self.pixels
+    }
+
+    /// The size to draw the image at to fit `max_w` x `max_h`: its own if
+    /// it fits, otherwise scaled down keeping its proportions.
+    pub fn fit(&self, max_w: u32, max_h: u32) -> (u32, u32) {
+        let (w, h) = (self.width as u64, self.height as u64);
+        let (max_w, max_h) = (max_w as u64, max_h as u64);
+        if w <= max_w && h <= max_h {
+            return (w as u32, h as u32);
+        }
+        // Whichever side is the tighter fit decides the scale
+        if w * max_h >= h * max_w {
+            (max_w as u32, (h * max_w / w).max(1) as u32)
+        } else {
+            ((w * max_h / h).max(1) as u32, max_h as u32)
+        }
+    }
+}
+
+/// Why a BMP couldn't be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BmpError {
+    /// Doesn't start with "BM"
+    NotBmp,
+    /// The data ends before the headers or the pixels do
+    Truncated,
+    /// An OS/2 or otherwise unknown info header, by its size
+    UnsupportedHeader(u32),
+    /// Bits per pixel other than 24 or 32
+    UnsupportedDepth(u16),
+    /// RLE, JPEG, PNG, or bit fields that aren't 8-bit channels
+    UnsupportedCompression(u32),
+    /// A width or height of 0 or less, or over `MAX_SIDE`
+    BadSize,
+    /// No room in the kernel heap for the pixels
+    OutOfMemory,
+}
+
+impl fmt::Display for BmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BmpError::NotBmp => f.write_str("Not a BMP file"),
+            BmpError::Truncated => f.write_str("BMP file is truncated"),
+            BmpError::UnsupportedHeader(size) => write!(f, "Unsupported BMP header ({} bytes)", size),
+            BmpError::UnsupportedDepth(bpp) => write!(f, "Unsupported BMP depth ({} bits per pixel, need 24 or 32)", bpp),
+            BmpError::UnsupportedCompression(c) => write!(f, "Unsupported BMP compression ({})", c),
+            BmpError::BadSize => write!(f, "BMP size is 0, negative or over {} pixels a side", MAX_SIDE),
+            BmpError::OutOfMemory => f.write_str("Out of memory"),
+        }
+    }
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16, BmpError> {
+    let bytes = data.get(at..at + 2).ok_or(BmpError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32, BmpError> {
+    let bytes = data.get(at..at + 4).ok_or(BmpError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Where an 8-bit channel is in a 32-bit pixel: its shift, or `None` for
+/// no such channel. Other masks aren't supported.
+fn channel(mask: u32) -> Result<Option<u32>, BmpError> {
+    match mask {
+        0 => Ok(None),
+        m if m >> m.trailing_zeros() == 0xff => Ok(Some(m.trailing_zeros())),
+        _ => Err(BmpError::UnsupportedCompression(BI_BITFIELDS)),
+    }
+}
+
+/// Decode the BMP file `data`.
+pub fn decode(data: &[u8]) -> Result<Image, BmpError> {
+    if data.get(0..2).ok_or(BmpError::Truncated)? != b"BM" {
+        return Err(BmpError::NotBmp);
+    }
+    let offset = u32_at(data, 10)? as usize;
+    let header_size = u32_at(data, FILE_HEADER)?;
+    if (header_size as usize) < INFO_HEADER {
+        return Err(BmpError::UnsupportedHeader(header_size));
+    }
+    let width = u32_at(data, 18)? as i32;
+    let height = u32_at(data, 22)? as i32;
+    let bpp = u16_at(data, 28)?;
+    let compression = u32_at(data, 30)?;
+    // Negative height means top-down rows; negative width means nothing
+    let (width, height, top_down) = (width.max(0) as u32, height.unsigned_abs(), height < 0);
+    if width == 0 || height == 0 || width > MAX_SIDE || height > MAX_SIDE {
+        return Err(BmpError::BadSize);
+    }
+    if bpp != 24 && bpp != 32 {
+        return Err(BmpError::UnsupportedDepth(bpp));
+    }
+
+    // Shifts of red, green, blue and alpha
+    let shifts = match (compression, bpp) {
+        (BI_RGB, _) => [Some(16), Some(8), Some(0), None],
+        (BI_BITFIELDS, 32) => {
+            // In the header from version 4 on, after it in version 3
+            let at = FILE_HEADER + INFO_HEADER;
+            let alpha = if header_size >= 56 { u32_at(data, at + 12)? } else { 0 };
+            [channel(u32_at(data, at)?)?, channel(u32_at(data, at + 4)?)?, channel(u32_at(data, at + 8)?)?, channel(alpha)?]
+        }
+        _ => return Err(BmpError::UnsupportedCompression(compression)),
+    };
+
+    let bytes_per_pixel = bpp as usize / 8;
+    // Rows are padded to a multiple of 4 bytes
+    let stride = (width as usize * bytes_per_pixel).div_ceil(4) * 4;
+    let end = offset.checked_add(stride * height as usize).ok_or(BmpError::Truncated)?;
+    // The last row's padding may be missing
+    if end - (stride - width as usize * bytes_per_pixel) > data.len() {
+        return Err(BmpError::Truncated);
+    }
+
+    let count = width as usize * height as usize;
+    let mut pixels = Vec::new();
+    pixels.try_reserve_exact(count).map_err(|_| BmpError::OutOfMemory)?;
+    for y in 0..height as usize {
+        let row = if top_down { y } else { height as usize - 1 - y };
+        let start = offset + row * stride;
+        for px in data[start..start + width as usize * bytes_per_pixel].chunks_exact(bytes_per_pixel) {
+            let value = match bpp {
+                24 => u32::from_le_bytes([px[0], px[1], px[2], 0]),
+                _ => u32::from_le_bytes([px[0], px[1], px[2], px[3]]),
+            };
+            let get = |shift: Option<u32>, default: u8| shift.map_or(default, |s| (value >> s) as u8);
+            let (r, g, b, a) = (get(shifts[0], 0), get(shifts[1], 0), get(shifts[2], 0), get(shifts[3], 255));
+            pixels.push((a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | b as u32);
+        }
+    }
+    Ok(Image { width, height, pixels })
+}
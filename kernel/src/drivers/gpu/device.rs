@@ -0,0 +1,270 @@
+// =============================================================================
+// APRK OS - VirtIO GPU Device
+// =============================================================================
+// The little of a virtio-gpu device the screen needs: one scanout showing a
+// 2D resource, backed by a framebuffer in our memory. virtio-drivers' GPU
+// driver transfers and flushes the whole resource every time, its commands
+// for a part of it being private; this one sends the rectangle asked for.
+//
+// Commands go out on the control queue one at a time, from a DMA page of
+// their own, and are polled until the device answers. The cursor queue
+// isn't set up: the cursor is drawn into the framebuffer.
+// =============================================================================
+
+use virtio_drivers::{BufferDirection, Error, Hal, Result};
+use virtio_drivers::transport::{mmio::MmioTransport, DeviceStatus, Transport};
+use crate::drivers::virtio::HalImpl;
+use crate::drivers::virtqueue::VirtQueue;
+use crate::mm::pmm::PAGE_SIZE;
+use core::mem::size_of;
+use core::ptr::NonNull;
+
+/// Feature bit: a virtio 1.x device, not a legacy one (VIRTIO_F_VERSION_1)
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// Command types
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+/// Response types: done, and done with the display info
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Pixel format: 32 bits, blue in the lowest byte, as `pixel` packs them
+const FORMAT_B8G8R8A8_UNORM: u32 = 1;
+/// The one resource and the scanout showing it
+const RESOURCE_ID: u32 = 0xbabe;
+const SCANOUT_ID: u32 = 0;
+/// Scanouts a device reports on
+const MAX_SCANOUTS: usize = 16;
+/// Where in the command page the device's answers go
+const RESP_OFFSET: usize = PAGE_SIZE / 2;
+
+/// Header of every command and response.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CtrlHeader {
+    type_: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+impl CtrlHeader {
+    fn of(type_: u32) -> Self {
+        Self { type_, ..Self::default() }
+    }
+}
+
+/// A rectangle of pixels.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayOne {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayInfo {
+    header: CtrlHeader,
+    modes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+struct ResourceCreate2d {
+    header: CtrlHeader,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+struct AttachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32,
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct SetScanout {
+    header: CtrlHeader,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+struct TransferToHost2d {
+    header: CtrlHeader,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct ResourceFlush {
+    header: CtrlHeader,
+    rect: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+const _: () = assert!(size_of::<DisplayInfo>() <= PAGE_SIZE - RESP_OFFSET);
+
+/// A virtio-gpu device and its control queue.
+pub struct Gpu {
+    transport: MmioTransport,
+    control: VirtQueue,
+    /// A DMA page: commands from its start, answers from `RESP_OFFSET`
+    page: NonNull<u8>,
+    width: u32,
+    height: u32,
+}
+
+// SAFETY: the command page is DMA memory nothing but this device touches
+unsafe impl Send for Gpu {}
+
+impl Gpu {
+    /// Set up the device on `transport` and find out the size of its first
+    /// scanout.
+    pub fn new(mut transport: MmioTransport) -> Result<Self> {
+        transport.set_status(DeviceStatus::empty());
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        let features = transport.read_device_features() & FEATURE_VERSION_1;
+        transport.write_driver_features(features);
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK);
+        if !transport.get_status().contains(DeviceStatus::FEATURES_OK) {
+            transport.set_status(DeviceStatus::FAILED);
+            return Err(Error::Unsupported);
+        }
+        transport.set_guest_page_size(PAGE_SIZE as u32);
+        let control = VirtQueue::new(&mut transport, 0)?;
+        let (_, page) = HalImpl::dma_alloc(1, BufferDirection::Both);
+        transport.finish_init();
+
+        let mut gpu = Self { transport, control, page, width: 0, height: 0 };
+        let info: DisplayInfo = gpu.request(CtrlHeader::of(CMD_GET_DISPLAY_INFO))?;
+        if info.header.type_ != RESP_OK_DISPLAY_INFO {
+            return Err(Error::IoError);
+        }
+        let mode = info.modes[SCANOUT_ID as usize].rect;
+        (gpu.width, gpu.height) = (mode.width, mode.height);
+        Ok(gpu)
+    }
+
+    /// Size of the screen.
+    pub fn resolution(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// The whole screen.
+    pub fn screen(&self) -> Rect {
+        Rect { x: 0, y: 0, width: self.width, height: self.height }
+    }
+
+    /// Allocate the framebuffer, make it the backing of a resource and show
+    /// that on the scanout. Returns the framebuffer, which lives as long as
+    /// the kernel. Call once.
+    pub fn setup_framebuffer(&mut self) -> Result<&'static mut [u8]> {
+        let (width, height) = (self.width, self.height);
+        self.command(ResourceCreate2d {
+            header: CtrlHeader::of(CMD_RESOURCE_CREATE_2D),
+            resource_id: RESOURCE_ID,
+            format: FORMAT_B8G8R8A8_UNORM,
+            width,
+            height,
+        })?;
+        let len = width as usize * height as usize * 4;
+        let (paddr, fb) = HalImpl::dma_alloc(len.div_ceil(PAGE_SIZE), BufferDirection::DriverToDevice);
+        self.command(AttachBacking {
+            header: CtrlHeader::of(CMD_RESOURCE_ATTACH_BACKING),
+            resource_id: RESOURCE_ID,
+            nr_entries: 1,
+            addr: paddr as u64,
+            length: len as u32,
+            padding: 0,
+        })?;
+        self.command(SetScanout {
+            header: CtrlHeader::of(CMD_SET_SCANOUT),
+            rect: self.screen(),
+            scanout_id: SCANOUT_ID,
+            resource_id: RESOURCE_ID,
+        })?;
+        // SAFETY: just allocated, and never freed
+        Ok(unsafe { core::slice::from_raw_parts_mut(fb.as_ptr(), len) })
+    }
+
+    /// Copy `rect` of the framebuffer to the device's resource and show it.
+    pub fn flush(&mut self, rect: Rect) -> Result {
+        let offset = (rect.y as u64 * self.width as u64 + rect.x as u64) * 4;
+        self.command(TransferToHost2d {
+            header: CtrlHeader::of(CMD_TRANSFER_TO_HOST_2D),
+            rect,
+            offset,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        })?;
+        self.command(ResourceFlush {
+            header: CtrlHeader::of(CMD_RESOURCE_FLUSH),
+            rect,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        })
+    }
+
+    /// Acknowledge an interrupt. Whether there was one to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Send `cmd`, which expects no data back.
+    fn command<C>(&mut self, cmd: C) -> Result {
+        let header: CtrlHeader = self.request(cmd)?;
+        match header.type_ {
+            RESP_OK_NODATA => Ok(()),
+            _ => Err(Error::IoError),
+        }
+    }
+
+    /// Send `cmd` and wait for the device's answer, an `R`.
+    fn request<C, R>(&mut self, cmd: C) -> Result<R> {
+        assert!(size_of::<C>() <= RESP_OFFSET);
+        let send = self.page.as_ptr();
+        // SAFETY: the answer's half of the command page
+        let recv = unsafe { send.add(RESP_OFFSET) };
+        // SAFETY: the page is ours, page-aligned, and the device only reads
+        // and writes it between `add` and `pop_used`
+        unsafe {
+            send.cast::<C>().write(cmd);
+            let inputs = [core::slice::from_raw_parts(send, size_of::<C>())];
+            let mut outputs = [core::slice::from_raw_parts_mut(recv, size_of::<R>())];
+            let token = self.control.add(&inputs, &mut outputs)?;
+            self.transport.notify(self.control.index());
+            while self.control.peek_used() != Some(token) {
+                core::hint::spin_loop();
+            }
+            self.control.pop_used(token, &inputs, &mut outputs)?;
+            Ok(recv.cast::<R>().read())
+        }
+    }
+}
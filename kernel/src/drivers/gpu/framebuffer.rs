@@ -0,0 +1,423 @@
+// =============================================================================
+// APRK OS - Framebuffer
+// =============================================================================
+// A rectangle of 32-bit pixels to draw into (B, G, R, A in memory, alpha
+// always opaque): the GPU's back buffer, its device framebuffer, or one in
+// the heap for drawing off screen (`HeapFramebuffer`), as the self tests
+// do.
+//
+// Every method clips against the bounds: whatever falls outside, at
+// negative coordinates included, is left undrawn. Drawing into the back
+// buffer also records what changed for `present`. Shapes are drawn in
+// integer arithmetic only (Bresenham lines, midpoint circles).
+// =============================================================================
+
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::drivers::font;
+use super::bmp::Image;
+
+/// Pack a color into a pixel.
+pub fn pixel((r, g, b): (u8, u8, u8)) -> u32 {
+    0xff00_0000 | (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+/// `src` over `dst` at opacity `alpha` (0-255), one channel, rounded.
+fn blend_channel(src: u8, dst: u8, alpha: u32) -> u8 {
+    ((src as u32 * alpha + dst as u32 * (255 - alpha) + 127) / 255) as u8
+}
+
+/// `color` over the pixel `dst` at opacity `alpha` (0-255).
+fn blend(dst: u32, (r, g, b): (u8, u8, u8), alpha: u8) -> u32 {
+    let alpha = alpha as u32;
+    let channel = |src: u8, shift: u32| (blend_channel(src, (dst >> shift) as u8, alpha) as u32) << shift;
+    0xff00_0000 | channel(r, 16) | channel(g, 8) | channel(b, 0)
+}
+
+/// Pixels to draw into.
+pub struct Framebuffer {
+    ptr: *mut u32,
+    width: u32,
+    height: u32,
+    /// Pixels from the start of one row to the next
+    stride: u32,
+    /// Drawing is recorded for `present` (the back buffer)
+    tracked: bool,
+}
+
+// SAFETY: a framebuffer is the only way to its pixels while it's in use
+// (see `from_raw`), so handing it to another task hands over sole access.
+// Not `Sync`: every write goes through `&mut self`, so sharing one needs a
+// lock, as `FB_CONFIG` is
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// Draw into the `width` x `height` pixels at `ptr`, rows `stride`
+    /// pixels apart. If `tracked`, what's drawn is marked dirty for
+    /// `present`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to `stride * height` pixels, with `stride >= width`,
+    /// that stay valid for as long as the framebuffer is used, and that
+    /// nothing else in the kernel touches meanwhile.
+    pub unsafe fn from_raw(ptr: *mut u32, width: u32, height: u32, stride: u32, tracked: bool) -> Self {
+        Self { ptr, width, height, stride, tracked }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The part of the `w` x `h` rectangle at (`x`, `y`) that's inside:
+    /// (x0, y0, x1, y1), the ends exclusive.
+    fn clip(&self, x: i32, y: i32, w: u32, h: u32) -> Option<(u32, u32, u32, u32)> {
+        let (x0, y0) = (x.max(0) as i64, y.max(0) as i64);
+        let x1 = (x as i64 + w as i64).min(self.width as i64);
+        let y1 = (y as i64 + h as i64).min(self.height as i64);
+        (x0 < x1 && y0 < y1).then_some((x0 as u32, y0 as u32, x1 as u32, y1 as u32))
+    }
+
+    /// Pixels `x0..x1` of row `y`, which must be inside.
+    fn span_mut(&mut self, y: u32, x0: u32, x1: u32) -> &mut [u32] {
+        debug_assert!(y < self.height && x0 <= x1 && x1 <= self.width);
+        // SAFETY: inside the pixels `from_raw` was given
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.add((y * self.stride + x0) as usize), (x1 - x0) as usize) }
+    }
+
+    /// Pixels `x0..x1` of row `y`, which must be inside.
+    fn span(&self, y: u32, x0: u32, x1: u32) -> &[u32] {
+        debug_assert!(y < self.height && x0 <= x1 && x1 <= self.width);
+        // SAFETY: inside the pixels `from_raw` was given
+        unsafe { core::slice::from_raw_parts(self.ptr.add((y * self.stride + x0) as usize), (x1 - x0) as usize) }
+    }
+
+    /// Record that the clipped rectangle (x0, y0, x1, y1) was drawn.
+    fn touch(&self, (x0, y0, x1, y1): (u32, u32, u32, u32)) {
+        if self.tracked {
+            super::mark_dirty(x0, y0, x1 - x0, y1 - y0);
+        }
+    }
+
+    /// Set the pixel at (`x`, `y`) if it's inside. Not recorded.
+    fn plot(&mut self, x: i64, y: i64, value: u32) {
+        if (0..self.width as i64).contains(&x) && (0..self.height as i64).contains(&y) {
+            self.span_mut(y as u32, x as u32, x as u32 + 1)[0] = value;
+        }
+    }
+
+    /// Fill pixels `x0..=x1` of row `y`, as far as they're inside. Not
+    /// recorded.
+    fn hline(&mut self, x0: i64, x1: i64, y: i64, value: u32) {
+        if !(0..self.height as i64).contains(&y) {
+            return;
+        }
+        let (x0, x1) = (x0.max(0), (x1 + 1).min(self.width as i64));
+        if x0 < x1 {
+            self.span_mut(y as u32, x0 as u32, x1 as u32).fill(value);
+        }
+    }
+
+    /// Record that the box from (`x0`, `y0`) to (`x1`, `y1`), inclusive,
+    /// was drawn, as far as it's inside.
+    fn touch_box(&self, x0: i64, y0: i64, x1: i64, y1: i64) {
+        let (x0, y0) = (x0.max(0), y0.max(0));
+        let (x1, y1) = ((x1 + 1).min(self.width as i64), (y1 + 1).min(self.height as i64));
+        if x0 < x1 && y0 < y1 {
+            self.touch((x0 as u32, y0 as u32, x1 as u32, y1 as u32));
+        }
+    }
+
+    /// The pixel at (`x`, `y`), if it's inside. Writing it isn't recorded
+    /// for `present`: see `touch_rect`.
+    pub fn pixel_mut(&mut self, x: i32, y: i32) -> Option<&mut u32> {
+        let (x0, y0, x1, _) = self.clip(x, y, 1, 1)?;
+        self.span_mut(y0, x0, x1).first_mut()
+    }
+
+    /// Record that the `w` x `h` pixels at (`x`, `y`) were changed through
+    /// `pixel_mut`.
+    pub fn touch_rect(&self, x: i32, y: i32, w: u32, h: u32) {
+        if let Some(rect) = self.clip(x, y, w, h) {
+            self.touch(rect);
+        }
+    }
+
+    /// Fill the `w` x `h` pixels at (`x`, `y`) with `color`.
+    pub fn fill_rect(&mut self, x: i32, y: i32, w: u32, h: u32, color: (u8, u8, u8)) {
+        let Some(rect @ (x0, y0, x1, y1)) = self.clip(x, y, w, h) else {
+            return;
+        };
+        let value = pixel(color);
+        for row in y0..y1 {
+            self.span_mut(row, x0, x1).fill(value);
+        }
+        self.touch(rect);
+    }
+
+    /// Outline the `w` x `h` rectangle at (`x`, `y`) with `color`, in lines
+    /// `thickness` pixels wide drawn inwards. A thickness of half the
+    /// rectangle or more fills it.
+    pub fn draw_rect(&mut self, x: i32, y: i32, w: u32, h: u32, thickness: u32, color: (u8, u8, u8)) {
+        if thickness == 0 {
+            return;
+        }
+        if thickness.saturating_mul(2) >= w.min(h) {
+            self.fill_rect(x, y, w, h, color);
+            return;
+        }
+        // Past i32 is past the bounds anyway
+        let offset = |at: i32, by: u32| (at as i64 + by as i64).min(i32::MAX as i64) as i32;
+        let inner = h - 2 * thickness;
+        self.fill_rect(x, y, w, thickness, color);
+        self.fill_rect(x, offset(y, h - thickness), w, thickness, color);
+        self.fill_rect(x, offset(y, thickness), thickness, inner, color);
+        self.fill_rect(offset(x, w - thickness), offset(y, thickness), thickness, inner, color);
+    }
+
+    /// Draw a one-pixel line from (`x0`, `y0`) to (`x1`, `y1`), both ends
+    /// included.
+    pub fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: (u8, u8, u8)) {
+        let (x0, y0, x1, y1) = (x0 as i64, y0 as i64, x1 as i64, y1 as i64);
+        // Entirely to one side of the bounds: nothing to draw
+        if x0.max(x1) < 0 || y0.max(y1) < 0 || x0.min(x1) >= self.width as i64 || y0.min(y1) >= self.height as i64 {
+            return;
+        }
+        let value = pixel(color);
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+        loop {
+            self.plot(x, y, value);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+        self.touch_box(x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1));
+    }
+
+    /// Call `f` with each (x, y) of the first octant of a midpoint circle of
+    /// `radius`, from (radius, 0) to the diagonal.
+    fn octant(radius: u32, mut f: impl FnMut(i64, i64)) {
+        let (mut x, mut y, mut err) = (radius as i64, 0i64, 1 - radius as i64);
+        while x >= y {
+            f(x, y);
+            y += 1;
+            if err < 0 {
+                err += 2 * y + 1;
+            } else {
+                x -= 1;
+                err += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Draw a one-pixel circle of `radius` around (`cx`, `cy`).
+    pub fn draw_circle(&mut self, cx: i32, cy: i32, radius: u32, color: (u8, u8, u8)) {
+        let (cx, cy, r) = (cx as i64, cy as i64, radius as i64);
+        let value = pixel(color);
+        Self::octant(radius, |x, y| {
+            for (px, py) in [(x, y), (y, x), (-y, x), (-x, y), (-x, -y), (-y, -x), (y, -x), (x, -y)] {
+                self.plot(cx + px, cy + py, value);
+            }
+        });
+        self.touch_box(cx - r, cy - r, cx + r, cy + r);
+    }
+
+    /// Fill the circle of `radius` around (`cx`, `cy`), its outline
+    /// included.
+    pub fn fill_circle(&mut self, cx: i32, cy: i32, radius: u32, color: (u8, u8, u8)) {
+        let (cx, cy, r) = (cx as i64, cy as i64, radius as i64);
+        let value = pixel(color);
+        Self::octant(radius, |x, y| {
+            for (half, dy) in [(x, y), (x, -y), (y, x), (y, -x)] {
+                self.hline(cx - half, cx + half, cy + dy, value);
+            }
+        });
+        self.touch_box(cx - r, cy - r, cx + r, cy + r);
+    }
+
+    /// Fill the triangle with corners `a`, `b` and `c`, its edges included.
+    pub fn fill_triangle(&mut self, a: (i32, i32), b: (i32, i32), c: (i32, i32), color: (u8, u8, u8)) {
+        let mut corners = [a, b, c].map(|(x, y)| (x as i64, y as i64));
+        corners.sort_unstable_by_key(|&(_, y)| y);
+        let [(x0, y0), (x1, y1), (x2, y2)] = corners;
+        let value = pixel(color);
+        let (left, right) = (x0.min(x1).min(x2), x0.max(x1).max(x2));
+        if y0 == y2 {
+            self.hline(left, right, y0, value);
+        } else {
+            // x on the edge from (xa, ya) to (xb, yb) at row y (the product
+            // can overflow i64 for corners far out)
+            let edge = |(xa, ya): (i64, i64), (xb, yb): (i64, i64), y: i64| {
+                if ya == yb { xa } else { xa + ((xb - xa) as i128 * (y - ya) as i128 / (yb - ya) as i128) as i64 }
+            };
+            for y in y0.max(0)..=y2.min(self.height as i64 - 1) {
+                let long = edge((x0, y0), (x2, y2), y);
+                let short = if y < y1 { edge((x0, y0), (x1, y1), y) } else { edge((x1, y1), (x2, y2), y) };
+                self.hline(long.min(short), long.max(short), y, value);
+            }
+        }
+        self.touch_box(left, y0, right, y2);
+    }
+
+    /// Fill the whole framebuffer with `color`.
+    pub fn clear(&mut self, color: (u8, u8, u8)) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// Blend `color` (R, G, B, alpha) over the `len` pixels from (`x`, `y`)
+    /// on.
+    pub fn blend_row(&mut self, x: i32, y: i32, len: u32, color: (u8, u8, u8, u8)) {
+        let (r, g, b, alpha) = color;
+        if alpha == 0 {
+            return;
+        }
+        let Some(rect @ (x0, y0, x1, _)) = self.clip(x, y, len, 1) else {
+            return;
+        };
+        let span = self.span_mut(y0, x0, x1);
+        match alpha {
+            255 => span.fill(pixel((r, g, b))),
+            _ => span.iter_mut().for_each(|dst| *dst = blend(*dst, (r, g, b), alpha)),
+        }
+        self.touch(rect);
+    }
+
+    /// Blend `color` (R, G, B, alpha) over the pixel at (`x`, `y`).
+    pub fn blend_pixel(&mut self, x: i32, y: i32, color: (u8, u8, u8, u8)) {
+        self.blend_row(x, y, 1, color);
+    }
+
+    /// Copy the `w` x `h` pixels of `src`, row after row, to (`x`, `y`).
+    /// Does nothing if `src` holds fewer.
+    pub fn blit(&mut self, x: i32, y: i32, w: u32, h: u32, src: &[u32]) {
+        if src.len() < (w as usize) * (h as usize) {
+            return;
+        }
+        let Some(rect @ (x0, y0, x1, y1)) = self.clip(x, y, w, h) else {
+            return;
+        };
+        for row in y0..y1 {
+            // Where the visible part of this row starts in `src`
+            let from = (row as i64 - y as i64) as usize * w as usize + (x0 as i64 - x as i64) as usize;
+            self.span_mut(row, x0, x1).copy_from_slice(&src[from..from + (x1 - x0) as usize]);
+        }
+        self.touch(rect);
+    }
+
+    /// Draw `img` with its top-left corner at (`x`, `y`), blended by its
+    /// alpha. With `fit`, an image bigger than that size is scaled down
+    /// (nearest pixel, keeping proportions) to `img.fit` of it.
+    pub fn blit_image(&mut self, img: &Image, x: i32, y: i32, fit: Option<(u32, u32)>) {
+        let (w, h) = match fit {
+            Some((max_w, max_h)) => img.fit(max_w, max_h),
+            None => (img.width(), img.height()),
+        };
+        let Some(rect @ (x0, y0, x1, y1)) = self.clip(x, y, w, h) else {
+            return;
+        };
+        let (src_w, src_h) = (img.width() as u64, img.height() as u64);
+        for row in y0..y1 {
+            let src_y = (row as i64 - y as i64) as u64 * src_h / h as u64;
+            let src_row = &img.pixels()[(src_y * src_w) as usize..][..src_w as usize];
+            for (px, dst) in (x0..x1).zip(self.span_mut(row, x0, x1)) {
+                let src_x = (px as i64 - x as i64) as u64 * src_w / w as u64;
+                let src = src_row[src_x as usize];
+                *dst = match (src >> 24) as u8 {
+                    0 => continue,
+                    255 => src,
+                    alpha => blend(*dst, ((src >> 16) as u8, (src >> 8) as u8, src as u8), alpha),
+                };
+            }
+        }
+        self.touch(rect);
+    }
+
+    /// Copy the `w` x `h` pixels at (`x`, `y`) of `src` to the same place
+    /// here, as far as both have them.
+    pub fn copy_rect(&mut self, src: &Framebuffer, x: i32, y: i32, w: u32, h: u32) {
+        let Some((x0, y0, x1, y1)) = self.clip(x, y, w, h) else {
+            return;
+        };
+        let Some(rect @ (x0, y0, x1, y1)) = src.clip(x0 as i32, y0 as i32, x1 - x0, y1 - y0) else {
+            return;
+        };
+        for row in y0..y1 {
+            self.span_mut(row, x0, x1).copy_from_slice(src.span(row, x0, x1));
+        }
+        self.touch(rect);
+    }
+
+    /// Move the first `height` rows up by `by`; the `by` rows at the bottom
+    /// of them keep what they had.
+    pub fn scroll_up(&mut self, height: u32, by: u32) {
+        let height = height.min(self.height);
+        if by == 0 || by >= height {
+            return;
+        }
+        for row in 0..height - by {
+            let (dst, src) = (row * self.stride, (row + by) * self.stride);
+            // SAFETY: both rows are inside; rows don't overlap
+            unsafe { core::ptr::copy_nonoverlapping(self.ptr.add(src as usize), self.ptr.add(dst as usize), self.width as usize); }
+        }
+        self.touch((0, 0, self.width, height));
+    }
+
+    /// Render `c` with its top-left corner at (`x`, `y`).
+    pub fn put_char(&mut self, x: i32, y: i32, c: char, fg: (u8, u8, u8), bg: (u8, u8, u8)) {
+        let Some(rect @ (x0, y0, x1, y1)) = self.clip(x, y, font::WIDTH, font::HEIGHT) else {
+            return;
+        };
+        let (fg, bg) = (pixel(fg), pixel(bg));
+        let glyph = font::glyph(c);
+        for row in y0..y1 {
+            let bits = glyph[(row as i64 - y as i64) as usize];
+            for (px, dst) in (x0..x1).zip(self.span_mut(row, x0, x1)) {
+                let col = (px as i64 - x as i64) as u32;
+                *dst = if bits & (1 << col) != 0 { fg } else { bg };
+            }
+        }
+        self.touch(rect);
+    }
+}
+
+/// A framebuffer in the kernel heap, for drawing off screen.
+pub struct HeapFramebuffer {
+    pixels: Vec<u32>,
+    width: u32,
+    height: u32,
+}
+
+impl HeapFramebuffer {
+    /// A `width` x `height` framebuffer, all black (and transparent).
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { pixels: vec![0; (width * height) as usize], width, height }
+    }
+
+    /// Call `f` with the framebuffer to draw with. Not tracked for
+    /// `present`.
+    pub fn draw<R>(&mut self, f: impl FnOnce(&mut Framebuffer) -> R) -> R {
+        // SAFETY: `pixels` holds `width * height` pixels, and isn't resized
+        // or freed while `f` runs
+        let mut fb = unsafe { Framebuffer::from_raw(self.pixels.as_mut_ptr(), self.width, self.height, self.width, false) };
+        f(&mut fb)
+    }
+
+    pub fn pixels(&self) -> &[u32] {
+        &self.pixels
+    }
+}
@@ -0,0 +1,566 @@
+pub mod bmp;
+mod device;
+pub mod framebuffer;
+
+pub use framebuffer::Framebuffer;
+
+use virtio_drivers::transport::DeviceType;
+use crate::drivers::font;
+use crate::drivers::virtio;
+use aprk_arch_arm64::{cpu, gic, mmu};
+use aprk_arch_arm64::timer::Timer;
+use alloc::vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use device::{Gpu, Rect};
+use framebuffer::{pixel, HeapFramebuffer};
+use spin::Mutex;
+
+pub static GPU: Mutex<Option<Gpu>> = Mutex::new(None);
+/// The back buffer everything is drawn into, and its only handle. `present`
+/// puts it on the screen. Whoever holds it is also the only one to write
+/// the device's framebuffer.
+pub static FB_CONFIG: Mutex<Option<Framebuffer>> = Mutex::new(None);
+/// The device's framebuffer
+static FRONT: AtomicUsize = AtomicUsize::new(0);
+/// Part of the back buffer drawn since the last `present`: x0, y0, x1 and
+/// y1 (exclusive), 16 bits each from the bottom
+static DIRTY: AtomicU64 = AtomicU64::new(NOTHING_DIRTY);
+/// `DIRTY` when nothing is: x0 and y0 past any pixel, x1 and y1 before
+const NOTHING_DIRTY: u64 = 0xffff_ffff;
+static CURRENT_PROGRESS: Mutex<u32> = Mutex::new(0);
+/// Screen size, for interrupt handlers, which mustn't wait for `FB_CONFIG`
+static WIDTH: AtomicU32 = AtomicU32::new(0);
+static HEIGHT: AtomicU32 = AtomicU32::new(0);
+/// GIC interrupt ID of the GPU, 0 if it has none
+static IRQ: AtomicU32 = AtomicU32::new(0);
+
+/// Cursor sprite: `#` black outline, `o` white, anything else transparent
+const CURSOR_SPRITE: [&[u8; CURSOR_W]; CURSOR_H] = [
+    b"#           ",
+    b"##          ",
+    b"#o#         ",
+    b"#oo#        ",
+    b"#ooo#       ",
+    b"#oooo#      ",
+    b"#ooooo#     ",
+    b"#oooooo#    ",
+    b"#ooooooo#   ",
+    b"#oooooooo#  ",
+    b"#ooooo##### ",
+    b"#oo#oo#     ",
+    b"#o# #oo#    ",
+    b"##  #oo#    ",
+    b"#    #oo#   ",
+    b"     ###    ",
+];
+const CURSOR_W: usize = 12;
+const CURSOR_H: usize = 16;
+
+/// The cursor on screen and the pixels it covers (save-under).
+struct Cursor {
+    /// Top-left corner, if it's drawn
+    at: Option<(u32, u32)>,
+    saved: [u32; CURSOR_W * CURSOR_H],
+}
+
+static CURSOR: Mutex<Cursor> = Mutex::new(Cursor { at: None, saved: [0; CURSOR_W * CURSOR_H] });
+
+fn spin_wait(cycles: u64) {
+    for _ in 0..cycles {
+        unsafe { core::arch::asm!("nop"); }
+    }
+}
+
+pub fn init() {
+    for device in virtio::devices_of(DeviceType::GPU) {
+        if let Some(transport) = device.transport() {
+            crate::log_info!("gpu", "Found VirtIO GPU at {:#x}", device.base);
+            match Gpu::new(transport) {
+                Ok(mut gpu) => {
+                    let (width, height) = gpu.resolution();
+                    crate::log_info!("gpu", "Initialized: {}x{}", width, height);
+                        
+                    // Set up framebuffer ONCE
+                    let fb = gpu.setup_framebuffer().unwrap();
+                    let front = map_framebuffer(fb.as_mut_ptr() as usize, fb.len());
+                    let back = vec![0u32; (width * height) as usize].leak().as_mut_ptr();
+                    FRONT.store(front, Ordering::Relaxed);
+                        
+                    WIDTH.store(width, Ordering::Relaxed);
+                    HEIGHT.store(height, Ordering::Relaxed);
+                    // SAFETY: leaked for good, and drawn through this alone
+                    *FB_CONFIG.lock() = Some(unsafe { Framebuffer::from_raw(back, width, height, width, true) });
+                    *GPU.lock() = Some(gpu);
+                    if gic::register_handler(device.irq, irq_handler) && gic::enable_irq(device.irq) {
+                        IRQ.store(device.irq, Ordering::Relaxed);
+                    } else {
+                        crate::log_warn!("gpu", "IRQ {} unavailable", device.irq);
+                    }
+                        
+                    draw_boot_screen();
+                    return;
+                }
+                Err(e) => crate::println!("[gpu] Failed to initialize: {:?}", e),
+            }
+        }
+    }
+}
+
+/// A command finished. `flush` waits for its commands itself (the driver
+/// polls the control queue), so there's nobody to wake: this only
+/// acknowledges the interrupt. If a flush holds the GPU, it acknowledges
+/// it instead once done.
+fn irq_handler(irq: u32) {
+    let acked = match GPU.try_lock() {
+        Some(mut gpu) => gpu.as_mut().is_some_and(|gpu| gpu.ack_interrupt()),
+        None => true,
+    };
+    crate::drivers::virtio::note_interrupt(irq, acked);
+}
+
+/// Send `rect` of the framebuffer to the screen, and acknowledge the
+/// interrupts the commands raised, which the interrupt handler couldn't
+/// while we held the GPU.
+fn flush(gpu: &mut Gpu, rect: Rect) -> virtio_drivers::Result {
+    let result = gpu.flush(rect);
+    if IRQ.load(Ordering::Relaxed) != 0 {
+        gpu.ack_interrupt();
+    }
+    result
+}
+
+fn pack_rect(x0: u32, y0: u32, x1: u32, y1: u32) -> u64 {
+    x0 as u64 | (y0 as u64) << 16 | (x1 as u64) << 32 | (y1 as u64) << 48
+}
+
+fn unpack_rect(rect: u64) -> (u32, u32, u32, u32) {
+    let field = |shift: u32| (rect >> shift) as u32 & 0xffff;
+    (field(0), field(16), field(32), field(48))
+}
+
+/// Note that the `w` x `h` pixels from (`x`, `y`) on of the back buffer
+/// were drawn, for the next `present`. Safe from IRQ context.
+pub fn mark_dirty(x: u32, y: u32, w: u32, h: u32) {
+    let Some((width, height)) = resolution() else {
+        return;
+    };
+    let (x1, y1) = (x.saturating_add(w).min(width), y.saturating_add(h).min(height));
+    if x >= x1 || y >= y1 {
+        return;
+    }
+    let mut rect = DIRTY.load(Ordering::Relaxed);
+    loop {
+        let (old_x0, old_y0, old_x1, old_y1) = unpack_rect(rect);
+        let union = pack_rect(old_x0.min(x), old_y0.min(y), old_x1.max(x1), old_y1.max(y1));
+        // Nothing stored if the region is already covered
+        if union == rect {
+            return;
+        }
+        match DIRTY.compare_exchange_weak(rect, union, Ordering::AcqRel, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(current) => rect = current,
+        }
+    }
+}
+
+/// Copy what was drawn since the last call from the back buffer to the
+/// device's framebuffer, and show it.
+///
+/// Only the dirty rectangle is copied, and only it is transferred to the
+/// device and flushed. If someone else holds the back buffer or is
+/// presenting, what's dirty is left to them: they present once done. So
+/// this never waits, and is safe with IRQs masked or from IRQ context.
+pub fn present() {
+    if let Some(fb_config) = FB_CONFIG.try_lock() {
+        if let Some(back) = fb_config.as_ref() {
+            present_from(back);
+        }
+    }
+}
+
+/// `present`, for whoever holds the back buffer (`FB_CONFIG`).
+fn present_from(back: &Framebuffer) {
+    let Some(mut front) = front_buffer() else {
+        return;
+    };
+    while DIRTY.load(Ordering::Acquire) != NOTHING_DIRTY {
+        let Some(mut gpu_lock) = GPU.try_lock() else {
+            return;
+        };
+        let Some(gpu) = gpu_lock.as_mut() else {
+            return;
+        };
+        let (x0, y0, x1, y1) = unpack_rect(DIRTY.swap(NOTHING_DIRTY, Ordering::AcqRel));
+        if x0 < x1 && y0 < y1 {
+            front.copy_rect(back, x0 as i32, y0 as i32, x1 - x0, y1 - y0);
+            let _ = flush(gpu, Rect { x: x0, y: y0, width: x1 - x0, height: y1 - y0 });
+        }
+    }
+}
+
+/// A handle on the device's framebuffer, if there's a screen. Only for
+/// whoever holds `FB_CONFIG`.
+fn front_buffer() -> Option<Framebuffer> {
+    let (width, height) = resolution()?;
+    let ptr = FRONT.load(Ordering::Relaxed) as *mut u32;
+    // SAFETY: `init` set it up to hold the screen's pixels, and it's never
+    // freed or unmapped. Holding `FB_CONFIG`, the caller is its only writer
+    Some(unsafe { Framebuffer::from_raw(ptr, width, height, width, false) })
+}
+
+/// Remap the framebuffer Normal Non-Cacheable so writes reach memory the
+/// device reads without cache maintenance.
+///
+/// Returns the VA to draw through. Falls back to the cacheable linear
+/// mapping if the remap fails.
+fn map_framebuffer(va: usize, len: usize) -> usize {
+    let Some((pa, _)) = mmu::translate(va) else {
+        return va;
+    };
+    // Nothing dirty may be left to be written back over the uncached view
+    unsafe { cpu::clean_invalidate_dcache_range(va, len); }
+    match mmu::map_device_region(pa, len, mmu::MemoryType::NonCacheable) {
+        Some(nc) => {
+            crate::log_info!("gpu", "Framebuffer {:#x} mapped non-cacheable at {:#x}", pa, nc);
+            nc
+        }
+        None => {
+            crate::log_warn!("gpu", "Failed to remap framebuffer, using cacheable mapping");
+            va
+        }
+    }
+}
+
+/// Time filling the whole screen and getting it on the display: straight
+/// into the device's framebuffer with a flush of the screen, and into the
+/// back buffer with `present`. What was on the screen is put back.
+pub fn fill_benchmark() {
+    const ROUNDS: u32 = 4;
+    let mut fb_config = FB_CONFIG.lock();
+    let (Some(back), Some(mut front)) = (fb_config.as_mut(), front_buffer()) else {
+        crate::println!("No framebuffer.");
+        return;
+    };
+    let (width, height) = (back.width(), back.height());
+    let mut saved = HeapFramebuffer::new(width, height);
+    saved.draw(|saved| saved.copy_rect(back, 0, 0, width, height));
+
+    let start = Timer::counter();
+    for round in 0..ROUNDS {
+        let shade = (round * 40) as u8;
+        front.clear((shade, shade, shade));
+        if let Some(gpu) = GPU.lock().as_mut() {
+            let _ = flush(gpu, gpu.screen());
+        }
+    }
+    let direct = Timer::ticks_to_duration(Timer::counter() - start) / ROUNDS;
+    let start = Timer::counter();
+    for round in 0..ROUNDS {
+        let shade = (round * 40) as u8;
+        back.clear((shade, shade, shade));
+        present_from(back);
+    }
+    let buffered = Timer::ticks_to_duration(Timer::counter() - start) / ROUNDS;
+    crate::println!("framebuffer + flush:   {:>6} us/frame", direct.as_micros());
+    crate::println!("back buffer + present: {:>6} us/frame", buffered.as_micros());
+
+    back.blit(0, 0, width, height, saved.pixels());
+    present_from(back);
+}
+
+/// Draw a test pattern with every shape primitive, most of them crossing
+/// or just past the screen edges where clipping goes wrong, and present
+/// it.
+pub fn demo() {
+    let mut fb_config = FB_CONFIG.lock();
+    let Some(fb) = fb_config.as_mut() else {
+        crate::println!("No framebuffer.");
+        return;
+    };
+    let (w, h) = (fb.width() as i32, fb.height() as i32);
+    let (cx, cy) = (w / 2, h / 2);
+    let (red, green, blue) = ((230, 60, 60), (60, 200, 90), (70, 110, 240));
+    let (yellow, white) = ((240, 210, 60), (255, 255, 255));
+    fb.clear((16, 16, 20));
+
+    // Frames: one on the edge, one half off it, one inset
+    fb.draw_rect(0, 0, w as u32, h as u32, 2, white);
+    fb.draw_rect(-20, -20, 80, 80, 6, yellow);
+    fb.draw_rect(w - 60, h - 60, 80, 80, 6, yellow);
+    fb.draw_rect(40, 40, (w - 80) as u32, (h - 80) as u32, 1, blue);
+
+    // Lines: the diagonals, a fan from the center to far off screen
+    fb.draw_line(0, 0, w - 1, h - 1, green);
+    fb.draw_line(w - 1, 0, 0, h - 1, green);
+    for i in 0..16 {
+        let (x, y) = (-w + i * w / 5, if i % 2 == 0 { -h } else { 2 * h });
+        fb.draw_line(cx, cy, x, y, blue);
+    }
+    fb.draw_line(-100, h / 3, w + 100, h / 3, red);
+    fb.draw_line(w / 3, -100, w / 3, h + 100, red);
+
+    // Circles centered on the corners and edges, and in the middle
+    for (x, y) in [(0, 0), (w - 1, 0), (0, h - 1), (w - 1, h - 1)] {
+        fb.fill_circle(x, y, 50, red);
+        fb.draw_circle(x, y, 70, white);
+    }
+    fb.fill_circle(cx, -10, 40, green);
+    fb.fill_circle(cx, h + 10, 40, green);
+    fb.draw_circle(cx, cy, (h / 3) as u32, yellow);
+    fb.fill_circle(cx, cy, 30, white);
+    fb.draw_circle(cx, cy, 0, red);
+
+    // Triangles sticking out of the left and right, one flat, one in the
+    // middle
+    fb.fill_triangle((-40, cy - 60), (60, cy), (-40, cy + 60), yellow);
+    fb.fill_triangle((w + 40, cy - 60), (w - 60, cy), (w + 40, cy + 60), yellow);
+    fb.fill_triangle((cx - 80, h / 4), (cx + 80, h / 4), (cx, h / 4), white);
+    fb.fill_triangle((cx - 60, 3 * h / 4 + 40), (cx + 60, 3 * h / 4 + 40), (cx, 3 * h / 4 - 40), blue);
+
+    // Entirely off screen: draws nothing
+    fb.draw_line(-50, -50, -10, -80, white);
+    fb.fill_triangle((w + 10, 0), (w + 50, 10), (w + 20, 40), white);
+    fb.fill_circle(-200, cy, 100, white);
+    drop(fb_config);
+    present();
+}
+
+/// Screen size, if there's a framebuffer. Safe from IRQ context.
+pub fn resolution() -> Option<(u32, u32)> {
+    match (WIDTH.load(Ordering::Relaxed), HEIGHT.load(Ordering::Relaxed)) {
+        (0, _) | (_, 0) => None,
+        size => Some(size),
+    }
+}
+
+/// Draw the cursor with its tip at (`x`, `y`), putting back what it
+/// covered where it was before.
+pub fn draw_cursor(x: u32, y: u32) {
+    let mut fb_config = FB_CONFIG.lock();
+    let Some(fb) = fb_config.as_mut() else {
+        return;
+    };
+    let (x, y) = (x as i32, y as i32);
+    // Each of the sprite's pixels: its index in the sprite, and where it
+    // goes with the tip at (`cx`, `cy`)
+    let pixels = |cx: i32, cy: i32| {
+        (0..CURSOR_H).flat_map(move |row| (0..CURSOR_W).map(move |col| (row * CURSOR_W + col, cx + col as i32, cy + row as i32)))
+    };
+    let mut cursor = CURSOR.lock();
+    if let Some((old_x, old_y)) = cursor.at.take() {
+        for (i, px, py) in pixels(old_x as i32, old_y as i32) {
+            if let Some(dst) = fb.pixel_mut(px, py) {
+                *dst = cursor.saved[i];
+            }
+        }
+        fb.touch_rect(old_x as i32, old_y as i32, CURSOR_W as u32, CURSOR_H as u32);
+    }
+    for (i, px, py) in pixels(x, y) {
+        let Some(dst) = fb.pixel_mut(px, py) else {
+            continue;
+        };
+        cursor.saved[i] = *dst;
+        match CURSOR_SPRITE[i / CURSOR_W][i % CURSOR_W] {
+            b'#' => *dst = 0xff00_0000,
+            b'o' => *dst = 0xffff_ffff,
+            _ => {}
+        }
+    }
+    cursor.at = Some((x as u32, y as u32));
+    fb.touch_rect(x, y, CURSOR_W as u32, CURSOR_H as u32);
+    drop(cursor);
+    drop(fb_config);
+    present();
+}
+
+/// Draw `c` on the screen with its top-left corner at (`x`, `y`).
+#[allow(dead_code)]
+pub fn draw_char(x: u32, y: u32, c: char, fg: (u8, u8, u8), bg: (u8, u8, u8)) {
+    if let Some(fb) = FB_CONFIG.lock().as_mut() {
+        fb.put_char(x as i32, y as i32, c, fg, bg);
+    }
+    present();
+}
+
+/// Draw `text` on the screen from (`x`, `y`) on, one glyph after the other;
+/// each '\n' starts a new row at `x`. What runs off the screen is cut.
+pub fn draw_text(x: u32, y: u32, text: &str, fg: (u8, u8, u8), bg: (u8, u8, u8)) {
+    let mut fb_config = FB_CONFIG.lock();
+    let Some(fb) = fb_config.as_mut() else {
+        return;
+    };
+    for (row, line) in text.split('\n').enumerate() {
+        let py = y + row as u32 * font::HEIGHT;
+        if py >= fb.height() {
+            break;
+        }
+        for (col, c) in line.chars().enumerate() {
+            let px = x + col as u32 * font::WIDTH;
+            if px >= fb.width() {
+                break;
+            }
+            fb.put_char(px as i32, py as i32, c, fg, bg);
+        }
+    }
+    drop(fb_config);
+    present();
+}
+
+/// Top color of the boot screen's gradient, which fades to black at the
+/// bottom
+const GRADIENT_TOP: (u8, u8, u8) = (20, 20, 25);
+
+/// Fill `fb` with the boot screen's gradient: dark gray to black, top to
+/// bottom.
+pub fn draw_gradient(fb: &mut Framebuffer) {
+    let (width, height) = (fb.width(), fb.height());
+    let (top_r, top_g, top_b) = GRADIENT_TOP;
+    for y in 0..height {
+        let fade = |top: u8| (top as u32 * (height - y) / height) as u8;
+        fb.fill_rect(0, y as i32, width, 1, (fade(top_r), fade(top_g), fade(top_b)));
+    }
+}
+
+/// Check the integer drawing against the floating-point math it replaced:
+/// draw a fixed scene (the gradient, then spans at every 17th opacity)
+/// into a buffer of its own both ways and compare. Rounding may differ by
+/// one per channel, no more.
+pub fn self_test() {
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 48;
+    let colors = [(255, 255, 255), (200, 30, 90), (0, 128, 255)];
+
+    let mut scene = HeapFramebuffer::new(WIDTH, HEIGHT);
+    scene.draw(|fb| {
+        draw_gradient(fb);
+        for (i, alpha) in (0..=255).step_by(17).enumerate() {
+            let (r, g, b) = colors[i % colors.len()];
+            fb.blend_row(i as i32, (i as u32 * 3 % HEIGHT) as i32, WIDTH / 2, (r, g, b, alpha as u8));
+        }
+    });
+    let fb = scene.pixels();
+
+    // The same scene, as the float code drew it
+    let mut reference = vec![0u32; (WIDTH * HEIGHT) as usize];
+    for y in 0..HEIGHT {
+        let ratio = y as f32 / HEIGHT as f32;
+        let fade = |top: u8| (top as f32 * (1.0 - ratio)) as u8;
+        let (top_r, top_g, top_b) = GRADIENT_TOP;
+        for x in 0..WIDTH {
+            reference[(y * WIDTH + x) as usize] = pixel((fade(top_r), fade(top_g), fade(top_b)));
+        }
+    }
+    for (i, alpha) in (0..=255).step_by(17).enumerate() {
+        let (r, g, b) = colors[i % colors.len()];
+        let (x, y) = (i as u32, i as u32 * 3 % HEIGHT);
+        let alpha = alpha as f32 / 255.0;
+        for px in x..(x + WIDTH / 2).min(WIDTH) {
+            let dst = &mut reference[(y * WIDTH + px) as usize];
+            let channel = |src: u8, shift: u32| {
+                ((src as f32 * alpha + ((*dst >> shift) as u8) as f32 * (1.0 - alpha)) as u8 as u32) << shift
+            };
+            *dst = 0xff00_0000 | channel(r, 16) | channel(g, 8) | channel(b, 0);
+        }
+    }
+
+    let checksum = |fb: &[u32]| fb.iter().fold(0u64, |sum, &p| sum + (p & 0xff) as u64 + (p >> 8 & 0xff) as u64 + (p >> 16 & 0xff) as u64);
+    let worst = fb.iter().zip(&reference)
+        .flat_map(|(&a, &b)| [0, 8, 16].map(|shift| ((a >> shift) as u8).abs_diff((b >> shift) as u8)))
+        .max()
+        .unwrap_or(0);
+    let (sum, expected) = (checksum(fb), checksum(&reference));
+    if worst <= 1 {
+        crate::println!("PASSED: integer drawing (checksum {}, float {}, largest channel difference {})", sum, expected, worst);
+    } else {
+        crate::println!("FAILED: integer drawing (checksum {}, float {}, largest channel difference {})", sum, expected, worst);
+    }
+}
+
+pub fn draw_boot_screen() {
+    let mut fb_config = FB_CONFIG.lock();
+    
+    if let Some(fb) = fb_config.as_mut() {
+        let (width, height) = (fb.width(), fb.height());
+        let logo_data = include_bytes!("../../../../assets/logo.bmp");
+        
+        // Draw background gradient
+        draw_gradient(fb);
+        // Drawn over: there's nothing under the cursor to put back
+        CURSOR.lock().at = None;
+
+        match bmp::decode(logo_data) {
+            Ok(mut logo) => {
+                // Simple alpha: if it's very dark, assume it's background
+                for px in logo.pixels_mut() {
+                    let luma = ((*px >> 16 & 0xff) + (*px >> 8 & 0xff) + (*px & 0xff)) / 3;
+                    if luma < 10 {
+                        *px = 0;
+                    }
+                }
+                let x_off = (width as i32 - logo.width() as i32) / 2;
+                let abs_height = logo.height() as i32;
+                let y_off = (height as i32 - abs_height) / 2 - 50;
+                fb.blit_image(&logo, x_off, y_off, None);
+
+                // Draw progress bar track
+                let bar_width = 300;
+                let bar_height = 6;
+                let bar_x = (width - bar_width) / 2;
+                let bar_y = (y_off + abs_height + 60) as u32;
+
+                // Track (Semi-transparent dark gray)
+                fb.fill_rect(bar_x as i32, bar_y as i32, bar_width, bar_height, (40, 40, 45));
+            }
+            Err(e) => crate::log_warn!("gpu", "Boot logo: {}", e),
+        }
+        drop(fb_config);
+        present();
+    }
+}
+
+pub fn update_progress(percent: u32) {
+    let mut current = CURRENT_PROGRESS.lock();
+    let start = *current;
+    let end = if percent > 100 { 100 } else { percent };
+    
+    if end <= start { return; }
+
+    let mut fb_config = FB_CONFIG.lock();
+    
+    if let Some(fb) = fb_config.as_mut() {
+        let (width, height) = (fb.width(), fb.height());
+        let logo_h = 558; 
+        let bar_width = 300;
+        let bar_height = 6;
+        let bar_x = (width - bar_width) / 2;
+        let bar_y = (height as i32 - logo_h) / 2 - 50 + logo_h + 60;
+
+        for p in start..=end {
+            let progress_width = (bar_width * p) / 100;
+            
+            // Draw progress bar for current percentage
+            for dx in 0..progress_width {
+                 for dy in 0..bar_height {
+                     fb.blend_pixel((bar_x + dx) as i32, bar_y + dy as i32, (255, 255, 255, 255));
+                 }
+            }
+            
+            // Add a subtle glow at the tip
+            if progress_width > 0 && progress_width < bar_width {
+                let tip_x = bar_x + progress_width;
+                let tip_y = bar_y as u32 + (bar_height / 2);
+                for i in 1..8 {
+                    let alpha = (128 / (i * 2)) as u8;
+                    fb.blend_pixel(tip_x as i32, tip_y as i32, (255, 255, 255, alpha));
+                }
+            }
+            
+            present_from(fb);
+            
+            // Subtle delay for animation effect
+            spin_wait(1_000_000); 
+        }
+    }
+    
+    *current = end;
+}
@@ -1,9 +1,18 @@
+pub mod fbcon;
+pub mod font;
 pub mod gpu;
 pub mod virtio;
 pub mod virtio_blk;
+pub mod virtio_console;
+pub mod virtio_input;
+pub mod virtio_net;
+pub mod virtqueue;
 
 pub fn init() {
-    virtio::init();
+    virtio::scan();
     gpu::init();
     virtio_blk::init();
+    virtio_console::init();
+    virtio_net::init();
+    virtio_input::init();
 }
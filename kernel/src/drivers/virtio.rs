@@ -1,40 +1,255 @@
+// =============================================================================
+// APRK OS - VirtIO Bus
+// =============================================================================
+// The virtio-mmio transports of the QEMU virt machine, and the HAL the
+// virtio-drivers crate allocates and shares memory through.
+//
+// `scan` probes every transport once at boot and records what's plugged
+// into it; drivers pick their devices from that table (`devices_of`)
+// instead of probing the bus themselves. A device's interrupt comes from
+// its device tree node when there is one, otherwise from the fixed layout
+// of the virt machine (SPI 16 + slot).
+//
+// Each driver's interrupt handler acknowledges the device's interrupt
+// status and reports what it found with `note_interrupt`. An interrupt
+// with no status bit set is "idle": a device that keeps sending them (a
+// storm, usually a missing ACK) is reported once its count within a
+// second reaches the IRQ storm threshold (`irqstat storm`).
+//
+// DMA memory (queues, the framebuffer) comes from the PMM as physically
+// contiguous pages, tagged `Dma`, and is reached through the linear map.
+// Buffers shared with a device are cleaned and invalidated from the data
+// cache on the way in, and those the device wrote invalidated again on
+// the way out: QEMU's devices see the caches, real hardware doesn't.
+// =============================================================================
+
 use virtio_drivers::{BufferDirection, Hal, PhysAddr};
+use virtio_drivers::transport::{mmio::{MmioTransport, VirtIOHeader}, DeviceType, Transport};
 use core::ptr::NonNull;
-use alloc::alloc::{alloc, dealloc, Layout};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use aprk_arch_arm64::gic::{self, TriggerMode};
+use aprk_arch_arm64::{cpu, dtb, exception, mmu};
+use aprk_arch_arm64::timer::Timer;
+use crate::mm::pmm::{self, PageTag, PAGE_SIZE};
+
+/// First virtio-mmio transport on the QEMU virt machine
+pub const MMIO_BASE: usize = 0x0a00_0000;
+/// Spacing between virtio-mmio transports
+pub const MMIO_STRIDE: usize = 0x200;
+/// Number of virtio-mmio transports
+pub const MMIO_SLOTS: usize = 32;
+/// GIC interrupt ID of the first transport (SPI 16)
+const MMIO_IRQ_BASE: u32 = 48;
+
+/// A device found on the virtio-mmio bus.
+#[derive(Clone, Copy, Debug)]
+pub struct VirtioDevice {
+    /// Transport number, from 0 at `MMIO_BASE`
+    pub slot: usize,
+    /// Physical address of the transport's registers
+    pub base: usize,
+    pub device_type: DeviceType,
+    /// Transport version: 1 (legacy) or 2
+    pub version: u32,
+    /// GIC interrupt ID
+    pub irq: u32,
+}
+
+impl VirtioDevice {
+    /// A transport for the driver to take the device over with. The
+    /// interrupt is declared edge-triggered, as QEMU signals virtio-mmio
+    /// interrupts on a rising edge, and left disabled until the driver
+    /// uses interrupts.
+    pub fn transport(&self) -> Option<MmioTransport> {
+        let header = NonNull::new(mmu::phys_to_virt(self.base) as *mut VirtIOHeader)?;
+        // SAFETY: `scan` found a valid transport there, mapped by `init`
+        let transport = unsafe { MmioTransport::new(header) }.ok()?;
+        gic::set_trigger(self.irq, TriggerMode::Edge);
+        Some(transport)
+    }
+
+    /// Interrupts taken from the device so far, and how many of them were
+    /// idle (no interrupt status to acknowledge).
+    pub fn interrupts(&self) -> (u64, u64) {
+        let counter = &IRQ_COUNTERS[self.slot];
+        (counter.count.load(Ordering::Relaxed), counter.idle.load(Ordering::Relaxed))
+    }
+}
+
+/// Interrupt counts of one transport.
+struct IrqCounter {
+    /// GIC interrupt ID, 0 for an empty slot
+    irq: AtomicU32,
+    count: AtomicU64,
+    idle: AtomicU64,
+    /// Start of the current one-second storm window, in ms of uptime
+    window_start_ms: AtomicU64,
+    /// Idle interrupts within the current window
+    window_idle: AtomicU32,
+    /// A storm was already reported
+    stormed: AtomicBool,
+}
+
+impl IrqCounter {
+    const fn new() -> Self {
+        Self {
+            irq: AtomicU32::new(0),
+            count: AtomicU64::new(0),
+            idle: AtomicU64::new(0),
+            window_start_ms: AtomicU64::new(0),
+            window_idle: AtomicU32::new(0),
+            stormed: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Interrupt counts, by slot
+static IRQ_COUNTERS: [IrqCounter; MMIO_SLOTS] = [const { IrqCounter::new() }; MMIO_SLOTS];
+
+/// Devices found by `scan`, by slot
+static DEVICES: Mutex<Vec<VirtioDevice>> = Mutex::new(Vec::new());
+
+/// GIC interrupt ID of the transport at `base`, in `slot`: from the
+/// device tree if it lists the transport, otherwise the virt machine's.
+fn mmio_irq(slot: usize, base: usize) -> u32 {
+    dtb::get()
+        .and_then(|dtb| dtb.find_node(|node| node.is_compatible("virtio,mmio") && node.reg(0).is_some_and(|(addr, _)| addr == base as u64)))
+        .and_then(|node| node.irq(0))
+        .unwrap_or(MMIO_IRQ_BASE + slot as u32)
+}
+
+/// Map the transports, probe each and record the devices plugged in.
+/// Runs once at boot, before the drivers look for their devices.
+pub fn scan() {
+    if !mmu::map_device(MMIO_BASE, MMIO_SLOTS * MMIO_STRIDE) {
+        crate::log_error!("virtio", "Failed to map the virtio-mmio transports");
+        return;
+    }
+    let mut devices = Vec::new();
+    for (slot, counter) in IRQ_COUNTERS.iter().enumerate() {
+        let base = MMIO_BASE + slot * MMIO_STRIDE;
+        let header = unsafe { NonNull::new_unchecked(mmu::phys_to_virt(base) as *mut VirtIOHeader) };
+        let Ok(transport) = (unsafe { MmioTransport::new(header) }) else {
+            continue;
+        };
+        let device_type = transport.device_type();
+        if device_type == DeviceType::Invalid {
+            continue;
+        }
+        let device = VirtioDevice { slot, base, device_type, version: transport.version() as u32, irq: mmio_irq(slot, base) };
+        crate::log_info!("virtio", "Found {:?} at {:#x} (slot {}, IRQ {})", device_type, base, slot, device.irq);
+        counter.irq.store(device.irq, Ordering::Relaxed);
+        devices.push(device);
+    }
+    *DEVICES.lock() = devices;
+}
+
+/// Every device found on the bus, by slot.
+pub fn devices() -> Vec<VirtioDevice> {
+    DEVICES.lock().clone()
+}
+
+/// The devices of type `device_type`, by slot.
+pub fn devices_of(device_type: DeviceType) -> Vec<VirtioDevice> {
+    DEVICES.lock().iter().filter(|device| device.device_type == device_type).copied().collect()
+}
+
+/// Account for an interrupt of the device on `irq`, which had interrupt
+/// status to acknowledge if `acked`. Called from the driver's interrupt
+/// handler.
+///
+/// Idle interrupts are reported once they storm; the line isn't masked,
+/// as the driver still waits on it.
+pub fn note_interrupt(irq: u32, acked: bool) {
+    let Some((slot, counter)) = IRQ_COUNTERS.iter().enumerate().find(|(_, c)| c.irq.load(Ordering::Relaxed) == irq) else {
+        return;
+    };
+    counter.count.fetch_add(1, Ordering::Relaxed);
+    if acked {
+        return;
+    }
+    counter.idle.fetch_add(1, Ordering::Relaxed);
+    // IRQs are masked while we run, so plain load/store sequences are safe
+    let now_ms = Timer::uptime().as_millis() as u64;
+    let window = if now_ms.saturating_sub(counter.window_start_ms.load(Ordering::Relaxed)) < 1000 {
+        counter.window_idle.load(Ordering::Relaxed) + 1
+    } else {
+        counter.window_start_ms.store(now_ms, Ordering::Relaxed);
+        1
+    };
+    counter.window_idle.store(window, Ordering::Relaxed);
+    let threshold = exception::storm_threshold();
+    if threshold != 0 && window >= threshold && !counter.stormed.swap(true, Ordering::Relaxed) {
+        crate::log_warn!("virtio", "Slot {} (IRQ {}) stormed: {} interrupts in 1s with no status to acknowledge",
+            slot, irq, window);
+    }
+}
+
+/// Physical address the device must use for a kernel buffer.
+fn virt_to_phys(va: usize) -> PhysAddr {
+    match mmu::translate(va) {
+        Some((pa, _)) => pa,
+        None => panic!("VirtIO HAL: buffer at {:#x} is not mapped", va),
+    }
+}
+
+/// Bytes from `va` on, up to `len`, that are physically contiguous: what
+/// a device can reach as one buffer.
+pub fn contiguous_len(va: usize, len: usize) -> usize {
+    let start = virt_to_phys(va);
+    let mut done = (4096 - va % 4096).min(len);
+    while done < len && mmu::translate(va + done).is_some_and(|(pa, _)| pa == start + done) {
+        done = (done + 4096).min(len);
+    }
+    done
+}
 
 pub struct HalImpl;
 
 unsafe impl Hal for HalImpl {
     fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
-        let layout = Layout::from_size_align(pages * 4096, 4096).unwrap();
-        let ptr = unsafe { alloc(layout) };
-        if ptr.is_null() {
-            panic!("VirtIO HAL: Failed to allocate DMA memory");
+        let Some(pa) = pmm::alloc_contiguous(pages, PageTag::Dma) else {
+            panic!("VirtIO HAL: Failed to allocate {} DMA pages", pages);
+        };
+        let va = mmu::phys_to_virt(pa);
+        // Zeroed, as the driver expects, and out of the cache before the
+        // device sees it
+        unsafe {
+            core::ptr::write_bytes(va as *mut u8, 0, pages * PAGE_SIZE);
+            cpu::clean_invalidate_dcache_range(va, pages * PAGE_SIZE);
         }
-        (ptr as usize, NonNull::new(ptr).unwrap())
+        (pa, NonNull::new(va as *mut u8).unwrap())
     }
 
     unsafe fn dma_dealloc(phys: PhysAddr, _virt: NonNull<u8>, pages: usize) -> i32 {
-        let layout = Layout::from_size_align(pages * 4096, 4096).unwrap();
-        dealloc(phys as *mut u8, layout);
+        pmm::free_contiguous(phys, pages);
         0
     }
 
-    #[allow(unused_variables)]
     unsafe fn mmio_phys_to_virt(phys: PhysAddr, size: usize) -> NonNull<u8> {
-        NonNull::new(phys as *mut u8).unwrap()
+        if !mmu::map_device(phys, size) {
+            panic!("VirtIO HAL: can't map MMIO at {:#x}", phys);
+        }
+        NonNull::new(mmu::phys_to_virt(phys) as *mut u8).unwrap()
     }
 
     unsafe fn share(buffer: NonNull<[u8]>, _direction: BufferDirection) -> PhysAddr {
-        buffer.as_ptr() as *mut u8 as usize
+        let va = buffer.as_ptr() as *mut u8 as usize;
+        // Whichever way the data goes, no dirty line may be written back
+        // over memory the device reads or writes
+        cpu::clean_invalidate_dcache_range(va, buffer.len());
+        virt_to_phys(va)
     }
 
-    unsafe fn unshare(_phys: PhysAddr, _buffer: NonNull<[u8]>, _direction: BufferDirection) {}
+    unsafe fn unshare(_phys: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+        if direction != BufferDirection::DriverToDevice {
+            // Drop lines fetched while the device was writing
+            cpu::clean_invalidate_dcache_range(buffer.as_ptr() as *mut u8 as usize, buffer.len());
+        }
+    }
 }
 
 
-
-pub fn init() {
-    // Discovery logic will be handled by specific drivers or a general bus scan later.
-    // For now, GPU driver will use its own discovery at a known MMIO address.
-}
@@ -1,65 +1,631 @@
-use virtio_drivers::{
-    transport::{mmio::{MmioTransport, VirtIOHeader}, Transport, DeviceType},
-    device::blk::VirtIOBlk,
-};
-use crate::drivers::virtio::HalImpl;
-use core::ptr::NonNull;
+// =============================================================================
+// APRK OS - VirtIO Block Devices
+// =============================================================================
+// Every virtio-blk device found on the MMIO bus, numbered from 0 (`blk0`,
+// `blk1`, ...). QEMU's virt machine plugs the first device on its command
+// line into the highest transport, so the bus's devices are taken in
+// reverse to keep the numbers in command line order.
+//
+// Requests are interrupt-driven: a task submits one, then sleeps on the
+// device's wait queue with the device unlocked, so other tasks can queue
+// requests of their own meanwhile. The device's interrupt wakes the queue,
+// and whichever waiter's request is next in the used ring collects it and
+// wakes the rest to check theirs. Before the scheduler runs, requests are
+// polled instead.
+//
+// A task with a request in flight counts as holding a lock (see
+// `sched::lock_taken`): the device is writing into its memory, so it
+// mustn't be torn down until the request is done.
+//
+// Each device is registered with the block layer as `blkN`; the
+// filesystem reaches it through `block::BlockDevice`, not these functions.
+//
+// `flush` sends a FLUSH request only to devices offering the feature. One
+// that doesn't either has no write cache (QEMU's cache=writethrough) or
+// can't be asked to empty it, so writes are as safe as they'll get once
+// they complete; a warning says so the first time.
+//
+// `discard` sends DISCARD requests to devices offering that, cut to the
+// device's limits; to others it does nothing. virtio-drivers' block driver
+// neither negotiates DISCARD nor builds the request, so the device is
+// driven here, on its transport and a queue of our own (`virtqueue`).
+// QEMU passes discards on to the image file with `-drive ...,discard=unmap`.
+// =============================================================================
+
+use virtio_drivers::transport::{mmio::MmioTransport, DeviceStatus, DeviceType, Transport};
+use crate::block::{self, BlockDevice, BlockError};
+use crate::drivers::virtio;
+use crate::drivers::virtqueue::VirtQueue;
+use crate::mm::pmm::PAGE_SIZE;
+use crate::sched::{self, wait::WaitQueue};
+use aprk_arch_arm64::{cpu, gic};
+use core::mem::size_of;
+use core::ptr::{addr_of, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use spin::Mutex;
-use alloc::vec::Vec;
-use alloc::vec;
 
-pub static BLK: Mutex<Option<VirtIOBlk<HalImpl, MmioTransport>>> = Mutex::new(None);
+/// Most block devices used
+pub const MAX_DEVICES: usize = 4;
+/// Bytes in a sector, the unit requests count in
+pub const SECTOR_SIZE: usize = 512;
+/// Feature bit: the device refuses writes (VIRTIO_BLK_F_RO)
+const FEATURE_RO: u64 = 1 << 5;
+/// Feature bit: the device takes FLUSH requests (VIRTIO_BLK_F_FLUSH)
+const FEATURE_FLUSH: u64 = 1 << 9;
+/// Feature bit: the device takes DISCARD requests (VIRTIO_BLK_F_DISCARD)
+const FEATURE_DISCARD: u64 = 1 << 13;
+/// Feature bit: a virtio 1.x device, not a legacy one (VIRTIO_F_VERSION_1)
+const FEATURE_VERSION_1: u64 = 1 << 32;
+/// Features taken whenever the device offers them
+const SUPPORTED_FEATURES: u64 = FEATURE_RO | FEATURE_FLUSH | FEATURE_DISCARD | FEATURE_VERSION_1;
 
-pub fn init() {
-    for i in 0..32 {
-        let base = 0x0a000000 + (i * 0x200);
-        let header = unsafe { NonNull::new_unchecked(base as *mut VirtIOHeader) };
-        if let Ok(transport) = unsafe { MmioTransport::new(header) } {
-            let dev_type = transport.device_type();
-            if dev_type != DeviceType::Invalid {
-                crate::println!("[blk] Found VirtIO device type {:?} at {:#x}", dev_type, base);
+/// Request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_DISCARD: u32 = 11;
+/// Request status: done, failed, not understood
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+/// Status before the device writes one
+const S_PENDING: u8 = u8::MAX;
+
+/// Request header, read by the device.
+#[repr(C)]
+#[derive(Default)]
+struct BlkReq {
+    type_: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Request status, written by the device.
+struct BlkResp {
+    status: u8,
+}
+
+impl Default for BlkResp {
+    fn default() -> Self {
+        Self { status: S_PENDING }
+    }
+}
+
+/// The sectors a DISCARD request covers.
+#[repr(C)]
+struct DiscardRange {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+/// The start of the device's configuration space, as far as it's used:
+/// each field 32 bits, as legacy devices want it read.
+#[repr(C)]
+struct BlkConfig {
+    capacity_low: u32,
+    capacity_high: u32,
+    size_max: u32,
+    seg_max: u32,
+    geometry: u32,
+    blk_size: u32,
+    topology: [u32; 2],
+    writeback_num_queues: u32,
+    max_discard_sectors: u32,
+    max_discard_seg: u32,
+    discard_sector_alignment: u32,
+}
+
+/// The bytes of `value`, to hand to the device.
+///
+/// # Safety
+///
+/// `T` must have no padding.
+unsafe fn as_bytes<T>(value: &T) -> &[u8] {
+    core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>())
+}
+
+/// What the device reported for a request.
+fn status(resp: &BlkResp) -> Result<(), virtio_drivers::Error> {
+    match resp.status {
+        S_OK => Ok(()),
+        S_UNSUPP => Err(virtio_drivers::Error::Unsupported),
+        _ => Err(virtio_drivers::Error::IoError),
+    }
+}
+
+/// A virtio-blk device and its request queue.
+struct Blk {
+    transport: MmioTransport,
+    queue: VirtQueue,
+    /// Features negotiated
+    features: u64,
+    /// Size in sectors
+    capacity: u64,
+    /// Most sectors one DISCARD may cover, and what its start and length
+    /// must be multiples of
+    max_discard: usize,
+    discard_alignment: usize,
+}
+
+impl Blk {
+    /// Negotiate features with the device on `transport`, read its
+    /// configuration and set up its queue.
+    fn new(mut transport: MmioTransport) -> Result<Self, virtio_drivers::Error> {
+        transport.set_status(DeviceStatus::empty());
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        let features = transport.read_device_features() & SUPPORTED_FEATURES;
+        transport.write_driver_features(features);
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK);
+        if !transport.get_status().contains(DeviceStatus::FEATURES_OK) {
+            transport.set_status(DeviceStatus::FAILED);
+            return Err(virtio_drivers::Error::Unsupported);
+        }
+        transport.set_guest_page_size(PAGE_SIZE as u32);
+
+        let config = transport.config_space::<BlkConfig>()?.as_ptr();
+        // SAFETY: the transport's configuration space, mapped with it
+        let field = |field: *const u32| unsafe { field.read_volatile() };
+        let (capacity, max_discard, discard_alignment) = unsafe {
+            let capacity = field(addr_of!((*config).capacity_low)) as u64 | (field(addr_of!((*config).capacity_high)) as u64) << 32;
+            if features & FEATURE_DISCARD != 0 {
+                (capacity, field(addr_of!((*config).max_discard_sectors)), field(addr_of!((*config).discard_sector_alignment)))
+            } else {
+                (capacity, 0, 0)
             }
-            if dev_type == DeviceType::Block {
-                crate::println!("[blk] Initializing VirtIO Block...");
-                match VirtIOBlk::<HalImpl, _>::new(transport) {
-                    Ok(blk) => {
-                        crate::println!("[blk] Initialized. Capacity: {} sectors", blk.capacity());
-                        *BLK.lock() = Some(blk);
-                        return;
-                    }
-                    Err(e) => crate::println!("[blk] Failed to initialize: {:?}", e),
+        };
+        let queue = VirtQueue::new(&mut transport, 0)?;
+        transport.finish_init();
+        Ok(Self {
+            transport, queue, features, capacity,
+            max_discard: max_discard as usize,
+            discard_alignment: (discard_alignment as usize).max(1),
+        })
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn readonly(&self) -> bool {
+        self.features & FEATURE_RO != 0
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// The token of the next request the device finished, if any.
+    fn peek_used(&self) -> Option<u16> {
+        self.queue.peek_used()
+    }
+
+    /// Make request `req` of `type_` at `sector` available, with its
+    /// `payload`, and notify the device.
+    ///
+    /// # Safety
+    ///
+    /// Everything passed must be left alone until `complete` collected it.
+    unsafe fn submit(&mut self, req: &mut BlkReq, type_: u32, sector: u64, payload: Payload, resp: &mut BlkResp)
+        -> Result<u16, virtio_drivers::Error>
+    {
+        *req = BlkReq { type_, reserved: 0, sector };
+        resp.status = S_PENDING;
+        let token = with_buffers(req, payload, resp, |inputs, outputs| self.queue.add(inputs, outputs))?;
+        self.transport.notify(self.queue.index());
+        Ok(token)
+    }
+
+    /// Collect request `token`, made with `submit` and the same buffers,
+    /// and return what the device reported.
+    ///
+    /// # Safety
+    ///
+    /// As for `submit`.
+    unsafe fn complete(&mut self, token: u16, req: &BlkReq, payload: Payload, resp: &mut BlkResp)
+        -> Result<(), virtio_drivers::Error>
+    {
+        with_buffers(req, payload, resp, |inputs, outputs| self.queue.pop_used(token, inputs, outputs))?;
+        status(resp)
+    }
+}
+
+/// The data of a request, besides its header and status.
+enum Payload<'a> {
+    None,
+    /// Read by the device
+    ToDevice(&'a [u8]),
+    /// Written by the device
+    FromDevice(&'a mut [u8]),
+}
+
+/// Run `f` on the buffers of a request: those the device reads, and those
+/// it writes.
+fn with_buffers<R>(req: &BlkReq, payload: Payload, resp: &mut BlkResp,
+    f: impl FnOnce(&[&[u8]], &mut [&mut [u8]]) -> R) -> R
+{
+    // SAFETY: `BlkReq` has no padding
+    let header = unsafe { as_bytes(req) };
+    let status = core::slice::from_mut(&mut resp.status);
+    match payload {
+        Payload::None => f(&[header], &mut [status]),
+        Payload::ToDevice(data) => f(&[header, data], &mut [status]),
+        Payload::FromDevice(data) => f(&[header], &mut [data, status]),
+    }
+}
+
+/// A block device and the tasks waiting on it.
+struct Device {
+    /// Only locked with IRQs masked: the interrupt handler takes it too
+    blk: Mutex<Option<Blk>>,
+    /// GIC interrupt ID, or 0 while requests are polled
+    irq: AtomicU32,
+    /// Negotiated FLUSH, and whether its absence was reported
+    can_flush: AtomicBool,
+    warned: AtomicBool,
+    /// Offers DISCARD, and it hasn't turned out unusable
+    can_discard: AtomicBool,
+    /// Tasks waiting for a request to complete or a queue slot to free up
+    waiters: WaitQueue,
+}
+
+impl Device {
+    const fn new() -> Self {
+        Self {
+            blk: Mutex::new(None),
+            irq: AtomicU32::new(0),
+            can_flush: AtomicBool::new(false),
+            warned: AtomicBool::new(false),
+            can_discard: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Run `f` on the device with IRQs masked. `None` if there's no device.
+    fn with<R>(&self, f: impl FnOnce(&mut Blk) -> R) -> Option<R> {
+        let daif = cpu::save_and_disable_interrupts();
+        let result = self.blk.lock().as_mut().map(f);
+        cpu::restore_interrupts(daif);
+        result
+    }
+
+    /// Wait for the device to move on: sleep until its interrupt if it
+    /// has one and the scheduler runs, otherwise spin. IRQs must be masked
+    /// since checking what to wait for.
+    fn wait(&self) {
+        if self.irq.load(Ordering::Relaxed) != 0 && sched::is_enabled() {
+            self.waiters.sleep();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+static DEVICES: [Device; MAX_DEVICES] = [const { Device::new() }; MAX_DEVICES];
+/// Devices initialized, the first `COUNT` of `DEVICES`
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init() {
+    for device in virtio::devices_of(DeviceType::Block).into_iter().rev() {
+        let dev = COUNT.load(Ordering::Relaxed);
+        if dev == MAX_DEVICES {
+            crate::log_warn!("blk", "More than {} block devices, ignoring the one at {:#x}", MAX_DEVICES, device.base);
+            continue;
+        }
+        let Some(transport) = device.transport() else {
+            continue;
+        };
+        crate::log_info!("blk", "Initializing VirtIO Block at {:#x} as blk{}...", device.base, dev);
+        match Blk::new(transport) {
+            Ok(blk) => {
+                let (irq, features) = (device.irq, blk.features);
+                let can_flush = features & FEATURE_FLUSH != 0;
+                crate::log_info!("blk", "blk{}: Initialized. Capacity: {} sectors, IRQ {}{}", dev, blk.capacity(), irq,
+                    if features & FEATURE_DISCARD != 0 { ", discard" } else { "" });
+                *DEVICES[dev].blk.lock() = Some(blk);
+                DEVICES[dev].can_flush.store(can_flush, Ordering::Relaxed);
+                DEVICES[dev].can_discard.store(features & FEATURE_DISCARD != 0, Ordering::Relaxed);
+                if gic::register_handler(irq, irq_handler) && gic::enable_irq(irq) {
+                    DEVICES[dev].irq.store(irq, Ordering::Relaxed);
+                } else {
+                    crate::log_warn!("blk", "blk{}: IRQ {} unavailable, polling", dev, irq);
                 }
+                COUNT.store(dev + 1, Ordering::Relaxed);
+                block::register(&alloc::format!("blk{}", dev), alloc::sync::Arc::new(Disk(dev)));
             }
+            Err(e) => crate::println!("[blk] Failed to initialize: {:?}", e),
         }
     }
 }
 
-pub fn read_block(block_id: usize, buf: &mut [u8]) -> Result<(), ()> {
-    let mut blk_lock = BLK.lock();
-    if let Some(ref mut blk) = *blk_lock {
-        match blk.read_blocks(block_id, buf) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                crate::println!("[blk] Read error at {}: {:?}", block_id, e);
-                Err(())
+/// A block device finished requests: wake its waiters to collect them.
+fn irq_handler(irq: u32) {
+    for device in DEVICES.iter().take(count()) {
+        if device.irq.load(Ordering::Relaxed) == irq {
+            if let Some(blk) = device.blk.lock().as_mut() {
+                virtio::note_interrupt(irq, blk.ack_interrupt());
             }
+            device.waiters.wake_all();
         }
-    } else {
-        Err(())
     }
 }
 
-pub fn write_block(block_id: usize, buf: &[u8]) -> Result<(), ()> {
-    let mut blk_lock = BLK.lock();
-    if let Some(ref mut blk) = *blk_lock {
-        match blk.write_blocks(block_id, buf) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                crate::println!("[blk] Write error at {}: {:?}", block_id, e);
-                Err(())
+/// Number of block devices found.
+pub fn count() -> usize {
+    COUNT.load(Ordering::Relaxed)
+}
+
+/// Capacity of device `dev` in 512-byte sectors, or 0 if there is no
+/// such device.
+pub fn capacity(dev: usize) -> u64 {
+    DEVICES.get(dev).and_then(|d| d.with(|blk| blk.capacity())).unwrap_or(0)
+}
+
+/// Whether device `dev` refuses writes. `false` if there is no such
+/// device.
+pub fn readonly(dev: usize) -> bool {
+    DEVICES.get(dev).and_then(|d| d.with(|blk| blk.readonly())).unwrap_or(false)
+}
+
+/// What a driver error means for the block layer: only `IoError` (the
+/// device's own VIRTIO_BLK_S_IOERR) is worth retrying.
+fn classify(e: virtio_drivers::Error) -> BlockError {
+    use virtio_drivers::Error;
+    match e {
+        Error::NotReady | Error::ConfigSpaceMissing | Error::ConfigSpaceTooSmall => BlockError::NotReady,
+        Error::Unsupported | Error::InvalidParam => BlockError::Unsupported,
+        _ => BlockError::IoError,
+    }
+}
+
+/// Check that `count` sectors from `start` fit on device `dev`, before
+/// anything is submitted.
+fn in_range(dev: usize, start: usize, count: usize) -> Result<(), BlockError> {
+    match (start as u64).checked_add(count as u64) {
+        Some(end) if end <= capacity(dev) => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Submit a request to device `dev` with `submit`, wait for the device to
+/// finish it, and collect it with `complete`. A failure is logged as
+/// `what`.
+fn request(
+    dev: usize,
+    what: core::fmt::Arguments,
+    submit: impl Fn(&mut Blk, &mut BlkReq, &mut BlkResp) -> Result<u16, virtio_drivers::Error>,
+    complete: impl FnOnce(&mut Blk, u16, &BlkReq, &mut BlkResp) -> Result<(), virtio_drivers::Error>,
+) -> Result<(), BlockError> {
+    let device = DEVICES.get(dev).filter(|_| dev < count()).ok_or(BlockError::NotReady)?;
+    let (mut req, mut resp) = (BlkReq::default(), BlkResp::default());
+    let fail = |e| {
+        crate::log_error!("blk", "blk{}: {} error: {:?}", dev, what, e);
+        classify(e)
+    };
+
+    sched::lock_taken();
+    let daif = cpu::save_and_disable_interrupts();
+    let result = loop {
+        let submitted = device.blk.lock().as_mut().map(|blk| submit(blk, &mut req, &mut resp));
+        match submitted {
+            Some(Ok(token)) => break Ok(token),
+            // Every descriptor is in use: wait for a request to finish
+            Some(Err(virtio_drivers::Error::QueueFull)) => device.wait(),
+            Some(Err(e)) => break Err(fail(e)),
+            None => break Err(BlockError::NotReady),
+        }
+    };
+    let result = result.and_then(|token| {
+        let mut complete = Some(complete);
+        loop {
+            let mut guard = device.blk.lock();
+            let blk = guard.as_mut().ok_or(BlockError::NotReady)?;
+            // Used requests are collected in the order the device finished
+            // them, so only the one at the head can be
+            if blk.peek_used() == Some(token) {
+                let done = complete.take().map(|complete| complete(blk, token, &req, &mut resp));
+                break done.unwrap_or(Ok(())).map_err(fail);
             }
+            drop(guard);
+            device.wait();
+        }
+    });
+    cpu::restore_interrupts(daif);
+    sched::lock_released();
+    // The next request in the used ring may belong to another task
+    device.waiters.wake_all();
+    result
+}
+
+/// Most sectors in one request
+const MAX_RUN: usize = 256;
+
+/// Read `count` sectors from device `dev` into `buf` (`count` * 512
+/// bytes), from `start` on, in as few requests as the buffer allows: runs
+/// of up to `MAX_RUN` sectors, split where the buffer isn't physically
+/// contiguous. A sector straddling such a split goes through a bounce
+/// buffer.
+pub fn read_blocks(dev: usize, start: usize, count: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+    assert_eq!(buf.len(), count * SECTOR_SIZE, "read_blocks: buffer isn't {} sectors", count);
+    in_range(dev, start, count)?;
+    let mut done = 0;
+    while done < count {
+        let rest = &mut buf[done * SECTOR_SIZE..];
+        let reachable = virtio::contiguous_len(rest.as_ptr() as usize, rest.len()) / SECTOR_SIZE;
+        let run = reachable.min(MAX_RUN);
+        if run == 0 {
+            let mut bounce = alloc::boxed::Box::new([0u8; SECTOR_SIZE]);
+            request_read(dev, start + done, &mut bounce[..])?;
+            rest[..SECTOR_SIZE].copy_from_slice(&bounce[..]);
+            done += 1;
+        } else {
+            request_read(dev, start + done, &mut rest[..run * SECTOR_SIZE])?;
+            done += run;
+        }
+    }
+    Ok(())
+}
+
+/// One read request for `buf.len() / 512` sectors into `buf`, which must
+/// be physically contiguous.
+fn request_read(dev: usize, block_id: usize, buf: &mut [u8]) -> Result<(), BlockError> {
+    let buf = NonNull::from(buf);
+    // SAFETY: `request` doesn't return until the device is done with the
+    // buffer, and only one of the two closures runs at a time
+    request(dev, format_args!("Read at {}", block_id),
+        |blk, req, resp| unsafe { blk.submit(req, T_IN, block_id as u64, Payload::FromDevice(&mut *buf.as_ptr()), resp) },
+        |blk, token, req, resp| unsafe { blk.complete(token, req, Payload::FromDevice(&mut *buf.as_ptr()), resp) })
+}
+
+/// Write `buf` to device `dev`, from sector `block_id` on.
+pub fn write_block(dev: usize, block_id: usize, buf: &[u8]) -> Result<(), BlockError> {
+    in_range(dev, block_id, buf.len() / SECTOR_SIZE)?;
+    // Kept in one piece for the device
+    if virtio::contiguous_len(buf.as_ptr() as usize, buf.len()) < buf.len() {
+        return write_block(dev, block_id, &alloc::vec::Vec::from(buf));
+    }
+    request(dev, format_args!("Write at {}", block_id),
+        |blk, req, resp| unsafe { blk.submit(req, T_OUT, block_id as u64, Payload::ToDevice(buf), resp) },
+        |blk, token, req, resp| unsafe { blk.complete(token, req, Payload::ToDevice(buf), resp) })
+}
+
+/// Ask device `dev` to commit written blocks to stable storage, with a
+/// FLUSH request. A no-op, with a warning the first time, if the device
+/// doesn't take FLUSH.
+pub fn flush(dev: usize) -> Result<(), BlockError> {
+    let device = DEVICES.get(dev).filter(|_| dev < count()).ok_or(BlockError::NotReady)?;
+    if !device.can_flush.load(Ordering::Relaxed) {
+        if !device.warned.swap(true, Ordering::Relaxed) {
+            crate::log_warn!("blk", "blk{}: No FLUSH support, written data may still be in the host's cache", dev);
+        }
+        return Ok(());
+    }
+    // Writes completed before it are covered; those still in flight may not be
+    request(dev, format_args!("Flush"),
+        |blk, req, resp| unsafe { blk.submit(req, T_FLUSH, 0, Payload::None, resp) },
+        |blk, token, req, resp| unsafe { blk.complete(token, req, Payload::None, resp) })
+}
+
+/// Whether device `dev` takes DISCARD, as far as anyone knows yet.
+pub fn can_discard(dev: usize) -> bool {
+    DEVICES.get(dev).is_some_and(|d| d.can_discard.load(Ordering::Relaxed))
+}
+
+/// Discard `sectors` sectors of device `dev` from `start` on: the whole
+/// units of the device's discard alignment inside the range, in requests
+/// no bigger than it takes. Does nothing on a device without DISCARD, and
+/// stops sending it to one that turns a request down.
+pub fn discard(dev: usize, start: usize, sectors: usize) -> Result<(), BlockError> {
+    let device = DEVICES.get(dev).filter(|_| dev < count()).ok_or(BlockError::NotReady)?;
+    in_range(dev, start, sectors)?;
+    if !device.can_discard.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let (max, align) = device.with(|blk| (blk.max_discard, blk.discard_alignment)).ok_or(BlockError::NotReady)?;
+    let (mut at, end) = (start.next_multiple_of(align), (start + sectors) / align * align);
+    while at < end {
+        let count = (end - at).min(max / align * align);
+        if count == 0 {
+            break;
+        }
+        let range = DiscardRange { sector: at as u64, num_sectors: count as u32, flags: 0 };
+        // SAFETY: `DiscardRange` has no padding
+        let data = unsafe { as_bytes(&range) };
+        let result = request(dev, format_args!("Discard at {}", at),
+            |blk, req, resp| unsafe { blk.submit(req, T_DISCARD, 0, Payload::ToDevice(data), resp) },
+            |blk, token, req, resp| unsafe { blk.complete(token, req, Payload::ToDevice(data), resp) });
+        if result == Err(BlockError::Unsupported) {
+            device.can_discard.store(false, Ordering::Relaxed);
         }
-    } else {
-        Err(())
+        result?;
+        at += count;
+    }
+    Ok(())
+}
+
+/// Device `dev` as seen by the block layer.
+struct Disk(usize);
+
+impl BlockDevice for Disk {
+    fn num_blocks(&self) -> u64 {
+        capacity(self.0)
+    }
+
+    fn read_blocks(&self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        read_blocks(self.0, start as usize, buf.len() / SECTOR_SIZE, buf)
+    }
+
+    fn write_blocks(&self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        write_block(self.0, start as usize, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        flush(self.0)
+    }
+
+    fn readonly(&self) -> bool {
+        readonly(self.0)
+    }
+
+    fn discard(&self, start: u64, count: u64) -> Result<(), BlockError> {
+        discard(self.0, start as usize, count as usize)
+    }
+
+    fn can_discard(&self) -> bool {
+        can_discard(self.0)
+    }
+}
+
+/// Sectors the benchmark reader has read so far
+static BENCH_SECTORS: AtomicUsize = AtomicUsize::new(0);
+/// Sectors the benchmark reader is to read from blk0
+static BENCH_TARGET: AtomicUsize = AtomicUsize::new(0);
+
+/// Read `BENCH_TARGET` sectors of blk0 straight from the device, 4 KB at
+/// a time, wrapping around at its end.
+extern "C" fn bench_reader() {
+    let mut buf = [0u8; 4096];
+    let sectors = (capacity(0) as usize / 8 * 8).max(8);
+    while BENCH_SECTORS.load(Ordering::Relaxed) < BENCH_TARGET.load(Ordering::Relaxed) {
+        let at = BENCH_SECTORS.load(Ordering::Relaxed) % sectors;
+        if read_blocks(0, at, 8, &mut buf).is_err() {
+            break;
+        }
+        BENCH_SECTORS.fetch_add(8, Ordering::Relaxed);
+    }
+    sched::exit_current_task();
+}
+
+/// Read `mb` megabytes from blk0, bypassing the block cache, from a
+/// background task while this one keeps yielding, and print the longest
+/// this one went without the CPU: how responsive the shell stays while
+/// the disk is busy.
+pub fn responsiveness_bench(mb: usize) {
+    use aprk_arch_arm64::timer::Timer;
+    if count() == 0 {
+        crate::println!("No block device");
+        return;
+    }
+    BENCH_SECTORS.store(0, Ordering::Relaxed);
+    BENCH_TARGET.store(mb * 2048, Ordering::Relaxed);
+    let start = Timer::counter();
+    let Some(pid) = sched::spawn_named(bench_reader, "blkbench", sched::Priority::Normal) else {
+        crate::println!("Couldn't start the reader task");
+        return;
+    };
+    let (mut last, mut longest, mut turns) = (Timer::counter(), 0, 0u64);
+    while sched::is_alive(pid) {
+        sched::schedule();
+        let now = Timer::counter();
+        longest = longest.max(now - last);
+        last = now;
+        turns += 1;
     }
+    sched::wait_for_exit(pid);
+    let elapsed = Timer::ticks_to_duration(Timer::counter() - start);
+    let kb = BENCH_SECTORS.load(Ordering::Relaxed) / 2;
+    crate::println!("Read {} KB in {} ms ({} KB/s), irq {}", kb, elapsed.as_millis(),
+        kb as u128 * 1000 / elapsed.as_millis().max(1),
+        if DEVICES[0].irq.load(Ordering::Relaxed) != 0 { "on" } else { "off (polling)" });
+    crate::println!("Shell ran {} times meanwhile, longest wait {} us", turns,
+        Timer::ticks_to_duration(longest).as_micros());
 }
@@ -0,0 +1,143 @@
+// =============================================================================
+// APRK OS - VirtIO Console
+// =============================================================================
+// The first virtio-console device (QEMU's virtio-serial-device with a
+// virtconsole port), as a second way to reach the kernel console. Only
+// port 0 is used: one receive and one transmit queue.
+//
+// Everything typed on it goes into the console UART port's input, so the
+// shell's line discipline (echo, editing, Ctrl-C) treats both alike.
+// Kernel output goes to it as well once it's selected as a console sink,
+// with `console=virtio` on the kernel command line or `console virtio` in
+// the shell.
+// =============================================================================
+
+use virtio_drivers::{
+    transport::{mmio::MmioTransport, DeviceType},
+    device::console::VirtIOConsole,
+};
+use crate::drivers::virtio::{self, HalImpl};
+use aprk_arch_arm64::console::ConsoleSink;
+use aprk_arch_arm64::{dtb, gic, uart};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+type Console = VirtIOConsole<HalImpl, MmioTransport>;
+
+/// Only locked with IRQs masked: the interrupt handler takes it too
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+/// Kernel output goes to the device
+static SELECTED: AtomicBool = AtomicBool::new(false);
+/// Whether the sink is in the console's sink table
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// The device as a console sink, passing output on while it's selected.
+struct Sink;
+
+static SINK: Sink = Sink;
+
+impl ConsoleSink for Sink {
+    fn name(&self) -> &str {
+        "virtio-console"
+    }
+
+    fn write_str(&self, s: &str) -> fmt::Result {
+        if !SELECTED.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        // Never wait: the lock may be held by the code this interrupted
+        let Some(mut guard) = CONSOLE.try_lock() else {
+            return Err(fmt::Error);
+        };
+        let console = guard.as_mut().ok_or(fmt::Error)?;
+        for c in s.bytes() {
+            if c == b'\n' {
+                console.send(b'\r').map_err(|_| fmt::Error)?;
+            }
+            console.send(c).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn init() {
+    let Some(device) = virtio::devices_of(DeviceType::Console).into_iter().next() else {
+        return;
+    };
+    let Some(transport) = device.transport() else {
+        return;
+    };
+    let console = match Console::new(transport) {
+        Ok(console) => console,
+        Err(e) => {
+            crate::log_error!("vcon", "Failed to initialize virtio-console: {:?}", e);
+            return;
+        }
+    };
+    *CONSOLE.lock() = Some(console);
+    if !gic::register_handler(device.irq, irq_handler) || !gic::enable_irq(device.irq) {
+        crate::log_warn!("vcon", "IRQ {} unavailable, no input", device.irq);
+    }
+    if !aprk_arch_arm64::console::register(&SINK) {
+        crate::log_warn!("vcon", "Console sink table full, no output");
+    } else {
+        REGISTERED.store(true, Ordering::Relaxed);
+    }
+    let selected = dtb::get()
+        .and_then(|dtb| dtb.find_path("/chosen"))
+        .and_then(|chosen| chosen.prop_str("bootargs"))
+        .is_some_and(|args| args.split_whitespace().any(|arg| arg == "console=virtio"));
+    SELECTED.store(selected, Ordering::Relaxed);
+    crate::log_info!("vcon", "virtio-console at {:#x}, IRQ {}{}", device.base, device.irq,
+        if selected { ", kernel console" } else { "" });
+}
+
+/// Whether there's a virtio console.
+pub fn is_present() -> bool {
+    REGISTERED.load(Ordering::Relaxed)
+}
+
+/// Send kernel output to the virtio console too, or stop.
+pub fn select(on: bool) -> bool {
+    if !is_present() {
+        return false;
+    }
+    SELECTED.store(on, Ordering::Relaxed);
+    true
+}
+
+/// Whether kernel output goes to the virtio console.
+pub fn is_selected() -> bool {
+    is_present() && SELECTED.load(Ordering::Relaxed)
+}
+
+/// Input arrived: pass it on as console input.
+fn irq_handler(irq: u32) {
+    let mut acked = false;
+    loop {
+        let mut input = [0u8; 64];
+        let mut len = 0;
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            if !acked {
+                virtio::note_interrupt(irq, console.ack_interrupt().unwrap_or(false));
+                acked = true;
+            }
+            while len < input.len() {
+                match console.recv(true) {
+                    Ok(Some(c)) => {
+                        input[len] = c;
+                        len += 1;
+                    }
+                    _ => break,
+                }
+            }
+        }
+        if len > 0 {
+            uart::inject_input(uart::console_port(), &input[..len]);
+        }
+        if len < input.len() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,185 @@
+// =============================================================================
+// APRK OS - VirtIO Pointing Devices
+// =============================================================================
+// virtio-input devices that move a pointer: tablets (absolute X/Y, as
+// QEMU's virtio-tablet-device) and mice (relative X/Y). Other input
+// devices, keyboards among them, are left alone.
+//
+// A device sends Linux input events: axis and button changes, then a
+// SYN_REPORT closing the report. The interrupt handler collects the
+// changes and hands each complete report to `crate::input`.
+// =============================================================================
+
+use virtio_drivers::{
+    transport::{mmio::MmioTransport, DeviceType},
+    device::input::{InputConfigSelect, VirtIOInput},
+};
+use crate::drivers::virtio::{self, HalImpl};
+use crate::input::{self, Motion, BUTTON_LEFT, BUTTON_MIDDLE, BUTTON_RIGHT};
+use aprk_arch_arm64::gic;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Most pointing devices used
+const MAX_DEVICES: usize = 2;
+
+/// Event types and codes (linux/input-event-codes.h)
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const SYN_REPORT: u16 = 0;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+
+type Input = VirtIOInput<HalImpl, MmioTransport>;
+
+/// A pointing device and the report it's sending.
+struct Pointer {
+    input: Input,
+    /// Range of each absolute axis (X, Y), `None` for a mouse
+    abs_range: Option<[(u32, u32); 2]>,
+    /// Changes since the last report
+    dx: i32,
+    dy: i32,
+    abs: [Option<u32>; 2],
+    /// Last absolute position, for reports moving one axis only
+    last_abs: [u32; 2],
+    buttons: u8,
+}
+
+impl Pointer {
+    /// Take one event. Returns the report it completes, if it's a
+    /// SYN_REPORT.
+    fn event(&mut self, event_type: u16, code: u16, value: u32) -> Option<(Motion, u8)> {
+        match (event_type, code) {
+            (EV_REL, REL_X) => self.dx += value as i32,
+            (EV_REL, REL_Y) => self.dy += value as i32,
+            (EV_ABS, ABS_X) => self.abs[0] = Some(value),
+            (EV_ABS, ABS_Y) => self.abs[1] = Some(value),
+            (EV_KEY, BTN_LEFT | BTN_RIGHT | BTN_MIDDLE) => {
+                let bit = match code {
+                    BTN_LEFT => BUTTON_LEFT,
+                    BTN_RIGHT => BUTTON_RIGHT,
+                    _ => BUTTON_MIDDLE,
+                };
+                if value != 0 { self.buttons |= bit } else { self.buttons &= !bit }
+            }
+            (EV_SYN, SYN_REPORT) => return Some((self.take_motion(), self.buttons)),
+            _ => {}
+        }
+        None
+    }
+
+    /// The motion collected since the last report.
+    fn take_motion(&mut self) -> Motion {
+        if let Some(range) = self.abs_range {
+            if self.abs == [None, None] {
+                return Motion::None;
+            }
+            for axis in 0..2 {
+                if let Some(value) = self.abs[axis].take() {
+                    self.last_abs[axis] = value;
+                }
+            }
+            let [(min_x, max_x), (min_y, max_y)] = range;
+            let [x, y] = self.last_abs;
+            return Motion::Absolute {
+                x: x.saturating_sub(min_x),
+                max_x: max_x.saturating_sub(min_x),
+                y: y.saturating_sub(min_y),
+                max_y: max_y.saturating_sub(min_y),
+            };
+        }
+        let (dx, dy) = (core::mem::take(&mut self.dx), core::mem::take(&mut self.dy));
+        if (dx, dy) == (0, 0) { Motion::None } else { Motion::Relative(dx, dy) }
+    }
+}
+
+/// Only locked from the interrupt handler, or with IRQs masked
+static DEVICES: [Mutex<Option<Pointer>>; MAX_DEVICES] = [const { Mutex::new(None) }; MAX_DEVICES];
+/// GIC interrupt ID of each device
+static IRQS: [AtomicU32; MAX_DEVICES] = [const { AtomicU32::new(0) }; MAX_DEVICES];
+/// Devices initialized, the first `COUNT` of `DEVICES`
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Pointing devices found.
+pub fn count() -> usize {
+    COUNT.load(Ordering::Relaxed)
+}
+
+/// Range of absolute axis `axis` of `input`: (min, max).
+fn abs_range(input: &mut Input, axis: u16) -> (u32, u32) {
+    // struct virtio_input_absinfo: min, max, fuzz, flat, res
+    let mut info = [0u8; 20];
+    input.query_config_select(InputConfigSelect::AbsInfo, axis as u8, &mut info);
+    let min = u32::from_le_bytes(info[0..4].try_into().unwrap());
+    let max = u32::from_le_bytes(info[4..8].try_into().unwrap());
+    (min, max)
+}
+
+pub fn init() {
+    for device in virtio::devices_of(DeviceType::Input) {
+        let Some(transport) = device.transport() else {
+            continue;
+        };
+        let mut input = match Input::new(transport) {
+            Ok(input) => input,
+            Err(e) => {
+                crate::log_error!("input", "Failed to initialize virtio-input at {:#x}: {:?}", device.base, e);
+                continue;
+            }
+        };
+        let mut name = [0u8; 64];
+        let len = input.query_config_select(InputConfigSelect::IdName, 0, &mut name) as usize;
+        let name = core::str::from_utf8(&name[..len.min(name.len())]).unwrap_or("?");
+        let mut bits = [0u8; 128];
+        let relative = input.query_config_select(InputConfigSelect::EvBits, EV_REL as u8, &mut bits) > 0;
+        let absolute = input.query_config_select(InputConfigSelect::EvBits, EV_ABS as u8, &mut bits) > 0;
+        if !relative && !absolute {
+            crate::log_info!("input", "{} at {:#x}: not a pointing device, ignored", name, device.base);
+            continue;
+        }
+        let dev = count();
+        if dev == MAX_DEVICES {
+            crate::log_warn!("input", "More than {} pointing devices, ignoring {}", MAX_DEVICES, name);
+            continue;
+        }
+        let abs_range = absolute.then(|| [abs_range(&mut input, ABS_X), abs_range(&mut input, ABS_Y)]);
+        crate::log_info!("input", "{} at {:#x}: {} pointer, IRQ {}", name, device.base,
+            if absolute { "absolute" } else { "relative" }, device.irq);
+        *DEVICES[dev].lock() = Some(Pointer { input, abs_range, dx: 0, dy: 0, abs: [None; 2], last_abs: [0; 2], buttons: 0 });
+        if gic::register_handler(device.irq, irq_handler) && gic::enable_irq(device.irq) {
+            IRQS[dev].store(device.irq, Ordering::Relaxed);
+        } else {
+            crate::log_warn!("input", "{}: IRQ {} unavailable, ignored", name, device.irq);
+            *DEVICES[dev].lock() = None;
+            continue;
+        }
+        COUNT.store(dev + 1, Ordering::Relaxed);
+    }
+}
+
+/// A pointing device has events: pass on the reports they complete.
+fn irq_handler(irq: u32) {
+    for (dev, device) in DEVICES.iter().enumerate().take(count()) {
+        if IRQS[dev].load(Ordering::Relaxed) != irq {
+            continue;
+        }
+        let mut guard = device.lock();
+        let Some(pointer) = guard.as_mut() else {
+            continue;
+        };
+        virtio::note_interrupt(irq, pointer.input.ack_interrupt());
+        while let Some(event) = pointer.input.pop_pending_event() {
+            if let Some((motion, buttons)) = pointer.event(event.event_type, event.code, event.value) {
+                input::report(motion, buttons);
+            }
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// =============================================================================
+// APRK OS - VirtIO Network Device
+// =============================================================================
+// The first virtio-net NIC found on the MMIO bus, as raw Ethernet frames
+// for the network stack (`crate::net`).
+//
+// Receiving is interrupt-driven: the device's interrupt moves every frame
+// it has received into a queue of copies and gives its buffers straight
+// back, then wakes the network task, which takes frames off the queue
+// with `receive`. Frames arriving while the queue is full are dropped and
+// counted. Without the interrupt, `receive` checks the device itself
+// every time the task runs.
+//
+// Sending copies the frame into a transmit buffer and waits for the device
+// to take it; a frame is at most a few microseconds' work for QEMU.
+// =============================================================================
+
+use virtio_drivers::{
+    transport::{mmio::MmioTransport, DeviceType},
+    device::net::VirtIONet,
+};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::drivers::virtio::{self, HalImpl};
+use crate::mm::quota::with_task_charge;
+use crate::net::{MacAddr, NetError};
+use crate::sched::{self, wait::WaitQueue};
+use aprk_arch_arm64::{cpu, gic};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Buffers in each of the receive and transmit queues
+const QUEUE_SIZE: usize = 16;
+/// Size of a receive buffer: a full Ethernet frame and the virtio-net header
+const BUF_LEN: usize = 2048;
+/// Most frames waiting for the network task before new ones are dropped
+const RX_QUEUE_LEN: usize = 64;
+
+type Net = VirtIONet<HalImpl, MmioTransport, QUEUE_SIZE>;
+
+/// Only locked with IRQs masked: the interrupt handler takes it too
+static NET: Mutex<Option<Net>> = Mutex::new(None);
+static MAC: Mutex<Option<MacAddr>> = Mutex::new(None);
+/// GIC interrupt ID, or 0 while the device is polled
+static IRQ: AtomicU32 = AtomicU32::new(0);
+/// Frames received and not yet taken, locked with IRQs masked too
+static RX_QUEUE: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new());
+/// The network task sleeps here while `RX_QUEUE` is empty
+static RX_WAIT: WaitQueue = WaitQueue::new();
+
+static RX_FRAMES: AtomicU64 = AtomicU64::new(0);
+static TX_FRAMES: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Frame counters of the NIC.
+pub struct NetStats {
+    pub rx_frames: u64,
+    pub tx_frames: u64,
+    /// Received while the queue was full
+    pub dropped: u64,
+    /// Whether frames come in by interrupt (otherwise polled)
+    pub irq: bool,
+}
+
+pub fn init() {
+    let Some(device) = virtio::devices_of(DeviceType::Network).into_iter().next() else {
+        return;
+    };
+    let Some(transport) = device.transport() else {
+        return;
+    };
+    match Net::new(transport, BUF_LEN) {
+        Ok(mut net) => {
+            let mac = MacAddr(net.mac_address());
+            crate::log_info!("net", "virtio-net at {:#x}: MAC {}, IRQ {}", device.base, mac, device.irq);
+            net.enable_interrupts();
+            *NET.lock() = Some(net);
+            *MAC.lock() = Some(mac);
+            if gic::register_handler(device.irq, irq_handler) && gic::enable_irq(device.irq) {
+                IRQ.store(device.irq, Ordering::Relaxed);
+            } else {
+                crate::log_warn!("net", "IRQ {} unavailable, polling", device.irq);
+            }
+        }
+        Err(e) => crate::log_error!("net", "Failed to initialize virtio-net: {:?}", e),
+    }
+}
+
+/// The NIC's MAC address, if there's a NIC.
+pub fn mac() -> Option<MacAddr> {
+    *MAC.lock()
+}
+
+pub fn stats() -> NetStats {
+    NetStats {
+        rx_frames: RX_FRAMES.load(Ordering::Relaxed),
+        tx_frames: TX_FRAMES.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        irq: IRQ.load(Ordering::Relaxed) != 0,
+    }
+}
+
+/// Copy every frame the device has received onto `RX_QUEUE` and give it
+/// its buffers back. IRQs must be masked. Returns whether any came in.
+///
+/// The copies are charged to nobody: this runs in whichever task the
+/// interrupt landed on, and the network task frees them.
+fn drain(net: &mut Net) -> bool {
+    with_task_charge(None, || drain_uncharged(net))
+}
+
+fn drain_uncharged(net: &mut Net) -> bool {
+    let mut received = false;
+    while net.can_recv() {
+        let Ok(rx) = net.receive() else {
+            break;
+        };
+        let mut queue = RX_QUEUE.lock();
+        if queue.len() < RX_QUEUE_LEN {
+            queue.push_back(rx.packet().to_vec());
+            RX_FRAMES.fetch_add(1, Ordering::Relaxed);
+        } else {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        drop(queue);
+        let _ = net.recycle_rx_buffer(rx);
+        received = true;
+    }
+    received
+}
+
+/// The NIC received frames (or finished sending): queue them and wake
+/// the network task.
+fn irq_handler(irq: u32) {
+    let received = match NET.lock().as_mut() {
+        Some(net) => {
+            virtio::note_interrupt(irq, net.ack_interrupt());
+            drain(net)
+        }
+        None => false,
+    };
+    if received {
+        RX_WAIT.wake_all();
+    }
+}
+
+/// The next frame received, sleeping until there is one.
+pub fn receive() -> Vec<u8> {
+    loop {
+        let polled = IRQ.load(Ordering::Relaxed) == 0 || !sched::is_enabled();
+        let daif = cpu::save_and_disable_interrupts();
+        if polled {
+            if let Some(net) = NET.lock().as_mut() {
+                drain(net);
+            }
+        }
+        let frame = RX_QUEUE.lock().pop_front();
+        if let Some(frame) = frame {
+            cpu::restore_interrupts(daif);
+            return frame;
+        }
+        if !polled {
+            RX_WAIT.sleep();
+        }
+        cpu::restore_interrupts(daif);
+        if polled {
+            sched::schedule();
+        }
+    }
+}
+
+/// Send one Ethernet frame.
+pub fn send(frame: &[u8]) -> Result<(), NetError> {
+    if frame.len() > BUF_LEN {
+        return Err(NetError::TooBig);
+    }
+    let daif = cpu::save_and_disable_interrupts();
+    let result = match NET.lock().as_mut() {
+        None => Err(NetError::NoDevice),
+        Some(net) if !net.can_send() => Err(NetError::Busy),
+        Some(net) => {
+            let mut tx = net.new_tx_buffer(frame.len());
+            tx.packet_mut().copy_from_slice(frame);
+            net.send(tx).map_err(|e| {
+                crate::log_warn!("net", "Sending a frame failed: {:?}", e);
+                NetError::Device
+            })
+        }
+    };
+    cpu::restore_interrupts(daif);
+    if result.is_ok() {
+        TX_FRAMES.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
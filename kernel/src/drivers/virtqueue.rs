@@ -0,0 +1,223 @@
+// =============================================================================
+// APRK OS - VirtIO Split Virtqueue
+// =============================================================================
+// A split virtqueue (virtio 1.2, 2.7) for drivers that build their own
+// requests on a transport, where virtio-drivers' device types only send
+// the ones they know about.
+//
+// The descriptor table, available ring and used ring share DMA pages, laid
+// out as legacy transports require: the used ring starts on the page after
+// the other two. A request is a chain of descriptors, the buffers the
+// device reads first and then those it writes, each shared through the
+// HAL while the device has it. Chains are collected in the order the
+// device used them: only the one at the head of the used ring can be.
+//
+// Neither indirect descriptors nor event suppression is negotiated: every
+// request is notified, and every used buffer raises an interrupt.
+// =============================================================================
+
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut, NonNull};
+use core::sync::atomic::{fence, Ordering};
+use virtio_drivers::{BufferDirection, Error, Hal, PhysAddr, Result};
+use virtio_drivers::transport::Transport;
+use crate::drivers::virtio::HalImpl;
+use crate::mm::pmm::PAGE_SIZE;
+
+/// Descriptors in a queue
+pub const QUEUE_SIZE: usize = 16;
+/// Descriptor flags: chained to `next`, written by the device
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+/// Bytes of the descriptor table, which the available ring follows
+const DESC_BYTES: usize = size_of::<[Descriptor; QUEUE_SIZE]>();
+/// DMA pages of a queue: the descriptors and available ring, then the used
+/// ring
+const PAGES: usize = 2;
+const _: () = assert!(DESC_BYTES + size_of::<AvailRing>() <= PAGE_SIZE && size_of::<UsedRing>() <= PAGE_SIZE);
+
+/// One virtqueue of a device. Lives as long as the device: its pages are
+/// never given back.
+pub struct VirtQueue {
+    index: u16,
+    desc: NonNull<Descriptor>,
+    avail: NonNull<AvailRing>,
+    used: NonNull<UsedRing>,
+    /// First unused descriptor, the rest linked through `next`
+    free_head: u16,
+    num_free: usize,
+    /// Available ring entries made so far, used ring entries collected
+    avail_idx: u16,
+    last_used: u16,
+}
+
+// SAFETY: the rings are DMA memory nothing but this queue and its device
+// touches
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// Set up queue `index` of the device on `transport`, which must be
+    /// between feature negotiation and DRIVER_OK.
+    pub fn new<T: Transport>(transport: &mut T, index: u16) -> Result<Self> {
+        if transport.queue_used(index) {
+            return Err(Error::AlreadyUsed);
+        }
+        if (transport.max_queue_size(index) as usize) < QUEUE_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        // Zeroed: empty rings, with interrupts wanted
+        let (paddr, vaddr) = HalImpl::dma_alloc(PAGES, BufferDirection::Both);
+        let desc = vaddr.cast::<Descriptor>();
+        // SAFETY: both inside the pages just allocated
+        let (avail, used) = unsafe { (vaddr.add(DESC_BYTES).cast(), vaddr.add(PAGE_SIZE).cast()) };
+        let mut queue = Self { index, desc, avail, used, free_head: 0, num_free: QUEUE_SIZE, avail_idx: 0, last_used: 0 };
+        for i in 0..QUEUE_SIZE - 1 {
+            queue.desc_mut(i as u16).next = i as u16 + 1;
+        }
+        transport.queue_set(index, QUEUE_SIZE as u32, paddr, paddr + DESC_BYTES, paddr + PAGE_SIZE);
+        Ok(queue)
+    }
+
+    /// The queue's index on its device.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    fn desc_mut(&mut self, i: u16) -> &mut Descriptor {
+        assert!((i as usize) < QUEUE_SIZE);
+        // SAFETY: inside the table, which only the device reads otherwise
+        unsafe { &mut *self.desc.as_ptr().add(i as usize) }
+    }
+
+    /// Run `f` on each buffer of a request, in chain order, with the way its
+    /// data goes.
+    fn for_each_buffer(inputs: &[&[u8]], outputs: &mut [&mut [u8]], mut f: impl FnMut(NonNull<[u8]>, BufferDirection)) {
+        for buf in inputs {
+            f(NonNull::from(*buf), BufferDirection::DriverToDevice);
+        }
+        for buf in outputs {
+            f(NonNull::from(&mut **buf), BufferDirection::DeviceToDriver);
+        }
+    }
+
+    /// Chain `inputs`, for the device to read, and `outputs`, for it to
+    /// write, and make them available. Returns the token to collect them
+    /// with; `QueueFull` if there aren't enough free descriptors. The caller
+    /// notifies the device.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must be left alone until `pop_used` has collected them.
+    pub unsafe fn add(&mut self, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Result<u16> {
+        let count = inputs.len() + outputs.len();
+        if count == 0 {
+            return Err(Error::InvalidParam);
+        }
+        if count > self.num_free {
+            return Err(Error::QueueFull);
+        }
+        let head = self.free_head;
+        let (mut at, mut last) = (head, head);
+        Self::for_each_buffer(inputs, outputs, |buf, direction| {
+            let desc = self.desc_mut(at);
+            desc.addr = HalImpl::share(buf, direction) as u64;
+            desc.len = buf.len() as u32;
+            desc.flags = DESC_F_NEXT | if direction == BufferDirection::DeviceToDriver { DESC_F_WRITE } else { 0 };
+            last = at;
+            at = desc.next;
+        });
+        self.desc_mut(last).flags &= !DESC_F_NEXT;
+        self.free_head = at;
+        self.num_free -= count;
+
+        let avail = self.avail.as_ptr();
+        addr_of_mut!((*avail).ring[self.avail_idx as usize % QUEUE_SIZE]).write_volatile(head);
+        // The chain and ring entry before the index that publishes them
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        addr_of_mut!((*avail).idx).write_volatile(self.avail_idx);
+        // And the index before the notification
+        fence(Ordering::SeqCst);
+        Ok(head)
+    }
+
+    /// The token of the request at the head of the used ring, if the device
+    /// is done with one.
+    pub fn peek_used(&self) -> Option<u16> {
+        let used = self.used.as_ptr();
+        // SAFETY: inside the used ring; the device writes it, hence volatile
+        unsafe {
+            if addr_of!((*used).idx).read_volatile() == self.last_used {
+                return None;
+            }
+            fence(Ordering::SeqCst);
+            Some(addr_of!((*used).ring[self.last_used as usize % QUEUE_SIZE].id).read_volatile() as u16)
+        }
+    }
+
+    /// Collect request `token`, at the head of the used ring, freeing its
+    /// descriptors. Returns the bytes the device wrote; `NotReady` if it
+    /// isn't done, `WrongToken` if another request is ahead of it.
+    ///
+    /// # Safety
+    ///
+    /// `inputs` and `outputs` must be the buffers the request was added
+    /// with.
+    pub unsafe fn pop_used(&mut self, token: u16, inputs: &[&[u8]], outputs: &mut [&mut [u8]]) -> Result<u32> {
+        match self.peek_used() {
+            Some(head) if head == token => {}
+            Some(_) => return Err(Error::WrongToken),
+            None => return Err(Error::NotReady),
+        }
+        let slot = self.last_used as usize % QUEUE_SIZE;
+        let len = addr_of!((*self.used.as_ptr()).ring[slot].len).read_volatile();
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let (mut at, mut last, mut count) = (token, token, 0);
+        Self::for_each_buffer(inputs, outputs, |buf, direction| {
+            let desc = self.desc_mut(at);
+            HalImpl::unshare(desc.addr as PhysAddr, buf, direction);
+            (desc.addr, desc.len, desc.flags) = (0, 0, 0);
+            last = at;
+            at = desc.next;
+            count += 1;
+        });
+        // The chain is still linked through `next`: put it back in front
+        let free_head = self.free_head;
+        self.desc_mut(last).next = free_head;
+        self.free_head = token;
+        self.num_free += count;
+        Ok(len)
+    }
+}
@@ -0,0 +1,303 @@
+// =============================================================================
+// APRK OS - File Descriptors
+// =============================================================================
+// Per-task file descriptor tables. Each task starts with stdin, stdout and
+// stderr open on its controlling terminal (the console port it was spawned
+// from). Serial ports can be opened as /dev/ttyS<n>; any other path is
+// opened on the filesystem, read-only unless `OPEN_WRITE` is given.
+//
+// Open files live in a kernel-wide table, each tagged with the task that
+// owns it; a task's descriptor holds an index into it. Files are closed
+// (and flushed) by `close`, and by the exit syscall for whatever a task
+// left open. A task killed with files open can't close them itself (it
+// may die in an interrupt handler), so `close_dead` does it for whoever
+// kills it or waits for it, and every open catches any left over.
+//
+// Open files, the filesystems and the block cache are shared by every
+// task, and often freed by another task than the one that allocated them:
+// everything done on them here is charged to nobody (see `mm::quota`).
+// =============================================================================
+
+use aprk_arch_arm64::uart;
+use spin::Mutex;
+use crate::console;
+use crate::fs::{FileHandle, FsError, SeekFrom};
+use crate::mm::quota::with_task_charge;
+use crate::sched;
+
+/// Maximum open file descriptors per task
+pub const MAX_FDS: usize = 8;
+/// Maximum files open at once across all tasks
+const MAX_FILES: usize = 16;
+
+/// `open` flag: open for writing too, creating the file if it's missing
+pub const OPEN_WRITE: u64 = 1 << 0;
+/// `open` flag: empty the file (with `OPEN_WRITE`)
+pub const OPEN_TRUNCATE: u64 = 1 << 1;
+/// `open` flag: every write goes to the end of the file (with `OPEN_WRITE`)
+pub const OPEN_APPEND: u64 = 1 << 2;
+
+/// One open file.
+struct FileSlot {
+    /// Task the file is open for
+    owner: usize,
+    /// Taken out while the file is being used, so the table isn't locked
+    /// across disk I/O
+    handle: Option<FileHandle>,
+    writable: bool,
+    append: bool,
+}
+
+static FILES: Mutex<[Option<FileSlot>; MAX_FILES]> = Mutex::new([const { None }; MAX_FILES]);
+
+/// What an open file descriptor refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileDesc {
+    /// Serial port by UART id
+    Tty(usize),
+    /// Filesystem file by index in the open file table
+    File(usize),
+}
+
+/// A task's open files, indexed by fd number
+pub type FdTable = [Option<FileDesc>; MAX_FDS];
+
+/// Descriptor table for a new task with stdio on terminal `tty`.
+pub const fn stdio(tty: usize) -> FdTable {
+    let mut fds = [None; MAX_FDS];
+    fds[0] = Some(FileDesc::Tty(tty));
+    fds[1] = Some(FileDesc::Tty(tty));
+    fds[2] = Some(FileDesc::Tty(tty));
+    fds
+}
+
+/// Resolve a device path to a descriptor target. `None` if `path` isn't
+/// a device.
+fn lookup(path: &str) -> Option<Result<FileDesc, FsError>> {
+    let path = crate::fs::path::normalize("/", path);
+    let port = path.strip_prefix("/dev/ttyS")?.parse::<usize>().ok()?;
+    if uart::init_port(port) {
+        Some(Ok(FileDesc::Tty(port)))
+    } else {
+        Some(Err(FsError::NoDevice))
+    }
+}
+
+/// Open the filesystem file at `path` for the current task, with `OPEN_*`
+/// `flags`. Returns its index in the open file table.
+fn open_file(path: &str, flags: u64) -> Result<usize, FsError> {
+    with_task_charge(None, || open_file_uncharged(path, flags))
+}
+
+fn open_file_uncharged(path: &str, flags: u64) -> Result<usize, FsError> {
+    let writable = flags & OPEN_WRITE != 0;
+    let handle = if writable {
+        crate::fs::open_write(path, flags & OPEN_TRUNCATE != 0)?
+    } else {
+        crate::fs::open(path)?
+    };
+    let slot = FileSlot {
+        owner: sched::current_task_id(),
+        handle: Some(handle),
+        writable,
+        append: flags & OPEN_APPEND != 0,
+    };
+    let mut files = FILES.lock();
+    let dead = take_dead(&mut files);
+    let index = files.iter().position(Option::is_none);
+    if let Some(index) = index {
+        files[index] = Some(slot);
+    }
+    drop(files);
+    dead.into_iter().flatten().for_each(close_slot);
+    index.ok_or(FsError::TooManyOpen)
+}
+
+/// Take the files of tasks that died without closing them out of the
+/// table. They're to be closed once it's unlocked: closing one locks the
+/// volume, and may even unmount it.
+fn take_dead(files: &mut [Option<FileSlot>; MAX_FILES]) -> [Option<FileSlot>; MAX_FILES] {
+    let mut dead = [const { None }; MAX_FILES];
+    for (slot, dead) in files.iter_mut().zip(dead.iter_mut()) {
+        if slot.as_ref().is_some_and(|slot| !sched::is_alive(slot.owner)) {
+            *dead = slot.take();
+        }
+    }
+    dead
+}
+
+/// Close and flush the files of tasks that died without closing them,
+/// killed or faulted. Called from task context, as the disk may be
+/// waited for.
+pub fn close_dead() {
+    let dead = take_dead(&mut FILES.lock());
+    dead.into_iter().flatten().for_each(close_slot);
+}
+
+/// Open a path in the current task with `OPEN_*` `flags` (ignored for
+/// terminals). Returns the new fd.
+pub fn open(path: &str, flags: u64) -> Result<usize, FsError> {
+    if sched::with_current_fds(|fds| fds.iter().all(Option::is_some)) {
+        return Err(FsError::TooManyOpen);
+    }
+    let file = match lookup(path) {
+        Some(file) => file?,
+        None => FileDesc::File(open_file(path, flags)?),
+    };
+    sched::with_current_fds(|fds| {
+        let fd = fds.iter().position(|f| f.is_none()).ok_or(FsError::TooManyOpen)?;
+        fds[fd] = Some(file);
+        Ok(fd)
+    })
+}
+
+/// Open `path` for writing as the standard output of a task about to be
+/// spawned, emptying it unless `append`. The current task owns it until
+/// `give` passes it on.
+pub fn open_redirect(path: &str, append: bool) -> Result<FileDesc, FsError> {
+    let flags = OPEN_WRITE | if append { OPEN_APPEND } else { OPEN_TRUNCATE };
+    open_file(path, flags).map(FileDesc::File)
+}
+
+/// Make task `pid` the owner of open file `file`.
+pub fn give(file: FileDesc, pid: usize) {
+    if let FileDesc::File(index) = file {
+        if let Some(slot) = FILES.lock()[index].as_mut() {
+            slot.owner = pid;
+        }
+    }
+}
+
+/// Close an open file no task has a descriptor for.
+pub fn release(file: FileDesc) {
+    if let FileDesc::File(index) = file {
+        close_file(index);
+    }
+}
+
+/// Close open file `index`, flushing it.
+fn close_file(index: usize) {
+    let slot = FILES.lock()[index].take();
+    if let Some(slot) = slot {
+        close_slot(slot);
+    }
+}
+
+/// Close a file taken out of the table, flushing it.
+fn close_slot(slot: FileSlot) {
+    if let Some(mut handle) = slot.handle {
+        with_task_charge(None, || {
+            if let Err(e) = handle.flush() {
+                crate::log_warn!("fd", "Flushing a closed file failed: {}", e);
+            }
+            drop(handle);
+        });
+    }
+}
+
+/// Close an fd in the current task.
+pub fn close(fd: usize) -> Result<(), FsError> {
+    let closed = sched::with_current_fds(|fds| fds.get_mut(fd).and_then(Option::take));
+    match closed {
+        Some(FileDesc::File(index)) => close_file(index),
+        Some(FileDesc::Tty(_)) => {}
+        None => return Err(FsError::BadDescriptor),
+    }
+    Ok(())
+}
+
+/// Close every fd of the current task, which is exiting.
+pub fn close_all() {
+    for fd in 0..MAX_FDS {
+        let _ = close(fd);
+    }
+}
+
+fn get(fd: usize) -> Result<FileDesc, FsError> {
+    sched::with_current_fds(|fds| fds.get(fd).copied().flatten()).ok_or(FsError::BadDescriptor)
+}
+
+/// Whether `fd` of the current task is the console, which `print!` writes
+/// to along with the kernel log and the other console sinks.
+pub fn is_console(fd: usize) -> bool {
+    matches!(get(fd), Ok(FileDesc::Tty(port)) if port == uart::console_port())
+}
+
+/// Run `f` on the handle of open file `index`, if it's open for writing or
+/// `write` is false. Also passes whether it was opened to append.
+fn with_file<R>(
+    index: usize,
+    write: bool,
+    f: impl FnOnce(&mut FileHandle, bool) -> Result<R, FsError>,
+) -> Result<R, FsError> {
+    let (mut handle, append) = {
+        let mut files = FILES.lock();
+        let slot = files[index].as_mut().filter(|slot| slot.writable || !write);
+        let slot = slot.ok_or(FsError::BadDescriptor)?;
+        // In use by another call on the same file
+        (slot.handle.take().ok_or(FsError::BadDescriptor)?, slot.append)
+    };
+    let result = with_task_charge(None, || f(&mut handle, append));
+    let mut files = FILES.lock();
+    if let Some(slot) = files[index].as_mut() {
+        slot.handle = Some(handle);
+    } else {
+        // Closed meanwhile
+        drop(files);
+        with_task_charge(None, || drop(handle));
+    }
+    result
+}
+
+/// Read from an fd, blocking until at least one byte is available.
+///
+/// Reads from the console follow its mode (see `console::Mode`).
+pub fn read(fd: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    match get(fd)? {
+        // The console goes through the line discipline; other ports are raw
+        FileDesc::Tty(port) if port == uart::console_port() => Ok(console::read(buf)),
+        FileDesc::Tty(port) => Ok(console::read_raw(port, buf)),
+        FileDesc::File(index) => with_file(index, false, |file, _| file.read(buf)),
+    }
+}
+
+/// Write to an fd.
+pub fn write(fd: usize, buf: &[u8]) -> Result<usize, FsError> {
+    match get(fd)? {
+        FileDesc::Tty(port) => {
+            if uart::write_port(port, buf) { Ok(buf.len()) } else { Err(FsError::NoDevice) }
+        }
+        FileDesc::File(index) => {
+            with_file(index, true, |file, append| {
+                if append {
+                    file.seek(SeekFrom::End(0))?;
+                }
+                file.write(buf)
+            })?;
+            Ok(buf.len())
+        }
+    }
+}
+
+/// Move the position of file fd `fd` to `offset` from the start (`whence`
+/// 0), the current position (1) or the end (2). Returns the new position.
+pub fn lseek(fd: usize, offset: i64, whence: u64) -> Result<u64, FsError> {
+    let FileDesc::File(index) = get(fd)? else {
+        return Err(FsError::Unsupported);
+    };
+    let pos = match whence {
+        0 => SeekFrom::Start(u64::try_from(offset).map_err(|_| FsError::InvalidArgument)?),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return Err(FsError::InvalidArgument),
+    };
+    with_file(index, false, |file, _| file.seek(pos))
+}
+
+/// Cut file fd `fd` to `len` bytes, or extend it with zeros.
+pub fn ftruncate(fd: usize, len: u64) -> Result<(), FsError> {
+    let FileDesc::File(index) = get(fd)? else {
+        return Err(FsError::Unsupported);
+    };
+    with_file(index, true, |file, _| file.truncate(len))
+}
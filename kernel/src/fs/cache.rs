@@ -0,0 +1,392 @@
+// =============================================================================
+// APRK OS - Block Cache
+// =============================================================================
+// Keeps recently used 512-byte disk blocks in memory, so walking FAT
+// chains and directories again doesn't go back to the device every time.
+// When full, the least recently used block is dropped. One cache serves
+// every block device, blocks being keyed by disk and number: a partition's
+// blocks are cached as the disk's, so both views see the same copies.
+//
+// The cache is write-back: writes only change the cached copy and mark it
+// dirty. Dirty blocks reach the disk when `flush` (`fs::sync`, umount,
+// poweroff) or an eviction writes them back, runs of consecutive dirty
+// blocks going in one request each. A block that can't be cached (no heap
+// left) is written straight through.
+//
+// Large reads of whole blocks skip the cache (`read_blocks`), going to the
+// device in one request rather than a block at a time, with any cached
+// copies laid over what the device returned. Unless every block is cached
+// already, as read-ahead (readahead.rs) leaves them on a sequential read:
+// those are counted apart from other hits, to show whether it pays off.
+//
+// Reads that miss don't keep the cache locked while the device works.
+// Writes back do, so a write can't slip in between writing a block back
+// and marking it clean.
+// =============================================================================
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::block::{self, BlockDevice, BlockError, Device};
+use super::FsError;
+
+/// Bytes per block
+pub const BLOCK_SIZE: usize = crate::block::BLOCK_SIZE;
+/// Blocks cached unless resized
+pub const DEFAULT_BLOCKS: usize = 256; // 128 KB
+/// Most blocks written back in one request
+const MAX_BATCH: usize = 64;
+
+/// A block: registry number of the disk, and block number on it
+type Key = (usize, u64);
+
+struct Entry {
+    key: Key,
+    /// `Cache::clock` when last used
+    used: u64,
+    /// Changed since it was last written to the disk
+    dirty: bool,
+    /// Read ahead, and not yet used
+    prefetched: bool,
+    data: [u8; BLOCK_SIZE],
+}
+
+struct Cache {
+    entries: Vec<Entry>,
+    /// Most entries
+    capacity: usize,
+    /// Block -> index in `entries`
+    index: BTreeMap<Key, usize>,
+    /// Bumped on every access, for LRU order
+    clock: u64,
+    stats: CacheStats,
+}
+
+/// Block cache counters since boot (or the last resize).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks dropped to make room
+    pub evictions: u64,
+    /// Blocks written by callers
+    pub writes: u64,
+    /// Blocks written back to the device, and the requests it took
+    pub written_back: u64,
+    pub write_requests: u64,
+    /// Blocks read by `read_blocks`, past the cache
+    pub direct: u64,
+    /// Blocks discarded as freed
+    pub discarded: u64,
+    /// Blocks read ahead, and those later used (not counted in `hits`)
+    pub prefetched: u64,
+    pub prefetch_hits: u64,
+    /// Blocks held now, dirty ones among them, and most that can be
+    pub cached: usize,
+    pub dirty: usize,
+    pub capacity: usize,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    entries: Vec::new(),
+    capacity: DEFAULT_BLOCKS,
+    index: BTreeMap::new(),
+    clock: 0,
+    stats: CacheStats {
+        hits: 0, misses: 0, evictions: 0, writes: 0, written_back: 0, write_requests: 0, direct: 0,
+        discarded: 0, prefetched: 0, prefetch_hits: 0, cached: 0, dirty: 0, capacity: 0,
+    },
+});
+
+impl Cache {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Store `data` as the cached copy of `key`, evicting the least
+    /// recently used block if the cache is full; a dirty one is written
+    /// back first. `Ok(false)` if there's no room: the heap is full, or
+    /// `capacity` is 0.
+    fn insert(&mut self, key: Key, data: &[u8; BLOCK_SIZE], dirty: bool) -> Result<bool, FsError> {
+        let used = self.tick();
+        if let Some(&i) = self.index.get(&key) {
+            let entry = &mut self.entries[i];
+            entry.data = *data;
+            entry.used = used;
+            entry.dirty |= dirty;
+            entry.prefetched = false;
+            return Ok(true);
+        }
+        let slot = if self.entries.len() < self.capacity {
+            if self.entries.try_reserve(1).is_err() {
+                return Ok(false);
+            }
+            self.entries.push(Entry { key, used, dirty, prefetched: false, data: *data });
+            self.entries.len() - 1
+        } else {
+            let Some(i) = (0..self.entries.len()).min_by_key(|&i| self.entries[i].used) else {
+                return Ok(false);
+            };
+            if self.entries[i].dirty {
+                self.write_back_run(self.entries[i].key)?;
+            }
+            self.index.remove(&self.entries[i].key);
+            self.stats.evictions += 1;
+            self.entries[i] = Entry { key, used, dirty, prefetched: false, data: *data };
+            i
+        };
+        self.index.insert(key, slot);
+        Ok(true)
+    }
+
+    /// Drop the cached copy of `key`, written back or not.
+    fn remove(&mut self, key: Key) {
+        let Some(i) = self.index.remove(&key) else {
+            return;
+        };
+        self.entries.swap_remove(i);
+        if let Some(moved) = self.entries.get(i).map(|entry| entry.key) {
+            self.index.insert(moved, i);
+        }
+    }
+
+    /// Count a demand read served by entry `i`.
+    fn hit(&mut self, i: usize) {
+        let used = self.tick();
+        let entry = &mut self.entries[i];
+        entry.used = used;
+        if core::mem::take(&mut entry.prefetched) {
+            self.stats.prefetch_hits += 1;
+        } else {
+            self.stats.hits += 1;
+        }
+    }
+
+    /// Write back the run of consecutive dirty blocks holding dirty block
+    /// `key`, in one request, and mark them clean.
+    fn write_back_run(&mut self, key: Key) -> Result<(), FsError> {
+        let (disk, block) = key;
+        let dirty = |cache: &Self, block: u64| {
+            cache.index.get(&(disk, block)).is_some_and(|&i| cache.entries[i].dirty)
+        };
+        let mut first = block;
+        while first > 0 && block - first < MAX_BATCH as u64 / 2 && dirty(self, first - 1) {
+            first -= 1;
+        }
+        let mut count = 1;
+        while count < MAX_BATCH && dirty(self, first + count as u64) {
+            count += 1;
+        }
+        self.write_back(disk, first, count)
+    }
+
+    /// Write back the `count` dirty blocks of disk `disk` from `first` on,
+    /// in one request, and mark them clean.
+    fn write_back(&mut self, disk: usize, first: u64, count: usize) -> Result<(), FsError> {
+        let dev = block::by_id(disk).ok_or(FsError::NoDevice)?;
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(count * BLOCK_SIZE).map_err(|_| FsError::OutOfMemory)?;
+        for block in first..first + count as u64 {
+            buf.extend_from_slice(&self.entries[self.index[&(disk, block)]].data);
+        }
+        dev.write_blocks(first, &buf).map_err(|e| device_error(e, Some(first), true))?;
+        for block in first..first + count as u64 {
+            let i = self.index[&(disk, block)];
+            self.entries[i].dirty = false;
+        }
+        self.stats.written_back += count as u64;
+        self.stats.write_requests += 1;
+        Ok(())
+    }
+
+    /// Write back every dirty block of disk `disk`, or of every disk,
+    /// batching consecutive blocks. Stops at the first failure, leaving
+    /// the blocks not yet written dirty.
+    fn write_back_all(&mut self, disk: Option<usize>) -> Result<(), FsError> {
+        let dirty: Vec<Key> = self.index.iter()
+            .filter(|&(&(d, _), &i)| disk.is_none_or(|disk| disk == d) && self.entries[i].dirty)
+            .map(|(&key, _)| key)
+            .collect();
+        let mut rest = &dirty[..];
+        while let Some(&(disk, first)) = rest.first() {
+            let count = rest.iter().take(MAX_BATCH).enumerate()
+                .take_while(|&(n, &(d, block))| d == disk && block == first + n as u64)
+                .count();
+            self.write_back(disk, first, count)?;
+            rest = &rest[count..];
+        }
+        Ok(())
+    }
+}
+
+/// The error for a device request on `block` (`None` for a flush) that
+/// failed with `e`, the block layer having given up retrying it.
+fn device_error(e: BlockError, block: Option<u64>, write: bool) -> FsError {
+    match e {
+        BlockError::NotReady => FsError::NoDevice,
+        BlockError::IoError => FsError::Io { block, write },
+        // Only a corrupted filesystem points past the end of its device
+        BlockError::OutOfRange => FsError::Corrupt,
+        BlockError::Unsupported => FsError::Unsupported,
+        BlockError::ReadOnly => FsError::ReadOnlyFs,
+    }
+}
+
+/// Read block `block` of `dev` into `buf`, from the cache if it's there.
+pub fn read_block(dev: &Device, block: u64, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), FsError> {
+    let key = (dev.disk, dev.start + block);
+    super::readahead::access(dev, block, 1);
+    {
+        let mut cache = CACHE.lock();
+        if let Some(&i) = cache.index.get(&key) {
+            cache.hit(i);
+            buf.copy_from_slice(&cache.entries[i].data);
+            return Ok(());
+        }
+        cache.stats.misses += 1;
+    }
+    // Not locked meanwhile, so other tasks' hits don't wait for the device
+    dev.read_blocks(block, buf).map_err(|e| device_error(e, Some(block), false))?;
+    let mut cache = CACHE.lock();
+    match cache.index.get(&key) {
+        // Written while we read it: the cached copy is the newer one
+        Some(&i) => buf.copy_from_slice(&cache.entries[i].data),
+        // Not caching it is no failure: it's read
+        None => {
+            let _ = cache.insert(key, buf, false);
+        }
+    }
+    Ok(())
+}
+
+/// Read `buf.len() / BLOCK_SIZE` blocks of `dev`, from `block` on,
+/// straight from the device in as few requests as it takes. For large
+/// sequential reads: the blocks aren't cached, so they don't push out the
+/// ones reused often. Cached copies, which may not have been written back
+/// yet, replace what the device returned. If all of them are cached,
+/// the device isn't asked at all.
+pub fn read_blocks(dev: &Device, block: u64, buf: &mut [u8]) -> Result<(), FsError> {
+    let count = (buf.len() / BLOCK_SIZE) as u64;
+    let first = dev.start + block;
+    super::readahead::access(dev, block, count);
+    {
+        let mut cache = CACHE.lock();
+        let cached: Vec<usize> = cache.index.range((dev.disk, first)..(dev.disk, first + count)).map(|(_, &i)| i).collect();
+        if cached.len() as u64 == count {
+            for (n, i) in cached.into_iter().enumerate() {
+                cache.hit(i);
+                buf[n * BLOCK_SIZE..][..BLOCK_SIZE].copy_from_slice(&cache.entries[i].data);
+            }
+            return Ok(());
+        }
+        cache.stats.direct += count;
+    }
+    dev.read_blocks(block, buf).map_err(|e| device_error(e, Some(block), false))?;
+    let cache = CACHE.lock();
+    for (&(_, at), &i) in cache.index.range((dev.disk, first)..(dev.disk, first + count)) {
+        let offset = (at - first) as usize * BLOCK_SIZE;
+        buf[offset..offset + BLOCK_SIZE].copy_from_slice(&cache.entries[i].data);
+    }
+    Ok(())
+}
+
+/// Cache `data`, read ahead from disk `disk` from block `first` on, as
+/// blocks not yet used. Blocks already cached are left alone: they may
+/// have been written since the read.
+pub(super) fn fill(disk: usize, first: u64, data: &[u8]) {
+    let mut cache = CACHE.lock();
+    for (n, block) in data.as_chunks::<BLOCK_SIZE>().0.iter().enumerate() {
+        let key = (disk, first + n as u64);
+        if cache.index.contains_key(&key) {
+            continue;
+        }
+        // Not caching it is no failure: nobody asked for it yet
+        if let Ok(true) = cache.insert(key, block, false) {
+            let i = cache.index[&key];
+            cache.entries[i].prefetched = true;
+            cache.stats.prefetched += 1;
+        }
+    }
+}
+
+/// Tell `dev` its blocks `start..start + count` are free, dropping any
+/// cached copies: written back, they'd put the data back. A no-op on a
+/// device that can't discard.
+pub fn discard(dev: &Device, start: u64, count: u64) -> Result<(), FsError> {
+    if !dev.can_discard() {
+        return Ok(());
+    }
+    {
+        let mut cache = CACHE.lock();
+        let first = dev.start + start;
+        let cached: Vec<Key> = cache.index.range((dev.disk, first)..(dev.disk, first + count)).map(|(&key, _)| key).collect();
+        for key in cached {
+            cache.remove(key);
+        }
+    }
+    dev.discard(start, count).map_err(|e| device_error(e, Some(start), true))?;
+    CACHE.lock().stats.discarded += count;
+    Ok(())
+}
+
+/// Write `buf` to block `block` of `dev`: to the cache, for `flush` or an
+/// eviction to write back, or to the device if it can't be cached.
+pub fn write_block(dev: &Device, block: u64, buf: &[u8; BLOCK_SIZE]) -> Result<(), FsError> {
+    if dev.readonly() {
+        return Err(FsError::ReadOnlyFs);
+    }
+    let key = (dev.disk, dev.start + block);
+    let mut cache = CACHE.lock();
+    cache.stats.writes += 1;
+    if !cache.insert(key, buf, true)? {
+        dev.write_blocks(block, buf).map_err(|e| device_error(e, Some(block), true))?;
+    }
+    Ok(())
+}
+
+/// Write back the dirty blocks on `dev`'s disk, then ask the device to
+/// commit them to stable storage.
+pub fn flush(dev: &Device) -> Result<(), FsError> {
+    CACHE.lock().write_back_all(Some(dev.disk))?;
+    dev.flush().map_err(|e| device_error(e, None, true))
+}
+
+/// `flush` every disk holding dirty blocks, returning the first error.
+pub fn flush_all() -> Result<(), FsError> {
+    let disks: Vec<usize> = {
+        let cache = CACHE.lock();
+        let mut disks: Vec<usize> = cache.entries.iter().filter(|e| e.dirty).map(|e| e.key.0).collect();
+        disks.sort_unstable();
+        disks.dedup();
+        disks
+    };
+    let mut result = Ok(());
+    for disk in disks {
+        let flushed = block::by_id(disk).ok_or(FsError::NoDevice).and_then(|dev| flush(&dev));
+        if result.is_ok() {
+            result = flushed;
+        }
+    }
+    result
+}
+
+/// Write back every dirty block, then drop every cached block and hold at
+/// most `blocks` from now on. Also resets the counters. Nothing changes if
+/// writing back fails.
+pub fn resize(blocks: usize) -> Result<(), FsError> {
+    let mut cache = CACHE.lock();
+    cache.write_back_all(None)?;
+    cache.entries = Vec::new();
+    cache.index.clear();
+    cache.capacity = blocks;
+    cache.stats = CacheStats::default();
+    Ok(())
+}
+
+/// Current counters.
+pub fn stats() -> CacheStats {
+    let cache = CACHE.lock();
+    let dirty = cache.entries.iter().filter(|e| e.dirty).count();
+    CacheStats { cached: cache.entries.len(), dirty, capacity: cache.capacity, ..cache.stats }
+}
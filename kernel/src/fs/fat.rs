@@ -0,0 +1,602 @@
+// =============================================================================
+// APRK OS - FAT Filesystem
+// =============================================================================
+// FAT32 volumes on block devices, through the fatfs crate. fatfs sees its
+// partition (or the whole disk) as one seekable byte stream
+// (`BlockStream`), which goes through the block cache in whole 512-byte
+// blocks.
+//
+// The volume is kept at a fixed address, so open files can keep a fatfs
+// `File` borrowing it as `'static`; it's only freed once the `FatFs` is
+// dropped, which the VFS does after the last open file on it is closed
+// (see `fs::FileHandle`). fatfs isn't thread-safe,
+// so everything that touches the volume, open files included, holds its
+// lock: a `KMutex`, taken for one operation or one read or write call, so
+// a task waiting for the disk sleeps and tasks reading big files take
+// turns between calls.
+//
+// Deletes and truncates on a device that can discard (TRIM) note the
+// clusters of the file's chain they're about to release, and discard those
+// once they have.
+// =============================================================================
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::ManuallyDrop;
+use spin::Mutex;
+use crate::sched::mutex::KMutex;
+use fatfs::{FileSystem, FsOptions, SeekFrom, Read, Seek, Write};
+use crate::block::{BlockDevice, Device};
+use super::cache::{self, BLOCK_SIZE};
+use super::{fsck, path, DirEntryInfo, FsError, FsStats};
+use super::vfs::{OpenFile, Vfs};
+
+/// A block device as a seekable byte stream.
+pub struct BlockStream {
+    dev: Device,
+    offset: u64,
+    /// Bytes on the device
+    size: u64,
+}
+
+impl BlockStream {
+    /// All of `dev`: a partition, or a disk without a partition table.
+    pub fn new(dev: Device) -> Self {
+        let size = dev.size();
+        Self { dev, offset: 0, size }
+    }
+
+    /// Block holding byte `offset`. Blocks past the end belong to
+    /// something else: a filesystem reaching for them is corrupted.
+    fn block(&self, offset: u64) -> Result<u64, FsError> {
+        if offset >= self.size {
+            crate::log_error!("fs", "Access at byte {} is past the end of {} ({} bytes)", offset, self.dev.name, self.size);
+            return Err(FsError::Corrupt);
+        }
+        Ok(offset / 512)
+    }
+}
+
+/// Where a seek to `pos` from `offset` lands on a device of `size` bytes.
+///
+/// Seeks from the end are clamped to the device; any seek landing before
+/// the start is refused (`None`).
+fn seek_target(offset: u64, size: u64, pos: SeekFrom) -> Option<u64> {
+    match pos {
+        SeekFrom::Start(off) => Some(off),
+        SeekFrom::Current(off) => offset.checked_add_signed(off),
+        SeekFrom::End(off) => size.checked_add_signed(off).map(|target| target.min(size)),
+    }
+}
+
+impl fatfs::IoBase for BlockStream {
+    type Error = FsError;
+}
+
+impl fatfs::IoError for FsError {
+    fn is_interrupted(&self) -> bool {
+        false
+    }
+
+    fn new_unexpected_eof_error() -> Self {
+        // fatfs only reads the device within the volume
+        FsError::Corrupt
+    }
+
+    fn new_write_zero_error() -> Self {
+        FsError::Io { block: None, write: true }
+    }
+}
+
+impl fatfs::Read for BlockStream {
+    /// Whole blocks in the middle of the range are read in one device
+    /// request; a partial block at either end goes through the cache.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let block_size = 512u64;
+        let mut read_bytes = 0;
+
+        while read_bytes < buf.len() {
+            let start_block = self.block(self.offset)?;
+            let offset_in_block = (self.offset % block_size) as usize;
+            let remaining_in_buf = buf.len() - read_bytes;
+
+            // Aligned, with at least a block to fill: read the whole run
+            let whole = (remaining_in_buf / 512) as u64;
+            let left_in_partition = (self.size - self.offset) / block_size;
+            let run = whole.min(left_in_partition) as usize;
+            if offset_in_block == 0 && run > 0 {
+                cache::read_blocks(&self.dev, start_block, &mut buf[read_bytes..read_bytes + run * 512])?;
+                read_bytes += run * 512;
+                self.offset += run as u64 * block_size;
+                continue;
+            }
+
+            let mut temp_buf = [0u8; 512];
+            cache::read_block(&self.dev, start_block, &mut temp_buf)?;
+            let to_copy = core::cmp::min(block_size as usize - offset_in_block, remaining_in_buf);
+            buf[read_bytes..read_bytes + to_copy].copy_from_slice(&temp_buf[offset_in_block..offset_in_block + to_copy]);
+            read_bytes += to_copy;
+            self.offset += to_copy as u64;
+        }
+
+        Ok(read_bytes)
+    }
+}
+
+impl fatfs::Seek for BlockStream {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        let Some(target) = seek_target(self.offset, self.size, pos) else {
+            crate::log_error!("fs", "Seek to {:?} from {} lands before the start of the disk", pos, self.offset);
+            return Err(FsError::Corrupt);
+        };
+        self.offset = target;
+        Ok(self.offset)
+    }
+}
+
+impl fatfs::Write for BlockStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let mut written = 0;
+        let block_size = 512u64;
+
+        while written < buf.len() {
+            let start_block = self.block(self.offset)?;
+            let offset_in_block = (self.offset % block_size) as usize;
+
+            let remaining_in_block = block_size as usize - offset_in_block;
+            let to_copy = core::cmp::min(remaining_in_block, buf.len() - written);
+
+            let mut temp_buf = [0u8; 512];
+            // A partial block keeps the bytes around the range: read it
+            // first so they're written back unchanged
+            if to_copy < block_size as usize {
+                cache::read_block(&self.dev, start_block, &mut temp_buf)?;
+            }
+            temp_buf[offset_in_block..offset_in_block + to_copy].copy_from_slice(&buf[written..written + to_copy]);
+            cache::write_block(&self.dev, start_block, &temp_buf)?;
+
+            written += to_copy;
+            self.offset += to_copy as u64;
+        }
+
+        Ok(written)
+    }
+    /// fatfs flushes after every file write; writing the cache back each
+    /// time would make it write-through. Sync points call `cache::flush`.
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+type Fs = FileSystem<BlockStream, fatfs::DefaultTimeProvider, fatfs::LossyOemCpConverter>;
+type File<'a> = fatfs::File<'a, BlockStream, fatfs::DefaultTimeProvider, fatfs::LossyOemCpConverter>;
+type Dir<'a> = fatfs::Dir<'a, BlockStream, fatfs::DefaultTimeProvider, fatfs::LossyOemCpConverter>;
+
+impl From<fatfs::Error<FsError>> for FsError {
+    fn from(e: fatfs::Error<FsError>) -> Self {
+        match e {
+            fatfs::Error::Io(e) => e,
+            fatfs::Error::NotFound => FsError::NotFound,
+            fatfs::Error::AlreadyExists => FsError::AlreadyExists,
+            fatfs::Error::DirectoryIsNotEmpty => FsError::NotEmpty,
+            fatfs::Error::InvalidFileNameLength => FsError::NameTooLong,
+            fatfs::Error::UnsupportedFileNameCharacter => FsError::InvalidName,
+            fatfs::Error::NotEnoughSpace => FsError::NoSpace,
+            fatfs::Error::CorruptedFileSystem | fatfs::Error::UnexpectedEof => FsError::Corrupt,
+            fatfs::Error::WriteZero => FsError::Io { block: None, write: true },
+            _ => FsError::Unsupported,
+        }
+    }
+}
+
+/// A mounted FAT volume.
+pub struct FatFs {
+    fs: &'static KMutex<Fs>,
+    /// `statfs` as of the last call, until the next write. Counting free
+    /// clusters can mean reading the whole FAT.
+    stats: &'static Mutex<Option<FsStats>>,
+    /// Device holding the volume
+    dev: Device,
+}
+
+impl FatFs {
+    /// Mount the FAT volume on `dev`.
+    pub fn mount(dev: Device) -> Result<Self, FsError> {
+        match FileSystem::new(BlockStream::new(dev.clone()), FsOptions::new()) {
+            Ok(fs) => {
+                crate::log_info!("fs", "FAT32 FileSystem initialized.");
+                Ok(Self {
+                    fs: Box::leak(Box::new(KMutex::new(fs))),
+                    stats: Box::leak(Box::new(Mutex::new(None))),
+                    dev,
+                })
+            }
+            Err(e) => {
+                crate::log_error!("fs", "Failed to initialize FileSystem: {:?}", e);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Run `f` on the volume, if the disk can be written. Forgets the
+    /// cached `statfs`, which the write may change.
+    fn writable<R>(&self, f: impl FnOnce(&Fs) -> Result<R, FsError>) -> Result<R, FsError> {
+        if self.dev.readonly() {
+            return Err(FsError::ReadOnlyFs);
+        }
+        let result = f(&self.fs.lock());
+        *self.stats.lock() = None;
+        result
+    }
+
+    /// Open the file `f` finds on the volume.
+    fn open_with(&self, f: impl FnOnce(&'static Fs) -> Result<File<'static>, FsError>) -> Result<Box<dyn OpenFile>, FsError> {
+        let guard = self.fs.lock();
+        // The volume outlives its open files; `FatFile` only uses it
+        // under the lock
+        let fs: &'static Fs = unsafe { &*(&*guard as *const Fs) };
+        let file = f(fs)?;
+        drop(guard);
+        Ok(Box::new(FatFile { lock: self.fs, stats: self.stats, dev: self.dev.clone(), file: ManuallyDrop::new(file) }))
+    }
+}
+
+impl Drop for FatFs {
+    fn drop(&mut self) {
+        // The VFS drops a filesystem only once no open file is left on it,
+        // so nothing borrows the volume any more
+        let fs = unsafe { Box::from_raw(self.fs as *const KMutex<Fs> as *mut KMutex<Fs>) };
+        drop(unsafe { Box::from_raw(self.stats as *const _ as *mut Mutex<Option<FsStats>>) });
+        // Writes back the free cluster count and clears the dirty flag
+        if let Err(e) = fs.into_inner().unmount() {
+            crate::log_warn!("fs", "Unmounting the FAT volume on {} failed: {:?}", self.dev.name, e);
+        }
+        let _ = cache::flush(&self.dev);
+    }
+}
+
+/// Sector runs, `(first, count)`, of the clusters of `file` from byte
+/// `from` on, which truncating it there releases. Consecutive clusters
+/// make one run. Empty if `dev` can't discard, so the chain isn't walked
+/// for nothing.
+fn chain_from(dev: &Device, file: &mut File, from: u64) -> Result<Vec<(u64, u64)>, FsError> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    if !dev.can_discard() {
+        return Ok(runs);
+    }
+    let mut pos = 0;
+    for extent in file.extents() {
+        let extent = extent?;
+        let start = pos;
+        pos += extent.size as u64;
+        // The cluster holding byte `from - 1` is kept
+        if start < from {
+            continue;
+        }
+        let first = extent.offset / BLOCK_SIZE as u64;
+        let count = (extent.size as u64).div_ceil(BLOCK_SIZE as u64);
+        match runs.last_mut() {
+            Some((start, sectors)) if *start + *sectors == first => *sectors += count,
+            _ => runs.push((first, count)),
+        }
+    }
+    Ok(runs)
+}
+
+/// Discard the sector `runs` a delete or truncate released. A failed
+/// discard only costs the space, so it's just logged.
+fn discard(dev: &Device, runs: Vec<(u64, u64)>) {
+    if let Err(e) = runs.into_iter().try_for_each(|(start, count)| cache::discard(dev, start, count)) {
+        crate::log_warn!("fs", "Discarding freed clusters on {} failed: {:?}", dev.name, e);
+    }
+}
+
+/// Cut `file` at its position, discarding the clusters that releases.
+fn truncate_file(dev: &Device, file: &mut File) -> Result<(), FsError> {
+    let pos = file.seek(SeekFrom::Current(0))?;
+    let runs = chain_from(dev, file, pos)?;
+    file.truncate()?;
+    discard(dev, runs);
+    Ok(())
+}
+
+/// Open the directory reached by walking `parts` down from the root.
+fn open_dir_at<'a, 'p>(fs: &'a Fs, parts: impl Iterator<Item = &'p str>) -> Result<Dir<'a>, FsError> {
+    let mut dir = fs.root_dir();
+    for part in parts {
+        dir = match dir.open_dir(part) {
+            Ok(next) => next,
+            Err(fatfs::Error::NotFound) if dir.open_file(part).is_ok() => return Err(FsError::NotADirectory),
+            Err(e) => return Err(e.into()),
+        };
+    }
+    Ok(dir)
+}
+
+/// Resolve normalized `path` to the directory holding it and its name
+/// there.
+fn resolve<'a, 'p>(fs: &'a Fs, path: &'p str) -> Result<(Dir<'a>, &'p str), FsError> {
+    let (parents, name) = path::split(path);
+    let name = name.ok_or(FsError::InvalidPath)?;
+    Ok((open_dir_at(fs, parents)?, name))
+}
+
+/// Whether a file or directory called `name` exists in `dir`.
+fn exists(dir: &Dir, name: &str) -> bool {
+    dir.open_file(name).is_ok() || dir.open_dir(name).is_ok()
+}
+
+/// Listing entry for a fatfs directory entry.
+fn info(entry: &fatfs::DirEntry<'_, BlockStream, fatfs::DefaultTimeProvider, fatfs::LossyOemCpConverter>) -> DirEntryInfo {
+    DirEntryInfo {
+        name: entry.file_name(),
+        size: if entry.is_dir() { 0 } else { entry.len() },
+        is_dir: entry.is_dir(),
+        modified: entry.modified(),
+    }
+}
+
+impl Vfs for FatFs {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn stat(&self, path: &str) -> Result<DirEntryInfo, FsError> {
+        let fs = self.fs.lock();
+        let (parents, name) = path::split(path);
+        let Some(name) = name else {
+            return Ok(DirEntryInfo::directory(String::from("/")));
+        };
+        for entry in open_dir_at(&fs, parents)?.iter() {
+            let entry = entry?;
+            if entry.eq_name(name) {
+                return Ok(info(&entry));
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn OpenFile>, FsError> {
+        self.open_with(|fs| {
+            let (dir, name) = resolve(fs, path)?;
+            Ok(dir.open_file(name)?)
+        })
+    }
+
+    fn open_write(&self, path: &str, truncate: bool) -> Result<Box<dyn OpenFile>, FsError> {
+        if self.dev.readonly() {
+            return Err(FsError::ReadOnlyFs);
+        }
+        self.open_with(|fs| {
+            *self.stats.lock() = None;
+            let (dir, name) = resolve(fs, path)?;
+            // Opens the file if it already exists
+            let mut file = dir.create_file(name)?;
+            if truncate {
+                truncate_file(&self.dev, &mut file)?;
+            }
+            Ok(file)
+        })
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<DirEntryInfo>, FsError> {
+        let fs = self.fs.lock();
+        let (parents, name) = path::split(path);
+        let dir = open_dir_at(&fs, parents.chain(name))?;
+        let mut entries = Vec::new();
+        for entry in dir.iter() {
+            entries.push(info(&entry?));
+        }
+        Ok(entries)
+    }
+
+    fn write(&self, path: &str, data: &[u8], append: bool) -> Result<(), FsError> {
+        self.writable(|fs| {
+            let (dir, name) = resolve(fs, path)?;
+            // Opens the file if it already exists
+            let mut file = dir.create_file(name)?;
+            if append {
+                file.seek(SeekFrom::End(0))?;
+            } else {
+                truncate_file(&self.dev, &mut file)?;
+            }
+            file.write_all(data)?;
+            file.flush()?;
+            Ok(())
+        })
+    }
+
+    fn create(&self, path: &str) -> Result<(), FsError> {
+        self.writable(|fs| {
+            let (dir, name) = resolve(fs, path)?;
+            if exists(&dir, name) {
+                return Err(FsError::AlreadyExists);
+            }
+            dir.create_file(name)?.flush()?;
+            Ok(())
+        })
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), FsError> {
+        self.writable(|fs| {
+            let (dir, name) = resolve(fs, path)?;
+            // fatfs opens a directory that's already there instead
+            if exists(&dir, name) {
+                return Err(FsError::AlreadyExists);
+            }
+            dir.create_dir(name)?;
+            Ok(())
+        })
+    }
+
+    fn remove(&self, path: &str) -> Result<(), FsError> {
+        self.writable(|fs| {
+            let (dir, name) = resolve(fs, path)?;
+            // An empty directory's cluster isn't discarded: fatfs only
+            // gives the extents of files
+            let runs = match dir.open_file(name) {
+                Ok(mut file) => chain_from(&self.dev, &mut file, 0)?,
+                Err(_) => Vec::new(),
+            };
+            dir.remove(name)?;
+            discard(&self.dev, runs);
+            Ok(())
+        })
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<(), FsError> {
+        self.writable(|fs| {
+            let (old_dir, old_name) = resolve(fs, old)?;
+            let (new_dir, new_name) = resolve(fs, new)?;
+            // Only the case changes: the new name finds the old entry, so
+            // go through a temporary name
+            if old.eq_ignore_ascii_case(new) && old != new {
+                let temp = alloc::format!("{}.~mv", old_name);
+                old_dir.rename(old_name, &old_dir, &temp)?;
+                old_dir.rename(&temp, &new_dir, new_name)?;
+                return Ok(());
+            }
+            old_dir.rename(old_name, &new_dir, new_name)?;
+            Ok(())
+        })
+    }
+
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        let fs = self.fs.lock();
+        if let Some(stats) = self.stats.lock().as_ref() {
+            return Ok(stats.clone());
+        }
+        let stats = fs.stats()?;
+        let stats = FsStats {
+            label: String::from(fs.volume_label().trim_end()),
+            cluster_size: stats.cluster_size() as u64,
+            total_clusters: stats.total_clusters() as u64,
+            free_clusters: stats.free_clusters() as u64,
+        };
+        *self.stats.lock() = Some(stats.clone());
+        Ok(stats)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        // Writes back the volume's dirty blocks, then flushes the device
+        cache::flush(&self.dev)
+    }
+
+    fn check(&self) -> Result<fsck::Report, FsError> {
+        // Holding the volume keeps writes out while the tables are read
+        let _fs = self.fs.lock();
+        fsck::check(&self.dev)
+    }
+}
+
+/// An open file on a FAT volume.
+struct FatFile {
+    lock: &'static KMutex<Fs>,
+    /// The volume's cached `statfs`, forgotten on writes
+    stats: &'static Mutex<Option<FsStats>>,
+    /// Device holding the volume
+    dev: Device,
+    /// Only touched with `lock` held, dropping included
+    file: ManuallyDrop<File<'static>>,
+}
+
+// The file is only used with the volume's lock held
+unsafe impl Send for FatFile {}
+
+impl OpenFile for FatFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _fs = self.lock.lock();
+        let mut done = 0;
+        while done < buf.len() {
+            match self.file.read(&mut buf[done..])? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        Ok(done)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> {
+        let _fs = self.lock.lock();
+        Ok(self.file.seek(pos)?)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), FsError> {
+        let _fs = self.lock.lock();
+        *self.stats.lock() = None;
+        self.file.write_all(buf)?;
+        // Write the new size into the directory entry now, into the block
+        // cache with the data, so a task killed before closing the file
+        // loses nothing the next sync would have saved
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), FsError> {
+        let _fs = self.lock.lock();
+        *self.stats.lock() = None;
+        let pos = self.file.seek(SeekFrom::Current(0))?;
+        let size = self.file.seek(SeekFrom::End(0))?;
+        if len < size {
+            self.file.seek(SeekFrom::Start(len))?;
+            truncate_file(&self.dev, &mut self.file)?;
+        } else {
+            // fatfs can't seek past the end: write the gap out
+            let zeros = [0u8; 512];
+            let mut left = len - size;
+            while left > 0 {
+                let n = left.min(zeros.len() as u64) as usize;
+                self.file.write_all(&zeros[..n])?;
+                left -= n as u64;
+            }
+        }
+        self.file.flush()?;
+        self.file.seek(SeekFrom::Start(pos.min(len)))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        let _fs = self.lock.lock();
+        self.file.flush()?;
+        cache::flush(&self.dev)
+    }
+}
+
+impl Drop for FatFile {
+    fn drop(&mut self) {
+        let _fs = self.lock.lock();
+        unsafe { ManuallyDrop::drop(&mut self.file) };
+    }
+}
+
+/// Check `seek_target` around the ends of a device.
+pub(super) fn seek_self_test() {
+    const SIZE: u64 = 1024;
+    const CASES: &[(u64, SeekFrom, Option<u64>)] = &[
+        (0, SeekFrom::End(0), Some(SIZE)),
+        (0, SeekFrom::End(1), Some(SIZE)),
+        (0, SeekFrom::End(-1), Some(SIZE - 1)),
+        (0, SeekFrom::End(-(SIZE as i64)), Some(0)),
+        (0, SeekFrom::End(-(SIZE as i64) - 1), None),
+        (0, SeekFrom::Current(-1), None),
+        (10, SeekFrom::Current(-10), Some(0)),
+        (10, SeekFrom::Current(-11), None),
+        (SIZE, SeekFrom::Current(0), Some(SIZE)),
+        (5, SeekFrom::Start(SIZE), Some(SIZE)),
+    ];
+    let failed = CASES.iter()
+        .filter(|&&(offset, pos, expected)| {
+            let got = seek_target(offset, SIZE, pos);
+            if got != expected {
+                crate::log_error!("fs", "Seek self-test: {:?} from {} gave {:?}, expected {:?}", pos, offset, got, expected);
+            }
+            got != expected
+        })
+        .count();
+    if failed == 0 {
+        crate::log_info!("fs", "Seek self-test passed ({} cases)", CASES.len());
+    } else {
+        crate::log_error!("fs", "Seek self-test FAILED ({} of {} cases)", failed, CASES.len());
+    }
+}
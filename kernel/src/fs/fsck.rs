@@ -0,0 +1,472 @@
+// =============================================================================
+// APRK OS - FAT Consistency Check
+// =============================================================================
+// Reads a FAT16/FAT32 volume's tables directly, bypassing fatfs, and
+// reports what a crash in the middle of a write can leave behind:
+//
+// - cross-linked clusters, reached from two chains (or twice from one)
+// - orphaned chains, allocated in the FAT but reached by no entry
+// - entries or chains pointing past the last cluster
+// - chains ending at a free or bad cluster instead of an end mark
+// - files whose size doesn't match the length of their chain
+//
+// Nothing is written. A `Walker` does the walk and records each problem
+// with where it was found (the directory entry's sector and offset), so a
+// repair can later work from the same `Problem`s.
+// =============================================================================
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::block::Device;
+use super::cache::{self, BLOCK_SIZE};
+use super::FsError;
+
+/// Entries in a FAT (normalized to FAT32 values)
+const FREE: u32 = 0;
+const BAD: u32 = 0x0FFF_FFF7;
+const END: u32 = 0x0FFF_FFF8;
+/// Bytes per directory entry
+const ENTRY_SIZE: usize = 32;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_LONG_NAME: u8 = 0x0F;
+/// Deepest directory nesting walked
+const MAX_DEPTH: usize = 64;
+
+/// Where the parts of a volume are, from its boot sector. Sectors are
+/// counted from the start of the volume.
+struct Layout {
+    /// Device holding the volume, from its boot sector on
+    dev: Device,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat32: bool,
+    root: Root,
+    data_start: u64,
+    /// Data clusters; valid numbers are 2 to `clusters + 1`
+    clusters: u32,
+}
+
+enum Root {
+    /// FAT16: a fixed run of sectors before the data area
+    Fixed { start: u64, sectors: u64 },
+    /// FAT32: a cluster chain like any directory
+    Cluster(u32),
+}
+
+impl Layout {
+    /// Read the boot sector of the volume on `dev`.
+    fn read(dev: &Device) -> Result<Self, FsError> {
+        let mut boot = [0u8; BLOCK_SIZE];
+        cache::read_block(dev, 0, &mut boot)?;
+        let u16_at = |i: usize| u16::from_le_bytes([boot[i], boot[i + 1]]) as u64;
+        let u32_at = |i: usize| u32::from_le_bytes([boot[i], boot[i + 1], boot[i + 2], boot[i + 3]]) as u64;
+
+        if boot[510..512] != [0x55, 0xAA] {
+            return Err(FsError::Corrupt);
+        }
+        // Blocks of the cache are sectors only at 512 bytes
+        if u16_at(11) != BLOCK_SIZE as u64 {
+            return Err(FsError::Unsupported);
+        }
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(14);
+        let fats = boot[16] as u64;
+        let root_entries = u16_at(17);
+        let total = if u16_at(19) != 0 { u16_at(19) } else { u32_at(32) };
+        let fat_sectors = if u16_at(22) != 0 { u16_at(22) } else { u32_at(36) };
+        if sectors_per_cluster == 0 || fats == 0 || fat_sectors == 0 {
+            return Err(FsError::Corrupt);
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(BLOCK_SIZE as u64);
+        let fat_start = reserved;
+        let root_start = fat_start + fats * fat_sectors;
+        let data_start = root_start + root_sectors;
+        let clusters = total.checked_sub(data_start).ok_or(FsError::Corrupt)? / sectors_per_cluster;
+        // The cluster count alone decides the FAT type
+        let (fat32, root) = match clusters {
+            0..4085 => return Err(FsError::Unsupported), // FAT12
+            4085..65525 => (false, Root::Fixed { start: root_start, sectors: root_sectors }),
+            _ => (true, Root::Cluster(u32_at(44) as u32)),
+        };
+        // Each entry must fit in the FAT
+        let entry_bytes = if fat32 { 4 } else { 2 };
+        if (clusters + 2) * entry_bytes > fat_sectors * BLOCK_SIZE as u64 {
+            return Err(FsError::Corrupt);
+        }
+        Ok(Self { dev: dev.clone(), sectors_per_cluster, fat_start, fat32, root, data_start, clusters: clusters as u32 })
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), FsError> {
+        cache::read_block(&self.dev, sector, buf)
+    }
+
+    /// First sector of data cluster `cluster`.
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        self.sectors_per_cluster * BLOCK_SIZE as u64
+    }
+
+    fn in_range(&self, cluster: u32) -> bool {
+        (2..self.clusters + 2).contains(&cluster)
+    }
+
+    /// The first FAT, with FAT16 end and bad marks widened to FAT32's.
+    fn read_fat(&self) -> Result<Vec<u32>, FsError> {
+        let count = self.clusters as usize + 2;
+        let mut fat = Vec::new();
+        fat.try_reserve_exact(count).map_err(|_| FsError::OutOfMemory)?;
+        let mut sector = [0u8; BLOCK_SIZE];
+        let mut next = self.fat_start;
+        while fat.len() < count {
+            self.read_sector(next, &mut sector)?;
+            next += 1;
+            if self.fat32 {
+                fat.extend(sector.as_chunks::<4>().0.iter().map(|&e| u32::from_le_bytes(e) & 0x0FFF_FFFF));
+            } else {
+                fat.extend(sector.as_chunks::<2>().0.iter().map(|&e| match u16::from_le_bytes(e) as u32 {
+                    entry @ 0xFFF7.. => entry | 0x0FFF_0000,
+                    entry => entry,
+                }));
+            }
+        }
+        fat.truncate(count);
+        Ok(fat)
+    }
+}
+
+/// A directory entry a problem was found at.
+#[derive(Debug, Clone)]
+pub struct EntryRef {
+    pub path: String,
+    /// Volume sector holding the entry, and its byte offset there
+    pub sector: u64,
+    pub offset: usize,
+}
+
+/// One inconsistency found by `check`.
+#[derive(Debug, Clone)]
+pub enum Problem {
+    /// `entry`'s chain reaches `cluster`, which an earlier chain (or this
+    /// one) already did
+    CrossLinked { entry: EntryRef, cluster: u32 },
+    /// A chain of `clusters` clusters from `start` that no entry reaches
+    Orphaned { start: u32, clusters: u32 },
+    /// `entry` or its chain points at `cluster`, past the last cluster
+    OutOfRange { entry: EntryRef, cluster: u32 },
+    /// `entry`'s chain ends at `cluster`, a free or bad one
+    BrokenChain { entry: EntryRef, cluster: u32 },
+    /// `entry`'s size needs a different number of clusters than its
+    /// chain has
+    SizeMismatch { entry: EntryRef, size: u32, clusters: u32 },
+}
+
+/// Kinds of `Problem`, for the summary
+pub const PROBLEM_KINDS: [&str; 5] = [
+    "cross-linked clusters",
+    "orphaned chains",
+    "clusters past the end",
+    "broken chains",
+    "size mismatches",
+];
+
+impl Problem {
+    /// Index of this problem's kind in `PROBLEM_KINDS`.
+    pub fn kind(&self) -> usize {
+        match self {
+            Problem::CrossLinked { .. } => 0,
+            Problem::Orphaned { .. } => 1,
+            Problem::OutOfRange { .. } => 2,
+            Problem::BrokenChain { .. } => 3,
+            Problem::SizeMismatch { .. } => 4,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::CrossLinked { entry, cluster } =>
+                write!(f, "{}: cluster {} is cross-linked", entry.path, cluster),
+            Problem::Orphaned { start, clusters } =>
+                write!(f, "orphaned chain of {} cluster(s) at {}", clusters, start),
+            Problem::OutOfRange { entry, cluster } =>
+                write!(f, "{}: points at cluster {}, past the end", entry.path, cluster),
+            Problem::BrokenChain { entry, cluster } =>
+                write!(f, "{}: chain ends at unallocated cluster {}", entry.path, cluster),
+            Problem::SizeMismatch { entry, size, clusters } =>
+                write!(f, "{}: size {} in a chain of {} cluster(s)", entry.path, size, clusters),
+        }
+    }
+}
+
+/// What `check` found.
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub files: usize,
+    pub dirs: usize,
+    /// Clusters reached from the directory tree, and clusters in all
+    pub used_clusters: u32,
+    pub clusters: u32,
+}
+
+impl Report {
+    /// Problems found of each kind, in `PROBLEM_KINDS` order.
+    pub fn counts(&self) -> [usize; PROBLEM_KINDS.len()] {
+        let mut counts = [0; PROBLEM_KINDS.len()];
+        for problem in &self.problems {
+            counts[problem.kind()] += 1;
+        }
+        counts
+    }
+}
+
+/// A walk over a volume's directory tree and FAT.
+struct Walker {
+    layout: Layout,
+    fat: Vec<u32>,
+    /// Clusters reached so far
+    seen: Vec<bool>,
+    report: Report,
+}
+
+/// A chain as far as it could be followed
+struct Chain {
+    clusters: u32,
+    /// Reached an end mark without a problem
+    complete: bool,
+}
+
+impl Walker {
+    fn new(layout: Layout) -> Result<Self, FsError> {
+        let fat = layout.read_fat()?;
+        let mut seen = Vec::new();
+        seen.try_reserve_exact(fat.len()).map_err(|_| FsError::OutOfMemory)?;
+        seen.resize(fat.len(), false);
+        let clusters = layout.clusters;
+        Ok(Self {
+            layout,
+            fat,
+            seen,
+            report: Report { problems: Vec::new(), files: 0, dirs: 0, used_clusters: 0, clusters },
+        })
+    }
+
+    /// Follow the chain of `entry` from `start`, marking its clusters.
+    fn follow(&mut self, entry: &EntryRef, start: u32) -> Chain {
+        let mut cluster = start;
+        let mut clusters = 0;
+        loop {
+            if !self.layout.in_range(cluster) {
+                self.report.problems.push(Problem::OutOfRange { entry: entry.clone(), cluster });
+                return Chain { clusters, complete: false };
+            }
+            if self.seen[cluster as usize] {
+                self.report.problems.push(Problem::CrossLinked { entry: entry.clone(), cluster });
+                return Chain { clusters, complete: false };
+            }
+            self.seen[cluster as usize] = true;
+            self.report.used_clusters += 1;
+            clusters += 1;
+            match self.fat[cluster as usize] {
+                END.. => return Chain { clusters, complete: true },
+                FREE | BAD => {
+                    self.report.problems.push(Problem::BrokenChain { entry: entry.clone(), cluster });
+                    return Chain { clusters, complete: false };
+                }
+                next => cluster = next,
+            }
+        }
+    }
+
+    /// Sectors of the first `len` clusters of the chain from `start`,
+    /// which `follow` has checked.
+    fn chain_sectors(&self, start: u32, len: u32) -> Vec<u64> {
+        let per_cluster = self.layout.sectors_per_cluster;
+        core::iter::successors(Some(start), |&c| Some(self.fat[c as usize]))
+            .take(len as usize)
+            .flat_map(|c| {
+                let first = self.layout.cluster_sector(c);
+                first..first + per_cluster
+            })
+            .collect()
+    }
+
+    /// Check the entries of the directory in `sectors`, and everything
+    /// under it.
+    fn walk_dir(&mut self, path: &str, sectors: Vec<u64>, depth: usize) -> Result<(), FsError> {
+        self.report.dirs += 1;
+        let mut names = LongName::new();
+        let mut buf = [0u8; BLOCK_SIZE];
+        for sector in sectors {
+            self.layout.read_sector(sector, &mut buf)?;
+            for offset in (0..BLOCK_SIZE).step_by(ENTRY_SIZE) {
+                let raw = &buf[offset..offset + ENTRY_SIZE];
+                match raw[0] {
+                    0x00 => return Ok(()), // No entries after this one
+                    0xE5 => {
+                        names.clear();
+                        continue;
+                    }
+                    _ => {}
+                }
+                let attr = raw[11];
+                if attr & 0x3F == ATTR_LONG_NAME {
+                    names.add(raw);
+                    continue;
+                }
+                let name = names.take(raw).unwrap_or_else(|| short_name(raw));
+                if attr & ATTR_VOLUME_ID != 0 || name == "." || name == ".." {
+                    continue;
+                }
+                let entry = EntryRef { path: format!("{}/{}", path.trim_end_matches('/'), name), sector, offset };
+                let start = (u16::from_le_bytes([raw[20], raw[21]]) as u32) << 16
+                    | u16::from_le_bytes([raw[26], raw[27]]) as u32;
+                let size = u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]);
+                if attr & ATTR_DIRECTORY != 0 {
+                    self.check_dir(entry, start, depth)?;
+                } else {
+                    self.check_file(entry, start, size);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_dir(&mut self, entry: EntryRef, start: u32, depth: usize) -> Result<(), FsError> {
+        if start == 0 {
+            // Only the FAT16 root lives outside the data area
+            self.report.problems.push(Problem::OutOfRange { entry, cluster: 0 });
+            return Ok(());
+        }
+        let chain = self.follow(&entry, start);
+        // A cross-linked directory may lead back up the tree
+        if chain.complete && depth < MAX_DEPTH {
+            let sectors = self.chain_sectors(start, chain.clusters);
+            self.walk_dir(&entry.path, sectors, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn check_file(&mut self, entry: EntryRef, start: u32, size: u32) {
+        self.report.files += 1;
+        let chain = match start {
+            0 => Chain { clusters: 0, complete: true },
+            _ => self.follow(&entry, start),
+        };
+        let needed = (size as u64).div_ceil(self.layout.cluster_bytes());
+        if chain.complete && chain.clusters as u64 != needed {
+            self.report.problems.push(Problem::SizeMismatch { entry, size, clusters: chain.clusters });
+        }
+    }
+
+    /// Report allocated clusters the tree didn't reach, a chain at a time.
+    fn find_orphans(&mut self) -> Result<(), FsError> {
+        let allocated = |fat: &[u32], seen: &[bool], c: usize| !seen[c] && fat[c] != FREE && fat[c] != BAD;
+        let count = self.fat.len();
+        // Unreached clusters another unreached cluster points at
+        let mut pointed = Vec::new();
+        pointed.try_reserve_exact(count).map_err(|_| FsError::OutOfMemory)?;
+        pointed.resize(count, false);
+        for c in 2..count {
+            let next = self.fat[c] as usize;
+            if allocated(&self.fat, &self.seen, c) && next < count {
+                pointed[next] = true;
+            }
+        }
+        // Chains from their heads first; what's left are loops
+        for heads_only in [true, false] {
+            for (c, &head) in pointed.iter().enumerate().skip(2) {
+                if !allocated(&self.fat, &self.seen, c) || (heads_only && head) {
+                    continue;
+                }
+                let mut clusters = 0;
+                let mut next = c;
+                while next < count && allocated(&self.fat, &self.seen, next) {
+                    self.seen[next] = true;
+                    clusters += 1;
+                    next = self.fat[next] as usize;
+                }
+                self.report.problems.push(Problem::Orphaned { start: c as u32, clusters });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Long file name pieces gathered from the entries before a short one.
+struct LongName {
+    units: [u16; 20 * 13],
+    /// Entries expected, and the short name checksum they carry
+    parts: usize,
+    checksum: u8,
+}
+
+impl LongName {
+    fn new() -> Self {
+        Self { units: [0; 20 * 13], parts: 0, checksum: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.parts = 0;
+    }
+
+    fn add(&mut self, raw: &[u8]) {
+        let seq = (raw[0] & 0x1F) as usize;
+        if seq == 0 || seq > 20 {
+            self.clear();
+            return;
+        }
+        if raw[0] & 0x40 != 0 {
+            self.parts = seq;
+            self.checksum = raw[13];
+            self.units[(seq - 1) * 13..seq * 13].fill(0xFFFF);
+        }
+        let at = (seq - 1) * 13;
+        for (i, pos) in [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30].into_iter().enumerate() {
+            self.units[at + i] = u16::from_le_bytes([raw[pos], raw[pos + 1]]);
+        }
+    }
+
+    /// The long name for short entry `raw`, if the pieces belong to it.
+    fn take(&mut self, raw: &[u8]) -> Option<String> {
+        let parts = core::mem::replace(&mut self.parts, 0);
+        let checksum = raw[..11].iter().fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b));
+        if parts == 0 || checksum != self.checksum {
+            return None;
+        }
+        let units = self.units[..parts * 13].iter().copied().take_while(|&u| u != 0 && u != 0xFFFF);
+        Some(char::decode_utf16(units).map(|c| c.unwrap_or('?')).collect())
+    }
+}
+
+/// The 8.3 name of entry `raw`, as "NAME.EXT".
+fn short_name(raw: &[u8]) -> String {
+    let text = |bytes: &[u8]| -> String {
+        bytes.trim_ascii_end().iter().map(|&b| if b.is_ascii_graphic() { b as char } else { '?' }).collect()
+    };
+    let (base, ext) = (text(&raw[..8]), text(&raw[8..11]));
+    if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+}
+
+/// Check the FAT volume on `dev`, a partition or whole disk. The caller
+/// keeps anything else from writing to it meanwhile.
+pub fn check(dev: &Device) -> Result<Report, FsError> {
+    let mut walker = Walker::new(Layout::read(dev)?)?;
+    match walker.layout.root {
+        Root::Fixed { start, sectors } => walker.walk_dir("/", (start..start + sectors).collect(), 0)?,
+        Root::Cluster(cluster) => {
+            // The boot sector stands in for the root's entry
+            let entry = EntryRef { path: String::from("/"), sector: 0, offset: 44 };
+            walker.check_dir(entry, cluster, 0)?;
+        }
+    }
+    walker.find_orphans()?;
+    Ok(walker.report)
+}
@@ -1,128 +1,525 @@
+// =============================================================================
+// APRK OS - Filesystem
+// =============================================================================
+// Path-based file operations for the shell, loader and syscalls. Paths
+// are normalized, then handed to whichever filesystem is mounted there
+// (see vfs.rs). The first FAT volume found is mounted at "/"; others can
+// be mounted on directories with `mount`. The tar ramdisk, loaded by the
+// bootloader or built in, is mounted read-only at /initrd. Without a FAT
+// volume, the root is a writable RAM overlay on the ramdisk (ramfs.rs).
+// =============================================================================
+
+use alloc::boxed::Box;
 use alloc::sync::Arc;
-use spin::Mutex;
-use fatfs::{FileSystem, FsOptions, SeekFrom, Read};
-use crate::drivers::virtio_blk;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::block;
 
-pub struct BlockDeviceWrapper;
+pub use fatfs::SeekFrom;
 
-impl fatfs::IoBase for BlockDeviceWrapper {
-    type Error = ();
-}
+pub mod cache;
+pub mod fat;
+pub mod fsck;
+pub mod partition;
+pub mod path;
+pub mod ramfs;
+pub mod readahead;
+pub mod selftest;
+pub mod tar;
+pub mod vfs;
 
-impl fatfs::Read for BlockDeviceWrapper {
-    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
-        Err(())
+pub fn init() {
+    path::self_test();
+    fat::seek_self_test();
+    tar::self_test();
+    ramfs::self_test();
+    partition::scan();
+    let (archive, source) = tar::ramdisk();
+    match mount_fat() {
+        Some((source, fat)) => {
+            let _ = vfs::mount("/", &source, Arc::new(fat));
+        }
+        // Still somewhere to write to, if only until the next reboot
+        None => {
+            crate::log_warn!("fs", "No FAT volume, the root is the ramdisk in RAM");
+            let _ = vfs::mount("/", "ramfs", Arc::new(ramfs::RamFs::new(Box::new(tar::TarFs::new(archive)))));
+        }
+    }
+    match tar::verify(archive) {
+        Ok(files) => crate::log_info!("fs", "Ramdisk ({}): {} files, {} KB", source, files, archive.len() / 1024),
+        Err(offset) => crate::log_error!("fs", "Ramdisk corrupt at byte {}, files after it are missing", offset),
     }
+    let _ = vfs::mount("/initrd", source, Arc::new(tar::TarFs::new(archive)));
 }
 
-// better approach: implement a seekable wrapper that tracks offset
-pub struct SeekableBlockDevice {
-    offset: u64,
+/// Mount the first FAT partition on the first disk, or the whole disk if
+/// it has none (or it won't mount). Returns its name too.
+fn mount_fat() -> Option<(String, fat::FatFs)> {
+    let disk = block::disks().into_iter().next()?;
+    let partition = partition::partitions(&disk.name).into_iter().find(partition::Partition::is_fat);
+    if let Some(device) = partition.and_then(|p| block::get(&p.name())) {
+        crate::log_info!("fs", "Mounting FAT from partition {}", device.name);
+        if let Ok(fat) = fat::FatFs::mount(device.clone()) {
+            return Some((device.name, fat));
+        }
+        crate::log_warn!("fs", "Partition {} won't mount, trying the whole disk", device.name);
+    }
+    let name = disk.name.clone();
+    fat::FatFs::mount(disk).ok().map(|fat| (name, fat))
 }
 
-impl SeekableBlockDevice {
-    pub fn new() -> Self {
-        Self { offset: 0 }
+/// Mount the FAT filesystem on block device `source` (`blk1`, `blk1p1`,
+/// `ram0`, see `block::get`) at the directory `point`.
+pub fn mount(source: &str, point: &str) -> Result<(), FsError> {
+    let point = absolute(point);
+    if !stat(&point)?.is_dir {
+        return Err(FsError::NotADirectory);
     }
+    let device = block::get(source).ok_or(FsError::NoDevice)?;
+    // Checked before mounting: a second FatFs on the same blocks would
+    // write over the first one's metadata when dropped
+    vfs::check_mountable(&point, source)?;
+    vfs::mount(&point, source, Arc::new(fat::FatFs::mount(device)?))
 }
 
-impl fatfs::IoBase for SeekableBlockDevice {
-    type Error = ();
+/// Unmount the filesystem mounted at `point`, which must have no open
+/// files or mounts inside it.
+pub fn umount(point: &str) -> Result<(), FsError> {
+    vfs::umount(&absolute(point))
 }
 
-impl fatfs::Read for SeekableBlockDevice {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let mut read_bytes = 0;
-        let block_size = 512u64;
-        
-        while read_bytes < buf.len() {
-            let start_block = (self.offset / block_size) as usize;
-            let offset_in_block = (self.offset % block_size) as usize;
-            
-            let mut temp_buf = [0u8; 512];
-            virtio_blk::read_block(start_block, &mut temp_buf)?;
-            
-            let remaining_in_block = block_size as usize - offset_in_block;
-            let remaining_in_buf = buf.len() - read_bytes;
-            let to_copy = core::cmp::min(remaining_in_block, remaining_in_buf);
-            
-            buf[read_bytes..read_bytes + to_copy].copy_from_slice(&temp_buf[offset_in_block..offset_in_block + to_copy]);
-            
-            read_bytes += to_copy;
-            self.offset += to_copy as u64;
-            
-            // If we didn't fill the block, we are done
-            if to_copy < remaining_in_block { break; }
+/// Why a filesystem operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// No such file or directory
+    NotFound,
+    /// A path component that should be a directory is a file
+    NotADirectory,
+    /// A file was needed but the path names a directory
+    IsADirectory,
+    /// Directory still has entries
+    NotEmpty,
+    /// Something already exists at the path
+    AlreadyExists,
+    /// The disk is write-protected, or the filesystem can't be written
+    ReadOnlyFs,
+    /// No filesystem mounted
+    NotMounted,
+    /// There is no disk
+    NoDevice,
+    /// A filesystem to unmount still has files open or other filesystems
+    /// mounted in it, or a device to mount is already mounted
+    Busy,
+    /// The path names the root directory where a file is needed
+    InvalidPath,
+    /// Rename between two different filesystems
+    CrossMount,
+    /// A path component is longer than `path::MAX_NAME`
+    NameTooLong,
+    /// A name holds a character the filesystem can't store
+    InvalidName,
+    /// Source and destination are the same file
+    SameFile,
+    /// No free clusters left
+    NoSpace,
+    /// The kernel heap can't hold what the operation needs
+    OutOfMemory,
+    /// The filesystem's own structures don't make sense
+    Corrupt,
+    /// Something the filesystem or this kernel doesn't do
+    Unsupported,
+    /// Not an open file descriptor, or not open for this
+    BadDescriptor,
+    /// A request that makes no sense, such as a bad seek origin
+    InvalidArgument,
+    /// Every file descriptor or open file slot is taken
+    TooManyOpen,
+    /// A syscall's buffer isn't mapped in the task's address space, or
+    /// not for the access it needs
+    BadAddress,
+    /// The disk failed reading or writing `block`, or some other request
+    /// (`None`)
+    Io { block: Option<u64>, write: bool },
+}
+
+impl FsError {
+    /// An I/O error not tied to one block.
+    pub const IO: FsError = FsError::Io { block: None, write: false };
+
+    /// The POSIX errno value for this error, which syscalls return
+    /// negated.
+    pub fn errno(self) -> u64 {
+        match self {
+            FsError::NotFound => 2,                         // ENOENT
+            FsError::Io { .. } => 5,                        // EIO
+            FsError::NoDevice => 6,                         // ENXIO
+            FsError::BadDescriptor => 9,                    // EBADF
+            FsError::OutOfMemory => 12,                     // ENOMEM
+            FsError::BadAddress => 14,                      // EFAULT
+            FsError::Busy => 16,                            // EBUSY
+            FsError::AlreadyExists => 17,                   // EEXIST
+            FsError::CrossMount => 18,                      // EXDEV
+            FsError::NotMounted => 19,                      // ENODEV
+            FsError::NotADirectory => 20,                   // ENOTDIR
+            FsError::IsADirectory => 21,                    // EISDIR
+            FsError::InvalidPath | FsError::InvalidName
+                | FsError::SameFile | FsError::InvalidArgument => 22, // EINVAL
+            FsError::TooManyOpen => 24,                     // EMFILE
+            FsError::NoSpace => 28,                         // ENOSPC
+            FsError::ReadOnlyFs => 30,                      // EROFS
+            FsError::NameTooLong => 36,                     // ENAMETOOLONG
+            FsError::NotEmpty => 39,                        // ENOTEMPTY
+            FsError::Unsupported => 95,                     // EOPNOTSUPP
+            FsError::Corrupt => 117,                        // EUCLEAN
         }
-        
-        Ok(read_bytes)
     }
 }
 
-impl fatfs::Seek for SeekableBlockDevice {
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
-        match pos {
-            SeekFrom::Start(off) => self.offset = off,
-            SeekFrom::Current(off) => self.offset = (self.offset as i64 + off) as u64,
-            SeekFrom::End(_off) => {
-                return Err(());
-            }
+impl core::fmt::Display for FsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        if let FsError::Io { block: Some(block), write } = self {
+            let op = if *write { "writing" } else { "reading" };
+            return write!(f, "I/O error {} sector {}", op, block);
         }
-        Ok(self.offset)
+        f.write_str(match self {
+            FsError::NotFound => "No such file or directory",
+            FsError::NotADirectory => "Not a directory",
+            FsError::IsADirectory => "Is a directory",
+            FsError::NotEmpty => "Directory not empty",
+            FsError::AlreadyExists => "Already exists",
+            FsError::ReadOnlyFs => "Read-only filesystem",
+            FsError::NotMounted => "No filesystem mounted",
+            FsError::Busy => "Device or resource busy",
+            FsError::NoDevice => "No disk",
+            FsError::InvalidPath => "Invalid path",
+            FsError::CrossMount => "Cannot move between filesystems",
+            FsError::NameTooLong => "File name too long",
+            FsError::InvalidName => "Invalid character in file name",
+            FsError::SameFile => "Source and destination are the same file",
+            FsError::NoSpace => "No space left on disk",
+            FsError::OutOfMemory => "Out of memory",
+            FsError::Corrupt => "Filesystem is corrupted",
+            FsError::Unsupported => "Operation not supported",
+            FsError::BadDescriptor => "Bad file descriptor",
+            FsError::InvalidArgument => "Invalid argument",
+            FsError::TooManyOpen => "Too many open files",
+            FsError::BadAddress => "Bad address",
+            FsError::Io { .. } => "I/O error",
+        })
     }
 }
 
-impl fatfs::Write for SeekableBlockDevice {
-    fn write(&mut self, _buf: &[u8]) -> Result<usize, Self::Error> {
-        Err(())
+/// One entry of a directory listing.
+#[derive(Debug, Clone)]
+pub struct DirEntryInfo {
+    pub name: String,
+    /// Bytes (0 for directories)
+    pub size: u64,
+    pub is_dir: bool,
+    pub modified: fatfs::DateTime,
+}
+
+impl DirEntryInfo {
+    /// A directory without a modification time of its own.
+    pub fn directory(name: String) -> Self {
+        let epoch = fatfs::DateTime::new(fatfs::Date::new(1980, 1, 1), fatfs::Time::new(0, 0, 0, 0));
+        Self { name, size: 0, is_dir: true, modified: epoch }
+    }
+}
+
+/// Size and free space of a filesystem.
+#[derive(Debug, Clone)]
+pub struct FsStats {
+    /// Volume label (may be empty)
+    pub label: String,
+    /// Bytes per cluster
+    pub cluster_size: u64,
+    pub total_clusters: u64,
+    pub free_clusters: u64,
+}
+
+impl FsStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.total_clusters * self.cluster_size
     }
-    fn flush(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free_clusters * self.cluster_size
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.total_bytes() - self.free_bytes()
     }
 }
 
-pub static FS: Mutex<Option<FileSystem<SeekableBlockDevice, fatfs::DefaultTimeProvider, fatfs::LossyOemCpConverter>>> = Mutex::new(None);
+/// Directory relative paths are taken from. Tasks have no working
+/// directory of their own yet.
+const CWD: &str = "/";
 
-pub fn init() {
-    let dev = SeekableBlockDevice::new();
-    match FileSystem::new(dev, FsOptions::new()) {
-        Ok(fs) => {
-            crate::println!("[fs] FAT32 FileSystem initialized.");
-            *FS.lock() = Some(fs);
+/// `path` in normalized absolute form.
+fn absolute(path: &str) -> String {
+    path::normalize(CWD, path)
+}
+
+/// The filesystem `path` is on, and the path within it.
+fn resolve(path: &str) -> Result<(Arc<dyn vfs::Vfs>, String), FsError> {
+    let path = absolute(path);
+    path::check_names(&path)?;
+    vfs::resolve(&path)
+}
+
+/// The entries of the directory at `path`, in on-disk order, followed by
+/// any mount points in it.
+pub fn list_dir(path: &str) -> Result<Vec<DirEntryInfo>, FsError> {
+    let path = absolute(path);
+    path::check_names(&path)?;
+    let (fs, inner) = vfs::resolve(&path)?;
+    let mut entries = fs.readdir(&inner)?;
+    for name in vfs::child_mounts(&path) {
+        if !entries.iter().any(|e| e.name.eq_ignore_ascii_case(&name)) {
+            entries.push(DirEntryInfo::directory(name));
+        }
+    }
+    Ok(entries)
+}
+
+/// Details of the file or directory at `path`.
+pub fn stat(path: &str) -> Result<DirEntryInfo, FsError> {
+    let (fs, inner) = resolve(path)?;
+    fs.stat(&inner)
+}
+
+/// Size and free space of the filesystem `path` is on.
+pub fn statfs(path: &str) -> Result<FsStats, FsError> {
+    let (fs, _) = resolve(path)?;
+    fs.statfs()
+}
+
+/// Check the filesystem `path` is on for inconsistencies, read-only.
+pub fn check(path: &str) -> Result<fsck::Report, FsError> {
+    let (fs, _) = resolve(path)?;
+    fs.check()
+}
+
+/// An open file, read a piece at a time.
+pub struct FileHandle {
+    file: Box<dyn vfs::OpenFile>,
+    size: u64,
+    /// Keeps the filesystem mounted while the file is open (dropped after
+    /// `file`)
+    _fs: Arc<dyn vfs::Vfs>,
+}
+
+impl FileHandle {
+    /// Read from the current position into `buf`. Returns the bytes read,
+    /// 0 at the end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.file.read(buf)
+    }
+
+    /// Fill `buf` from the current position, failing if the file ends
+    /// first.
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), FsError> {
+        match self.file.read(buf)? {
+            n if n == buf.len() => Ok(()),
+            _ => Err(FsError::IO),
         }
-        Err(e) => {
-            crate::println!("[fs] Failed to initialize FileSystem: {:?}", e);
+    }
+
+    /// Move the position, returning the new one.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> {
+        self.file.seek(pos)
+    }
+
+    /// Write all of `buf` at the current position.
+    pub fn write(&mut self, buf: &[u8]) -> Result<(), FsError> {
+        self.file.write(buf)
+    }
+
+    /// Cut the file to `len` bytes, or extend it with zeros. The position
+    /// stays where it was (or at the new end, if that's before it).
+    pub fn truncate(&mut self, len: u64) -> Result<(), FsError> {
+        self.file.truncate(len)
+    }
+
+    /// Get what has been written onto the disk.
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        self.file.flush()
+    }
+
+    /// Bytes in the file when it was opened.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Open the file at `path` for reading.
+pub fn open(path: &str) -> Result<FileHandle, FsError> {
+    let (fs, inner) = resolve(path)?;
+    let info = fs.stat(&inner)?;
+    if info.is_dir {
+        return Err(FsError::IsADirectory);
+    }
+    Ok(FileHandle { file: fs.open(&inner)?, size: info.size, _fs: fs })
+}
+
+/// Open the file at `path` for reading and writing, creating it if it
+/// doesn't exist. With `truncate`, an existing file is emptied.
+pub fn open_write(path: &str, truncate: bool) -> Result<FileHandle, FsError> {
+    let (fs, inner) = resolve(path)?;
+    let size = match fs.stat(&inner) {
+        Ok(info) if info.is_dir => return Err(FsError::IsADirectory),
+        Ok(info) if !truncate => info.size,
+        _ => 0,
+    };
+    Ok(FileHandle { file: fs.open_write(&inner, truncate)?, size, _fs: fs })
+}
+
+/// Whether `a` and `b` name the same file: on the same filesystem, in the
+/// same directory, and found as the same entry (names match whatever their
+/// case, and a long name matches its 8.3 alias).
+fn same_file(a: &str, b: &str) -> bool {
+    let (Ok((a_fs, a_inner)), Ok((b_fs, b_inner))) = (resolve(a), resolve(b)) else {
+        return false;
+    };
+    let parent = |p: &str| String::from(p.rsplit_once('/').map_or("", |(dir, _)| dir));
+    if !Arc::ptr_eq(&a_fs, &b_fs) || !parent(&a_inner).eq_ignore_ascii_case(&parent(&b_inner)) {
+        return false;
+    }
+    match (a_fs.stat(&a_inner), b_fs.stat(&b_inner)) {
+        (Ok(a), Ok(b)) => a.name == b.name,
+        _ => false,
+    }
+}
+
+/// Copy the file at `src` to `dst`, replacing it if it exists. If `dst` is
+/// a directory, the copy goes in it under the same name. Returns the
+/// bytes copied.
+pub fn copy(src: &str, dst: &str) -> Result<u64, FsError> {
+    copy_with_progress(src, dst, |_| {})
+}
+
+/// `copy`, calling `progress` with the bytes copied so far after every
+/// chunk.
+///
+/// A copy that fails partway removes the destination rather than leave a
+/// truncated file behind.
+pub fn copy_with_progress(src: &str, dst: &str, mut progress: impl FnMut(u64)) -> Result<u64, FsError> {
+    let mut dst = absolute(dst);
+    if stat(&dst).is_ok_and(|info| info.is_dir) {
+        let src = absolute(src);
+        let name = path::split(&src).1.ok_or(FsError::InvalidPath)?;
+        dst = path::normalize(&dst, name);
+    }
+    if same_file(src, &dst) {
+        return Err(FsError::SameFile);
+    }
+    let mut from = open(src)?;
+    let mut to = open_write(&dst, true)?;
+    let mut chunk = [0u8; 4096];
+    let mut copied = 0;
+    let result = loop {
+        let n = match from.read(&mut chunk) {
+            Ok(0) => break to.flush(),
+            Ok(n) => n,
+            Err(e) => break Err(e),
+        };
+        if let Err(e) = to.write(&chunk[..n]) {
+            break Err(e);
         }
+        copied += n as u64;
+        progress(copied);
+    };
+    drop(to);
+    if let Err(e) = result {
+        let _ = remove(&dst);
+        return Err(e);
     }
+    Ok(copied)
 }
 
-pub fn list_root() {
-    if let Some(ref fs) = *FS.lock() {
-        let root = fs.root_dir();
-        crate::println!("[fs] Root directory content:");
-        for entry in root.iter() {
-            let entry = entry.unwrap();
-            crate::println!("  {} ({})", entry.file_name(), if entry.is_dir() { "DIR" } else { "FILE" });
+/// Read the whole file at `path`. Only for files known to be small: use
+/// `open` to read anything else a piece at a time.
+///
+/// Fails with `OutOfMemory` if the kernel heap can't hold it.
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let mut file = open(path)?;
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = file.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        // Files can be written from user space: don't let one take the
+        // kernel down by being too big to hold
+        if buf.try_reserve(n).is_err() {
+            crate::log_warn!("fs", "Out of memory reading {} ({} bytes so far)", path, buf.len());
+            return Err(FsError::OutOfMemory);
         }
+        buf.extend_from_slice(&chunk[..n]);
     }
+    Ok(buf)
 }
 
-pub fn read_file(path: &str) -> Option<alloc::vec::Vec<u8>> {
-    if let Some(ref fs) = *FS.lock() {
-        let root = fs.root_dir();
-        match root.open_file(path) {
-            Ok(mut file) => {
-                let mut buf = alloc::vec::Vec::new();
-                let mut chunk = [0u8; 512];
-                while let Ok(n) = file.read(&mut chunk) {
-                    if n == 0 { break; }
-                    buf.extend_from_slice(&chunk[..n]);
-                }
-                Some(buf)
-            }
-            Err(_) => None,
+/// SHA-256 digest of the file at `path`, read a chunk at a time so files
+/// of any size can be hashed.
+pub fn hash_file(path: &str) -> Result<[u8; crate::crypto::sha256::DIGEST_SIZE], FsError> {
+    let mut file = open(path)?;
+    let mut hash = crate::crypto::sha256::Sha256::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        match file.read(&mut chunk)? {
+            0 => return Ok(hash.finish()),
+            n => hash.update(&chunk[..n]),
         }
-    } else {
-        None
     }
 }
+
+/// Make sure everything written so far is on the disk: every mounted
+/// filesystem's, then blocks written to devices directly.
+pub fn sync() -> Result<(), FsError> {
+    vfs::sync_all().and(cache::flush_all())
+}
+
+/// Replace the contents of the file at `path` with `data`, creating it if
+/// it doesn't exist.
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let (fs, inner) = resolve(path)?;
+    fs.write(&inner, data, false)
+}
+
+/// Add `data` to the end of the file at `path`, creating it if it doesn't
+/// exist.
+pub fn append_file(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let (fs, inner) = resolve(path)?;
+    fs.write(&inner, data, true)
+}
+
+/// Create an empty file at `path`.
+pub fn create_file(path: &str) -> Result<(), FsError> {
+    let (fs, inner) = resolve(path)?;
+    fs.create(&inner)
+}
+
+/// Create a directory at `path`.
+pub fn mkdir(path: &str) -> Result<(), FsError> {
+    let (fs, inner) = resolve(path)?;
+    fs.mkdir(&inner)
+}
+
+/// Delete the file or empty directory at `path`.
+pub fn remove(path: &str) -> Result<(), FsError> {
+    let (fs, inner) = resolve(path)?;
+    fs.remove(&inner)
+}
+
+/// Move the file or directory at `old` to `new`, which must not exist.
+pub fn rename(old: &str, new: &str) -> Result<(), FsError> {
+    let (old_fs, old_inner) = resolve(old)?;
+    let (new_fs, new_inner) = resolve(new)?;
+    if !Arc::ptr_eq(&old_fs, &new_fs) {
+        return Err(FsError::CrossMount);
+    }
+    old_fs.rename(&old_inner, &new_inner)
+}
@@ -0,0 +1,132 @@
+// =============================================================================
+// APRK OS - MBR Partition Table
+// =============================================================================
+// Disk images made with a normal partition table keep the filesystem in a
+// partition, not at block 0. `scan` reads block 0 of each disk at startup
+// and, if it holds an MBR, registers each used primary entry as a block
+// device of its own (see `block::register_partition`): disk `blk0` gets
+// `blk0p1`, `blk0p2`, ...
+//
+// A FAT "superfloppy" image has a boot sector at block 0 with the same
+// 0x55AA signature, so a table only counts if its entries are sane: a
+// known status byte, and every used entry inside the disk.
+// =============================================================================
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::cache::{self, BLOCK_SIZE};
+use crate::block::{self, BlockDevice};
+
+/// Primary partition entries in an MBR
+pub const MAX_PARTITIONS: usize = 4;
+/// Offset of the partition table in block 0
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+
+/// One primary partition.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// Name of the disk holding it
+    pub disk: String,
+    /// Number in the table, from 1
+    pub number: usize,
+    /// MBR partition type byte
+    pub kind: u8,
+    pub bootable: bool,
+    /// First block
+    pub start: u64,
+    /// Length in blocks
+    pub blocks: u64,
+}
+
+impl Partition {
+    /// Name, such as `blk0p1`.
+    pub fn name(&self) -> String {
+        format!("{}p{}", self.disk, self.number)
+    }
+
+    /// Whether the type byte says FAT.
+    pub fn is_fat(&self) -> bool {
+        matches!(self.kind, 0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E)
+    }
+
+    /// Name of the partition type.
+    pub fn kind_name(&self) -> &'static str {
+        match self.kind {
+            0x01 => "FAT12",
+            0x04 | 0x06 | 0x0E => "FAT16",
+            0x0B | 0x0C => "FAT32",
+            0x05 | 0x0F => "Extended",
+            0x07 => "NTFS/exFAT",
+            0x82 => "Linux swap",
+            0x83 => "Linux",
+            0xEE => "GPT protective",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// Every partition found by `scan`, in disk and table order
+static PARTITIONS: Mutex<Vec<Partition>> = Mutex::new(Vec::new());
+
+/// The partitions in `block`, if it holds a valid MBR for `disk` of
+/// `capacity` blocks.
+fn parse(block: &[u8; BLOCK_SIZE], disk: &str, capacity: u64) -> Option<Vec<Partition>> {
+    if block[510..512] != [0x55, 0xAA] {
+        return None;
+    }
+    let mut table = Vec::new();
+    for i in 0..MAX_PARTITIONS {
+        let entry = &block[TABLE_OFFSET + i * ENTRY_SIZE..][..ENTRY_SIZE];
+        let status = entry[0];
+        if status != 0x00 && status != 0x80 {
+            return None;
+        }
+        let kind = entry[4];
+        let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64;
+        let blocks = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) as u64;
+        if kind == 0 || blocks == 0 {
+            continue;
+        }
+        if start == 0 || start + blocks > capacity {
+            return None;
+        }
+        table.push(Partition { disk: String::from(disk), number: i + 1, kind, bootable: status == 0x80, start, blocks });
+    }
+    (!table.is_empty()).then_some(table)
+}
+
+/// Read the partition table from block 0 of each disk and register its
+/// partitions.
+pub fn scan() {
+    for disk in block::disks() {
+        let mut sector = [0u8; BLOCK_SIZE];
+        if cache::read_block(&disk, 0, &mut sector).is_err() {
+            continue;
+        }
+        let Some(table) = parse(&sector, &disk.name, disk.num_blocks()) else {
+            crate::log_info!("fs", "{}: No partition table, using the whole disk", disk.name);
+            continue;
+        };
+        for partition in table {
+            crate::log_info!("fs", "Partition {}: {} ({:#04x}), {} blocks at {}",
+                partition.name(), partition.kind_name(), partition.kind, partition.blocks, partition.start);
+            if block::register_partition(&partition.name(), &disk, partition.start, partition.blocks).is_some() {
+                PARTITIONS.lock().push(partition);
+            }
+        }
+    }
+}
+
+/// The partitions found by `scan` on disk `disk`, in table order. Empty
+/// for a disk without a partition table.
+pub fn partitions(disk: &str) -> Vec<Partition> {
+    PARTITIONS.lock().iter().filter(|p| p.disk == disk).cloned().collect()
+}
+
+/// The partition registered as `name`, if it's one `scan` found.
+pub fn find(name: &str) -> Option<Partition> {
+    PARTITIONS.lock().iter().find(|p| p.name() == name).cloned()
+}
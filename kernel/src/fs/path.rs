@@ -0,0 +1,95 @@
+// =============================================================================
+// APRK OS - Path Normalization
+// =============================================================================
+// Paths are resolved to a canonical absolute form before the filesystem
+// sees them: relative paths are taken from a base directory, "." and
+// empty components (repeated or trailing slashes) are dropped, and ".."
+// removes the component before it (".." at the root stays at the root).
+// This is pure string work, checked by `self_test` at boot.
+//
+// Names are compared case-insensitively by the filesystems, as FAT does;
+// nothing here changes their case.
+// =============================================================================
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::FsError;
+
+/// Longest file or directory name, in UTF-16 units (the FAT long name
+/// limit)
+pub const MAX_NAME: usize = 255;
+
+/// Refuse a path with a component longer than `MAX_NAME`, before any
+/// filesystem sees it.
+pub fn check_names(path: &str) -> Result<(), FsError> {
+    if path.split('/').any(|name| name.encode_utf16().count() > MAX_NAME) {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+/// `path` as an absolute path with no ".", ".." or empty components,
+/// taking relative paths from `base` (itself absolute). The root is "/".
+pub fn normalize(base: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    let start = if path.starts_with('/') { "" } else { base };
+    for part in start.split('/').chain(path.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            name => parts.push(name),
+        }
+    }
+    let mut out = String::with_capacity(path.len() + base.len() + 1);
+    for part in &parts {
+        out.push('/');
+        out.push_str(part);
+    }
+    if out.is_empty() {
+        out.push('/');
+    }
+    out
+}
+
+/// Split a normalized path into its parent directory's components and
+/// its last component. The root has no last component.
+pub fn split(path: &str) -> (impl Iterator<Item = &str>, Option<&str>) {
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    (parent.split('/').filter(|part| !part.is_empty()), (!name.is_empty()).then_some(name))
+}
+
+/// Check `normalize` against known answers.
+pub fn self_test() {
+    const CASES: &[(&str, &str, &str)] = &[
+        ("/", "", "/"),
+        ("/", "hello.txt", "/hello.txt"),
+        ("/", "/boot/config.txt", "/boot/config.txt"),
+        ("/", "boot//config.txt", "/boot/config.txt"),
+        ("/", "boot/./config.txt", "/boot/config.txt"),
+        ("/", "boot/dir/", "/boot/dir"),
+        ("/", "boot/dir/../config.txt", "/boot/config.txt"),
+        ("/", "../../x", "/x"),
+        ("/", "/..", "/"),
+        ("/", "./.", "/"),
+        ("/home/user", "notes", "/home/user/notes"),
+        ("/home/user", "../other/./f", "/home/other/f"),
+        ("/home/user", "/abs", "/abs"),
+        ("/home/user/", "..", "/home"),
+    ];
+    let mut failed = 0;
+    for &(base, path, expected) in CASES {
+        let got = normalize(base, path);
+        if got != expected {
+            crate::log_error!("fs", "Path self-test: {:?} from {:?} gave {:?}, expected {:?}",
+                path, base, got, expected);
+            failed += 1;
+        }
+    }
+    if failed == 0 {
+        crate::log_info!("fs", "Path self-test passed ({} cases)", CASES.len());
+    } else {
+        crate::log_error!("fs", "Path self-test FAILED ({} of {} cases)", failed, CASES.len());
+    }
+}
@@ -0,0 +1,418 @@
+// =============================================================================
+// APRK OS - RAM Overlay Filesystem
+// =============================================================================
+// A writable layer over a read-only filesystem (the tar ramdisk), mounted
+// as the root when there's no FAT volume. Files written or created live in
+// heap buffers keyed by path; lookups try those first and fall through to
+// the layer below. Deleting something that's in the lower layer leaves a
+// whiteout, hiding it and everything under it. Writing to a lower file
+// copies it up first.
+//
+// Nothing here survives a reboot.
+// =============================================================================
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::vfs::{OpenFile, Vfs};
+use super::{DirEntryInfo, FsError, FsStats, SeekFrom};
+
+/// Contents of a file in the overlay, shared with the files open on it
+type Data = Arc<Mutex<Vec<u8>>>;
+
+/// What the overlay holds at a path.
+#[derive(Clone)]
+enum Node {
+    File(Data),
+    Dir,
+    /// Deleted: hides the lower layer's entry and anything under it
+    Whiteout,
+}
+
+/// A writable overlay on a read-only filesystem.
+pub struct RamFs {
+    lower: Box<dyn Vfs>,
+    /// Keyed by normalized absolute path
+    nodes: Mutex<BTreeMap<String, Node>>,
+}
+
+/// The directory holding normalized `path`, and its name in it.
+fn parent(path: &str) -> Result<(&str, &str), FsError> {
+    match path.rsplit_once('/') {
+        Some((_, "")) | None => Err(FsError::InvalidPath),
+        Some(("", name)) => Ok(("/", name)),
+        Some(parent_and_name) => Ok(parent_and_name),
+    }
+}
+
+/// `name` inside the directory at `dir`.
+fn child(dir: &str, name: &str) -> String {
+    if dir == "/" {
+        alloc::format!("/{}", name)
+    } else {
+        alloc::format!("{}/{}", dir, name)
+    }
+}
+
+/// Listing entry for an overlay file.
+fn file_info(name: &str, data: &Data) -> DirEntryInfo {
+    let mut info = DirEntryInfo::directory(String::from(name));
+    info.is_dir = false;
+    info.size = data.lock().len() as u64;
+    info
+}
+
+impl RamFs {
+    pub fn new(lower: Box<dyn Vfs>) -> Self {
+        Self { lower, nodes: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// The overlay's node at `path`, if it has one.
+    fn node(&self, path: &str) -> Option<Node> {
+        self.nodes.lock().get(path).cloned()
+    }
+
+    /// Whether a whiteout at `path` or above hides it in the lower layer.
+    fn hidden(&self, path: &str) -> bool {
+        let nodes = self.nodes.lock();
+        let mut at = path;
+        loop {
+            if let Some(Node::Whiteout) = nodes.get(at) {
+                return true;
+            }
+            match parent(at) {
+                Ok((up, _)) => at = up,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Details of `path` from the lower layer, unless it's hidden.
+    fn lower_stat(&self, path: &str) -> Result<DirEntryInfo, FsError> {
+        if self.hidden(path) {
+            return Err(FsError::NotFound);
+        }
+        self.lower.stat(path)
+    }
+
+    /// Check the directory `path` would be created in exists, and that
+    /// nothing is at `path` yet.
+    fn creatable(&self, path: &str) -> Result<(), FsError> {
+        let (dir, _) = parent(path)?;
+        if !self.stat(dir)?.is_dir {
+            return Err(FsError::NotADirectory);
+        }
+        match self.stat(path) {
+            Ok(_) => Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The overlay's copy of the file at `path`, copying it up from the
+    /// lower layer if it's only there.
+    fn copy_up(&self, path: &str) -> Result<Data, FsError> {
+        match self.node(path) {
+            Some(Node::File(data)) => return Ok(data),
+            Some(Node::Dir) => return Err(FsError::IsADirectory),
+            Some(Node::Whiteout) => return Err(FsError::NotFound),
+            None => {}
+        }
+        if self.lower_stat(path)?.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        let mut file = self.lower.open(path)?;
+        let mut contents = Vec::new();
+        let mut chunk = [0u8; 4096];
+        loop {
+            let n = file.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            contents.try_reserve(n).map_err(|_| FsError::OutOfMemory)?;
+            contents.extend_from_slice(&chunk[..n]);
+        }
+        let data = Arc::new(Mutex::new(contents));
+        self.nodes.lock().insert(String::from(path), Node::File(data.clone()));
+        Ok(data)
+    }
+
+    /// Delete `path` without checking it's an empty directory.
+    fn unlink(&self, path: &str) {
+        let in_lower = self.lower_stat(path).is_ok();
+        let mut nodes = self.nodes.lock();
+        if in_lower {
+            nodes.insert(String::from(path), Node::Whiteout);
+        } else {
+            nodes.remove(path);
+        }
+    }
+
+    /// Copy the directory tree at `old` to `new` in the overlay, then
+    /// delete it at `old`.
+    fn move_tree(&self, old: &str, new: &str) -> Result<(), FsError> {
+        if !self.stat(old)?.is_dir {
+            let data = self.copy_up(old)?;
+            self.nodes.lock().insert(String::from(new), Node::File(data));
+            self.unlink(old);
+            return Ok(());
+        }
+        self.nodes.lock().insert(String::from(new), Node::Dir);
+        for entry in self.readdir(old)? {
+            self.move_tree(&child(old, &entry.name), &child(new, &entry.name))?;
+        }
+        self.unlink(old);
+        Ok(())
+    }
+
+    /// Bytes of heap the overlay's files and names take up.
+    fn used_bytes(&self) -> u64 {
+        self.nodes.lock().iter()
+            .map(|(path, node)| {
+                let data = match node {
+                    Node::File(data) => data.lock().capacity(),
+                    _ => 0,
+                };
+                (path.capacity() + core::mem::size_of::<Node>() + data) as u64
+            })
+            .sum()
+    }
+}
+
+impl Vfs for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn stat(&self, path: &str) -> Result<DirEntryInfo, FsError> {
+        let Ok((_, name)) = parent(path) else {
+            return Ok(DirEntryInfo::directory(String::from("/")));
+        };
+        match self.node(path) {
+            Some(Node::File(data)) => Ok(file_info(name, &data)),
+            Some(Node::Dir) => Ok(DirEntryInfo::directory(String::from(name))),
+            Some(Node::Whiteout) => Err(FsError::NotFound),
+            None => self.lower_stat(path),
+        }
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn OpenFile>, FsError> {
+        match self.node(path) {
+            Some(Node::File(data)) => Ok(Box::new(RamFile { data, pos: 0, writable: false })),
+            Some(Node::Dir) => Err(FsError::IsADirectory),
+            Some(Node::Whiteout) => Err(FsError::NotFound),
+            None if self.hidden(path) => Err(FsError::NotFound),
+            None => self.lower.open(path),
+        }
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<DirEntryInfo>, FsError> {
+        if !self.stat(path)?.is_dir {
+            return Err(FsError::NotADirectory);
+        }
+        let mut entries = match self.hidden(path) {
+            true => Vec::new(),
+            false => self.lower.readdir(path).unwrap_or_default(),
+        };
+        let nodes = self.nodes.lock();
+        // Overlay entries replace or hide lower ones of the same name
+        let ours = |name: &str| nodes.get(&child(path, name));
+        entries.retain(|e| ours(&e.name).is_none());
+        let prefix = child(path, "");
+        for (key, node) in nodes.range(prefix.clone()..) {
+            let Some(name) = key.strip_prefix(&prefix) else {
+                break;
+            };
+            match node {
+                _ if name.contains('/') => {}
+                Node::File(data) => entries.push(file_info(name, data)),
+                Node::Dir => entries.push(DirEntryInfo::directory(String::from(name))),
+                Node::Whiteout => {}
+            }
+        }
+        Ok(entries)
+    }
+
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        let used = self.used_bytes();
+        let free = crate::mm::heap::stats().free as u64;
+        Ok(FsStats { label: String::new(), cluster_size: 1, total_clusters: used + free, free_clusters: free })
+    }
+
+    fn write(&self, path: &str, data: &[u8], append: bool) -> Result<(), FsError> {
+        let mut file = self.open_write(path, !append)?;
+        file.seek(SeekFrom::End(0))?;
+        file.write(data)
+    }
+
+    fn open_write(&self, path: &str, truncate: bool) -> Result<Box<dyn OpenFile>, FsError> {
+        let data = match self.stat(path) {
+            Ok(info) if info.is_dir => return Err(FsError::IsADirectory),
+            // A lower file about to be emptied needn't be copied
+            Ok(_) if truncate => {
+                let data = Arc::new(Mutex::new(Vec::new()));
+                self.nodes.lock().insert(String::from(path), Node::File(data.clone()));
+                data
+            }
+            Ok(_) => self.copy_up(path)?,
+            Err(FsError::NotFound) => {
+                self.create(path)?;
+                self.copy_up(path)?
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Box::new(RamFile { data, pos: 0, writable: true }))
+    }
+
+    fn create(&self, path: &str) -> Result<(), FsError> {
+        self.creatable(path)?;
+        self.nodes.lock().insert(String::from(path), Node::File(Arc::new(Mutex::new(Vec::new()))));
+        Ok(())
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), FsError> {
+        self.creatable(path)?;
+        self.nodes.lock().insert(String::from(path), Node::Dir);
+        Ok(())
+    }
+
+    fn remove(&self, path: &str) -> Result<(), FsError> {
+        parent(path)?;
+        if self.stat(path)?.is_dir && !self.readdir(path)?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        self.unlink(path);
+        Ok(())
+    }
+
+    fn rename(&self, old: &str, new: &str) -> Result<(), FsError> {
+        self.stat(old)?;
+        if old == new {
+            return Ok(());
+        }
+        // A directory can't go inside itself
+        if new.strip_prefix(old).is_some_and(|rest| rest.starts_with('/')) {
+            return Err(FsError::InvalidArgument);
+        }
+        self.creatable(new)?;
+        self.move_tree(old, new)
+    }
+}
+
+/// A file open on the overlay.
+struct RamFile {
+    data: Data,
+    pos: u64,
+    /// Opened with `open_write`
+    writable: bool,
+}
+
+impl OpenFile for RamFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.data.lock();
+        let start = (self.pos as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> {
+        let len = self.data.lock().len() as u64;
+        let new = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(n) => len.checked_add_signed(n),
+        };
+        self.pos = new.ok_or(FsError::InvalidArgument)?;
+        Ok(self.pos)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), FsError> {
+        if !self.writable {
+            return Err(FsError::ReadOnlyFs);
+        }
+        let mut data = self.data.lock();
+        let start = self.pos as usize;
+        let end = start.checked_add(buf.len()).ok_or(FsError::InvalidArgument)?;
+        let len = data.len();
+        if end > len {
+            // Written past the end: the gap reads as zeros
+            data.try_reserve(end - len).map_err(|_| FsError::OutOfMemory)?;
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        self.pos = end as u64;
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), FsError> {
+        if !self.writable {
+            return Err(FsError::ReadOnlyFs);
+        }
+        let mut data = self.data.lock();
+        let (len, old) = (len as usize, data.len());
+        if len > old {
+            data.try_reserve(len - old).map_err(|_| FsError::OutOfMemory)?;
+        }
+        data.resize(len, 0);
+        data.shrink_to_fit();
+        Ok(())
+    }
+}
+
+/// Write, read back, copy up, delete and rename on an overlay over a
+/// built-in test archive, checking the lower layer shows through where it
+/// should.
+pub fn self_test() {
+    const ARCHIVE: &[u8] = include_bytes!("../../../assets/tar-names.tar");
+    let fs = RamFs::new(Box::new(super::tar::TarFs::new(ARCHIVE)));
+    let read = |path: &str| -> Option<Vec<u8>> {
+        let mut file = fs.open(path).ok()?;
+        let mut buf = [0u8; 64];
+        let n = file.read(&mut buf).ok()?;
+        Some(Vec::from(&buf[..n]))
+    };
+    let names = |path: &str| -> Vec<String> {
+        let mut names: Vec<String> = fs.readdir(path).unwrap_or_default().into_iter().map(|e| e.name).collect();
+        names.sort_unstable();
+        names
+    };
+
+    let failure = (|| {
+        if read("/tar-test/short.txt").as_deref() != Some(b"short\n") {
+            return Err("reading through to the lower layer");
+        }
+        fs.write("/tar-test/short.txt", b"more\n", true).map_err(|_| "appending to a lower file")?;
+        if read("/tar-test/short.txt").as_deref() != Some(b"short\nmore\n") {
+            return Err("copy-up before appending");
+        }
+        fs.mkdir("/new").map_err(|_| "mkdir")?;
+        fs.write("/new/a.txt", b"new\n", false).map_err(|_| "creating a file")?;
+        fs.rename("/new", "/moved").map_err(|_| "renaming a directory")?;
+        if read("/moved/a.txt").as_deref() != Some(b"new\n") || fs.stat("/new").is_ok() {
+            return Err("renamed directory contents");
+        }
+        fs.remove("/tar-test/short.txt").map_err(|_| "removing a lower file")?;
+        if fs.stat("/tar-test/short.txt").is_ok() || names("/tar-test").contains(&String::from("short.txt")) {
+            return Err("whiteout of a removed lower file");
+        }
+        if fs.remove("/tar-test") != Err(FsError::NotEmpty) {
+            return Err("removing a non-empty lower directory");
+        }
+        if names("/") != ["moved", "tar-test"] {
+            return Err("listing the root");
+        }
+        if fs.statfs().map_or(true, |s| s.used_bytes() == 0) {
+            return Err("overlay usage");
+        }
+        Ok(())
+    })();
+    match failure {
+        Ok(()) => crate::log_info!("fs", "RAM overlay self-test passed"),
+        Err(step) => crate::log_error!("fs", "RAM overlay self-test FAILED: {}", step),
+    }
+}
@@ -0,0 +1,160 @@
+// =============================================================================
+// APRK OS - Read-Ahead
+// =============================================================================
+// ELF loading and `cat` read files front to back, each read waiting out a
+// whole device round trip. The block cache reports every read here; when
+// a disk's reads carry on where the last one stopped, the next `window`
+// blocks are queued for a background task, which reads them through the
+// interrupt-driven request path into the cache while the reader gets on
+// with what it has. Reads elsewhere on the disk restart the stream.
+//
+// Streams are tracked per disk, not per file: a file read on its own is a
+// run of consecutive blocks wherever FAT clusters are contiguous. At most
+// `MAX_OUTSTANDING` windows are queued or being read at once, so demand
+// reads never wait behind a long line of guesses.
+// =============================================================================
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::block::{self, BlockDevice, Device};
+use crate::sched::{self, wait::WaitQueue};
+use super::cache::{self, BLOCK_SIZE};
+
+/// Blocks read ahead unless changed (`fscache readahead`)
+pub const DEFAULT_WINDOW: usize = 32; // 16 KB
+/// Most windows queued or in flight
+const MAX_OUTSTANDING: usize = 2;
+
+/// Blocks read ahead at a time, 0 for none
+static WINDOW: AtomicUsize = AtomicUsize::new(DEFAULT_WINDOW);
+
+/// Sequential reads on one disk.
+struct Stream {
+    /// Block right after the last one read
+    next: u64,
+    /// First block not yet queued
+    ahead: u64,
+}
+
+/// Blocks to read ahead: disk, first block, count.
+struct Job {
+    disk: usize,
+    first: u64,
+    count: usize,
+}
+
+struct State {
+    /// Registry number of each disk -> its stream
+    streams: BTreeMap<usize, Stream>,
+    queue: VecDeque<Job>,
+    /// Windows queued or being read
+    outstanding: usize,
+    /// PID of the task reading ahead, 0 until it's started
+    worker: usize,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    streams: BTreeMap::new(),
+    queue: VecDeque::new(),
+    outstanding: 0,
+    worker: 0,
+});
+/// The worker sleeps here while the queue is empty
+static JOBS: WaitQueue = WaitQueue::new();
+
+/// Read ahead `blocks` blocks at a time from now on, or none if 0.
+pub fn set_window(blocks: usize) {
+    WINDOW.store(blocks, Ordering::Relaxed);
+}
+
+/// Blocks read ahead at a time.
+pub fn window() -> usize {
+    WINDOW.load(Ordering::Relaxed)
+}
+
+/// Note a read of `count` blocks of `dev` from `block` on, queueing the
+/// blocks after it if the read carries on a sequential stream.
+pub fn access(dev: &Device, block: u64, count: u64) {
+    let window = window() as u64;
+    if window == 0 || !sched::is_enabled() {
+        return;
+    }
+    let Some(disk_blocks) = block::by_id(dev.disk).map(|disk| disk.num_blocks()) else {
+        return;
+    };
+    let first = dev.start + block;
+    // Held with IRQs masked everywhere, so never by a preempted task
+    let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+    let start_worker = queue(&mut STATE.lock(), dev.disk, first, count, window, disk_blocks);
+    aprk_arch_arm64::cpu::restore_interrupts(daif);
+    let Some(start_worker) = start_worker else {
+        return;
+    };
+    // A job left queued by a failed spawn is picked up by the next worker
+    if start_worker {
+        if let Some(pid) = sched::spawn_named(worker, "readahead", sched::Priority::Low) {
+            let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+            STATE.lock().worker = pid;
+            aprk_arch_arm64::cpu::restore_interrupts(daif);
+        }
+    }
+    JOBS.wake_all();
+}
+
+/// Carry `disk`'s stream on to a read of `count` blocks from `first`,
+/// queueing the next window if it's due. Returns whether a job was queued
+/// and, if so, whether the worker needs starting.
+fn queue(state: &mut State, disk: usize, first: u64, count: u64, window: u64, disk_blocks: u64) -> Option<bool> {
+    let stream = state.streams.entry(disk).or_insert(Stream { next: u64::MAX, ahead: 0 });
+    let sequential = first == stream.next;
+    stream.next = first + count;
+    if !sequential {
+        stream.ahead = stream.next;
+        return None;
+    }
+    // Stay a window ahead, topping up once half of it has been read
+    stream.ahead = stream.ahead.max(stream.next);
+    let target = (stream.next + window).min(disk_blocks);
+    if target < stream.ahead + window / 2 || state.outstanding >= MAX_OUTSTANDING {
+        return None;
+    }
+    let job = Job { disk, first: stream.ahead, count: (target - stream.ahead) as usize };
+    stream.ahead = target;
+    if job.count == 0 {
+        return None;
+    }
+    state.queue.push_back(job);
+    state.outstanding += 1;
+    Some(state.worker == 0 || !sched::is_alive(state.worker))
+}
+
+/// Read queued windows into the block cache, sleeping while there are none.
+extern "C" fn worker() {
+    loop {
+        let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+        let job = STATE.lock().queue.pop_front();
+        let Some(job) = job else {
+            JOBS.sleep();
+            aprk_arch_arm64::cpu::restore_interrupts(daif);
+            continue;
+        };
+        aprk_arch_arm64::cpu::restore_interrupts(daif);
+
+        let mut buf = Vec::new();
+        if buf.try_reserve_exact(job.count * BLOCK_SIZE).is_ok() {
+            buf.resize(job.count * BLOCK_SIZE, 0);
+            // A failed guess costs nothing: the reader asks for the blocks
+            // itself when it gets there
+            if let Some(disk) = block::by_id(job.disk) {
+                if disk.read_blocks(job.first, &mut buf).is_ok() {
+                    cache::fill(job.disk, job.first, &buf);
+                }
+            }
+        }
+        let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+        STATE.lock().outstanding -= 1;
+        aprk_arch_arm64::cpu::restore_interrupts(daif);
+    }
+}
@@ -0,0 +1,256 @@
+// =============================================================================
+// APRK OS - Filesystem Self-Test
+// =============================================================================
+// Builds a small tree of nested directories on the disk, reaches files in
+// it through paths with ".", "..", repeated and trailing slashes, moves
+// copies and deletes them, and removes the tree again. Then checks file names:
+// lookups in any case, names with spaces, 8.3 short names and the length
+// limit. Then two tasks read a file each at the same time, and must take
+// turns rather than one waiting for the other to finish. Last, a file
+// spanning several read chunks is hashed. Needs a writable disk.
+// =============================================================================
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use aprk_arch_arm64::timer::Timer;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use crate::sched::{self, Priority};
+use super::{FsError, SeekFrom, append_file, hash_file, copy, create_file, list_dir, mkdir, open, read_file, remove, rename, stat, write_file};
+
+/// Top of the test tree
+const TOP: &str = "/fstest";
+/// Directory for the file name checks
+const NAMES: &str = "/fsnames";
+
+/// Run the nested-directory test, printing each failed step.
+pub fn nested_dirs() {
+    // A tree left behind by an interrupted run
+    cleanup();
+
+    let mut failed = 0;
+    let mut check = |what: &str, ok: bool| {
+        if !ok {
+            crate::println!("  FAILED: {}", what);
+            failed += 1;
+        }
+    };
+
+    check("mkdir /fstest", mkdir(TOP).is_ok());
+    check("mkdir fstest/a (relative)", mkdir("fstest/a").is_ok());
+    check("mkdir /fstest/a/b/ (trailing slash)", mkdir("/fstest/a/b/").is_ok());
+    check("mkdir an existing directory", mkdir("/fstest//a") == Err(FsError::AlreadyExists));
+    check("mkdir under a missing directory", mkdir("/fstest/x/y") == Err(FsError::NotFound));
+
+    check("write /fstest/a/b/f.txt", write_file("/fstest/a/b/f.txt", b"nested").is_ok());
+    check("append through ./ and ..", append_file("/fstest/./a/b/../b/f.txt", b" file").is_ok());
+    check("read through // and ..", read_file("fstest//a/../a/b/f.txt").as_deref() == Ok(&b"nested file"[..]));
+    check("read through a file as a directory", read_file("/fstest/a/b/f.txt/x") == Err(FsError::NotADirectory));
+    check("open a directory as a file", open("/fstest/a").err() == Some(FsError::IsADirectory));
+    check("touch an existing file", create_file("/fstest/a/b/f.txt") == Err(FsError::AlreadyExists));
+
+    check("rename across directories", rename("/fstest/a/b/f.txt", "/fstest/g.txt").is_ok());
+    check("old name is gone", read_file("/fstest/a/b/f.txt") == Err(FsError::NotFound));
+    check("new name reads back", read_file("/fstest/g.txt").as_deref() == Ok(&b"nested file"[..]));
+    check("rm a non-empty directory", remove("/fstest/a") == Err(FsError::NotEmpty));
+    check("rm the root", remove("/..") == Err(FsError::InvalidPath));
+
+    check("cp to a new file", copy("/fstest/g.txt", "/fstest/c.txt") == Ok(11));
+    check("copy reads back", read_file("/fstest/c.txt").as_deref() == Ok(&b"nested file"[..]));
+    check("cp over an existing file", copy("/fstest/a/../c.txt", "/fstest/g.txt") == Ok(11));
+    check("cp onto itself", copy("/fstest/g.txt", "/FSTEST/./G.TXT") == Err(FsError::SameFile));
+    check("cp into a directory", copy("/fstest/g.txt", "/fstest/a/b") == Ok(11));
+    check("copy in the directory reads back", read_file("/fstest/a/b/g.txt").as_deref() == Ok(&b"nested file"[..]));
+    check("cp a missing file", copy("/fstest/none", "/fstest/n.txt") == Err(FsError::NotFound));
+
+    check("rm /fstest/a/b/g.txt", remove("/fstest/a/b/g.txt").is_ok());
+    check("rm /fstest/c.txt", remove("/fstest/c.txt").is_ok());
+    check("rm /fstest/g.txt", remove("/fstest/g.txt").is_ok());
+    check("rm /fstest/a/b", remove("/fstest/a/b").is_ok());
+    check("rm /fstest/a", remove("/fstest/a").is_ok());
+    check("rm /fstest", remove(TOP).is_ok());
+
+    if failed == 0 {
+        crate::println!("PASSED: nested directory operations");
+    } else {
+        crate::println!("FAILED: {} step(s)", failed);
+        cleanup();
+    }
+}
+
+/// Remove whatever is left of the test tree.
+fn cleanup() {
+    for path in ["/fstest/g.txt", "/fstest/c.txt", "/fstest/a/b/g.txt", "/fstest/a/b/f.txt", "/fstest/a/b", "/fstest/a", TOP] {
+        let _ = remove(path);
+    }
+}
+
+/// Run the file name test, printing each failed step.
+pub fn names() {
+    let long = |len: usize| {
+        let mut path = String::from("/fsnames/");
+        path.extend(core::iter::repeat_n('n', len));
+        path
+    };
+    let cleanup = || {
+        for path in ["/fsnames/hello.txt", "/fsnames/my long file name.txt", &long(255), NAMES] {
+            let _ = remove(path);
+        }
+    };
+    cleanup();
+
+    let mut failed = 0;
+    let mut check = |what: &str, ok: bool| {
+        if !ok {
+            crate::println!("  FAILED: {}", what);
+            failed += 1;
+        }
+    };
+    let reads = |path: &str, data: &[u8]| read_file(path).as_deref() == Ok(data);
+
+    check("mkdir /fsnames", mkdir(NAMES).is_ok());
+
+    check("write Hello.TXT", write_file("/fsnames/Hello.TXT", b"case").is_ok());
+    check("read as hello.txt", reads("/fsnames/hello.txt", b"case"));
+    check("read as HELLO.TXT", reads("/FSNAMES/HELLO.TXT", b"case"));
+    check("open as hElLo.TxT", open("/fsnames/hElLo.TxT").is_ok());
+    check("stat keeps the written case", stat("/fsnames/hello.txt").is_ok_and(|e| e.name == "Hello.TXT"));
+    check("create in another case", create_file("/fsnames/HELLO.txt") == Err(FsError::AlreadyExists));
+    check("rename to change only the case", rename("/fsnames/Hello.TXT", "/fsnames/hello.txt").is_ok());
+    check("listing shows the new case", list_dir(NAMES).is_ok_and(|l| l.iter().any(|e| e.name == "hello.txt")));
+
+    check("write a name with spaces", write_file("/fsnames/my long file name.txt", b"spaces").is_ok());
+    check("read it back", reads("/fsnames/My Long File Name.TXT", b"spaces"));
+    check("read it by its 8.3 name", reads("/fsnames/MYLONG~1.TXT", b"spaces"));
+    check("listing shows the long name",
+        list_dir(NAMES).is_ok_and(|l| l.iter().any(|e| e.name == "my long file name.txt")));
+
+    check("write a 255-character name", write_file(&long(255), b"long").is_ok());
+    check("read it back", reads(&long(255), b"long"));
+    check("write a 256-character name", write_file(&long(256), b"long") == Err(FsError::NameTooLong));
+    check("stat a 256-character name", stat(&long(256)).err() == Some(FsError::NameTooLong));
+    check("mkdir under a 256-character name", mkdir(&alloc::format!("{}/d", long(256))) == Err(FsError::NameTooLong));
+
+    check("rm the 255-character name", remove(&long(255)).is_ok());
+    check("rm by the 8.3 name", remove("/fsnames/MYLONG~1.TXT").is_ok());
+    check("rm HELLO.TXT", remove("/fsnames/HELLO.TXT").is_ok());
+    check("rm /fsnames", remove(NAMES).is_ok());
+
+    if failed == 0 {
+        crate::println!("PASSED: file names");
+    } else {
+        crate::println!("FAILED: {} step(s)", failed);
+        cleanup();
+    }
+}
+
+/// Files read at the same time, one per reader task
+const READ_FILES: [&str; 2] = ["/fsread0.tmp", "/fsread1.tmp"];
+/// Size of each: together more than the block cache holds
+const READ_SIZE: usize = 128 * 1024;
+/// Bytes per read call
+const READ_CHUNK: usize = 4096;
+/// How long the readers keep reading, starting over at the end
+const READ_TIME: Duration = Duration::from_millis(500);
+
+/// Hands out reader numbers
+static NEXT_READER: AtomicUsize = AtomicUsize::new(0);
+/// The reader of each chunk read, in order
+static READ_ORDER: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+/// Failed reads, and chunks with another file's contents
+static READ_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn reader() {
+    let n = NEXT_READER.fetch_add(1, Ordering::Relaxed);
+    let Ok(mut file) = open(READ_FILES[n]) else {
+        READ_ERRORS.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    let mut buf = vec![0u8; READ_CHUNK];
+    let end = Timer::uptime() + READ_TIME;
+    while Timer::uptime() < end {
+        match file.read(&mut buf) {
+            Ok(0) => {
+                if file.seek(SeekFrom::Start(0)).is_err() {
+                    READ_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+            Ok(len) => {
+                if buf[..len].iter().any(|&b| b != b'0' + n as u8) {
+                    READ_ERRORS.fetch_add(1, Ordering::Relaxed);
+                }
+                READ_ORDER.lock().push(n as u8);
+            }
+            Err(_) => {
+                READ_ERRORS.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+}
+
+/// Read two files from two tasks at once and check they took turns.
+pub fn concurrent_reads() {
+    let cleanup = || {
+        for path in READ_FILES {
+            let _ = remove(path);
+        }
+    };
+    for (n, path) in READ_FILES.iter().enumerate() {
+        if let Err(e) = write_file(path, &vec![b'0' + n as u8; READ_SIZE]) {
+            crate::println!("FAILED: write {}: {}", path, e);
+            cleanup();
+            return;
+        }
+    }
+    NEXT_READER.store(0, Ordering::Relaxed);
+    READ_ERRORS.store(0, Ordering::Relaxed);
+    READ_ORDER.lock().clear();
+
+    let pids = READ_FILES.map(|_| sched::spawn_named(reader, "fsreader", Priority::Normal));
+    for &pid in pids.iter().flatten() {
+        sched::wait_for_exit(pid);
+    }
+    // The last reader's stack is freed once another task has run
+    sched::schedule();
+    cleanup();
+
+    let order = core::mem::take(&mut *READ_ORDER.lock());
+    let chunks = |n: u8| order.iter().filter(|&&r| r == n).count();
+    let turns = 1 + order.windows(2).filter(|w| w[0] != w[1]).count();
+    let errors = READ_ERRORS.load(Ordering::Relaxed);
+    crate::println!("2 readers: {} and {} chunks of {} bytes in {} turns, {} errors",
+        chunks(0), chunks(1), READ_CHUNK, turns, errors);
+    if pids.iter().any(Option::is_none) {
+        crate::println!("FAILED: couldn't start the readers");
+    } else if errors > 0 {
+        crate::println!("FAILED: {} bad reads", errors);
+    } else if turns <= 2 {
+        crate::println!("FAILED: one reader waited for the other to finish");
+    } else {
+        crate::println!("PASSED: concurrent reads take turns");
+    }
+}
+
+/// File written and hashed by `hashing`
+const HASH_FILE: &str = "/fshash.tmp";
+
+/// Hash a file bigger than one read chunk, with a length that isn't a
+/// whole number of blocks, and compare it to hashing the data in memory.
+pub fn hashing() {
+    let data: Vec<u8> = (0..10_007u32).map(|i| (i * 7 % 251) as u8).collect();
+    if let Err(e) = write_file(HASH_FILE, &data) {
+        crate::println!("FAILED: write {}: {}", HASH_FILE, e);
+        return;
+    }
+    let hashed = hash_file(HASH_FILE);
+    let _ = remove(HASH_FILE);
+    match hashed {
+        Ok(digest) if digest == crate::crypto::sha256::digest(&data) => crate::println!("PASSED: file hashing"),
+        Ok(_) => crate::println!("FAILED: the file's digest differs from the data's"),
+        Err(e) => crate::println!("FAILED: hash {}: {}", HASH_FILE, e),
+    }
+}
@@ -0,0 +1,418 @@
+// =============================================================================
+// APRK OS - Tar Ramdisk
+// =============================================================================
+// A ustar archive loaded into RAM next to the kernel, mounted read-only at
+// /initrd. A bootloader passes it through the device tree (`/chosen`
+// `linux,initrd-start`/`-end`); QEMU ignores `-initrd` for ELF kernels, so
+// qemu-run.sh instead loads disk.tar at `PROBE_ADDR` with a loader device,
+// and that address is checked for a tar header too. Without either, the
+// copy of disk.tar built into the kernel image is used, so changing the
+// ramdisk needs no rebuild unless there's no bootloader to load it.
+//
+// Tar stores full paths, in whatever form the tool wrote them ("./bin/x",
+// "bin/", "/etc/rc"), and only lists directories it was given explicitly.
+// Names are normalized to "bin/x" on reading, and directories are implied
+// by the paths under them. Paths too long for the 100-byte name field
+// come either split across the ustar prefix field or in a GNU 'L' entry
+// before the file's own; pax headers are skipped. Every header's checksum
+// is checked, and iteration stops at the first bad one.
+// =============================================================================
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::vfs::{OpenFile, Vfs};
+use super::{DirEntryInfo, FsError, FsStats, SeekFrom};
+
+/// Header and data granularity
+const BLOCK: usize = 512;
+/// Where qemu-run.sh has QEMU load the archive (128 MB into RAM)
+const PROBE_ADDR: usize = 0x4800_0000;
+
+/// The ramdisk built into the kernel, used when none was loaded
+static EMBEDDED: &[u8] = include_bytes!("../../../disk.tar");
+
+/// Physical start and length of the ramdisk, once `locate` found it
+static RAMDISK_START: AtomicUsize = AtomicUsize::new(0);
+static RAMDISK_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// One member of an archive.
+pub struct Entry<'a> {
+    /// Path without a leading "/" or "./" or a trailing "/", like "bin/hello"
+    pub name: String,
+    pub is_dir: bool,
+    pub data: &'a [u8],
+    /// Seconds since 1970
+    pub mtime: u64,
+}
+
+/// The value of a NUL- or space-terminated octal header field.
+fn octal(field: &[u8]) -> Option<u64> {
+    let mut value: u64 = 0;
+    for &b in field.iter().skip_while(|&&b| b == b' ') {
+        match b {
+            b'0'..=b'7' => value = value.checked_mul(8)? + (b - b'0') as u64,
+            0 | b' ' => break,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+/// A NUL-terminated header field as text.
+fn text(field: &[u8]) -> &str {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).unwrap_or("")
+}
+
+/// `path` in the form entries are named by: no "/" or "./" in front,
+/// no "." or ".." components, no "/" at the end. The root is "".
+fn normalize(path: &str) -> String {
+    let mut path = super::path::normalize("/", path);
+    path.remove(0);
+    path
+}
+
+/// Whether `header`'s checksum field matches its bytes, summed with the
+/// field itself taken as spaces. Old tars summed them as signed bytes.
+fn checksum_ok(header: &[u8]) -> bool {
+    let Some(stored) = octal(&header[148..156]) else {
+        return false;
+    };
+    let field = |i: usize| (148..156).contains(&i);
+    let unsigned: u64 = header.iter().enumerate().map(|(i, &b)| if field(i) { 32 } else { b as u64 }).sum();
+    let signed: i64 = header.iter().enumerate().map(|(i, &b)| if field(i) { 32 } else { b as i8 as i64 }).sum();
+    stored == unsigned || stored as i64 == signed
+}
+
+/// Walks the members of an archive, in order.
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    /// Offset of a header that failed its checksum
+    corrupt: Option<usize>,
+}
+
+impl Entries<'_> {
+    /// Where the archive turned out to be corrupt, if the walk stopped
+    /// early because of it.
+    pub fn corrupt(&self) -> Option<usize> {
+        self.corrupt
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        // Name from a GNU 'L' header, for the entry after it
+        let mut long_name = None;
+        loop {
+            let header = self.archive.get(self.offset..self.offset + BLOCK)?;
+            // Two zero blocks end the archive; one is enough to stop
+            if header.iter().all(|&b| b == 0) {
+                return None;
+            }
+            if !checksum_ok(header) {
+                self.corrupt = Some(self.offset);
+                return None;
+            }
+            let size = octal(&header[124..136])? as usize;
+            let data_start = self.offset + BLOCK;
+            let data = self.archive.get(data_start..data_start.checked_add(size)?)?;
+            self.offset = data_start + size.next_multiple_of(BLOCK);
+
+            let kind = header[156];
+            let name = match kind {
+                // pax per-file and global attributes: nothing to list
+                b'x' | b'g' => continue,
+                b'L' => {
+                    long_name = Some(String::from(text(data)));
+                    continue;
+                }
+                // GNU long link target: links aren't listed anyway
+                b'K' => continue,
+                _ => long_name.take().unwrap_or_else(|| header_name(header)),
+            };
+            // Links, devices and FIFOs have nothing to read
+            let is_dir = kind == b'5' || (kind == b'0' || kind == 0) && name.ends_with('/');
+            if !is_dir && kind != b'0' && kind != 0 && kind != b'7' {
+                continue;
+            }
+            let name = normalize(&name);
+            // The archive's own root, as "./"
+            if name.is_empty() {
+                continue;
+            }
+            let mtime = octal(&header[136..148]).unwrap_or(0);
+            return Some(Entry { name, is_dir, data: if is_dir { &[] } else { data }, mtime });
+        }
+    }
+}
+
+/// The path in a header, joining the ustar prefix field if it has one.
+fn header_name(header: &[u8]) -> String {
+    let name = text(&header[0..100]);
+    // GNU headers ("ustar  ") keep access times where the prefix would be
+    let prefix = if &header[257..263] == b"ustar\0" { text(&header[345..500]) } else { "" };
+    if prefix.is_empty() {
+        String::from(name)
+    } else {
+        alloc::format!("{}/{}", prefix, name)
+    }
+}
+
+/// The members of `archive`.
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries { archive, offset: 0, corrupt: None }
+}
+
+/// Check every header of `archive`. Fails with the offset of the first
+/// corrupt one.
+pub fn verify(archive: &[u8]) -> Result<usize, usize> {
+    let mut entries = entries(archive);
+    let count = entries.by_ref().count();
+    entries.corrupt().map_or(Ok(count), Err)
+}
+
+/// The file at `path`, which may start with "/" or "./".
+pub fn get_file<'a>(archive: &'a [u8], path: &str) -> Option<Entry<'a>> {
+    let path = normalize(path);
+    entries(archive).find(|e| !e.is_dir && e.name == path)
+}
+
+/// The immediate children of the directory at `prefix` ("" or "/" for the
+/// root), directories included whether the archive lists them or only
+/// files inside them. `None` if there's no such directory.
+pub fn list_dir(archive: &[u8], prefix: &str) -> Option<Vec<DirEntryInfo>> {
+    let prefix = normalize(prefix);
+    let mut found = prefix.is_empty();
+    let mut children: Vec<DirEntryInfo> = Vec::new();
+    for entry in entries(archive) {
+        let rest = if prefix.is_empty() {
+            entry.name.as_str()
+        } else if entry.name == prefix {
+            found |= entry.is_dir;
+            continue;
+        } else {
+            match entry.name.strip_prefix(prefix.as_str()).and_then(|r| r.strip_prefix('/')) {
+                Some(rest) => rest,
+                None => continue,
+            }
+        };
+        found = true;
+        let (name, nested) = match rest.split_once('/') {
+            Some((name, _)) => (name, true),
+            None => (rest, false),
+        };
+        let info = if nested { DirEntryInfo::directory(String::from(name)) } else { info(&entry, name) };
+        // A directory can come up once for itself and once per file in it
+        match children.iter_mut().find(|c| c.name == name) {
+            Some(child) if info.is_dir && !nested => *child = info,
+            Some(_) => {}
+            None => children.push(info),
+        }
+    }
+    found.then_some(children)
+}
+
+/// Directory listing details of `entry`, called `name`.
+fn info(entry: &Entry, name: &str) -> DirEntryInfo {
+    let (year, month, day) = civil_date(entry.mtime / 86400);
+    let secs = entry.mtime % 86400;
+    // FAT dates start at 1980
+    let year = year.clamp(1980, 2107);
+    let modified = fatfs::DateTime::new(
+        fatfs::Date::new(year as u16, month as u16, day as u16),
+        fatfs::Time::new((secs / 3600) as u16, (secs / 60 % 60) as u16, (secs % 60) as u16, 0),
+    );
+    DirEntryInfo { name: String::from(name), size: entry.data.len() as u64, is_dir: entry.is_dir, modified }
+}
+
+/// Year, month and day of `days` after 1970-01-01.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // Howard Hinnant's days-to-civil, counting eras of 400 years from 0000-03-01
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+/// Length of the archive at the start of `bytes`, up to and including its
+/// end-of-archive block. `None` if it doesn't start with a ustar header.
+fn archive_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.get(257..262)? != b"ustar" || !checksum_ok(&bytes[..BLOCK]) {
+        return None;
+    }
+    let mut offset = 0;
+    while let Some(header) = bytes.get(offset..offset + BLOCK) {
+        if header.iter().all(|&b| b == 0) {
+            return Some(offset + BLOCK);
+        }
+        // A corrupt header: keep what comes before it
+        let Some(size) = octal(&header[124..136]).filter(|_| checksum_ok(header)) else {
+            return Some(offset);
+        };
+        offset += BLOCK + (size as usize).next_multiple_of(BLOCK);
+    }
+    // Ran into the end of RAM: take what fits
+    Some(bytes.len())
+}
+
+/// Find the ramdisk in the RAM from `ram_base` to `ram_end` and note
+/// where it is. Returns its physical start and length, for the caller to
+/// reserve.
+pub fn locate(ram_base: usize, ram_end: usize) -> Option<(usize, usize)> {
+    let chosen = aprk_arch_arm64::dtb::get().and_then(|dtb| dtb.initrd());
+    let start = match chosen {
+        Some((start, _)) => start as usize,
+        None => PROBE_ADDR,
+    };
+    if start < ram_base || start + BLOCK > ram_end {
+        return None;
+    }
+    let bytes = unsafe {
+        core::slice::from_raw_parts(aprk_arch_arm64::mmu::phys_to_virt(start) as *const u8, ram_end - start)
+    };
+    let len = match chosen {
+        Some((start, end)) if end as usize <= ram_end => (end - start) as usize,
+        _ => archive_len(bytes)?,
+    };
+    RAMDISK_START.store(start, Ordering::Relaxed);
+    RAMDISK_LEN.store(len, Ordering::Relaxed);
+    Some((start, len))
+}
+
+/// The ramdisk `locate` found, or the built-in one, and where it came
+/// from (`initrd` or `builtin`).
+pub fn ramdisk() -> (&'static [u8], &'static str) {
+    let len = RAMDISK_LEN.load(Ordering::Relaxed);
+    if len == 0 {
+        return (EMBEDDED, "builtin");
+    }
+    let start = aprk_arch_arm64::mmu::phys_to_virt(RAMDISK_START.load(Ordering::Relaxed));
+    (unsafe { core::slice::from_raw_parts(start as *const u8, len) }, "initrd")
+}
+
+/// A read-only filesystem over a tar archive.
+pub struct TarFs {
+    archive: &'static [u8],
+}
+
+impl TarFs {
+    pub fn new(archive: &'static [u8]) -> Self {
+        Self { archive }
+    }
+}
+
+impl Vfs for TarFs {
+    fn name(&self) -> &'static str {
+        "tar"
+    }
+
+    fn stat(&self, path: &str) -> Result<DirEntryInfo, FsError> {
+        let name = normalize(path);
+        if name.is_empty() {
+            return Ok(DirEntryInfo::directory(String::from("/")));
+        }
+        let leaf = name.rsplit('/').next().unwrap_or(&name);
+        if let Some(entry) = entries(self.archive).find(|e| e.name == name) {
+            return Ok(info(&entry, leaf));
+        }
+        // A directory only implied by the files in it
+        let inside = alloc::format!("{}/", name);
+        if entries(self.archive).any(|e| e.name.starts_with(&inside)) {
+            return Ok(DirEntryInfo::directory(String::from(leaf)));
+        }
+        Err(FsError::NotFound)
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn OpenFile>, FsError> {
+        match get_file(self.archive, path) {
+            Some(entry) => Ok(Box::new(TarFile { data: entry.data, pos: 0 })),
+            None if self.stat(path)?.is_dir => Err(FsError::IsADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn readdir(&self, path: &str) -> Result<Vec<DirEntryInfo>, FsError> {
+        match list_dir(self.archive, path) {
+            Some(children) => Ok(children),
+            None if get_file(self.archive, path).is_some() => Err(FsError::NotADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn statfs(&self) -> Result<FsStats, FsError> {
+        let blocks = self.archive.len().div_ceil(BLOCK) as u64;
+        Ok(FsStats { label: String::from("initrd"), cluster_size: BLOCK as u64, total_clusters: blocks, free_clusters: 0 })
+    }
+}
+
+/// A file in the archive, read straight out of it.
+struct TarFile {
+    data: &'static [u8],
+    pos: u64,
+}
+
+impl OpenFile for TarFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let start = (self.pos as usize).min(self.data.len());
+        let n = buf.len().min(self.data.len() - start);
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> {
+        let new = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(n) => (self.data.len() as u64).checked_add_signed(n),
+        };
+        self.pos = new.ok_or(FsError::InvalidArgument)?;
+        Ok(self.pos)
+    }
+}
+
+/// Read a test archive with a GNU long name, a path split across the
+/// ustar prefix field, a "./" directory entry and a long symlink target,
+/// then check a corrupted copy is caught.
+pub fn self_test() {
+    const ARCHIVE: &[u8] = include_bytes!("../../../assets/tar-names.tar");
+    const LONG: &str = "tar-test/long-directory-name-long-directory-name-long-directory-name-/\
+        a-file-name-longer-than-the-hundred-byte-ustar-name-field-\
+        a-file-name-longer-than-the-hundred-byte-ustar-name-field-end.txt";
+    const DEEP: &str = "tar-test/level0-directory/level1-directory/level2-directory/level3-directory/\
+        level4-directory/level5-directory/level6-directory/level7-directory/deep.txt";
+    let read = |path| get_file(ARCHIVE, path).map(|e| e.data);
+    let root = list_dir(ARCHIVE, "/tar-test").unwrap_or_default();
+    let mut names: Vec<&str> = root.iter().map(|e| e.name.as_str()).collect();
+    names.sort_unstable();
+
+    let mut corrupt = Vec::from(ARCHIVE);
+    // The header of the second file
+    corrupt[BLOCK * 4 + 10] ^= 1;
+    let failure = if read(LONG) != Some(b"long name\n") {
+        "GNU long name"
+    } else if read(DEEP) != Some(b"deep prefix\n") {
+        "ustar prefix field"
+    } else if names != ["level0-directory", "long-directory-name-long-directory-name-long-directory-name-", "short.txt"] {
+        "listing (symlink or directory entries)"
+    } else if verify(ARCHIVE) != Ok(4) {
+        "checksums of a good archive"
+    } else if verify(&corrupt) != Err(BLOCK * 4) {
+        "checksum of a corrupt header"
+    } else {
+        crate::log_info!("fs", "Tar self-test passed");
+        return;
+    };
+    crate::log_error!("fs", "Tar self-test FAILED: {}", failure);
+}
@@ -0,0 +1,244 @@
+// =============================================================================
+// APRK OS - Virtual Filesystem
+// =============================================================================
+// One namespace over every mounted filesystem. Each filesystem implements
+// `Vfs` and is mounted at a directory; a path belongs to the mount whose
+// point is its longest prefix, and the filesystem sees it relative to its
+// own root ("/" being the mount point itself). Paths reaching a `Vfs`
+// are always normalized and absolute.
+//
+// Mount points show up in listings of the directory they sit in, whether
+// or not that directory holds an entry of the same name.
+//
+// Every open file holds a reference to its filesystem (see
+// `fs::FileHandle`), so a filesystem with files open is busy and can't be
+// unmounted; it's dropped once it's unmounted and the last file is closed.
+// =============================================================================
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::{fsck, DirEntryInfo, FsError, FsStats, SeekFrom};
+
+/// A mountable filesystem. Operations it doesn't support fail with
+/// `ReadOnlyFs`.
+pub trait Vfs: Send + Sync {
+    /// Filesystem type, for `mount`
+    fn name(&self) -> &'static str;
+
+    /// Details of the file or directory at `path`.
+    fn stat(&self, path: &str) -> Result<DirEntryInfo, FsError>;
+
+    /// Open the file at `path` for reading, at its start.
+    fn open(&self, path: &str) -> Result<Box<dyn OpenFile>, FsError>;
+
+    /// The entries of the directory at `path`.
+    fn readdir(&self, path: &str) -> Result<Vec<DirEntryInfo>, FsError>;
+
+    /// Size and free space of the whole filesystem.
+    fn statfs(&self) -> Result<FsStats, FsError>;
+
+    /// Replace (or with `append`, extend) the file at `path`, creating it
+    /// if it doesn't exist.
+    fn write(&self, _path: &str, _data: &[u8], _append: bool) -> Result<(), FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    /// Open the file at `path` for reading and writing, creating it if it
+    /// doesn't exist. With `truncate`, an existing file is emptied.
+    fn open_write(&self, _path: &str, _truncate: bool) -> Result<Box<dyn OpenFile>, FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    /// Create an empty file at `path`, which must not exist.
+    fn create(&self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    /// Create a directory at `path`, which must not exist.
+    fn mkdir(&self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    /// Delete the file or empty directory at `path`.
+    fn remove(&self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    /// Move `old` to `new` within this filesystem.
+    fn rename(&self, _old: &str, _new: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    /// Write out anything held back.
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    /// Check the on-disk structures for inconsistencies, changing
+    /// nothing. Fails with `Unsupported` where there's nothing to check.
+    fn check(&self) -> Result<fsck::Report, FsError> {
+        Err(FsError::Unsupported)
+    }
+}
+
+/// A file opened on some filesystem.
+pub trait OpenFile: Send {
+    /// Read from the current position. Returns the bytes read, short only
+    /// at the end of the file.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Move the position, returning the new one.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError>;
+
+    /// Write all of `buf` at the current position. Only files opened with
+    /// `Vfs::open_write` can be written.
+    fn write(&mut self, _buf: &[u8]) -> Result<(), FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    /// Cut the file to `len` bytes, or extend it with zeros. The position
+    /// stays where it was (or at the new end, if that's before it).
+    fn truncate(&mut self, _len: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+
+    /// Get what has been written onto the disk.
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+struct Mount {
+    /// Normalized absolute path
+    point: String,
+    /// Device it was mounted from, such as `blk0p1`
+    source: String,
+    fs: Arc<dyn Vfs>,
+}
+
+/// A mounted filesystem, as listed by `mounts`.
+pub struct MountInfo {
+    pub point: String,
+    pub source: String,
+    /// Filesystem type
+    pub fs: &'static str,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Whether device names `a` and `b` share blocks: the same device, or a
+/// disk and one of its partitions.
+fn overlaps(a: &str, b: &str) -> bool {
+    let within = |part: &str, disk: &str| part.strip_prefix(disk).is_some_and(|rest| rest.starts_with('p'));
+    a == b || within(a, b) || within(b, a)
+}
+
+/// Check that `source` could be mounted at `point`: nothing is mounted
+/// there, and `source` isn't mounted anywhere.
+pub fn check_mountable(point: &str, source: &str) -> Result<(), FsError> {
+    check_free(&MOUNTS.lock(), point, source)
+}
+
+/// `check_mountable`, against the mount table `mounts`.
+fn check_free(mounts: &[Mount], point: &str, source: &str) -> Result<(), FsError> {
+    if mounts.iter().any(|m| m.point == point) {
+        return Err(FsError::AlreadyExists);
+    }
+    if mounts.iter().any(|m| overlaps(&m.source, source)) {
+        return Err(FsError::Busy);
+    }
+    Ok(())
+}
+
+/// Mount `fs`, from device `source`, at `point` (a normalized absolute
+/// path).
+pub fn mount(point: &str, source: &str, fs: Arc<dyn Vfs>) -> Result<(), FsError> {
+    let name = fs.name();
+    // Checked and added under one lock, so two mounts can't both pass
+    let mut mounts = MOUNTS.lock();
+    check_free(&mounts, point, source)?;
+    mounts.push(Mount { point: String::from(point), source: String::from(source), fs });
+    drop(mounts);
+    crate::log_info!("fs", "Mounted {} from {} at {}", name, source, point);
+    Ok(())
+}
+
+/// Unmount the filesystem at `point` (a normalized absolute path). Fails
+/// with `Busy` while it has open files or holds another mount point, and
+/// for the root.
+pub fn umount(point: &str) -> Result<(), FsError> {
+    let mount = {
+        let mut mounts = MOUNTS.lock();
+        let i = mounts.iter().position(|m| m.point == point).ok_or(FsError::NotMounted)?;
+        let nested = mounts.iter().any(|m| m.point != point && strip_point(&m.point, point).is_some());
+        // The table's own reference is the only one left once every file
+        // on it is closed
+        if point == "/" || nested || Arc::strong_count(&mounts[i].fs) > 1 {
+            return Err(FsError::Busy);
+        }
+        mounts.remove(i)
+    };
+    crate::log_info!("fs", "Unmounted {} from {}", mount.source, point);
+    // Dropped with the table unlocked: the filesystem writes back on drop
+    drop(mount);
+    Ok(())
+}
+
+/// The part of `path` under mount point `point`, as an absolute path, if
+/// `path` is inside it.
+fn strip_point<'a>(path: &'a str, point: &str) -> Option<&'a str> {
+    if point == "/" {
+        return Some(path);
+    }
+    match path.strip_prefix(point)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// The filesystem normalized absolute `path` is on, and the path within
+/// it.
+pub fn resolve(path: &str) -> Result<(Arc<dyn Vfs>, String), FsError> {
+    let mounts = MOUNTS.lock();
+    mounts.iter()
+        .filter_map(|m| strip_point(path, &m.point).map(|rest| (m, rest)))
+        .max_by_key(|(m, _)| m.point.len())
+        .map(|(m, rest)| (m.fs.clone(), String::from(rest)))
+        .ok_or(FsError::NotMounted)
+}
+
+/// Names of the mount points directly inside the directory at `path`.
+pub fn child_mounts(path: &str) -> Vec<String> {
+    let mounts = MOUNTS.lock();
+    mounts.iter()
+        .filter_map(|m| {
+            let (parent, name) = m.point.rsplit_once('/')?;
+            let parent = if parent.is_empty() { "/" } else { parent };
+            (parent == path && !name.is_empty()).then(|| String::from(name))
+        })
+        .collect()
+}
+
+/// Every mounted filesystem.
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS.lock().iter()
+        .map(|m| MountInfo { point: m.point.clone(), source: m.source.clone(), fs: m.fs.name() })
+        .collect()
+}
+
+/// Sync every mounted filesystem, returning the first error.
+pub fn sync_all() -> Result<(), FsError> {
+    let all: Vec<Arc<dyn Vfs>> = MOUNTS.lock().iter().map(|m| m.fs.clone()).collect();
+    let mut result = Ok(());
+    for fs in &all {
+        let synced = fs.sync();
+        if result.is_ok() {
+            result = synced;
+        }
+    }
+    result
+}
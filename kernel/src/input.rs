@@ -0,0 +1,171 @@
+// =============================================================================
+// APRK OS - Pointer Input
+// =============================================================================
+// Where the pointer is and which buttons are down, fed by the pointing
+// devices (`drivers::virtio_input`) one report at a time: a tablet says
+// where it points, a mouse how far it moved. The position is kept on the
+// framebuffer, clamped to its resolution (640x480 without one).
+//
+// Each report that moves the pointer or changes a button also queues a
+// `PointerEvent`, up to `QUEUE_LEN` of them, for whoever reads the queue
+// (`mousetest`, say); older events are dropped to make room. The `cursor`
+// task redraws the cursor after each report that moved it.
+//
+// Reports come from interrupt handlers, so the state is only locked with
+// IRQs masked.
+// =============================================================================
+
+use alloc::collections::VecDeque;
+use aprk_arch_arm64::cpu;
+use spin::Mutex;
+use crate::drivers::gpu;
+use crate::sched::{self, wait::WaitQueue};
+
+/// Button bits of `buttons`
+pub const BUTTON_LEFT: u8 = 1 << 0;
+pub const BUTTON_RIGHT: u8 = 1 << 1;
+pub const BUTTON_MIDDLE: u8 = 1 << 2;
+
+/// Most events queued
+const QUEUE_LEN: usize = 64;
+/// Screen size assumed without a framebuffer
+const DEFAULT_SIZE: (u32, u32) = (640, 480);
+
+/// How a report moves the pointer.
+#[derive(Clone, Copy, Debug)]
+pub enum Motion {
+    None,
+    /// Moved by this much (mouse)
+    Relative(i32, i32),
+    /// Points at `x` of `0..=max_x` across and `y` of `0..=max_y` down
+    /// (tablet)
+    Absolute { x: u32, max_x: u32, y: u32, max_y: u32 },
+}
+
+/// What changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PointerEventKind {
+    Moved,
+    /// The buttons in the mask went down
+    Pressed(u8),
+    /// The buttons in the mask went up
+    Released(u8),
+}
+
+/// A change of the pointer, and where it was then.
+#[derive(Clone, Copy, Debug)]
+pub struct PointerEvent {
+    pub kind: PointerEventKind,
+    pub x: u32,
+    pub y: u32,
+    pub buttons: u8,
+}
+
+struct Pointer {
+    x: u32,
+    y: u32,
+    buttons: u8,
+    /// Reports that moved the pointer, so the cursor task knows to redraw
+    moves: u64,
+    events: VecDeque<PointerEvent>,
+}
+
+static POINTER: Mutex<Pointer> = Mutex::new(Pointer { x: 0, y: 0, buttons: 0, moves: 0, events: VecDeque::new() });
+/// The cursor task sleeps here until the pointer moves
+static MOVED: WaitQueue = WaitQueue::new();
+
+/// Take a report from a pointing device: `motion`, with `buttons` down
+/// afterwards. Safe from IRQ context.
+pub fn report(motion: Motion, buttons: u8) {
+    let (width, height) = gpu::resolution().unwrap_or(DEFAULT_SIZE);
+    let daif = cpu::save_and_disable_interrupts();
+    let mut pointer = POINTER.lock();
+    let (x, y) = match motion {
+        Motion::None => (pointer.x, pointer.y),
+        Motion::Relative(dx, dy) => (
+            pointer.x.saturating_add_signed(dx).min(width - 1),
+            pointer.y.saturating_add_signed(dy).min(height - 1),
+        ),
+        Motion::Absolute { x, max_x, y, max_y } => (
+            (x.min(max_x) as u64 * (width - 1) as u64 / max_x.max(1) as u64) as u32,
+            (y.min(max_y) as u64 * (height - 1) as u64 / max_y.max(1) as u64) as u32,
+        ),
+    };
+    let moved = (x, y) != (pointer.x, pointer.y);
+    let pressed = buttons & !pointer.buttons;
+    let released = pointer.buttons & !buttons;
+    pointer.x = x;
+    pointer.y = y;
+    pointer.buttons = buttons;
+    let kinds = [
+        moved.then_some(PointerEventKind::Moved),
+        (pressed != 0).then_some(PointerEventKind::Pressed(pressed)),
+        (released != 0).then_some(PointerEventKind::Released(released)),
+    ];
+    for kind in kinds.into_iter().flatten() {
+        if pointer.events.len() == QUEUE_LEN {
+            pointer.events.pop_front();
+        }
+        pointer.events.push_back(PointerEvent { kind, x, y, buttons });
+    }
+    if moved {
+        pointer.moves += 1;
+    }
+    drop(pointer);
+    cpu::restore_interrupts(daif);
+    if moved {
+        MOVED.wake_all();
+    }
+}
+
+/// Where the pointer is, and which buttons are down.
+pub fn pointer_state() -> (u32, u32, u8) {
+    let daif = cpu::save_and_disable_interrupts();
+    let pointer = POINTER.lock();
+    let state = (pointer.x, pointer.y, pointer.buttons);
+    drop(pointer);
+    cpu::restore_interrupts(daif);
+    state
+}
+
+/// The oldest event queued, if any.
+pub fn next_event() -> Option<PointerEvent> {
+    let daif = cpu::save_and_disable_interrupts();
+    let event = POINTER.lock().events.pop_front();
+    cpu::restore_interrupts(daif);
+    event
+}
+
+/// Forget the events queued so far.
+pub fn clear_events() {
+    let daif = cpu::save_and_disable_interrupts();
+    POINTER.lock().events.clear();
+    cpu::restore_interrupts(daif);
+}
+
+/// Start the task drawing the cursor, if there's a pointing device and a
+/// screen to draw it on.
+pub fn start() {
+    if crate::drivers::virtio_input::count() > 0 && gpu::resolution().is_some() {
+        sched::spawn_named(cursor_task, "cursor", sched::Priority::Low);
+    }
+}
+
+/// Draw the cursor where the pointer is each time it moves.
+extern "C" fn cursor_task() {
+    let mut drawn = u64::MAX;
+    loop {
+        let daif = cpu::save_and_disable_interrupts();
+        let pointer = POINTER.lock();
+        let (x, y, moves) = (pointer.x, pointer.y, pointer.moves);
+        drop(pointer);
+        if moves == drawn {
+            MOVED.sleep();
+            cpu::restore_interrupts(daif);
+            continue;
+        }
+        cpu::restore_interrupts(daif);
+        gpu::draw_cursor(x, y);
+        drawn = moves;
+    }
+}
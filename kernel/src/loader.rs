@@ -1,5 +1,8 @@
-use core::ptr;
-use aprk_arch_arm64::{println, cpu};
+use aprk_arch_arm64::mmu::{self, PageFlags, PAGE_SIZE};
+use crate::mm::pmm::PageTag;
+use crate::mm::user;
+use aprk_arch_arm64::{cpu, log_error, log_info};
+use crate::fs::{FileHandle, SeekFrom};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -41,90 +44,212 @@ struct ProgramHeader {
 
 const PT_LOAD: u32 = 1;
 
-/// Load an ELF binary into memory.
-/// Returns the Entry Point address.
-pub unsafe fn load_elf(data: &[u8]) -> Option<u64> {
-    if data.len() < core::mem::size_of::<ElfHeader>() {
-         println!("[loader] File too small");
-         return None;
+// Segment permission flags
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// Most loadable segments in a program
+const MAX_SEGMENTS: usize = 8;
+
+/// A loaded program: where to start it and the user pages it occupies.
+/// The pages belong to the task that runs it, which frees them on exit.
+pub struct Image {
+    pub entry: u64,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A PT_LOAD segment, checked against the file
+#[derive(Clone, Copy)]
+struct Segment {
+    vaddr: usize,
+    offset: usize,
+    file_size: usize,
+    mem_size: usize,
+    flags: PageFlags,
+}
+
+impl Segment {
+    /// First page and end of the last page the segment touches
+    fn pages(&self) -> (usize, usize) {
+        (self.vaddr & !(PAGE_SIZE - 1), (self.vaddr + self.mem_size).next_multiple_of(PAGE_SIZE))
+    }
+
+    fn has_page(&self, page: usize) -> bool {
+        let (first, last) = self.pages();
+        (first..last).contains(&page)
+    }
+}
+
+/// Page permissions for segment flags `p_flags`. Only segments marked
+/// executable may run (W^X: never both writable and executable).
+fn segment_flags(vaddr: u64, p_flags: u32) -> PageFlags {
+    if p_flags & (PF_X | PF_W) == PF_X | PF_W {
+        log_info!("loader", "Segment at {:#x} is W+X, mapping it executable only", vaddr);
+    }
+    if p_flags & PF_X != 0 {
+        PageFlags::USER_RX
+    } else if p_flags & PF_W != 0 {
+        PageFlags::USER_RW
+    } else {
+        PageFlags::USER_RO
     }
+}
 
-    // Read header manually to guarantee no alignment issues
-    let mut header = core::mem::MaybeUninit::<ElfHeader>::uninit();
-    ptr::copy_nonoverlapping(
-        data.as_ptr(), 
-        header.as_mut_ptr() as *mut u8, 
-        core::mem::size_of::<ElfHeader>()
-    );
-    let header = header.assume_init();
+/// Free the pages of a loaded program that never got to run.
+pub fn unload(image: &Image) {
+    user::release(image.start, image.end, usize::MAX, PageTag::Anonymous);
+}
+
+/// Read a `T` from `file` at `offset`.
+///
+/// # Safety
+/// Any bit pattern must be a valid `T`.
+unsafe fn read_struct<T>(file: &mut FileHandle, offset: u64) -> Option<T> {
+    let mut value = core::mem::MaybeUninit::<T>::uninit();
+    let bytes = core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, core::mem::size_of::<T>());
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.read_exact(bytes).ok()?;
+    Some(value.assume_init())
+}
+
+/// Load an ELF binary from `file` into fresh pages of the user address
+/// space, reading it a piece at a time.
+///
+/// Each segment's pages get the permissions its `p_flags` ask for: text
+/// RX, rodata R, data/bss RW. Refuses programs whose segments would share
+/// a page with different permissions, or overlap pages already in use.
+pub unsafe fn load_elf(file: &mut FileHandle) -> Option<Image> {
+    let file_len = file.size();
+    let Some(header) = read_struct::<ElfHeader>(file, 0) else {
+         log_error!("loader", "File too small");
+         return None;
+    };
 
     // Validate Magic (0x7F, 'E', 'L', 'F')
     if header.magic != [0x7f, 0x45, 0x4c, 0x46] {
-        println!("[loader] Invalid ELF Magic");
+        log_error!("loader", "Invalid ELF Magic");
         return None;
     }
     
     // Check Architecture (0xB7 = AArch64) -> 183 decimal
     if header.machine != 183 {
-         println!("[loader] Wrong Architecture: {}", header.machine);
+         log_error!("loader", "Wrong Architecture: {}", header.machine);
          return None;
     }
 
-    println!("[loader] Loading ELF at Entry: {:#x}", header.entry);
+    log_info!("loader", "Loading ELF at Entry: {:#x}", header.entry);
+
+    // Segments are mapped in the active address space: make that the
+    // user tasks' one for as long as the loading task runs
+    let Some(space) = user::space() else {
+        log_error!("loader", "No user address space");
+        return None;
+    };
+    crate::sched::set_current_space(space);
+
+    // Collect the loadable segments and check them before touching memory
+    let mut segments = [None::<Segment>; MAX_SEGMENTS];
+    let mut count = 0;
+    let ent_size = header.phentsize as u64;
+    if header.phoff + header.phnum as u64 * ent_size > file_len {
+        log_error!("loader", "Program headers out of bounds");
+        return None;
+    }
 
-    // Iterate Program Headers
-    let ph_table = data.as_ptr().add(header.phoff as usize);
-    let ent_size = header.phentsize as usize;
-    
     for i in 0..header.phnum {
-        let ph_ptr = ph_table.add((i as usize) * ent_size);
-        
-        // Manual copy for Program Header
-        let mut ph = core::mem::MaybeUninit::<ProgramHeader>::uninit();
-        ptr::copy_nonoverlapping(
-            ph_ptr, 
-            ph.as_mut_ptr() as *mut u8, 
-            core::mem::size_of::<ProgramHeader>()
-        );
-        let ph = ph.assume_init();
-        
-        if ph.type_ == PT_LOAD {
-            // Check if Mem Size is 0 (useless segment)
-            if ph.memsz == 0 { continue; }
-
-            // println!("[loader] Segment: VAddr {:#x}, Size {:#x}", ph.vaddr, ph.memsz);
-            
-            // Destination in Memory
-            let dest = ph.vaddr as *mut u8;
-            
-            // Source in File
-            let src = data.as_ptr().add(ph.offset as usize);
-            
-            // Size present in file
-            let file_size = ph.filesz as usize;
-            
-            // Total size in memory
-            let mem_size = ph.memsz as usize;
-            
-            // 1. Copy file data
-            if file_size > 0 {
-                ptr::copy_nonoverlapping(src, dest, file_size);
-            }
-            
-            // 2. Zero remaining memory (BSS)
-            if mem_size > file_size {
-                let bss_dest = dest.add(file_size);
-                let bss_size = mem_size - file_size;
-                ptr::write_bytes(bss_dest, 0, bss_size);
+        let Some(ph) = read_struct::<ProgramHeader>(file, header.phoff + i as u64 * ent_size) else {
+            log_error!("loader", "Cannot read program header {}", i);
+            return None;
+        };
+
+        // Segments with no memory size are useless
+        if ph.type_ != PT_LOAD || ph.memsz == 0 {
+            continue;
+        }
+        let in_file = ph.offset.checked_add(ph.filesz).is_some_and(|end| end <= file_len);
+        let in_user = ph.vaddr.checked_add(ph.memsz).is_some_and(|end| end <= mmu::VA_LIMIT as u64);
+        if !in_file || !in_user || ph.filesz > ph.memsz {
+            log_error!("loader", "Bad segment at {:#x}", ph.vaddr);
+            return None;
+        }
+        if count == MAX_SEGMENTS {
+            log_error!("loader", "More than {} segments", MAX_SEGMENTS);
+            return None;
+        }
+        segments[count] = Some(Segment {
+            vaddr: ph.vaddr as usize,
+            offset: ph.offset as usize,
+            file_size: ph.filesz as usize,
+            mem_size: ph.memsz as usize,
+            flags: segment_flags(ph.vaddr, ph.flags),
+        });
+        count += 1;
+    }
+    let segments = &segments[..count];
+
+    // Every page gets exactly one set of permissions
+    for (i, a) in segments.iter().flatten().enumerate() {
+        let (a_start, a_end) = a.pages();
+        for b in segments[..i].iter().flatten() {
+            let (b_start, b_end) = b.pages();
+            if a_start < b_end && b_start < a_end && a.flags != b.flags {
+                log_error!("loader", "Segments at {:#x} and {:#x} need different permissions on a shared page",
+                    b.vaddr, a.vaddr);
+                return None;
             }
-            
-            // 3. Clean D-Cache for this segment to ensure visibility to I-Cache
-            cpu::clean_dcache_range(dest as usize, mem_size);
+        }
+        if (a_start..a_end).step_by(PAGE_SIZE).any(|page| mmu::translate(page).is_some()
+            && !segments[..i].iter().flatten().any(|b| b.has_page(page)))
+        {
+            log_error!("loader", "Segment at {:#x} overlaps memory in use", a.vaddr);
+            return None;
+        }
+    }
+    let Some(start) = segments.iter().flatten().map(|s| s.pages().0).min() else {
+        log_error!("loader", "No loadable segments");
+        return None;
+    };
+    let end = segments.iter().flatten().map(|s| s.pages().1).max().unwrap_or(start);
+    let image = Image { entry: header.entry, start, end };
+
+    for (i, segment) in segments.iter().flatten().enumerate() {
+        // Fresh pages, writable for loading (pages an earlier segment
+        // shares are already there)
+        let (first, last) = segment.pages();
+        let mapped = (first..last).step_by(PAGE_SIZE).all(|page| {
+            segments[..i].iter().flatten().any(|b| b.has_page(page))
+                || user::map_image_page(page)
+        });
+        if !mapped {
+            log_error!("loader", "Out of memory for segment at {:#x}", segment.vaddr);
+            unload(&image);
+            return None;
+        }
+
+        // Read file data straight into place; the rest (BSS) is already zero
+        let dest = core::slice::from_raw_parts_mut(segment.vaddr as *mut u8, segment.file_size);
+        let read = file.seek(SeekFrom::Start(segment.offset as u64)).and_then(|_| file.read_exact(dest));
+        if read.is_err() {
+            log_error!("loader", "Read error loading segment at {:#x}", segment.vaddr);
+            unload(&image);
+            return None;
+        }
+
+        // Clean D-Cache for this segment to ensure visibility to I-Cache
+        cpu::clean_dcache_range(segment.vaddr, segment.mem_size);
+    }
+
+    // Apply each segment's permissions now that everything is loaded
+    for segment in segments.iter().flatten() {
+        let (first, last) = segment.pages();
+        for page in (first..last).step_by(PAGE_SIZE) {
+            user::protect(page, segment.flags);
         }
     }
 
     // Flush Cache to ensure instructions are visible
     cpu::flush_instruction_cache();
 
-    Some(header.entry)
+    Some(image)
 }
@@ -14,14 +14,21 @@
 
 extern crate alloc;
 
-use aprk_arch_arm64::{self as arch, cpu, println};
+use aprk_arch_arm64::{self as arch, cpu, log_debug, log_error, log_info, log_warn, println};
+use core::fmt::Write;
 use core::panic::PanicInfo;
 use crate::syscall::handle_syscall;
 
+mod block;
+mod console;
+mod crypto;
 mod drivers;
+mod fd;
 pub mod fs;
+mod input;
 mod loader;
 mod mm;
+mod net;
 mod sched;
 mod shell;
 mod syscall;
@@ -33,9 +40,9 @@ const VERSION: &str = "0.1.0";
 const CODENAME: &str = "Genesis";
 
 #[no_mangle]
-pub extern "C" fn kernel_main() -> ! {
+pub extern "C" fn kernel_main(dtb_phys: usize) -> ! {
     // 1. Initialize architecture-specific hardware (MMU, Exceptions, GIC, Timer)
-    arch::init();
+    arch::init(dtb_phys);
     
     // 2. Initialize Memory Management (PMM + Heap)
     mm::init();
@@ -65,6 +72,13 @@ pub extern "C" fn kernel_main() -> ! {
     // 80% - Scheduler Ready
     drivers::gpu::update_progress(80);
 
+    // Every interrupt handler is registered by now: make the page tables
+    // and write-once statics read-only
+    arch::mmu::protect_tables();
+    if arch::mmu::probe_table_write() {
+        log_error!("mm", "Page tables are still writable after protect_tables");
+    }
+
     // 6. Enable Scheduling
     sched::enable();
     println!("[kernel] Preemptive scheduler enabled.");
@@ -72,15 +86,21 @@ pub extern "C" fn kernel_main() -> ! {
     // 100% - System Ready
     drivers::gpu::update_progress(100);
     println!("[kernel] System ready. (Press Ctrl+A, X to exit QEMU)");
+    drivers::fbcon::start();
 
-    // 7. Spawn Shell
+    // 7. Spawn Shell, and the cursor if there's a pointer
+    input::start();
     sched::spawn_named(shell::shell_task, "shell", sched::Priority::High);
 
     // 8. Start Scheduling
     sched::schedule();
 
+    // Idle loop: sleep until an interrupt, then let woken tasks run
     loop {
         unsafe { core::arch::asm!("wfe"); }
+        #[cfg(feature = "debug-alloc")]
+        mm::track::sweep_if_due();
+        sched::schedule();
     }
 }
 
@@ -89,9 +109,63 @@ pub extern "Rust" fn kernel_tick() {
     sched::tick();
 }
 
+#[no_mangle]
+pub extern "Rust" fn kernel_input_wait(port: usize) {
+    sched::wait::wait_for_tty_input(port);
+}
+
+#[no_mangle]
+pub extern "Rust" fn kernel_input_wake(port: usize) {
+    sched::wait::TTY_INPUT[port].wake_all();
+}
+
+/// Copy `line` into `buf`, truncated to fit. Returns the length copied.
+fn copy_line(line: &str, buf: &mut [u8]) -> usize {
+    let n = line.len().min(buf.len());
+    buf[..n].copy_from_slice(&line.as_bytes()[..n]);
+    n
+}
+
+#[no_mangle]
+pub extern "Rust" fn kernel_console_read_line(buf: &mut [u8]) -> usize {
+    copy_line(&console::read_line(), buf)
+}
+
+#[no_mangle]
+pub extern "Rust" fn kernel_console_try_read_line(buf: &mut [u8]) -> Option<usize> {
+    console::try_read_line().map(|line| copy_line(&line, buf))
+}
+
+#[no_mangle]
+pub extern "Rust" fn kernel_console_interrupt(port: usize) -> bool {
+    // Only the console port has a foreground task
+    port == arch::uart::console_port() && console::interrupt_foreground()
+}
+
+#[no_mangle]
+pub extern "Rust" fn kernel_stack_fault(addr: usize) -> bool {
+    sched::stack_fault(addr)
+}
+
+#[no_mangle]
+pub extern "Rust" fn kernel_user_fault(addr: usize) -> bool {
+    sched::heap_fault(addr)
+}
+
+#[no_mangle]
+pub extern "Rust" fn kernel_user_exception(esr: u64, elr: u64, far: u64) -> ! {
+    sched::user_exception(esr, elr, far)
+}
+
+#[no_mangle]
+pub extern "Rust" fn kernel_cow_fault(addr: usize) -> bool {
+    mm::cow::fault(addr)
+}
+
 #[no_mangle]
 pub extern "C" fn kernel_syscall_handler(id: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
-    handle_syscall(id, arg0, arg1, arg2)
+    // Whatever the kernel allocates for a syscall counts against the caller
+    mm::quota::with_task_charge(Some(sched::current_task_id()), || handle_syscall(id, arg0, arg1, arg2))
 }
 
 fn print_banner() {
@@ -113,18 +187,23 @@ fn print_system_info() {
     println!("[boot] Stack Pointer: {:#018x}", cpu::read_sp());
 }
 
+/// Report the panic straight to the console UART: the log, the other sinks
+/// and the UART's own lock may be held by the code that panicked.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!();
-    println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
-    println!("!!                     KERNEL PANIC                        !!");
-    println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
-    println!();
+    cpu::disable_interrupts();
+    let mut out = arch::uart::panic_writer();
+    let _ = writeln!(out);
+    let _ = writeln!(out, "!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+    let _ = writeln!(out, "!!                     KERNEL PANIC                        !!");
+    let _ = writeln!(out, "!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+    let _ = writeln!(out);
     if let Some(location) = info.location() {
-        println!("Location: {}:{}:{}", location.file(), location.line(), location.column());
+        let _ = writeln!(out, "Location: {}:{}:{}", location.file(), location.line(), location.column());
     }
-    println!("Message: {}", info.message());
-    println!();
-    println!("System halted.");
+    let _ = writeln!(out, "Message: {}", info.message());
+    let _ = writeln!(out);
+    let _ = writeln!(out, "System halted.");
+    arch::uart::flush();
     cpu::halt();
 }
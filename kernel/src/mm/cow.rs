@@ -0,0 +1,62 @@
+// =============================================================================
+// APRK OS - Copy-on-Write Pages
+// =============================================================================
+// Pages shared copy-on-write between address spaces are mapped read-only
+// in all of them. The first write from any one faults, and the writer gets
+// a private copy (or, if it's the last one still sharing, the page itself
+// made writable again).
+//
+// Each address space mapping a shared page holds a PMM reference to it.
+// =============================================================================
+
+use aprk_arch_arm64::mmu::{self, AddressSpace, PAGE_SIZE};
+use super::pmm;
+
+/// Share the pages mapped in `range` of `src` with `dst`, copy-on-write.
+///
+/// Returns `false` if the pages couldn't all be shared (see
+/// `mmu::share_cow`).
+pub fn share(dst: &AddressSpace, src: &AddressSpace, range: core::ops::Range<usize>) -> bool {
+    mmu::share_cow(dst, src, range, |pa| {
+        pmm::get_page(pa);
+    })
+}
+
+/// Handle a write fault at `addr` in the active address space.
+///
+/// Returns `false` if the page isn't copy-on-write (a real protection
+/// violation) or no memory is left for the copy.
+pub fn fault(addr: usize) -> bool {
+    let page = addr & !(PAGE_SIZE - 1);
+    let Some(pa) = mmu::cow_page(page) else {
+        return false;
+    };
+    let Some((_, mut flags)) = mmu::translate(page) else {
+        return false;
+    };
+    flags.write = true;
+
+    // The last sharer takes the page over
+    if pmm::page_refs(pa) <= 1 {
+        return mmu::remap(page, pa, flags);
+    }
+
+    let tag = pmm::tag_of(pa).unwrap_or(pmm::PageTag::Anonymous);
+    let Some(copy) = pmm::alloc_page_tagged(tag) else {
+        crate::log_error!("mm", "Out of memory copying page at {:#x}", page);
+        return false;
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            mmu::phys_to_virt(pa) as *const u8,
+            mmu::phys_to_virt(copy) as *mut u8,
+            PAGE_SIZE,
+        );
+    }
+    if !mmu::remap(page, copy, flags) {
+        pmm::free_page_tagged(copy, tag);
+        return false;
+    }
+    pmm::put_page(pa);
+    true
+}
@@ -3,29 +3,241 @@
 // =============================================================================
 // Initializes the Global Allocator so we can use Box, Vec, String, etc.
 // Uses linked_list_allocator crate for stability.
+//
+// The heap starts small, right after the kernel image, and grows from the
+// PMM when an allocation doesn't fit: by claiming the pages just past the
+// end of an extent and extending it, or failing that by adding a new
+// extent elsewhere in RAM, up to `MAX_SIZE` in all. It never shrinks.
+//
+// The global allocator counts allocations with atomics on their way in
+// and out, and warns once live bytes pass 80% of the heap's size. With
+// the debug-alloc feature allocations go through track.rs, which records
+// them and guards them with redzones. Allocations made on a task's behalf
+// are charged to it by quota.rs first, and fail once it's over quota.
 // =============================================================================
 
-use linked_list_allocator::LockedHeap;
+use aprk_arch_arm64::{cpu, mmu};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use super::pmm::{self, PageTag, PAGE_SIZE};
+
+/// Size of the first extent, reserved at boot
+pub const INITIAL_SIZE: usize = 4 * 1024 * 1024; // 4 MB
+/// Alignment of the heap's physical start
+pub const HEAP_ALIGN: usize = 2 * 1024 * 1024;
+/// Most the heap may grow to, all extents together
+pub const MAX_SIZE: usize = 256 * 1024 * 1024;
+/// Most separate extents
+const MAX_EXTENTS: usize = 16;
+/// Least the heap grows by at a time
+const GROW_MIN: usize = 1024 * 1024;
+
+/// The heap's extents. An unused slot has size 0.
+struct KernelHeap(Mutex<[Heap; MAX_EXTENTS]>);
+
+const NO_EXTENT: Heap = Heap::empty();
+
+static HEAP: KernelHeap = KernelHeap(Mutex::new([NO_EXTENT; MAX_EXTENTS]));
+
+/// Counts allocations, then hands them to `HEAP`.
+struct Instrumented;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Instrumented = Instrumented;
 
-// Heap starts after the kernel bitmap, let's pick a safe spot.
-// RAM: 0x4000_0000 
-// Kernel loads at 0x4008_0000.
-// Let's put the Heap at 0x4100_0000 (16MB mark) and give it 16MB.
-pub const HEAP_START: usize = 0x4100_0000;
-pub const HEAP_SIZE: usize = 16 * 1024 * 1024; // 16 MB
+/// Bytes in all extents
+static SIZE: AtomicUsize = AtomicUsize::new(0);
+/// Allocations and bytes (as requested) live right now
+static LIVE_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Allocations since boot, and the most bytes ever live at once
+static TOTAL_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Set while live bytes are over the warning threshold
+static NEAR_FULL: AtomicBool = AtomicBool::new(false);
 
-pub fn init() {
+/// A snapshot of kernel heap usage, in bytes unless noted.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// All extents together
+    pub size: usize,
+    /// In use and free as the allocator sees it, padding included
+    pub used: usize,
+    pub free: usize,
+    /// Allocations live right now (a count), and the bytes they asked for
+    pub live_allocs: usize,
+    pub live_bytes: usize,
+    /// Allocations since boot (a count)
+    pub total_allocs: usize,
+    /// Highest `live_bytes` has been
+    pub peak_bytes: usize,
+}
+
+/// Physical start of the first extent, chosen by `mm::init` (the kernel
+/// uses the heap through the linear map)
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+
+/// Initialize the heap over the `INITIAL_SIZE` bytes of RAM at physical
+/// address `start`, which must already be reserved.
+pub fn init(start: usize) {
+    HEAP_START.store(start, Ordering::Relaxed);
+    let virt = mmu::phys_to_virt(start);
+    let daif = cpu::save_and_disable_interrupts();
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+        HEAP.0.lock()[0].init(virt as *mut u8, INITIAL_SIZE);
+    }
+    cpu::restore_interrupts(daif);
+    SIZE.store(INITIAL_SIZE, Ordering::Relaxed);
+    crate::log_info!("mm", "Heap Initialized at {:#x} (Size: {} MB, up to {} MB)",
+        virt, INITIAL_SIZE / 1024 / 1024, MAX_SIZE / 1024 / 1024);
+}
+
+/// Physical start of the first extent.
+pub fn start() -> usize {
+    HEAP_START.load(Ordering::Relaxed)
+}
+
+/// Current kernel heap usage.
+pub fn stats() -> HeapStats {
+    let daif = cpu::save_and_disable_interrupts();
+    let extents = HEAP.0.lock();
+    let (used, free) = extents.iter().fold((0, 0), |(used, free), heap| (used + heap.used(), free + heap.free()));
+    drop(extents);
+    cpu::restore_interrupts(daif);
+    HeapStats {
+        size: SIZE.load(Ordering::Relaxed),
+        used,
+        free,
+        live_allocs: LIVE_ALLOCS.load(Ordering::Relaxed),
+        live_bytes: LIVE_BYTES.load(Ordering::Relaxed),
+        total_allocs: TOTAL_ALLOCS.load(Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+/// Physical start and size of each extent of the heap.
+pub fn extents() -> [Option<(usize, usize)>; MAX_EXTENTS] {
+    let daif = cpu::save_and_disable_interrupts();
+    let extents = HEAP.0.lock();
+    let ranges = core::array::from_fn(|i| {
+        let heap = &extents[i];
+        (heap.size() != 0).then(|| (mmu::virt_to_phys(heap.bottom() as usize), heap.size()))
+    });
+    drop(extents);
+    cpu::restore_interrupts(daif);
+    ranges
+}
+
+/// Allocate `layout` from the first extent with room for it.
+fn first_fit(extents: &mut [Heap; MAX_EXTENTS], layout: Layout) -> *mut u8 {
+    extents.iter_mut()
+        .filter(|heap| heap.size() != 0)
+        .find_map(|heap| heap.allocate_first_fit(layout).ok())
+        .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr())
+}
+
+/// Add enough memory to the heap for `layout`.
+///
+/// Returns `false` if the heap is at `MAX_SIZE`, out of extents, or the
+/// PMM has no memory to give.
+fn grow(extents: &mut [Heap; MAX_EXTENTS], layout: Layout) -> bool {
+    let size: usize = extents.iter().map(Heap::size).sum();
+    let by = (layout.size() + layout.align()).max(GROW_MIN).next_multiple_of(PAGE_SIZE);
+    if size + by > MAX_SIZE {
+        return false;
+    }
+
+    // Extending an extent lets the allocation use the free space at its end
+    for heap in extents.iter_mut().filter(|heap| heap.size() != 0) {
+        let top = mmu::virt_to_phys(heap.top() as usize);
+        if pmm::claim_pages(top, by / PAGE_SIZE, PageTag::KernelHeap) {
+            unsafe { heap.extend(by); }
+            SIZE.fetch_add(by, Ordering::Relaxed);
+            return true;
+        }
+    }
+
+    let order = (by / PAGE_SIZE).next_power_of_two().trailing_zeros() as usize;
+    if size + (PAGE_SIZE << order) > MAX_SIZE {
+        return false;
+    }
+    let Some(slot) = extents.iter_mut().find(|heap| heap.size() == 0) else {
+        return false;
+    };
+    let Some(pa) = pmm::alloc_pages_tagged(order, PageTag::KernelHeap) else {
+        return false;
+    };
+    *slot = unsafe { Heap::new(mmu::phys_to_virt(pa) as *mut u8, PAGE_SIZE << order) };
+    SIZE.fetch_add(PAGE_SIZE << order, Ordering::Relaxed);
+    true
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let daif = cpu::save_and_disable_interrupts();
+        let mut extents = self.0.lock();
+        let mut ptr = first_fit(&mut extents, layout);
+        if ptr.is_null() && grow(&mut extents, layout) {
+            ptr = first_fit(&mut extents, layout);
+        }
+        drop(extents);
+        cpu::restore_interrupts(daif);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let daif = cpu::save_and_disable_interrupts();
+        let mut extents = self.0.lock();
+        if let Some(heap) = extents.iter_mut().find(|heap| (heap.bottom()..heap.top()).contains(&ptr)) {
+            heap.deallocate(NonNull::new_unchecked(ptr), layout);
+        }
+        drop(extents);
+        cpu::restore_interrupts(daif);
+    }
+}
+
+unsafe impl GlobalAlloc for Instrumented {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(not(feature = "debug-alloc"))]
+        let ptr = HEAP.alloc(layout);
+        #[cfg(feature = "debug-alloc")]
+        let ptr = super::track::alloc(&HEAP, layout);
+        if !ptr.is_null() {
+            super::quota::charge(layout.size());
+            TOTAL_ALLOCS.fetch_add(1, Ordering::Relaxed);
+            LIVE_ALLOCS.fetch_add(1, Ordering::Relaxed);
+            let live = LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_BYTES.fetch_max(live, Ordering::Relaxed);
+            // Warn on crossing 80%, and again only after dropping below 70%
+            let size = SIZE.load(Ordering::Relaxed);
+            if live * 10 > size * 8 && !NEAR_FULL.swap(true, Ordering::Relaxed) {
+                crate::log_warn!("mm", "Kernel heap over 80% used ({} of {} KB)", live / 1024, size / 1024);
+            }
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(not(feature = "debug-alloc"))]
+        HEAP.dealloc(ptr, layout);
+        #[cfg(feature = "debug-alloc")]
+        super::track::dealloc(&HEAP, ptr, layout);
+        super::quota::credit(layout.size());
+        LIVE_ALLOCS.fetch_sub(1, Ordering::Relaxed);
+        let live = LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed) - layout.size();
+        if live * 10 < SIZE.load(Ordering::Relaxed) * 7 {
+            NEAR_FULL.store(false, Ordering::Relaxed);
+        }
     }
-    crate::println!("[mm] Heap Initialized at {:#x} (Size: {} MB)", HEAP_START, HEAP_SIZE / 1024 / 1024);
 }
 
 // Handler for Allocation Errors (OOM)
 #[alloc_error_handler]
 fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
+    // Quotas never fail an allocation (see `quota`): this is the heap
+    // itself out of memory
     panic!("allocation error: {:?}", layout)
 }
@@ -0,0 +1,106 @@
+// =============================================================================
+// APRK OS - Kernel Stacks
+// =============================================================================
+// Task kernel stacks live in their own 16MB region of the kernel half,
+// away from the heap. The region is cut into 64KB slots; a stack is
+// mapped at the top of its slot from fresh PMM pages and the rest of the
+// slot is left unmapped, so every stack has a hole of at least one page
+// beneath it before its neighbour.
+// =============================================================================
+
+use aprk_arch_arm64::cpu;
+use aprk_arch_arm64::mmu::{self, PageFlags, PAGE_SIZE};
+use spin::Mutex;
+use super::MapFlags;
+use super::pmm::PageTag;
+
+/// Kernel stack region (68GB into the kernel half, above the I/O window)
+pub const REGION_BASE: usize = mmu::KERNEL_OFFSET + 0x11_0000_0000;
+pub const REGION_SIZE: usize = 16 * 1024 * 1024;
+/// One slot: the stack at the top, unmapped below
+const SLOT_SIZE: usize = 64 * 1024;
+const SLOTS: usize = REGION_SIZE / SLOT_SIZE;
+
+/// Slots in use, one bit each
+static SLOT_MAP: Mutex<[u64; SLOTS / 64]> = Mutex::new([0; SLOTS / 64]);
+
+/// A mapped kernel stack. Dropping it unmaps the stack and frees its
+/// pages, so it must not be in use by then.
+pub struct KernelStack {
+    slot: usize,
+    size: usize,
+}
+
+impl KernelStack {
+    /// Allocate and map a zeroed stack of `size` bytes (rounded up to
+    /// pages). Returns `None` if no slot or memory is free, or `size`
+    /// doesn't leave room for a guard page in a slot.
+    pub fn new(size: usize) -> Option<Self> {
+        let size = size.next_multiple_of(PAGE_SIZE);
+        if size == 0 || size + PAGE_SIZE > SLOT_SIZE {
+            return None;
+        }
+        let slot = claim_slot()?;
+        let stack = Self { slot, size };
+        for page in (stack.base()..stack.top()).step_by(PAGE_SIZE) {
+            if !super::map_new(page, PageFlags::KERNEL_RW, MapFlags::ZERO, PageTag::KernelStack) {
+                return None; // Drop unmaps what was mapped so far
+            }
+        }
+        Some(stack)
+    }
+
+    /// Lowest address of the stack
+    pub fn base(&self) -> usize {
+        self.top() - self.size
+    }
+
+    /// Initial stack pointer (one past the highest address)
+    pub fn top(&self) -> usize {
+        REGION_BASE + (self.slot + 1) * SLOT_SIZE
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The unmapped part of the slot, beneath the stack
+    pub fn guard(&self) -> (usize, usize) {
+        (REGION_BASE + self.slot * SLOT_SIZE, self.base())
+    }
+
+    /// Deepest the stack has been used, in bytes. The stack starts out
+    /// zeroed, so this is found by scanning up for the first non-zero
+    /// word.
+    pub fn peak_usage(&self) -> usize {
+        let words = self.size / 8;
+        let base = self.base() as *const u64;
+        let untouched = (0..words)
+            .take_while(|&i| unsafe { core::ptr::read_volatile(base.add(i)) } == 0)
+            .count();
+        (words - untouched) * 8
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        for page in (self.base()..self.top()).step_by(PAGE_SIZE) {
+            super::unmap_free(page, PageTag::KernelStack);
+        }
+        let daif = cpu::save_and_disable_interrupts();
+        SLOT_MAP.lock()[self.slot / 64] &= !(1 << (self.slot % 64));
+        cpu::restore_interrupts(daif);
+    }
+}
+
+fn claim_slot() -> Option<usize> {
+    let daif = cpu::save_and_disable_interrupts();
+    let mut map = SLOT_MAP.lock();
+    let slot = (0..SLOTS).find(|&i| map[i / 64] & (1 << (i % 64)) == 0);
+    if let Some(i) = slot {
+        map[i / 64] |= 1 << (i % 64);
+    }
+    drop(map);
+    cpu::restore_interrupts(daif);
+    slot
+}
@@ -1,15 +1,447 @@
+use aprk_arch_arm64::mmu::{self, MemoryType, PageFlags, PteAttrs};
+use pmm::PageTag;
+
+pub mod cow;
 pub mod pmm;
 pub mod heap;
+pub mod kstack;
+pub mod quota;
+pub mod slab;
+pub mod stress;
+#[cfg(feature = "debug-alloc")]
+pub mod track;
+pub mod user;
+
+/// Byte freed pages are filled with in debug builds
+pub const POISON: u8 = 0xDE;
+
+/// Allocate `layout` from the kernel heap, or `None` if it can't be had.
+///
+/// Unlike `Box`/`Vec`, running out isn't fatal: use this (or the
+/// `try_reserve` family) wherever the size comes from user space. Fails
+/// too if the current charge target has no quota left for it (see
+/// `quota`). Free with `alloc::alloc::dealloc`.
+#[allow(dead_code)]
+pub fn try_alloc(layout: core::alloc::Layout) -> Option<core::ptr::NonNull<u8>> {
+    if layout.size() == 0 || !quota::allows(layout.size()) {
+        return None;
+    }
+    core::ptr::NonNull::new(unsafe { alloc::alloc::alloc(layout) })
+}
+
+/// How `map_new` prepares the page it allocates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapFlags(u8);
+
+impl MapFlags {
+    pub const NONE: Self = Self(0);
+    /// Fill the page with zeros before mapping it. Always applied to
+    /// EL0-accessible pages, whatever the caller asks for.
+    pub const ZERO: Self = Self(1 << 0);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for MapFlags {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Allocate a physical page for `tag` and map it at `va`.
+///
+/// Pages mapped EL0-accessible are always zeroed, so user space never
+/// sees what the kernel left in them. Returns `false` if physical memory
+/// ran out or the page couldn't be mapped.
+pub fn map_new(va: usize, flags: PageFlags, policy: MapFlags, tag: PageTag) -> bool {
+    let frame = if flags.user || policy.contains(MapFlags::ZERO) {
+        pmm::alloc_page_zeroed(tag)
+    } else {
+        pmm::alloc_page_tagged(tag)
+    };
+    let Some(frame) = frame else {
+        return false;
+    };
+    mmu::map_page(va, frame, flags) || {
+        pmm::free_page_tagged(frame, tag);
+        false
+    }
+}
+
+/// Unmap the page at `va` and free the physical page behind it, unless
+/// another address space still shares it copy-on-write. In debug builds
+/// the page is filled with `POISON` first, so stale pointers into it read
+/// obvious garbage.
+///
+/// The page must have been allocated for `tag`; one belonging to another
+/// subsystem is unmapped but not freed. Returns `false` if nothing was
+/// mapped there.
+pub fn unmap_free(va: usize, tag: PageTag) -> bool {
+    let Some((pa, _)) = mmu::translate(va) else {
+        return false;
+    };
+    let pa = pa & !(pmm::PAGE_SIZE - 1);
+    mmu::unmap_page(va);
+    if cfg!(debug_assertions) && pmm::page_refs(pa) == 1 && pmm::tag_of(pa) == Some(tag) {
+        unsafe { core::ptr::write_bytes(mmu::phys_to_virt(pa) as *mut u8, POISON, pmm::PAGE_SIZE); }
+    }
+    pmm::free_page_tagged(pa, tag);
+    true
+}
 
 pub fn init() {
-    // We need the end of the kernel to know where free memory starts.
-    // This symbol comes from the linker script.
+    // The kernel image's bounds come from the linker script
     extern "C" {
-        static __kernel_end: usize;
+        static __text_start: u8;
+        static __kernel_end: u8;
     }
+    let kernel_start = mmu::virt_to_phys(core::ptr::addr_of!(__text_start) as usize);
+    let kernel_end = mmu::virt_to_phys(core::ptr::addr_of!(__kernel_end) as usize);
     
-    let kernel_end = unsafe { &__kernel_end as *const _ as usize };
-    
-    pmm::init(kernel_end);
-    heap::init();
+    // Manage exactly the RAM the MMU mapped, minus what's already in it.
+    // The boot page tables and the table pool are part of the image.
+    let (ram_base, ram_size) = mmu::ram();
+    pmm::init(ram_base, ram_size);
+    pmm::reserve_range(kernel_start, kernel_end - kernel_start, "kernel image");
+    // The heap goes right after the kernel image, or after the device tree
+    // or ramdisk if that's in the way
+    let mut heap_start = kernel_end.next_multiple_of(heap::HEAP_ALIGN);
+    if let Some(dtb) = aprk_arch_arm64::dtb::get() {
+        let dtb_start = mmu::virt_to_phys(dtb.addr());
+        pmm::reserve_range(dtb_start, dtb.size(), "device tree");
+        if dtb_start < heap_start + heap::INITIAL_SIZE && heap_start < dtb_start + dtb.size() {
+            heap_start = (dtb_start + dtb.size()).next_multiple_of(heap::HEAP_ALIGN);
+        }
+    }
+    if let Some((initrd_start, initrd_len)) = crate::fs::tar::locate(ram_base, ram_base + ram_size) {
+        pmm::reserve_range(initrd_start, initrd_len, "initrd");
+        if initrd_start < heap_start + heap::INITIAL_SIZE && heap_start < initrd_start + initrd_len {
+            heap_start = (initrd_start + initrd_len).next_multiple_of(heap::HEAP_ALIGN);
+        }
+    }
+    if heap_start + heap::INITIAL_SIZE > ram_base + ram_size {
+        panic!("No room for the kernel heap in {} MB of RAM", ram_size >> 20);
+    }
+    pmm::reserve_range(heap_start, heap::INITIAL_SIZE, "kernel heap");
+    heap::init(heap_start);
+    user::init();
+    mapping_self_test();
+    zero_self_test();
+    block_mapping_self_test();
+    cow_self_test();
+    wx_self_test();
+}
+
+/// Print the kernel address space: named regions, then every mapping.
+pub fn vmmap() {
+    extern "C" {
+        static __text_start: u8;
+        static __text_end: u8;
+        static __rodata_start: u8;
+        static __rodata_end: u8;
+        static __data_start: u8;
+        static __kernel_end: u8;
+    }
+    let sym = |s: &u8| s as *const u8 as usize;
+    let regions = unsafe {
+        [
+            ("kernel text", sym(&__text_start), sym(&__text_end)),
+            ("kernel rodata", sym(&__rodata_start), sym(&__rodata_end)),
+            ("kernel data/bss", sym(&__data_start), sym(&__kernel_end)),
+            ("kernel heap", mmu::phys_to_virt(heap::start()),
+                mmu::phys_to_virt(heap::start() + heap::INITIAL_SIZE)),
+            ("kernel stacks", kstack::REGION_BASE, kstack::REGION_BASE + kstack::REGION_SIZE),
+            ("user heap", user::HEAP_BASE, user::HEAP_BASE + user::HEAP_SIZE),
+            ("user stacks", user::STACK_BASE, user::STACK_BASE + user::STACK_REGION_SIZE),
+        ]
+    };
+
+    crate::println!("Regions:");
+    for (name, start, end) in regions {
+        crate::println!("  {:<16} {:#018x}-{:#018x} ({} KB)", name, start, end, (end - start) / 1024);
+    }
+    crate::println!("User half (TTBR0):");
+    mmu::dump_range(0, mmu::VA_LIMIT);
+    crate::println!("Kernel half (TTBR1):");
+    mmu::dump_range(mmu::KERNEL_OFFSET, usize::MAX);
+}
+
+/// Print every mapping in the active translation tables, merging
+/// neighbours with contiguous physical addresses and identical
+/// attributes, and warn about ranges that are both writable and
+/// executable.
+pub fn ptdump() {
+    /// A run of mappings being accumulated
+    struct Run {
+        va: usize,
+        pa: usize,
+        len: usize,
+        attrs: PteAttrs,
+    }
+
+    fn print_run(run: &Run) {
+        let memory = match run.attrs.memory {
+            MemoryType::Normal => "Normal",
+            MemoryType::NonCacheable => "NC",
+            MemoryType::Device => "Device",
+        };
+        let access = match run.attrs.ap {
+            0b00 => "RW/--",
+            0b01 => "RW/RW",
+            0b10 => "RO/--",
+            _ => "RO/RO",
+        };
+        let (len, unit) = mmu::size_unit(run.len);
+        crate::println!("{:#018x}-{:#018x}  {:#012x}-{:#012x}  {:>5}{}  {:<6}  {:02b} {}  {} {}{}",
+            run.va, run.va + run.len, run.pa, run.pa + run.len, len, unit, memory,
+            run.attrs.ap, access,
+            if run.attrs.uxn { "UXN" } else { "   " },
+            if run.attrs.pxn { "PXN" } else { "   " },
+            if run.attrs.writable() && run.attrs.executable() { "  <-- W+X" } else { "" });
+    }
+
+    crate::println!("{:<37}  {:<25}  {:>6}  {:<6}  {:<8}  {}",
+        "VIRTUAL", "PHYSICAL", "SIZE", "TYPE", "AP EL1/0", "XN");
+    let mut run: Option<Run> = None;
+    let mut wx = 0;
+    mmu::walk(|va, pa, size, attrs| {
+        if let Some(r) = &mut run {
+            if r.attrs == attrs && r.va.wrapping_add(r.len) == va && r.pa + r.len == pa {
+                r.len += size;
+                return;
+            }
+            print_run(r);
+        }
+        if attrs.writable() && attrs.executable() {
+            wx += 1;
+        }
+        run = Some(Run { va, pa, len: size, attrs });
+    });
+    if let Some(r) = &run {
+        print_run(r);
+    }
+    if wx != 0 {
+        crate::log_warn!("mm", "{} writable and executable range(s) mapped", wx);
+    }
+}
+
+/// Print physical memory and kernel heap use, and the resident size of
+/// each user heap. `verbose` adds the used pages broken down by tag.
+pub fn meminfo(verbose: bool) {
+    let stats = pmm::stats();
+    let kb = |pages: usize| pages * pmm::PAGE_SIZE / 1024;
+    crate::println!("Physical: {} pages used ({} KB), {} free ({} KB), {} total, peak {} used",
+        stats.used, kb(stats.used), stats.free, kb(stats.free), stats.total, stats.peak);
+    if verbose {
+        for (tag, pages) in PageTag::ALL.iter().zip(pmm::usage_by_tag()) {
+            crate::println!("  {:<12} {:>6} pages ({} KB)", alloc::format!("{:?}", tag), pages, kb(pages));
+        }
+        crate::println!("Slab caches:");
+        for cache in slab::stats().into_iter().flatten() {
+            crate::println!("  {:<12} {:>4}-byte slots: {} live, {} free, {} pages",
+                cache.name, cache.slot_size, cache.live, cache.free, cache.pages);
+        }
+    }
+    let heap = heap::stats();
+    crate::println!("Kernel heap: {} KB used, {} KB free, {} KB in all",
+        heap.used / 1024, heap.free / 1024, heap.size / 1024);
+    crate::println!("  {} live allocations ({} KB), peak {} KB, {} allocations since boot",
+        heap.live_allocs, heap.live_bytes / 1024, heap.peak_bytes / 1024, heap.total_allocs);
+    for (start, size) in heap::extents().into_iter().flatten() {
+        crate::println!("  {:#010x}-{:#010x} {:>6} KB", start, start + size, size / 1024);
+    }
+    crate::println!("Reserved:");
+    for r in pmm::reservations().iter().flatten() {
+        crate::println!("  {:#010x}-{:#010x} {:>6} KB  {}", r.start, r.start + r.len, r.len.div_ceil(1024), r.tag);
+    }
+    let (tables, pool) = mmu::table_pool_usage();
+    crate::println!("Page tables: {} of {} pool tables used", tables, pool);
+    crate::println!();
+    crate::sched::print_heaps();
+}
+
+/// Check W^X: jumping into a heap buffer holding a `ret` must take an
+/// instruction abort.
+fn wx_self_test() {
+    /// AArch64 `ret`
+    const RET: u32 = 0xd65f_03c0;
+    let code = alloc::vec![RET; 4];
+    let executed = unsafe { mmu::probe_exec(code.as_ptr() as usize) };
+    if executed {
+        crate::log_error!("mm", "W^X self-test FAILED: heap memory is executable");
+    } else {
+        crate::log_info!("mm", "W^X self-test passed (heap is not executable)");
+    }
+}
+
+/// Check the page policies: write a secret into a page mapped with
+/// `map_new`, release it with `unmap_free`, and make sure a fresh page
+/// mapped at the same VA reads back as zeros.
+fn zero_self_test() {
+    /// The `mapping_self_test` VA, unused outside the self-tests
+    const TEST_VA: usize = mmu::KERNEL_OFFSET + 0x40_0000_0000;
+    const SECRET: u64 = 0x5EC2_E75E_C2E7_5EC2;
+
+    if !map_new(TEST_VA, PageFlags::KERNEL_RW, MapFlags::NONE, PageTag::Anonymous) {
+        crate::log_error!("mm", "Zero-page self-test: no free page");
+        return;
+    }
+    let Some((frame, _)) = mmu::translate(TEST_VA) else {
+        return;
+    };
+    unsafe { core::ptr::write_volatile(TEST_VA as *mut u64, SECRET); }
+    unmap_free(TEST_VA, PageTag::Anonymous);
+
+    let poisoned = !cfg!(debug_assertions)
+        || unsafe { core::ptr::read_volatile(mmu::phys_to_virt(frame) as *const u8) } == POISON;
+    let mut ok = map_new(TEST_VA, PageFlags::KERNEL_RW, MapFlags::ZERO, PageTag::Anonymous);
+    if ok {
+        let words = TEST_VA as *const u64;
+        ok = (0..pmm::PAGE_SIZE / 8).all(|i| unsafe { core::ptr::read_volatile(words.add(i)) } == 0);
+        unmap_free(TEST_VA, PageTag::Anonymous);
+    }
+
+    if ok && poisoned {
+        crate::log_info!("mm", "Zero-page self-test passed (freed page {}, remap reads zero)",
+            if cfg!(debug_assertions) { "poisoned" } else { "released" });
+    } else {
+        crate::log_error!("mm", "Zero-page self-test FAILED (poisoned: {}, zeroed: {})", poisoned, ok);
+    }
+}
+
+/// Check `mmu::map_page`: map a PMM page at an unused VA and inside a
+/// boot-time block (forcing a split), write through the mappings, then
+/// remap read-only and make sure a write faults.
+fn mapping_self_test() {
+    /// Far above the linear map of RAM: needs fresh L2 and L3 tables
+    const TEST_VA: usize = mmu::KERNEL_OFFSET + 0x40_0000_0000;
+
+    let Some(frame) = pmm::alloc_page() else {
+        crate::log_error!("mm", "Mapping self-test: no free page");
+        return;
+    };
+
+    let ok = unsafe {
+        let mut ok = mmu::map_page(TEST_VA, frame, PageFlags::KERNEL_RW);
+        if ok {
+            core::ptr::write_volatile(TEST_VA as *mut u64, 0x5A5A_A5A5);
+            ok = core::ptr::read_volatile(mmu::phys_to_virt(frame) as *const u64) == 0x5A5A_A5A5;
+        }
+        if ok {
+            // Remapping the frame's own linear-map page splits the 2MB
+            // block around it (unless it's in the kernel image's pages)
+            let linear = mmu::phys_to_virt(frame);
+            ok = mmu::map_page(linear, frame, PageFlags::KERNEL_RW)
+                && core::ptr::read_volatile(linear as *const u64) == 0x5A5A_A5A5;
+        }
+        if ok {
+            ok = mmu::map_page(TEST_VA, frame, PageFlags::KERNEL_RO)
+                && core::ptr::read_volatile(TEST_VA as *const u64) == 0x5A5A_A5A5
+                && !mmu::probe_write(TEST_VA as *mut u8, 0);
+        }
+        mmu::unmap_page(TEST_VA);
+        ok
+    };
+    pmm::free_page(frame);
+
+    if ok {
+        crate::log_info!("mm", "Mapping self-test passed (map, split, read-only fault)");
+    } else {
+        crate::log_error!("mm", "Mapping self-test FAILED");
+    }
+}
+
+/// Map 64MB of RAM with `map_range` and check that it takes 2MB blocks
+/// (only a table or two), then unmap one page out of the middle of a
+/// block and check that its neighbours survive the demotion.
+fn block_mapping_self_test() {
+    /// A 1GB slot of its own, above the 4KB mapping self-test
+    const TEST_VA: usize = mmu::KERNEL_OFFSET + 0x41_0000_0000;
+    const LEN: usize = 64 * 1024 * 1024;
+    /// Inside the second 2MB block
+    const HOLE: usize = 3 * 1024 * 1024 + 4096;
+
+    let (ram_base, ram_size) = mmu::ram();
+    if ram_size < LEN {
+        crate::log_warn!("mm", "Block mapping self-test skipped: less than 64MB of RAM");
+        return;
+    }
+
+    let (tables_before, _) = mmu::table_pool_usage();
+    let mut ok = mmu::map_range(TEST_VA, ram_base, LEN, PageFlags::KERNEL_RO);
+    let tables = mmu::table_pool_usage().0 - tables_before;
+    ok = ok && tables <= 2;
+
+    let maps_to = |offset: usize| mmu::translate(TEST_VA + offset).map(|(pa, _)| pa);
+    ok = ok && maps_to(5 * 1024 * 1024 + 8) == Some(ram_base + 5 * 1024 * 1024 + 8);
+    if ok {
+        ok = mmu::unmap_range(TEST_VA + HOLE, 4096)
+            && maps_to(HOLE).is_none()
+            && maps_to(HOLE - 4096) == Some(ram_base + HOLE - 4096)
+            && maps_to(HOLE + 4096) == Some(ram_base + HOLE + 4096);
+    }
+    mmu::unmap_range(TEST_VA, LEN);
+    ok = ok && maps_to(0).is_none() && maps_to(LEN - 4096).is_none();
+
+    if ok {
+        crate::log_info!("mm", "Block mapping self-test passed (64MB in {} table(s), demotion)", tables);
+    } else {
+        crate::log_error!("mm", "Block mapping self-test FAILED");
+    }
+}
+
+/// Share a page copy-on-write between two fresh address spaces, write to
+/// it from one, and check the other still sees the original data.
+fn cow_self_test() {
+    /// Any user VA will do: the address spaces are private to the test
+    const TEST_VA: usize = 0x20_0000_0000;
+    const ORIGINAL: u64 = 0x0123_4567_89AB_CDEF;
+    const CHANGED: u64 = 0xFEDC_BA98_7654_3210;
+
+    let (Some(a), Some(b)) = (mmu::AddressSpace::new(), mmu::AddressSpace::new()) else {
+        crate::log_error!("mm", "COW self-test: no address spaces");
+        return;
+    };
+    let read = |space: &mmu::AddressSpace| {
+        space.activate();
+        unsafe { core::ptr::read_volatile(TEST_VA as *const u64) }
+    };
+
+    a.activate();
+    let mut ok = map_new(TEST_VA, PageFlags::USER_RW, MapFlags::ZERO, PageTag::Anonymous);
+    if ok {
+        unsafe { core::ptr::write_volatile(TEST_VA as *mut u64, ORIGINAL); }
+        ok = cow::share(&b, &a, TEST_VA..TEST_VA + pmm::PAGE_SIZE)
+            && mmu::translate(TEST_VA).is_some_and(|(pa, flags)| {
+                !flags.write && pmm::page_refs(pa & !(pmm::PAGE_SIZE - 1)) == 2
+            });
+    }
+    if ok {
+        // Faults, and `a` gets its own copy
+        unsafe { core::ptr::write_volatile(TEST_VA as *mut u64, CHANGED); }
+        ok = read(&a) == CHANGED && read(&b) == ORIGINAL;
+    }
+    if ok {
+        // `b` is the last sharer: it takes the page over without a copy
+        let before = mmu::translate(TEST_VA).map(|(pa, _)| pa);
+        unsafe { core::ptr::write_volatile(TEST_VA as *mut u64, CHANGED); }
+        ok = mmu::translate(TEST_VA).map(|(pa, _)| pa) == before && read(&a) == CHANGED;
+    }
+
+    for space in [&a, &b] {
+        space.activate();
+        unmap_free(TEST_VA, PageTag::Anonymous);
+    }
+    if let Some(space) = user::space() {
+        space.activate();
+    }
+
+    if ok {
+        crate::log_info!("mm", "COW self-test passed (writer copied, sharer kept original)");
+    } else {
+        crate::log_error!("mm", "COW self-test FAILED");
+    }
 }
@@ -1,86 +1,644 @@
 // =============================================================================
 // APRK OS - Physical Memory Manager (PMM)
 // =============================================================================
-// Tracks usage of physical RAM using a bitmap.
+// A binary buddy allocator over physical RAM, behind a spinlock taken
+// with IRQs masked so any context may allocate.
+//
+// Free memory is kept as blocks of 2^order pages, each aligned to its own
+// size, on one free list per order. Allocating splits a larger block when
+// no block of the right order is free; freeing merges a block with its
+// buddy (the other half of the block it was split from) for as long as
+// the buddy is free too. The list links live in the free pages
+// themselves, so the lists are only built on the first allocation, once
+// the kernel image and friends have been reserved and won't be written.
+//
+// Every page in use has a reference count, 1 when allocated. Pages mapped
+// in several places (shared copy-on-write, say) take a reference per
+// mapping with `get_page`, and `put_page` frees the page with the last.
 // =============================================================================
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use aprk_arch_arm64::{cpu, mmu};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
 
-// Memory Map for QEMU Virt
-pub const RAM_START: usize = 0x4000_0000;
-pub const RAM_SIZE: usize = 512 * 1024 * 1024; // 512 MB
 pub const PAGE_SIZE: usize = 4096;
-pub const TOTAL_PAGES: usize = RAM_SIZE / PAGE_SIZE; // 131,072 pages
+/// Pages in the largest RAM the MMU can map
+const MAX_PAGES: usize = mmu::MAX_RAM_SIZE / PAGE_SIZE;
 
 // Bitmap size: 1 bit per page.
-// 131,072 bits / 64 bits/u64 = 2048 u64s = 16KB
-const BITMAP_SIZE: usize = TOTAL_PAGES / 64;
+// 1,048,576 bits / 64 bits/u64 = 16384 u64s = 128KB
+const BITMAP_SIZE: usize = MAX_PAGES / 64;
 
-static mut BITMAP: [u64; BITMAP_SIZE] = [0; BITMAP_SIZE];
-static ALLOC_START: AtomicUsize = AtomicUsize::new(0);
+/// Largest block order: 2^10 pages, 4MB
+pub const MAX_ORDER: usize = 10;
+/// `Buddy::order` of a page that doesn't start a free block. Orders are
+/// kept plus one, so this can be zero
+const NOT_FREE: u8 = 0;
+/// End of a free list. Links hold page indexes plus one, so this can be
+/// zero too: with every initial value zero, `BUDDY` lands in .bss rather
+/// than taking megabytes of the image
+const NIL: usize = 0;
 
-/// Initialize the PMM.
-/// Marks kernel memory as used.
-pub fn init(kernel_end: usize) {
-    let kernel_pages = (kernel_end - RAM_START + PAGE_SIZE - 1) / PAGE_SIZE;
-    
-    // Mark kernel pages as used
-    for i in 0..kernel_pages {
-        unsafe { set_bit(i) };
+/// Who a page was allocated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PageTag {
+    /// Claimed with `reserve_range` (the kernel image among others)
+    Reserved,
+    KernelStack,
+    UserStack,
+    PageTable,
+    Dma,
+    UserHeap,
+    /// Kernel heap extents added after boot
+    KernelHeap,
+    /// Carved into objects by a slab cache
+    Slab,
+    /// Anything else, including untagged allocations
+    Anonymous,
+}
+
+impl PageTag {
+    pub const ALL: [PageTag; 9] = [
+        PageTag::Reserved, PageTag::KernelStack, PageTag::UserStack, PageTag::PageTable,
+        PageTag::Dma, PageTag::UserHeap, PageTag::KernelHeap, PageTag::Slab, PageTag::Anonymous,
+    ];
+
+    fn from_u8(value: u8) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or(PageTag::Anonymous)
+    }
+}
+
+/// Page usage and the free lists. Pages are numbered from the start of
+/// managed RAM.
+struct Buddy {
+    /// One bit per page, set if the page is in use
+    bits: [u64; BITMAP_SIZE],
+    /// The tag of each used page (1 byte per page: 128KB per 512MB of RAM)
+    tags: [u8; MAX_PAGES],
+    /// References to each used page
+    refs: [u16; MAX_PAGES],
+    /// The order plus one of each free block, on its first page; `NOT_FREE`
+    /// elsewhere
+    order: [u8; MAX_PAGES],
+    /// First block on each order's free list, as a link (index plus one).
+    /// Each free block's first page holds the links to the next and
+    /// previous blocks on its list.
+    free: [usize; MAX_ORDER + 1],
+    /// Whether the free lists have been built
+    ready: bool,
+}
+
+/// Locked with IRQs masked, so IRQ handlers can allocate too; nothing
+/// called under the lock allocates or logs.
+static BUDDY: Mutex<Buddy> = Mutex::new(Buddy {
+    bits: [0; BITMAP_SIZE],
+    tags: [0; MAX_PAGES],
+    refs: [0; MAX_PAGES],
+    order: [NOT_FREE; MAX_PAGES],
+    free: [NIL; MAX_ORDER + 1],
+    ready: false,
+});
+
+/// Managed RAM, set by `init`
+static RAM_START: AtomicUsize = AtomicUsize::new(0);
+static TOTAL_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Pages allocated right now, and the most there have ever been
+static USED_PAGES: AtomicUsize = AtomicUsize::new(0);
+static PEAK_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a bad free panics after it's reported (on by default in debug
+/// builds; otherwise it's ignored)
+static PANIC_ON_BAD_FREE: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+/// Bad frees reported since boot
+static BAD_FREES: AtomicUsize = AtomicUsize::new(0);
+
+/// A snapshot of physical memory usage, in pages.
+#[derive(Debug, Clone, Copy)]
+pub struct PmmStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+    /// Highest `used` has been since boot
+    pub peak: usize,
+}
+
+/// A named range of RAM the PMM never hands out.
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub start: usize,
+    pub len: usize,
+    pub tag: &'static str,
+}
+
+/// Most reservations that can be recorded
+const MAX_RESERVATIONS: usize = 16;
+
+static RESERVED: Mutex<[Option<Reservation>; MAX_RESERVATIONS]> = Mutex::new([None; MAX_RESERVATIONS]);
+
+/// Initialize the PMM for the RAM at `[ram_start, ram_start + ram_size)`,
+/// all free. Whatever already occupies RAM (the kernel image first of all)
+/// must be claimed with `reserve_range` before anything is allocated: the
+/// first allocation writes into every free page.
+pub fn init(ram_start: usize, ram_size: usize) {
+    RAM_START.store(ram_start, Ordering::Relaxed);
+    TOTAL_PAGES.store((ram_size / PAGE_SIZE).min(MAX_PAGES), Ordering::Relaxed);
+    crate::log_info!("mm", "PMM Initialized. {} pages.", total_pages());
+}
+
+/// Mark the pages covering `[start, start + len)` as used for good, under
+/// the name `tag`. The part outside managed RAM is ignored; reservations
+/// may overlap.
+///
+/// Returns `false` if the reservation table is full.
+pub fn reserve_range(start: usize, len: usize, tag: &'static str) -> bool {
+    let ram_start = RAM_START.load(Ordering::Relaxed);
+    let first = start.saturating_sub(ram_start) / PAGE_SIZE;
+    let end = ((start + len).saturating_sub(ram_start)).div_ceil(PAGE_SIZE).min(total_pages());
+
+    let daif = cpu::save_and_disable_interrupts();
+    let mut reserved = RESERVED.lock();
+    let recorded = match reserved.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(Reservation { start, len, tag });
+            let mut buddy = BUDDY.lock();
+            let mut claimed = 0;
+            for i in first..end {
+                if !buddy.is_set(i) {
+                    if buddy.ready {
+                        buddy.carve(i);
+                    }
+                    buddy.set(i, 1, PageTag::Reserved);
+                    claimed += 1;
+                }
+            }
+            drop(buddy);
+            let used = USED_PAGES.fetch_add(claimed, Ordering::Relaxed) + claimed;
+            PEAK_PAGES.fetch_max(used, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    };
+    drop(reserved);
+    cpu::restore_interrupts(daif);
+
+    if recorded {
+        crate::log_info!("mm", "Reserved {:#x}-{:#x} ({})", start, start + len, tag);
+    } else {
+        crate::log_error!("mm", "Too many reservations, {:#x}-{:#x} ({}) not reserved", start, start + len, tag);
     }
-    
-    // Set search start hint
-    ALLOC_START.store(kernel_pages, Ordering::Relaxed);
-    
-    crate::println!("[mm] PMM Initialized. Kernel uses {} pages.", kernel_pages);
+    recorded
+}
+
+/// The reservations made so far.
+pub fn reservations() -> [Option<Reservation>; MAX_RESERVATIONS] {
+    let daif = cpu::save_and_disable_interrupts();
+    let reserved = *RESERVED.lock();
+    cpu::restore_interrupts(daif);
+    reserved
+}
+
+/// The reservation containing `addr`, if any.
+fn reserved_at(addr: usize) -> Option<Reservation> {
+    reservations().into_iter().flatten().find(|r| (r.start..r.start + r.len).contains(&addr))
+}
+
+/// Number of physical pages managed.
+pub fn total_pages() -> usize {
+    TOTAL_PAGES.load(Ordering::Relaxed)
+}
+
+/// Current page counts (the kernel image counts as used).
+pub fn stats() -> PmmStats {
+    let total = total_pages();
+    let used = USED_PAGES.load(Ordering::Relaxed);
+    PmmStats { total, used, free: total - used, peak: PEAK_PAGES.load(Ordering::Relaxed) }
+}
+
+/// Used pages with each tag, in `PageTag::ALL` order.
+pub fn usage_by_tag() -> [usize; PageTag::ALL.len()] {
+    let mut counts = [0; PageTag::ALL.len()];
+    let daif = cpu::save_and_disable_interrupts();
+    let buddy = BUDDY.lock();
+    for i in (0..total_pages()).filter(|&i| buddy.is_set(i)) {
+        counts[PageTag::from_u8(buddy.tags[i]) as usize] += 1;
+    }
+    drop(buddy);
+    cpu::restore_interrupts(daif);
+    counts
+}
+
+/// The tag of the page at `phys_addr`, if it's allocated.
+pub fn tag_of(phys_addr: usize) -> Option<PageTag> {
+    let idx = page_index(phys_addr)?;
+    let daif = cpu::save_and_disable_interrupts();
+    let buddy = BUDDY.lock();
+    let tag = buddy.is_set(idx).then(|| PageTag::from_u8(buddy.tags[idx]));
+    drop(buddy);
+    cpu::restore_interrupts(daif);
+    tag
+}
+
+/// Index of the managed page at `phys_addr`.
+fn page_index(phys_addr: usize) -> Option<usize> {
+    let ram_start = RAM_START.load(Ordering::Relaxed);
+    let idx = phys_addr.checked_sub(ram_start)? / PAGE_SIZE;
+    (idx < total_pages()).then_some(idx)
 }
 
 /// Allocate a single physical page.
 /// Returns the physical address.
-#[allow(dead_code)]
 pub fn alloc_page() -> Option<usize> {
-    let start = ALLOC_START.load(Ordering::Relaxed);
-    
-    for i in start..TOTAL_PAGES {
-        if unsafe { !is_bit_set(i) } {
-            unsafe { set_bit(i) };
-            ALLOC_START.store(i + 1, Ordering::Relaxed);
-            return Some(RAM_START + i * PAGE_SIZE);
+    alloc_page_tagged(PageTag::Anonymous)
+}
+
+/// Allocate a single physical page filled with zeros, for `tag`.
+pub fn alloc_page_zeroed(tag: PageTag) -> Option<usize> {
+    let pa = alloc_page_tagged(tag)?;
+    unsafe { core::ptr::write_bytes(mmu::phys_to_virt(pa) as *mut u8, 0, PAGE_SIZE); }
+    Some(pa)
+}
+
+/// Allocate a single physical page for `tag`.
+pub fn alloc_page_tagged(tag: PageTag) -> Option<usize> {
+    alloc_pages_tagged(0, tag)
+}
+
+/// Allocate 2^`order` physically contiguous pages, aligned to their size.
+/// Returns the physical address of the first.
+pub fn alloc_pages(order: usize) -> Option<usize> {
+    alloc_pages_tagged(order, PageTag::Anonymous)
+}
+
+/// Allocate 2^`order` physically contiguous pages for `tag`, aligned to
+/// their size.
+pub fn alloc_pages_tagged(order: usize, tag: PageTag) -> Option<usize> {
+    if order > MAX_ORDER {
+        return None;
+    }
+    let daif = cpu::save_and_disable_interrupts();
+    let mut buddy = BUDDY.lock();
+    if !buddy.ready {
+        buddy.build();
+    }
+    let found = buddy.alloc(order);
+    if let Some(idx) = found {
+        buddy.set(idx, 1 << order, tag);
+        let used = USED_PAGES.fetch_add(1 << order, Ordering::Relaxed) + (1 << order);
+        PEAK_PAGES.fetch_max(used, Ordering::Relaxed);
+    }
+    drop(buddy);
+    cpu::restore_interrupts(daif);
+
+    found.map(|i| RAM_START.load(Ordering::Relaxed) + i * PAGE_SIZE)
+}
+
+/// Allocate `count` physically contiguous pages for `tag`: a block of the
+/// order that fits them, with the pages past `count` given back. Free
+/// them with `free_contiguous`, or one at a time with `free_page`.
+pub fn alloc_contiguous(count: usize, tag: PageTag) -> Option<usize> {
+    let count = count.max(1);
+    let order = count.next_power_of_two().trailing_zeros() as usize;
+    let pa = alloc_pages_tagged(order, tag)?;
+    for page in count..1 << order {
+        put_pages(pa + page * PAGE_SIZE, 0);
+    }
+    Some(pa)
+}
+
+/// Free the `count` pages at `phys_addr` from `alloc_contiguous`.
+#[track_caller]
+pub fn free_contiguous(phys_addr: usize, count: usize) {
+    for page in 0..count.max(1) {
+        put_pages(phys_addr + page * PAGE_SIZE, 0);
+    }
+}
+
+/// Allocate the `count` pages starting at `phys_addr` for `tag`, if they
+/// are all free. Free them one at a time with `free_page`.
+///
+/// Returns `false` if any of them is in use or outside RAM.
+pub fn claim_pages(phys_addr: usize, count: usize, tag: PageTag) -> bool {
+    let Some(idx) = page_index(phys_addr) else {
+        return false;
+    };
+    if !phys_addr.is_multiple_of(PAGE_SIZE) || idx + count > total_pages() {
+        return false;
+    }
+    let daif = cpu::save_and_disable_interrupts();
+    let mut buddy = BUDDY.lock();
+    if !buddy.ready {
+        buddy.build();
+    }
+    let free = (idx..idx + count).all(|i| !buddy.is_set(i));
+    if free {
+        for i in idx..idx + count {
+            buddy.carve(i);
         }
+        buddy.set(idx, count, tag);
+        let used = USED_PAGES.fetch_add(count, Ordering::Relaxed) + count;
+        PEAK_PAGES.fetch_max(used, Ordering::Relaxed);
+    }
+    drop(buddy);
+    cpu::restore_interrupts(daif);
+    free
+}
+
+/// Free a physical page, checking it was allocated for `tag`. A page
+/// owned by another subsystem is left alone and the mismatch logged.
+#[track_caller]
+pub fn free_page_tagged(phys_addr: usize, tag: PageTag) {
+    match tag_of(phys_addr) {
+        Some(owner) if owner != tag => crate::log_error!("mm",
+            "Refusing to free {:#x} as {:?}: it belongs to {:?}", phys_addr, tag, owner),
+        _ => free_page(phys_addr),
     }
-    
-    // Wrap around if needed (primitive)
-    None
 }
 
-/// Free a physical page.
-#[allow(dead_code)]
+/// Free a physical page: drop a reference to it, as `put_page` does.
+#[track_caller]
 pub fn free_page(phys_addr: usize) {
-    if phys_addr < RAM_START || phys_addr >= RAM_START + RAM_SIZE {
-        return;
+    put_pages(phys_addr, 0);
+}
+
+/// Free the 2^`order` pages at `phys_addr`, allocated with `alloc_pages`,
+/// dropping a reference to each.
+#[track_caller]
+pub fn free_pages(phys_addr: usize, order: usize) {
+    put_pages(phys_addr, order);
+}
+
+/// Take another reference to the page at `phys_addr`.
+///
+/// Returns `false` (and logs) if the page isn't in use.
+#[track_caller]
+pub fn get_page(phys_addr: usize) -> bool {
+    let Some(idx) = page_index(phys_addr) else {
+        return false;
+    };
+    let daif = cpu::save_and_disable_interrupts();
+    let mut buddy = BUDDY.lock();
+    let in_use = buddy.is_set(idx);
+    if in_use {
+        // A saturated count leaks the page rather than freeing it early
+        buddy.refs[idx] = buddy.refs[idx].saturating_add(1);
+    }
+    drop(buddy);
+    cpu::restore_interrupts(daif);
+
+    if !in_use {
+        crate::log_error!("mm", "Reference taken to free page {:#x} (at {})",
+            phys_addr, core::panic::Location::caller());
+    }
+    in_use
+}
+
+/// Drop a reference to the page at `phys_addr`, freeing it if that was
+/// the last.
+///
+/// Returns `true` if the page was freed.
+#[track_caller]
+pub fn put_page(phys_addr: usize) -> bool {
+    put_pages(phys_addr, 0)
+}
+
+/// References to the page at `phys_addr` (0 if it's free).
+pub fn page_refs(phys_addr: usize) -> usize {
+    let Some(idx) = page_index(phys_addr) else {
+        return 0;
+    };
+    let daif = cpu::save_and_disable_interrupts();
+    let buddy = BUDDY.lock();
+    let refs = if buddy.is_set(idx) { buddy.refs[idx] as usize } else { 0 };
+    drop(buddy);
+    cpu::restore_interrupts(daif);
+    refs
+}
+
+/// What `put_pages` did with a block.
+enum Put {
+    /// Every page still has references
+    Kept,
+    /// Some or all of the pages were freed
+    Freed,
+    /// A page in the block (index, last tag) was already free; nothing
+    /// was changed
+    Underflow(usize, u8),
+    /// The last reference to a reserved page; nothing was changed
+    Reserved,
+}
+
+/// Drop a reference to each of the 2^`order` pages at `phys_addr`, and
+/// free those left without any.
+///
+/// Returns `true` if any page was freed.
+#[track_caller]
+fn put_pages(phys_addr: usize, order: usize) -> bool {
+    if !phys_addr.is_multiple_of(PAGE_SIZE) {
+        bad_free(format_args!("Invalid free of {:#x}: not page-aligned", phys_addr));
+        return false;
     }
-    
-    let page_idx = (phys_addr - RAM_START) / PAGE_SIZE;
-    unsafe { clear_bit(page_idx) };
-    
-    // Reset hint if we freed a lower page
-    let current_start = ALLOC_START.load(Ordering::Relaxed);
-    if page_idx < current_start {
-        ALLOC_START.store(page_idx, Ordering::Relaxed);
+    let Some(idx) = page_index(phys_addr) else {
+        bad_free(format_args!("Invalid free of {:#x}: outside RAM", phys_addr));
+        return false;
+    };
+    let count = 1 << order;
+    if order > MAX_ORDER || idx % count != 0 || idx + count > total_pages() {
+        bad_free(format_args!("Invalid free of {:#x}: not a block of order {}", phys_addr, order));
+        return false;
+    }
+
+    let daif = cpu::save_and_disable_interrupts();
+    let mut buddy = BUDDY.lock();
+    let pages = idx..idx + count;
+    let put = if let Some(i) = pages.clone().find(|&i| !buddy.is_set(i)) {
+        Put::Underflow(i, buddy.tags[i])
+    } else if pages.clone().any(|i| buddy.refs[i] == 1 && buddy.tags[i] == PageTag::Reserved as u8) {
+        Put::Reserved
+    } else {
+        for i in pages.clone() {
+            buddy.refs[i] -= 1;
+        }
+        let freed = pages.clone().filter(|&i| buddy.refs[i] == 0).count();
+        if freed == count {
+            buddy.clear(idx, count);
+            buddy.release(idx, order);
+        } else {
+            for i in pages {
+                if buddy.refs[i] == 0 {
+                    buddy.clear(i, 1);
+                    buddy.release(i, 0);
+                }
+            }
+        }
+        USED_PAGES.fetch_sub(freed, Ordering::Relaxed);
+        if freed == 0 { Put::Kept } else { Put::Freed }
+    };
+    drop(buddy);
+    cpu::restore_interrupts(daif);
+
+    match put {
+        Put::Kept => false,
+        Put::Freed => true,
+        Put::Underflow(i, tag) => {
+            let page = RAM_START.load(Ordering::Relaxed) + i * PAGE_SIZE;
+            bad_free(format_args!("Double free of {:#x} (last owned by {:?})", page, PageTag::from_u8(tag)));
+            false
+        }
+        Put::Reserved => {
+            let tag = reserved_at(phys_addr).map_or("?", |r| r.tag);
+            crate::log_warn!("mm", "Refusing to free {:#x}: reserved ({})", phys_addr, tag);
+            false
+        }
     }
 }
 
-// Bitmap Helpers
-unsafe fn set_bit(idx: usize) {
-    BITMAP[idx / 64] |= 1 << (idx % 64);
+/// Report a free the PMM refused, with the caller and a backtrace, then
+/// panic if `PANIC_ON_BAD_FREE` is set.
+#[track_caller]
+fn bad_free(what: core::fmt::Arguments) {
+    BAD_FREES.fetch_add(1, Ordering::Relaxed);
+    crate::log_error!("mm", "{} (at {})", what, core::panic::Location::caller());
+    cpu::backtrace(|lr| crate::log_error!("mm", "  called from {:#x}", lr));
+    if PANIC_ON_BAD_FREE.load(Ordering::Relaxed) {
+        panic!("{}", what);
+    }
 }
 
-#[allow(dead_code)]
-unsafe fn clear_bit(idx: usize) {
-    BITMAP[idx / 64] &= !(1 << (idx % 64));
+/// Choose whether a bad free panics or is only reported. Returns the
+/// previous setting.
+pub fn set_panic_on_bad_free(panic: bool) -> bool {
+    PANIC_ON_BAD_FREE.swap(panic, Ordering::Relaxed)
 }
 
-#[allow(dead_code)]
-unsafe fn is_bit_set(idx: usize) -> bool {
-    (BITMAP[idx / 64] & (1 << (idx % 64))) != 0
+/// Bad frees reported since boot.
+pub fn bad_frees() -> usize {
+    BAD_FREES.load(Ordering::Relaxed)
+}
+
+impl Buddy {
+    // Bitmap helpers
+
+    fn set(&mut self, idx: usize, count: usize, tag: PageTag) {
+        for i in idx..idx + count {
+            self.bits[i / 64] |= 1 << (i % 64);
+            self.tags[i] = tag as u8;
+            self.refs[i] = 1;
+        }
+    }
+
+    fn clear(&mut self, idx: usize, count: usize) {
+        for i in idx..idx + count {
+            self.bits[i / 64] &= !(1 << (i % 64));
+        }
+    }
+
+    fn is_set(&self, idx: usize) -> bool {
+        (self.bits[idx / 64] & (1 << (idx % 64))) != 0
+    }
+
+    // Free lists
+
+    /// The next and previous links in the free block at link `link`.
+    fn links(link: usize) -> *mut [usize; 2] {
+        mmu::phys_to_virt(RAM_START.load(Ordering::Relaxed) + (link - 1) * PAGE_SIZE) as *mut [usize; 2]
+    }
+
+    /// Put the free block at `idx` on the `order` list.
+    fn push(&mut self, idx: usize, order: usize) {
+        let next = self.free[order];
+        unsafe {
+            *Self::links(idx + 1) = [next, NIL];
+            if next != NIL {
+                (*Self::links(next))[1] = idx + 1;
+            }
+        }
+        self.free[order] = idx + 1;
+        self.order[idx] = order as u8 + 1;
+    }
+
+    /// Take the free block at `idx` off the `order` list.
+    fn unlink(&mut self, idx: usize, order: usize) {
+        let [next, prev] = unsafe { *Self::links(idx + 1) };
+        if prev == NIL {
+            self.free[order] = next;
+        } else {
+            unsafe { (*Self::links(prev))[0] = next; }
+        }
+        if next != NIL {
+            unsafe { (*Self::links(next))[1] = prev; }
+        }
+        self.order[idx] = NOT_FREE;
+    }
+
+    /// Build the free lists from every page not in use, in the largest
+    /// aligned blocks that fit.
+    fn build(&mut self) {
+        let total = total_pages();
+        let mut idx = 0;
+        while idx < total {
+            if self.is_set(idx) {
+                idx += 1;
+                continue;
+            }
+            let order = (0..=MAX_ORDER).rev()
+                .find(|&o| {
+                    idx % (1 << o) == 0 && idx + (1 << o) <= total
+                        && (idx..idx + (1 << o)).all(|i| !self.is_set(i))
+                })
+                .unwrap_or(0);
+            self.push(idx, order);
+            idx += 1 << order;
+        }
+        self.ready = true;
+    }
+
+    /// Take a free block of `order` off the lists, splitting a larger one
+    /// if need be.
+    fn alloc(&mut self, order: usize) -> Option<usize> {
+        let mut from = (order..=MAX_ORDER).find(|&o| self.free[o] != NIL)?;
+        let idx = self.free[from] - 1;
+        self.unlink(idx, from);
+        // Return the upper halves
+        while from > order {
+            from -= 1;
+            self.push(idx + (1 << from), from);
+        }
+        Some(idx)
+    }
+
+    /// Put the block of `order` at `idx` back, merged with its buddies.
+    fn release(&mut self, mut idx: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = idx ^ (1 << order);
+            if buddy + (1 << order) > total_pages() || self.order[buddy] != order as u8 + 1 {
+                break;
+            }
+            self.unlink(buddy, order);
+            idx = idx.min(buddy);
+            order += 1;
+        }
+        self.push(idx, order);
+    }
+
+    /// Take the free page `idx` out of the free block containing it,
+    /// returning the rest of the block to the lists.
+    fn carve(&mut self, idx: usize) {
+        let Some(mut order) = (0..=MAX_ORDER).find(|&o| self.order[idx & !((1 << o) - 1)] == o as u8 + 1) else {
+            return;
+        };
+        let mut block = idx & !((1 << order) - 1);
+        self.unlink(block, order);
+        while order > 0 {
+            order -= 1;
+            let half = 1 << order;
+            if idx < block + half {
+                self.push(block + half, order);
+            } else {
+                self.push(block, order);
+                block += half;
+            }
+        }
+    }
 }
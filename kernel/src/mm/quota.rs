@@ -0,0 +1,182 @@
+// =============================================================================
+// APRK OS - Per-Task Kernel Heap Quotas
+// =============================================================================
+// Kernel heap memory allocated on a task's behalf is charged to it, so one
+// task can't exhaust the heap everyone shares. Each task runs with a
+// charge target (see `sched::charge_target`): syscalls charge the caller,
+// and code acting for some other task wraps the work in
+// `with_task_charge`. Allocations outside any scope are the kernel's own
+// and never charged.
+//
+// Frees made in a scope are credited back to it, so whatever a task's
+// syscalls allocate should also be freed on its behalf. Structures shared
+// between tasks (the block cache, filesystems, open files) are worked on
+// with `with_task_charge(None)`: whoever frees them is seldom who
+// allocated them.
+//
+// The allocator only keeps count; it never fails an allocation for being
+// over quota, since an infallible one failing would take the kernel down
+// with whatever locks are held. Quotas are enforced where a syscall sizes
+// an allocation from user input: `allows` is checked first (as
+// `mm::try_alloc` does) and the syscall fails with ENOMEM. A task's
+// account is dropped when it exits.
+// =============================================================================
+
+use aprk_arch_arm64::cpu;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Quota of a task nobody has set one for
+pub const DEFAULT_QUOTA: usize = 1024 * 1024; // 1 MB
+/// Most tasks with an account at once
+const MAX_ACCOUNTS: usize = 16;
+
+/// Quota given to tasks without one of their own
+static DEFAULT: AtomicUsize = AtomicUsize::new(DEFAULT_QUOTA);
+
+/// Kernel heap charged to one task, in bytes unless noted.
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub charged: usize,
+    /// Highest `charged` has been
+    pub peak: usize,
+    pub quota: usize,
+    /// Allocations refused for going over quota (a count)
+    pub refused: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Account {
+    pid: usize,
+    usage: Usage,
+    /// Set once a refusal has been logged, until the quota changes
+    warned: bool,
+}
+
+static ACCOUNTS: Mutex<[Option<Account>; MAX_ACCOUNTS]> = Mutex::new([None; MAX_ACCOUNTS]);
+
+/// Run `f` with the current task's kernel heap allocations charged to
+/// task `pid`, or to nobody for `None`.
+pub fn with_task_charge<R>(pid: Option<usize>, f: impl FnOnce() -> R) -> R {
+    let outer = crate::sched::swap_charge(pid);
+    let result = f();
+    crate::sched::swap_charge(outer);
+    result
+}
+
+/// Run `f` on the account of `pid`, opening one if it has none. Returns
+/// `None` if every account is taken.
+fn with_account<R>(pid: usize, f: impl FnOnce(&mut Account) -> R) -> Option<R> {
+    let daif = cpu::save_and_disable_interrupts();
+    let mut accounts = ACCOUNTS.lock();
+    let slot = match accounts.iter().position(|a| a.is_some_and(|a| a.pid == pid)) {
+        Some(i) => Some(i),
+        None => accounts.iter().position(Option::is_none),
+    };
+    let result = slot.map(|i| {
+        let account = accounts[i].get_or_insert(Account {
+            pid,
+            usage: Usage { charged: 0, peak: 0, quota: DEFAULT.load(Ordering::Relaxed), refused: 0 },
+            warned: false,
+        });
+        f(account)
+    });
+    drop(accounts);
+    cpu::restore_interrupts(daif);
+    result
+}
+
+/// Whether the current charge target has room for `size` more bytes
+/// (always, if there's no target). A refusal is counted, and the first
+/// one logged.
+///
+/// Check this before a fallible allocation whose size comes from user
+/// space, and fail with ENOMEM if it says no.
+pub fn allows(size: usize) -> bool {
+    let Some(pid) = crate::sched::charge_target() else {
+        return true;
+    };
+    // With every account taken, tasks go unchecked rather than failing
+    let refused = with_account(pid, |account| {
+        let usage = &mut account.usage;
+        if usage.charged.saturating_add(size) <= usage.quota {
+            return None;
+        }
+        usage.refused += 1;
+        Some((!core::mem::replace(&mut account.warned, true)).then_some(*usage))
+    }).flatten();
+    match refused {
+        Some(first) => {
+            if let Some(usage) = first {
+                crate::log_warn!("mm", "Task {} over its kernel heap quota ({} of {} KB used, {} bytes asked)",
+                    pid, usage.charged / 1024, usage.quota / 1024, size);
+            }
+            false
+        }
+        None => true,
+    }
+}
+
+/// Charge `size` bytes to the current charge target, if there is one,
+/// even past its quota. Called by the global allocator.
+pub(super) fn charge(size: usize) {
+    if let Some(pid) = crate::sched::charge_target() {
+        with_account(pid, |account| {
+            let usage = &mut account.usage;
+            usage.charged += size;
+            usage.peak = usage.peak.max(usage.charged);
+        });
+    }
+}
+
+/// Credit `size` bytes back to the current charge target, if there is
+/// one. Called by the global allocator.
+pub(super) fn credit(size: usize) {
+    if let Some(pid) = crate::sched::charge_target() {
+        with_account(pid, |account| account.usage.charged = account.usage.charged.saturating_sub(size));
+    }
+}
+
+/// Drop the account of `pid`, which has exited.
+pub fn release(pid: usize) {
+    let daif = cpu::save_and_disable_interrupts();
+    let mut accounts = ACCOUNTS.lock();
+    let account = accounts.iter_mut().find(|a| a.is_some_and(|a| a.pid == pid)).and_then(Option::take);
+    drop(accounts);
+    cpu::restore_interrupts(daif);
+    if let Some(account) = account.filter(|a| a.usage.charged != 0) {
+        crate::log_info!("mm", "Task {} exited with {} bytes of kernel heap charged", pid, account.usage.charged);
+    }
+}
+
+/// What task `pid` has charged, and its quota.
+pub fn usage(pid: usize) -> Usage {
+    let daif = cpu::save_and_disable_interrupts();
+    let account = ACCOUNTS.lock().iter().flatten().find(|a| a.pid == pid).copied();
+    cpu::restore_interrupts(daif);
+    account.map_or(
+        Usage { charged: 0, peak: 0, quota: DEFAULT.load(Ordering::Relaxed), refused: 0 },
+        |account| account.usage,
+    )
+}
+
+/// Set the quota of task `pid` in bytes.
+///
+/// Returns `false` if every account is taken.
+pub fn set_quota(pid: usize, bytes: usize) -> bool {
+    with_account(pid, |account| {
+        account.usage.quota = bytes;
+        account.warned = false;
+    }).is_some()
+}
+
+/// Quota of tasks without one of their own, in bytes.
+pub fn default_quota() -> usize {
+    DEFAULT.load(Ordering::Relaxed)
+}
+
+/// Set the quota given to tasks from now on (existing accounts keep
+/// theirs).
+pub fn set_default_quota(bytes: usize) {
+    DEFAULT.store(bytes, Ordering::Relaxed);
+}
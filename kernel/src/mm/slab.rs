@@ -0,0 +1,169 @@
+// =============================================================================
+// APRK OS - Slab Caches
+// =============================================================================
+// A cache hands out fixed-size objects of one type, carved from whole PMM
+// pages. Free slots are kept on a list threaded through the slots
+// themselves, so allocating and freeing are constant time and never touch
+// the kernel heap. Pages are kept by the cache once carved.
+//
+// Caches are locked with IRQs masked, so objects can be allocated and
+// freed from IRQ handlers (waking a wait queue frees its nodes).
+// =============================================================================
+
+use aprk_arch_arm64::{cpu, mmu};
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+use spin::Mutex;
+use super::pmm::{self, PageTag, PAGE_SIZE};
+
+/// Most caches `meminfo` can list
+const MAX_CACHES: usize = 8;
+
+/// A snapshot of one cache's usage.
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    /// Bytes per slot
+    pub slot_size: usize,
+    /// Pages carved into slots
+    pub pages: usize,
+    /// Objects allocated right now, and slots free
+    pub live: usize,
+    pub free: usize,
+}
+
+/// Caches that have carved at least one page, for `meminfo`
+static CACHES: Mutex<[Option<&'static dyn Stats>; MAX_CACHES]> = Mutex::new([None; MAX_CACHES]);
+
+trait Stats: Sync {
+    fn stats(&self) -> SlabStats;
+}
+
+struct Inner {
+    /// First free slot (a VA), or 0. Each free slot holds the next.
+    free: usize,
+    pages: usize,
+    live: usize,
+    free_slots: usize,
+    registered: bool,
+}
+
+/// A cache of `T`-sized slots.
+pub struct Cache<T> {
+    name: &'static str,
+    inner: Mutex<Inner>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: 'static> Cache<T> {
+    /// Slot size: room for a `T`, or for the free-list link
+    const SLOT: usize = {
+        let size = if core::mem::size_of::<T>() > core::mem::size_of::<usize>() {
+            core::mem::size_of::<T>()
+        } else {
+            core::mem::size_of::<usize>()
+        };
+        let align = if core::mem::align_of::<T>() > core::mem::align_of::<usize>() {
+            core::mem::align_of::<T>()
+        } else {
+            core::mem::align_of::<usize>()
+        };
+        size.next_multiple_of(align)
+    };
+
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            inner: Mutex::new(Inner { free: 0, pages: 0, live: 0, free_slots: 0, registered: false }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Move `value` into a free slot.
+    ///
+    /// Returns `None` (dropping `value`) if the PMM has no page to carve.
+    pub fn alloc(&'static self, value: T) -> Option<NonNull<T>> {
+        const { assert!(Self::SLOT <= PAGE_SIZE) };
+        let daif = cpu::save_and_disable_interrupts();
+        let mut inner = self.inner.lock();
+        if inner.free == 0 {
+            self.carve(&mut inner);
+        }
+        let slot = inner.free;
+        if slot != 0 {
+            inner.free = unsafe { *(slot as *const usize) };
+            inner.free_slots -= 1;
+            inner.live += 1;
+        }
+        let register = slot != 0 && !core::mem::replace(&mut inner.registered, true);
+        drop(inner);
+        cpu::restore_interrupts(daif);
+
+        if register {
+            let daif = cpu::save_and_disable_interrupts();
+            if let Some(entry) = CACHES.lock().iter_mut().find(|entry| entry.is_none()) {
+                *entry = Some(self);
+            }
+            cpu::restore_interrupts(daif);
+        }
+        let ptr = NonNull::new(slot as *mut T)?;
+        unsafe { ptr.as_ptr().write(value); }
+        Some(ptr)
+    }
+
+    /// Drop the object at `ptr` and free its slot.
+    ///
+    /// # Safety
+    /// `ptr` must come from `alloc` on this cache and not be used again.
+    pub unsafe fn free(&self, ptr: NonNull<T>) {
+        ptr.as_ptr().drop_in_place();
+        let slot = ptr.as_ptr() as usize;
+        let daif = cpu::save_and_disable_interrupts();
+        let mut inner = self.inner.lock();
+        *(slot as *mut usize) = inner.free;
+        inner.free = slot;
+        inner.free_slots += 1;
+        inner.live -= 1;
+        drop(inner);
+        cpu::restore_interrupts(daif);
+    }
+
+    /// Carve a fresh page into free slots.
+    fn carve(&self, inner: &mut Inner) {
+        let Some(pa) = pmm::alloc_page_tagged(PageTag::Slab) else {
+            return;
+        };
+        let page = mmu::phys_to_virt(pa);
+        for slot in (0..PAGE_SIZE / Self::SLOT).rev().map(|i| page + i * Self::SLOT) {
+            unsafe { *(slot as *mut usize) = inner.free; }
+            inner.free = slot;
+            inner.free_slots += 1;
+        }
+        inner.pages += 1;
+    }
+}
+
+impl<T: 'static> Stats for Cache<T> {
+    fn stats(&self) -> SlabStats {
+        let daif = cpu::save_and_disable_interrupts();
+        let inner = self.inner.lock();
+        let stats = SlabStats {
+            name: self.name,
+            slot_size: Self::SLOT,
+            pages: inner.pages,
+            live: inner.live,
+            free: inner.free_slots,
+        };
+        drop(inner);
+        cpu::restore_interrupts(daif);
+        stats
+    }
+}
+
+/// Usage of every cache in use.
+pub fn stats() -> [Option<SlabStats>; MAX_CACHES] {
+    let daif = cpu::save_and_disable_interrupts();
+    let caches = *CACHES.lock();
+    cpu::restore_interrupts(daif);
+    caches.map(|cache| cache.map(|cache| cache.stats()))
+}
@@ -0,0 +1,208 @@
+// =============================================================================
+// APRK OS - PMM Stress Test
+// =============================================================================
+// Several kernel tasks allocate and free pages as fast as they can while
+// the timer preempts them. Each stamps the pages it holds with its own
+// tag and checks the tags before freeing, so a page handed out twice is
+// caught. Afterwards the number of used pages must be back where it was.
+// Then a handful of bad frees (a double free, unaligned, outside RAM and a
+// misaligned block) must each be reported and change nothing.
+//
+// `pmm_bench` times a single task allocating and freeing blocks of mixed
+// orders instead, and `slab_bench` small objects from a slab cache against
+// the same objects boxed on the heap.
+// =============================================================================
+
+use aprk_arch_arm64::mmu;
+use aprk_arch_arm64::timer::Timer;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::sched::{self, Priority};
+use alloc::boxed::Box;
+use core::ptr::NonNull;
+use super::pmm;
+use super::slab::Cache;
+
+/// Worker tasks run at once
+const WORKERS: usize = 4;
+/// Pages each worker holds at a time
+const BATCH: usize = 16;
+
+/// Rounds of allocate-then-free each worker does
+static ROUNDS: AtomicUsize = AtomicUsize::new(0);
+/// Hands out worker numbers
+static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+/// Pages found stamped by another worker, and allocations that failed
+static CORRUPTED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn worker() {
+    let tag = 0x5354_5245_5353_0000 | NEXT_WORKER.fetch_add(1, Ordering::Relaxed) as u64;
+    let mut pages = [0usize; BATCH];
+    for _ in 0..ROUNDS.load(Ordering::Relaxed) {
+        let mut held = 0;
+        for slot in pages.iter_mut() {
+            let Some(pa) = pmm::alloc_page() else {
+                FAILED.fetch_add(1, Ordering::Relaxed);
+                break;
+            };
+            unsafe { core::ptr::write_volatile(mmu::phys_to_virt(pa) as *mut u64, tag); }
+            *slot = pa;
+            held += 1;
+        }
+        for &pa in &pages[..held] {
+            if unsafe { core::ptr::read_volatile(mmu::phys_to_virt(pa) as *const u64) } != tag {
+                CORRUPTED.fetch_add(1, Ordering::Relaxed);
+            }
+            pmm::free_page(pa);
+        }
+    }
+}
+
+/// Run the stress test with `rounds` rounds per worker and report whether
+/// the PMM came out consistent.
+pub fn pmm_stress(rounds: usize) {
+    ROUNDS.store(rounds, Ordering::Relaxed);
+    NEXT_WORKER.store(0, Ordering::Relaxed);
+    CORRUPTED.store(0, Ordering::Relaxed);
+    FAILED.store(0, Ordering::Relaxed);
+    let baseline = pmm::stats().used;
+
+    let mut pids = [None; WORKERS];
+    for pid in pids.iter_mut() {
+        *pid = sched::spawn_named(worker, "pmmstress", Priority::Normal);
+    }
+    let started = pids.iter().flatten().count();
+    for &pid in pids.iter().flatten() {
+        sched::wait_for_exit(pid);
+    }
+    // The last worker's stack is freed once another task has run
+    sched::schedule();
+
+    let used = pmm::stats().used;
+    let corrupted = CORRUPTED.load(Ordering::Relaxed);
+    crate::println!("{} workers x {} rounds x {} pages: {} failed allocations, {} corrupted pages",
+        started, rounds, BATCH, FAILED.load(Ordering::Relaxed), corrupted);
+    if used == baseline && corrupted == 0 {
+        crate::println!("PASSED: {} pages used before and after", used);
+    } else {
+        crate::println!("FAILED: {} pages used before, {} after", baseline, used);
+    }
+    bad_frees();
+}
+
+/// Free pages the wrong ways and check each is caught and ignored.
+fn bad_frees() {
+    let Some(pa) = pmm::alloc_pages(1) else {
+        crate::println!("Bad frees: no free pages to test with");
+        return;
+    };
+    pmm::free_pages(pa, 1);
+
+    crate::println!("Bad frees (4 errors expected):");
+    let panics = pmm::set_panic_on_bad_free(false);
+    let reported = pmm::bad_frees();
+    let used = pmm::stats().used;
+    pmm::free_page(pa);
+    pmm::free_page(pa + 8);
+    pmm::free_page(usize::MAX & !(pmm::PAGE_SIZE - 1));
+    pmm::free_pages(pa + pmm::PAGE_SIZE, 1);
+    pmm::set_panic_on_bad_free(panics);
+
+    let caught = pmm::bad_frees() - reported;
+    if caught == 4 && pmm::stats().used == used {
+        crate::println!("PASSED: all bad frees caught");
+    } else {
+        crate::println!("FAILED: {} of 4 bad frees caught, {} pages used before, {} after",
+            caught, used, pmm::stats().used);
+    }
+}
+
+/// Blocks `pmm_bench` holds at once
+const BENCH_HELD: usize = 64;
+
+/// Time `cycles` allocations of blocks of order 0 to 3 (mostly single
+/// pages), each freeing the block allocated `BENCH_HELD` cycles earlier.
+pub fn pmm_bench(cycles: usize) {
+    let cycles = cycles.max(1);
+    let baseline = pmm::stats().used;
+    let mut held = [None::<(usize, usize)>; BENCH_HELD];
+    // Fixed-seed xorshift, so runs are comparable
+    let mut seed: u64 = 0x2545_F491_4F6C_DD1D;
+    let mut failed = 0;
+
+    let start = Timer::counter();
+    for cycle in 0..cycles {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        // Half single pages, the rest orders 1 to 3
+        let order = (seed as usize % 8).saturating_sub(4);
+        let slot = &mut held[cycle % BENCH_HELD];
+        if let Some((pa, order)) = slot.take() {
+            pmm::free_pages(pa, order);
+        }
+        match pmm::alloc_pages(order) {
+            Some(pa) => *slot = Some((pa, order)),
+            None => failed += 1,
+        }
+    }
+    for (pa, order) in held.iter().flatten() {
+        pmm::free_pages(*pa, *order);
+    }
+    let elapsed = Timer::ticks_to_duration(Timer::counter() - start);
+
+    crate::println!("{} alloc/free cycles: {} ns/cycle, {} failed allocations",
+        cycles, elapsed.as_nanos() as u64 / cycles as u64, failed);
+    let used = pmm::stats().used;
+    if used != baseline {
+        crate::println!("FAILED: {} pages used before, {} after", baseline, used);
+    }
+}
+
+/// A small object, like a queue node or message
+struct Object([u64; 8]);
+
+static OBJECTS: Cache<Object> = Cache::new("slabbench");
+
+/// Time `cycles` allocate-and-free cycles of a 64-byte object, each
+/// freeing the object allocated `BENCH_HELD` cycles earlier: from a slab
+/// cache, then boxed on the kernel heap.
+pub fn slab_bench(cycles: usize) {
+    let cycles = cycles.max(1);
+    let time = |run: &mut dyn FnMut(usize)| {
+        let start = Timer::counter();
+        for cycle in 0..cycles {
+            run(cycle);
+        }
+        Timer::ticks_to_duration(Timer::counter() - start).as_nanos() as u64 / cycles as u64
+    };
+
+    let mut held = [None::<NonNull<Object>>; BENCH_HELD];
+    let slab = time(&mut |cycle| {
+        let slot = &mut held[cycle % BENCH_HELD];
+        if let Some(object) = slot.take() {
+            unsafe {
+                core::hint::black_box(object.as_ref().0[0]);
+                OBJECTS.free(object);
+            }
+        }
+        *slot = OBJECTS.alloc(Object([cycle as u64; 8]));
+    });
+    for object in held.iter_mut().filter_map(Option::take) {
+        unsafe { OBJECTS.free(object); }
+    }
+
+    let mut boxed: [Option<Box<Object>>; BENCH_HELD] = [const { None }; BENCH_HELD];
+    let heap = time(&mut |cycle| {
+        let slot = &mut boxed[cycle % BENCH_HELD];
+        if let Some(object) = slot.take() {
+            core::hint::black_box(object.0[0]);
+        }
+        *slot = Some(Box::new(Object([cycle as u64; 8])));
+    });
+    drop(boxed);
+
+    crate::println!("{} alloc/free cycles of a {}-byte object:", cycles, core::mem::size_of::<Object>());
+    crate::println!("  slab cache:  {:>6} ns/cycle", slab);
+    crate::println!("  kernel heap: {:>6} ns/cycle", heap);
+}
@@ -0,0 +1,270 @@
+// =============================================================================
+// APRK OS - Kernel Heap Allocation Tracking (debug-alloc feature)
+// =============================================================================
+// Records every live heap allocation in a fixed table: its size, when it
+// was made, and the return addresses of its callers, read from the
+// frame-pointer chain. `leaks` groups the old ones by call chain, so
+// whatever keeps piling up stands out.
+//
+// Each allocation is also padded with redzones filled with `REDZONE_BYTE`
+// on both sides. They're checked when it's freed, and for all live
+// allocations by `sweep` from the idle loop, so an overrun is reported
+// with the allocation's call chain close to when it happened. Freed
+// memory is filled with `FREED_BYTE`.
+//
+// The table never allocates. When it fills up, tracking stops for new
+// allocations (and the report says so) until slots are freed again.
+// =============================================================================
+
+use aprk_arch_arm64::{cpu, print};
+use aprk_arch_arm64::timer::Timer;
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Allocations the table can hold
+const MAX_TRACKED: usize = 2048;
+/// Return addresses recorded per allocation
+const DEPTH: usize = 4;
+/// Frames to skip: `record`, `alloc`, the global allocator, and the
+/// allocation shim
+const SKIP: usize = 4;
+/// Most distinct call chains `report` lists
+const MAX_SITES: usize = 32;
+/// Bytes of redzone after each allocation, and at least before it
+const REDZONE: usize = 16;
+/// What redzones are filled with
+const REDZONE_BYTE: u8 = 0xFC;
+/// What freed allocations are filled with
+const FREED_BYTE: u8 = 0xDD;
+/// Seconds between sweeps
+const SWEEP_INTERVAL: u64 = 1;
+/// Most corrupted allocations one sweep reports
+const MAX_REPORTED: usize = 4;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// 0 if the slot is free
+    ptr: usize,
+    size: usize,
+    align: usize,
+    /// `Timer::counter()` at allocation
+    time: u64,
+    callers: [usize; DEPTH],
+    /// Set once a sweep has reported the redzones corrupted
+    reported: bool,
+}
+
+const FREE: Entry = Entry { ptr: 0, size: 0, align: 0, time: 0, callers: [0; DEPTH], reported: false };
+
+static TABLE: Mutex<[Entry; MAX_TRACKED]> = Mutex::new([FREE; MAX_TRACKED]);
+/// Set once an allocation went untracked because the table was full
+static OVERFLOWED: AtomicBool = AtomicBool::new(false);
+/// `Timer::counter()` at the last sweep
+static LAST_SWEEP: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes before an allocation with alignment `align`: a redzone, rounded
+/// up to keep the allocation aligned.
+fn front(align: usize) -> usize {
+    REDZONE.max(align)
+}
+
+/// `layout` with room for the redzones.
+fn padded(layout: Layout) -> Option<Layout> {
+    let size = front(layout.align()) + layout.size() + REDZONE;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Allocate `layout` from `heap` between redzones, and record it.
+#[inline(never)]
+pub unsafe fn alloc(heap: &impl GlobalAlloc, layout: Layout) -> *mut u8 {
+    let Some(padded) = padded(layout) else {
+        return core::ptr::null_mut();
+    };
+    let raw = heap.alloc(padded);
+    if raw.is_null() {
+        return raw;
+    }
+    let ptr = raw.add(front(layout.align()));
+    core::ptr::write_bytes(raw, REDZONE_BYTE, front(layout.align()));
+    core::ptr::write_bytes(ptr.add(layout.size()), REDZONE_BYTE, REDZONE);
+    record(ptr, layout);
+    ptr
+}
+
+/// Check the redzones of the allocation at `ptr`, forget it, and give it
+/// back to `heap` filled with `FREED_BYTE`.
+pub unsafe fn dealloc(heap: &impl GlobalAlloc, ptr: *mut u8, layout: Layout) {
+    let entry = forget(ptr);
+    if let Some(offset) = corrupted(ptr as usize, layout.size(), layout.align()) {
+        report_corruption(ptr as usize, layout.size(), offset, entry);
+        crate::log_error!("mm", "  freed from:");
+        cpu::backtrace(|lr| crate::log_error!("mm", "    {:#x}", lr));
+    }
+    core::ptr::write_bytes(ptr, FREED_BYTE, layout.size());
+    let front = front(layout.align());
+    if let Some(padded) = padded(layout) {
+        heap.dealloc(ptr.sub(front), padded);
+    }
+}
+
+/// Offset from `ptr` of the first redzone byte that was overwritten, if
+/// any.
+fn corrupted(ptr: usize, size: usize, align: usize) -> Option<isize> {
+    let before = (ptr - front(align)..ptr).rev();
+    let after = ptr + size..ptr + size + REDZONE;
+    // Nearest the allocation first: that's where an overrun starts
+    after.chain(before)
+        .find(|&addr| unsafe { *(addr as *const u8) } != REDZONE_BYTE)
+        .map(|addr| addr as isize - ptr as isize)
+}
+
+/// Log a redzone found overwritten at `offset` from the `size`-byte
+/// allocation at `ptr`, with where it was allocated if that's known.
+fn report_corruption(ptr: usize, size: usize, offset: isize, entry: Option<Entry>) {
+    let (side, distance) = if offset < 0 {
+        ("before its start", offset.unsigned_abs())
+    } else {
+        ("past its end", offset as usize - size + 1)
+    };
+    crate::log_error!("mm", "Heap redzone overwritten: {}-byte allocation at {:#x}, {} byte(s) {}",
+        size, ptr, distance, side);
+    match entry {
+        Some(entry) => {
+            crate::log_error!("mm", "  allocated from:");
+            for lr in entry.callers.iter().filter(|&&lr| lr != 0) {
+                crate::log_error!("mm", "    {:#x}", lr);
+            }
+        }
+        None => crate::log_error!("mm", "  (allocation site unknown: not tracked)"),
+    }
+}
+
+/// Record the allocation of `layout` at `ptr`.
+#[inline(never)]
+fn record(ptr: *mut u8, layout: Layout) {
+    let mut callers = [0; DEPTH];
+    let mut frame = 0usize;
+    cpu::backtrace(|lr| {
+        if let Some(slot) = frame.checked_sub(SKIP).and_then(|i| callers.get_mut(i)) {
+            *slot = lr;
+        }
+        frame += 1;
+    });
+    let entry = Entry {
+        ptr: ptr as usize,
+        size: layout.size(),
+        align: layout.align(),
+        time: Timer::counter(),
+        callers,
+        reported: false,
+    };
+
+    let daif = cpu::save_and_disable_interrupts();
+    let mut table = TABLE.lock();
+    match table.iter_mut().find(|slot| slot.ptr == 0) {
+        Some(slot) => *slot = entry,
+        None => OVERFLOWED.store(true, Ordering::Relaxed),
+    }
+    drop(table);
+    cpu::restore_interrupts(daif);
+}
+
+/// Forget the allocation at `ptr`, returning what was recorded about it.
+fn forget(ptr: *mut u8) -> Option<Entry> {
+    let daif = cpu::save_and_disable_interrupts();
+    let mut table = TABLE.lock();
+    let entry = table.iter_mut().find(|slot| slot.ptr == ptr as usize)
+        .map(|slot| core::mem::replace(slot, FREE));
+    drop(table);
+    cpu::restore_interrupts(daif);
+    entry
+}
+
+/// Check the redzones of every live allocation, reporting each one found
+/// overwritten once.
+pub fn sweep() {
+    let mut bad: [Option<(Entry, isize)>; MAX_REPORTED] = [None; MAX_REPORTED];
+    let daif = cpu::save_and_disable_interrupts();
+    let mut table = TABLE.lock();
+    // Entries are forgotten before their memory is freed, so all of these
+    // are still allocated
+    let mut found = 0;
+    for entry in table.iter_mut().filter(|e| e.ptr != 0 && !e.reported) {
+        if found == MAX_REPORTED {
+            break;
+        }
+        if let Some(offset) = corrupted(entry.ptr, entry.size, entry.align) {
+            entry.reported = true;
+            bad[found] = Some((*entry, offset));
+            found += 1;
+        }
+    }
+    drop(table);
+    cpu::restore_interrupts(daif);
+
+    for (entry, offset) in bad.into_iter().flatten() {
+        report_corruption(entry.ptr, entry.size, offset, Some(entry));
+    }
+}
+
+/// Run `sweep` if `SWEEP_INTERVAL` has passed since the last one. Called
+/// from the idle loop.
+pub fn sweep_if_due() {
+    let now = Timer::counter();
+    let last = LAST_SWEEP.load(Ordering::Relaxed);
+    if now - last >= SWEEP_INTERVAL * Timer::frequency() {
+        LAST_SWEEP.store(now, Ordering::Relaxed);
+        sweep();
+    }
+}
+
+/// Print the live allocations at least `min_age` seconds old, grouped by
+/// call chain, most bytes first.
+pub fn report(min_age: u64) {
+    /// (callers, allocations, bytes, oldest allocation time)
+    type Site = ([usize; DEPTH], usize, usize, u64);
+    let mut sites: [Option<Site>; MAX_SITES] = [None; MAX_SITES];
+    let mut ungrouped = 0;
+    let now = Timer::counter();
+    let cutoff = now.saturating_sub(min_age * Timer::frequency());
+
+    // Nothing may allocate while the table is locked
+    let daif = cpu::save_and_disable_interrupts();
+    let table = TABLE.lock();
+    for entry in table.iter().filter(|e| e.ptr != 0 && e.time <= cutoff) {
+        let site = sites.iter_mut().find(|s| s.map_or(true, |(callers, ..)| callers == entry.callers));
+        match site {
+            Some(Some((_, count, bytes, oldest))) => {
+                *count += 1;
+                *bytes += entry.size;
+                *oldest = (*oldest).min(entry.time);
+            }
+            Some(empty) => *empty = Some((entry.callers, 1, entry.size, entry.time)),
+            None => ungrouped += 1,
+        }
+    }
+    drop(table);
+    cpu::restore_interrupts(daif);
+
+    let mut sites: alloc::vec::Vec<Site> = sites.into_iter().flatten().collect();
+    sites.sort_unstable_by_key(|&(_, _, bytes, _)| core::cmp::Reverse(bytes));
+    if sites.is_empty() {
+        crate::println!("No live allocations older than {}s", min_age);
+    }
+    for (callers, count, bytes, oldest) in sites {
+        crate::println!("{:>5} allocs {:>8} bytes, oldest {:>4}s:", count, bytes,
+            Timer::ticks_to_duration(now - oldest).as_secs());
+        print!("    ");
+        for lr in callers.iter().filter(|&&lr| lr != 0) {
+            print!(" {:#x}", lr);
+        }
+        crate::println!();
+    }
+    if ungrouped != 0 {
+        crate::println!("({} more allocations from other call sites not shown)", ungrouped);
+    }
+    if OVERFLOWED.load(Ordering::Relaxed) {
+        crate::println!("Table overflowed: some allocations were never tracked ({} slots)", MAX_TRACKED);
+    }
+}
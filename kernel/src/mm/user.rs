@@ -0,0 +1,208 @@
+// =============================================================================
+// APRK OS - User Memory
+// =============================================================================
+// User tasks live in the lower half of the address space (TTBR0), which
+// maps nothing of the kernel's. All user tasks currently share the
+// address space set up at boot:
+//
+//   0x4020_0000  program images (where user programs are linked)
+//   0x6000_0000  user heap, served by the alloc/dealloc syscalls
+//   0x7000_0000  user stacks, each with an unmapped guard page below
+//   0x10_0000_0000  per-task sbrk heaps, one 1GB window per task slot
+//
+// Everything here is backed by PMM pages, zeroed by `map_new` before
+// first use (EL0-accessible pages always are). The
+// sbrk heaps are demand-paged: moving the break maps nothing, and pages
+// are filled in by the data-abort handler when first touched.
+// =============================================================================
+
+use alloc::sync::Arc;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use aprk_arch_arm64::mmu::{self, AddressSpace, PageFlags, PAGE_SIZE};
+use linked_list_allocator::LockedHeap;
+use spin::Mutex;
+use super::MapFlags;
+use super::pmm::PageTag;
+
+/// User heap, shared by all user tasks
+pub const HEAP_BASE: usize = 0x6000_0000;
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MB
+
+/// User stack region, handed out in fixed slots
+pub const STACK_BASE: usize = 0x7000_0000;
+pub const STACK_REGION_SIZE: usize = 16 * 1024 * 1024; // 16 MB
+/// One stack slot: guard page plus up to 124KB of stack
+const STACK_SLOT: usize = 128 * 1024;
+/// Stack slots in the region
+const STACK_SLOTS: usize = STACK_REGION_SIZE / STACK_SLOT;
+const _: () = assert!(STACK_SLOTS <= u128::BITS as usize);
+
+/// Per-task break-managed heaps
+pub const BRK_BASE: usize = 0x10_0000_0000;
+/// Address space reserved for each task's break-managed heap
+pub const BRK_WINDOW: usize = 1 << 30; // 1 GB
+
+static USER_HEAP: LockedHeap = LockedHeap::empty();
+
+/// The shared user address space
+static SPACE: Mutex<Option<Arc<AddressSpace>>> = Mutex::new(None);
+
+/// Stack slots in use, a bit each; freed ones are handed out again
+static STACK_SLOTS_USED: Mutex<u128> = Mutex::new(0);
+
+pub fn init() {
+    *SPACE.lock() = Some(Arc::new(unsafe { AddressSpace::boot() }));
+    if !map_fresh(HEAP_BASE, HEAP_SIZE, PageTag::UserHeap) {
+        crate::log_error!("mm", "Out of memory for the user heap");
+        return;
+    }
+    unsafe {
+        USER_HEAP.lock().init(HEAP_BASE as *mut u8, HEAP_SIZE);
+    }
+    crate::log_info!("mm", "User heap at {:#x} (Size: {} MB)", HEAP_BASE, HEAP_SIZE / 1024 / 1024);
+}
+
+/// Back `[va, va + len)` with fresh zeroed pages for `tag`, mapped user
+/// read-write.
+fn map_fresh(va: usize, len: usize, tag: PageTag) -> bool {
+    (va..va + len).step_by(PAGE_SIZE)
+        .all(|page| super::map_new(page, PageFlags::USER_RW, MapFlags::ZERO, tag))
+}
+
+/// The address space user tasks run in.
+pub fn space() -> Option<Arc<AddressSpace>> {
+    let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+    let space = SPACE.lock().clone();
+    aprk_arch_arm64::cpu::restore_interrupts(daif);
+    space
+}
+
+/// Start of the break-managed heap for the task in scheduler slot `slot`.
+pub const fn brk_base(slot: usize) -> usize {
+    BRK_BASE + slot * BRK_WINDOW
+}
+
+/// Back the page containing `va` with a fresh zeroed page after a fault.
+pub fn fault_in(va: usize) -> bool {
+    map_fresh(va & !(PAGE_SIZE - 1), PAGE_SIZE, PageTag::UserHeap)
+}
+
+/// Unmap and free the pages mapped in `[start, end)`, which were allocated
+/// for `tag`, stopping early once `pages` have been found.
+///
+/// Returns the number of pages released.
+pub fn release(start: usize, end: usize, pages: usize, tag: PageTag) -> usize {
+    let mut released = 0;
+    let mut page = start.next_multiple_of(PAGE_SIZE);
+    while page < end && released < pages {
+        if super::unmap_free(page, tag) {
+            released += 1;
+        }
+        page += PAGE_SIZE;
+    }
+    released
+}
+
+/// Allocate from the user heap. Returns null if it is exhausted.
+pub fn alloc(layout: Layout) -> *mut u8 {
+    USER_HEAP.lock().allocate_first_fit(layout)
+        .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr())
+}
+
+/// Return memory to the user heap.
+///
+/// Returns `false` (and does nothing) if `ptr` isn't in the user heap.
+///
+/// # Safety
+/// `ptr` must have come from `alloc` with the same layout.
+pub unsafe fn dealloc(ptr: *mut u8, layout: Layout) -> bool {
+    let addr = ptr as usize;
+    if addr < HEAP_BASE || addr + layout.size() > HEAP_BASE + HEAP_SIZE {
+        return false;
+    }
+    match NonNull::new(ptr) {
+        Some(ptr) => {
+            USER_HEAP.lock().deallocate(ptr, layout);
+            true
+        }
+        None => false,
+    }
+}
+
+/// A user stack: `size` bytes of pages above an unmapped guard page, in
+/// one slot of the stack region. Freed with `free_stack`.
+pub struct UserStack {
+    guard: usize,
+    size: usize,
+}
+
+impl UserStack {
+    /// The guard page
+    pub fn guard(&self) -> usize {
+        self.guard
+    }
+
+    /// Lowest address of the stack
+    pub fn base(&self) -> usize {
+        self.guard + PAGE_SIZE
+    }
+
+    /// Initial stack pointer
+    pub fn top(&self) -> usize {
+        self.base() + self.size
+    }
+}
+
+/// Run `f` on the stack slots in use, with IRQs masked.
+fn with_stack_slots<R>(f: impl FnOnce(&mut u128) -> R) -> R {
+    let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+    let result = f(&mut STACK_SLOTS_USED.lock());
+    aprk_arch_arm64::cpu::restore_interrupts(daif);
+    result
+}
+
+/// Allocate and map a user stack of `size` bytes below an unmapped guard
+/// page.
+///
+/// Returns `None` if the stack region or physical memory ran out.
+pub fn alloc_stack(size: usize) -> Option<UserStack> {
+    let size = size.next_multiple_of(PAGE_SIZE);
+    if size + PAGE_SIZE > STACK_SLOT {
+        return None;
+    }
+    let slot = with_stack_slots(|used| {
+        let slot = (0..STACK_SLOTS).find(|&slot| *used & (1 << slot) == 0)?;
+        *used |= 1 << slot;
+        Some(slot)
+    })?;
+    let stack = UserStack { guard: STACK_BASE + slot * STACK_SLOT, size };
+    if !map_fresh(stack.base(), size, PageTag::UserStack) {
+        free_stack(stack);
+        return None;
+    }
+    Some(stack)
+}
+
+/// Unmap and free the pages of `stack`, which must be mapped in the active
+/// address space, and give its slot back.
+pub fn free_stack(stack: UserStack) {
+    release(stack.base(), stack.top(), usize::MAX, PageTag::UserStack);
+    let slot = (stack.guard - STACK_BASE) / STACK_SLOT;
+    with_stack_slots(|used| *used &= !(1 << slot));
+}
+
+/// Map a fresh zeroed page at `page` for a program image, read-write so
+/// it can be loaded.
+pub fn map_image_page(page: usize) -> bool {
+    page < mmu::VA_LIMIT && super::map_new(page, PageFlags::USER_RW, MapFlags::ZERO, PageTag::Anonymous)
+}
+
+/// Change the permissions of the user page mapped at `page`, keeping the
+/// physical page behind it.
+pub fn protect(page: usize, flags: PageFlags) -> bool {
+    match mmu::translate(page) {
+        Some((pa, _)) if page < mmu::VA_LIMIT => mmu::remap(page, pa, flags),
+        _ => false,
+    }
+}
@@ -0,0 +1,117 @@
+// =============================================================================
+// APRK OS - ARP
+// =============================================================================
+// Maps IPv4 addresses on the local network to MAC addresses (RFC 826).
+// Requests for our address are answered, and the asker remembered, as it's
+// about to be talked to. `resolve` asks for an address it doesn't know and
+// waits a little for the answer, which the network task records.
+//
+// Entries never expire: the table is small and forgotten whenever the
+// interface's address changes.
+// =============================================================================
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::time::Duration;
+use spin::Mutex;
+use aprk_arch_arm64::timer::Timer;
+use crate::sched;
+use super::{Ipv4Addr, MacAddr, NetError, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+
+/// Hardware type: Ethernet
+const HTYPE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+/// Length of an ARP packet for IPv4 over Ethernet
+const PACKET_LEN: usize = 28;
+/// Requests sent before giving up on an address
+const TRIES: u32 = 3;
+/// Time to wait for each reply
+const REPLY_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Known addresses
+static TABLE: Mutex<BTreeMap<Ipv4Addr, MacAddr>> = Mutex::new(BTreeMap::new());
+
+/// Forget every address.
+pub fn clear() {
+    TABLE.lock().clear();
+}
+
+/// The addresses known so far.
+pub fn entries() -> Vec<(Ipv4Addr, MacAddr)> {
+    TABLE.lock().iter().map(|(&ip, &mac)| (ip, mac)).collect()
+}
+
+/// Handle a received ARP packet.
+pub(super) fn input(packet: &[u8]) {
+    let Some(config) = super::config() else {
+        return;
+    };
+    if packet.len() < PACKET_LEN
+        || u16::from_be_bytes([packet[0], packet[1]]) != HTYPE_ETHERNET
+        || u16::from_be_bytes([packet[2], packet[3]]) != ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let op = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac = MacAddr(packet[8..14].try_into().unwrap());
+    let sender_ip = Ipv4Addr(packet[14..18].try_into().unwrap());
+    let target_ip = Ipv4Addr(packet[24..28].try_into().unwrap());
+
+    let mut table = TABLE.lock();
+    if target_ip == config.addr {
+        table.insert(sender_ip, sender_mac);
+    } else if let Some(known) = table.get_mut(&sender_ip) {
+        // Someone else's request still says where the sender is now
+        *known = sender_mac;
+    }
+    drop(table);
+
+    if op == OP_REQUEST && target_ip == config.addr {
+        let _ = send(OP_REPLY, sender_mac, sender_ip);
+    }
+}
+
+/// Send an ARP packet of type `op` about `target_ip` to `target_mac`.
+fn send(op: u16, target_mac: MacAddr, target_ip: Ipv4Addr) -> Result<(), NetError> {
+    let config = super::config().ok_or(NetError::NotConfigured)?;
+    let mac = crate::drivers::virtio_net::mac().ok_or(NetError::NoDevice)?;
+    let mut packet = Vec::with_capacity(PACKET_LEN);
+    packet.extend_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+    packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet.extend_from_slice(&[6, 4]);
+    packet.extend_from_slice(&op.to_be_bytes());
+    packet.extend_from_slice(&mac.0);
+    packet.extend_from_slice(&config.addr.0);
+    // A request leaves the target's MAC address blank
+    packet.extend_from_slice(if op == OP_REQUEST { &[0; 6] } else { &target_mac.0 });
+    packet.extend_from_slice(&target_ip.0);
+    let dst = if op == OP_REQUEST { MacAddr::BROADCAST } else { target_mac };
+    super::send_frame(dst, ETHERTYPE_ARP, &packet)
+}
+
+/// The MAC address of `addr`, on the local network, asking for it if it
+/// isn't known. Must not be called by the network task, which collects
+/// the answer.
+pub fn resolve(addr: Ipv4Addr) -> Result<MacAddr, NetError> {
+    let config = super::config().ok_or(NetError::NotConfigured)?;
+    if addr == Ipv4Addr::BROADCAST || addr == config.broadcast() {
+        return Ok(MacAddr::BROADCAST);
+    }
+    for _ in 0..TRIES {
+        if let Some(&mac) = TABLE.lock().get(&addr) {
+            return Ok(mac);
+        }
+        send(OP_REQUEST, MacAddr::BROADCAST, addr)?;
+        let until = Timer::uptime() + REPLY_TIMEOUT;
+        while Timer::uptime() < until {
+            if let Some(&mac) = TABLE.lock().get(&addr) {
+                return Ok(mac);
+            }
+            sched::schedule();
+        }
+    }
+    Err(NetError::Unreachable)
+}
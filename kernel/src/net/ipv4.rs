@@ -0,0 +1,113 @@
+// =============================================================================
+// APRK OS - IPv4 and ICMP
+// =============================================================================
+// Checks received packets are whole, unfragmented and for us, and passes
+// them on by protocol. ICMP only answers echo requests (ping): the reply
+// goes straight back to the MAC address the request came from, so the
+// network task never has to wait for ARP.
+// =============================================================================
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use super::{arp, udp, Ipv4Addr, MacAddr, NetError, ETHERTYPE_IPV4, MTU};
+
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_UDP: u8 = 17;
+/// Header without options
+const HEADER_LEN: usize = 20;
+/// Hop limit of packets sent
+const TTL: u8 = 64;
+/// Fragment offset and more-fragments bits of the flags field
+const FRAGMENT_MASK: u16 = 0x3fff;
+/// Largest payload of a packet
+pub const MAX_PAYLOAD: usize = MTU - HEADER_LEN;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// Identification of the next packet sent
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Handle a received IPv4 packet, which came from `src_mac`.
+pub(super) fn input(src_mac: MacAddr, packet: &[u8]) {
+    let Some(config) = super::config() else {
+        return;
+    };
+    if packet.len() < HEADER_LEN || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = (packet[0] & 0xf) as usize * 4;
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if header_len < HEADER_LEN || total_len < header_len || total_len > packet.len() {
+        return;
+    }
+    if super::checksum(0, &packet[..header_len]) != 0 {
+        return;
+    }
+    if u16::from_be_bytes([packet[6], packet[7]]) & FRAGMENT_MASK != 0 {
+        return;
+    }
+    let src = Ipv4Addr(packet[12..16].try_into().unwrap());
+    let dst = Ipv4Addr(packet[16..20].try_into().unwrap());
+    if dst != config.addr && dst != config.broadcast() && dst != Ipv4Addr::BROADCAST {
+        return;
+    }
+    // Frames are padded to the Ethernet minimum: the length field says
+    // where the packet ends
+    let payload = &packet[header_len..total_len];
+    match packet[9] {
+        PROTO_ICMP if dst == config.addr => icmp(src_mac, src, payload),
+        PROTO_UDP => udp::input(src, dst, payload),
+        _ => {}
+    }
+}
+
+/// Answer an echo request from `src`.
+fn icmp(src_mac: MacAddr, src: Ipv4Addr, message: &[u8]) {
+    if message.len() < 8 || message[0] != ICMP_ECHO_REQUEST || super::checksum(0, message) != 0 {
+        return;
+    }
+    // Same identifier, sequence number and data back
+    let mut reply = message.to_vec();
+    reply[0] = ICMP_ECHO_REPLY;
+    reply[2..4].fill(0);
+    let sum = super::checksum(0, &reply);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    let _ = send_via(src_mac, src, PROTO_ICMP, &reply);
+}
+
+/// Send `payload` of protocol `proto` to `dst`, through the gateway if it
+/// isn't on the local network.
+pub fn send(dst: Ipv4Addr, proto: u8, payload: &[u8]) -> Result<(), NetError> {
+    let config = super::config().ok_or(NetError::NotConfigured)?;
+    let next_hop = if config.is_local(dst) || dst == Ipv4Addr::BROADCAST {
+        dst
+    } else {
+        config.gateway.ok_or(NetError::Unreachable)?
+    };
+    let mac = arp::resolve(next_hop)?;
+    send_via(mac, dst, proto, payload)
+}
+
+/// Send `payload` of protocol `proto` to `dst` in a frame for `mac`.
+fn send_via(mac: MacAddr, dst: Ipv4Addr, proto: u8, payload: &[u8]) -> Result<(), NetError> {
+    let config = super::config().ok_or(NetError::NotConfigured)?;
+    if payload.len() > MAX_PAYLOAD {
+        return Err(NetError::TooBig);
+    }
+    let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+    packet.push(0x45); // Version 4, five-word header
+    packet.push(0);
+    packet.extend_from_slice(&((HEADER_LEN + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    packet.extend_from_slice(&0x4000u16.to_be_bytes()); // Don't fragment
+    packet.push(TTL);
+    packet.push(proto);
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&config.addr.0);
+    packet.extend_from_slice(&dst.0);
+    let sum = super::checksum(0, &packet);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    super::send_frame(mac, ETHERTYPE_IPV4, &packet)
+}
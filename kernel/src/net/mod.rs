@@ -0,0 +1,222 @@
+// =============================================================================
+// APRK OS - Network Stack
+// =============================================================================
+// Just enough IPv4 over Ethernet to be reached: one interface (the
+// virtio-net NIC) with a static address set by `ifconfig`, answering ARP
+// requests and pings, and UDP sockets bound to a port.
+//
+// Received frames are handled by the `net` task, started once the
+// interface has an address: it takes each frame from the driver and hands
+// it up the layers (`arp`, `ipv4`, then ICMP or `udp`), which answer
+// from there or queue the data for a socket. Sending happens in the
+// sending task, resolving the next hop's MAC address first (`arp`).
+//
+// Not supported: fragments, IP options we'd have to act on, routing
+// beyond one gateway, DHCP.
+// =============================================================================
+
+pub mod arp;
+pub mod ipv4;
+pub mod udp;
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use crate::drivers::virtio_net;
+use crate::sched;
+
+/// EtherType of ARP packets
+const ETHERTYPE_ARP: u16 = 0x0806;
+/// EtherType of IPv4 packets
+const ETHERTYPE_IPV4: u16 = 0x0800;
+/// Ethernet header: destination, source, EtherType
+const ETH_HEADER_LEN: usize = 14;
+/// Shortest frame, without the FCS the device adds
+const ETH_MIN_LEN: usize = 60;
+/// Largest payload of a frame
+pub const MTU: usize = 1500;
+
+/// An IPv4 address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255; 4]);
+
+    /// Parse dotted-quad notation ("10.0.2.15").
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut octets = [0u8; 4];
+        let mut parts = s.split('.');
+        for octet in &mut octets {
+            *octet = parts.next()?.parse().ok()?;
+        }
+        parts.next().is_none().then_some(Ipv4Addr(octets))
+    }
+
+    fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// An Ethernet MAC address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+/// Address of the interface.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub addr: Ipv4Addr,
+    /// Network prefix length (24 for 255.255.255.0)
+    pub prefix: u8,
+    /// Where packets for other networks go, if anywhere
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Config {
+    fn netmask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0)
+    }
+
+    /// Whether `addr` is on the interface's network.
+    pub fn is_local(&self, addr: Ipv4Addr) -> bool {
+        (addr.to_u32() ^ self.addr.to_u32()) & self.netmask() == 0
+    }
+
+    /// The broadcast address of the interface's network.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr((self.addr.to_u32() | !self.netmask()).to_be_bytes())
+    }
+}
+
+/// What went wrong sending or binding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetError {
+    /// No NIC
+    NoDevice,
+    /// The interface has no address yet
+    NotConfigured,
+    /// No route to the address, or no ARP reply from it
+    Unreachable,
+    /// The port is already bound
+    AddrInUse,
+    /// The data doesn't fit in one packet
+    TooBig,
+    /// The NIC's transmit queue is full
+    Busy,
+    /// The NIC failed the request
+    Device,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NetError::NoDevice => "No network device",
+            NetError::NotConfigured => "Interface has no address (see ifconfig)",
+            NetError::Unreachable => "Host unreachable",
+            NetError::AddrInUse => "Address already in use",
+            NetError::TooBig => "Message too long",
+            NetError::Busy => "Device busy",
+            NetError::Device => "Network device error",
+        })
+    }
+}
+
+static CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+/// PID of the task handling received frames, 0 until it's started
+static WORKER: Mutex<usize> = Mutex::new(0);
+
+/// The interface's address, if it has one.
+pub fn config() -> Option<Config> {
+    *CONFIG.lock()
+}
+
+/// Give the interface its address, starting the task that answers on it.
+pub fn configure(config: Config) -> Result<(), NetError> {
+    virtio_net::mac().ok_or(NetError::NoDevice)?;
+    *CONFIG.lock() = Some(config);
+    arp::clear();
+    let mut worker = WORKER.lock();
+    if *worker == 0 || !sched::is_alive(*worker) {
+        *worker = sched::spawn_named(receive_task, "net", sched::Priority::Normal).ok_or(NetError::Device)?;
+    }
+    crate::log_info!("net", "Address {}/{}", config.addr, config.prefix);
+    Ok(())
+}
+
+/// Handle received frames, forever.
+extern "C" fn receive_task() {
+    loop {
+        let frame = virtio_net::receive();
+        input(&frame);
+    }
+}
+
+/// Hand a received frame to the layer above.
+fn input(frame: &[u8]) {
+    let Some(mac) = virtio_net::mac() else {
+        return;
+    };
+    if frame.len() < ETH_HEADER_LEN {
+        return;
+    }
+    let dst = MacAddr(frame[0..6].try_into().unwrap());
+    let src = MacAddr(frame[6..12].try_into().unwrap());
+    if dst != mac && dst != MacAddr::BROADCAST {
+        return;
+    }
+    let payload = &frame[ETH_HEADER_LEN..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_ARP => arp::input(payload),
+        ETHERTYPE_IPV4 => ipv4::input(src, payload),
+        _ => {}
+    }
+}
+
+/// Send `payload` to `dst` in a frame of type `ethertype`.
+fn send_frame(dst: MacAddr, ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+    let mac = virtio_net::mac().ok_or(NetError::NoDevice)?;
+    if payload.len() > MTU {
+        return Err(NetError::TooBig);
+    }
+    let mut frame = Vec::with_capacity(ETH_MIN_LEN.max(ETH_HEADER_LEN + payload.len()));
+    frame.extend_from_slice(&dst.0);
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.resize(frame.len().max(ETH_MIN_LEN), 0);
+    virtio_net::send(&frame)
+}
+
+/// Internet checksum (RFC 1071) of `data`, carrying on from `sum`, the
+/// running total of a pseudo-header (0 for none).
+fn checksum(mut sum: u32, data: &[u8]) -> u16 {
+    let (words, rest) = data.as_chunks::<2>();
+    for &word in words {
+        sum += u16::from_be_bytes(word) as u32;
+    }
+    if let [last] = rest {
+        sum += (*last as u32) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,116 @@
+// =============================================================================
+// APRK OS - UDP
+// =============================================================================
+// Sockets bound to a local port. Datagrams for a bound port are queued on
+// it, up to `QUEUE_LEN`, until the owner takes them with `recv`; the rest
+// are dropped. Dropping the socket frees its port.
+// =============================================================================
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::ipv4::{self, MAX_PAYLOAD, PROTO_UDP};
+use super::{Ipv4Addr, NetError};
+
+/// Source port, destination port, length, checksum
+const HEADER_LEN: usize = 8;
+/// Most datagrams queued on a socket
+const QUEUE_LEN: usize = 32;
+
+/// A datagram received.
+pub struct Datagram {
+    pub src: Ipv4Addr,
+    pub src_port: u16,
+    pub data: Vec<u8>,
+}
+
+/// Queued datagrams of each bound port
+static PORTS: Mutex<BTreeMap<u16, VecDeque<Datagram>>> = Mutex::new(BTreeMap::new());
+
+/// A UDP socket bound to a local port.
+pub struct Socket {
+    port: u16,
+}
+
+impl Socket {
+    /// Bind `port`, which no other socket may have.
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let mut ports = PORTS.lock();
+        if ports.contains_key(&port) {
+            return Err(NetError::AddrInUse);
+        }
+        ports.insert(port, VecDeque::new());
+        Ok(Self { port })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The oldest datagram queued, if any.
+    pub fn recv(&self) -> Option<Datagram> {
+        PORTS.lock().get_mut(&self.port)?.pop_front()
+    }
+
+    /// Send `data` to port `dst_port` of `dst`.
+    pub fn send_to(&self, dst: Ipv4Addr, dst_port: u16, data: &[u8]) -> Result<(), NetError> {
+        send(self.port, dst, dst_port, data)
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        PORTS.lock().remove(&self.port);
+    }
+}
+
+/// Running checksum total of the pseudo-header.
+fn pseudo_header(src: Ipv4Addr, dst: Ipv4Addr, len: usize) -> u32 {
+    let words = [src.0, dst.0].concat();
+    words.as_chunks::<2>().0.iter().map(|&w| u16::from_be_bytes(w) as u32).sum::<u32>() + PROTO_UDP as u32 + len as u32
+}
+
+/// Handle a UDP datagram from `src` to `dst`.
+pub(super) fn input(src: Ipv4Addr, dst: Ipv4Addr, packet: &[u8]) {
+    if packet.len() < HEADER_LEN {
+        return;
+    }
+    let src_port = u16::from_be_bytes([packet[0], packet[1]]);
+    let dst_port = u16::from_be_bytes([packet[2], packet[3]]);
+    let len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+    if len < HEADER_LEN || len > packet.len() {
+        return;
+    }
+    let packet = &packet[..len];
+    // A zero checksum means the sender didn't compute one
+    if packet[6..8] != [0, 0] && super::checksum(pseudo_header(src, dst, len), packet) != 0 {
+        return;
+    }
+    if let Some(queue) = PORTS.lock().get_mut(&dst_port) {
+        if queue.len() < QUEUE_LEN {
+            queue.push_back(Datagram { src, src_port, data: packet[HEADER_LEN..].to_vec() });
+        }
+    }
+}
+
+/// Send `data` from local port `src_port` to port `dst_port` of `dst`.
+pub fn send(src_port: u16, dst: Ipv4Addr, dst_port: u16, data: &[u8]) -> Result<(), NetError> {
+    let config = super::config().ok_or(NetError::NotConfigured)?;
+    let len = HEADER_LEN + data.len();
+    if len > MAX_PAYLOAD {
+        return Err(NetError::TooBig);
+    }
+    let mut packet = Vec::with_capacity(len);
+    packet.extend_from_slice(&src_port.to_be_bytes());
+    packet.extend_from_slice(&dst_port.to_be_bytes());
+    packet.extend_from_slice(&(len as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(data);
+    let sum = match super::checksum(pseudo_header(config.addr, dst, len), &packet) {
+        // Zero would mean no checksum: send its other form
+        0 => 0xffff,
+        sum => sum,
+    };
+    packet[6..8].copy_from_slice(&sum.to_be_bytes());
+    ipv4::send(dst, PROTO_UDP, &packet)
+}
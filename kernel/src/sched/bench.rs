@@ -0,0 +1,74 @@
+// =============================================================================
+// APRK OS - Context Switch Benchmark
+// =============================================================================
+// Two kernel tasks, each in its own (empty) user address space, hand a
+// turn counter back and forth by yielding. Every hand-over is a task
+// switch that also switches TTBR0, so the run is timed twice: once
+// relying on ASIDs, and once flushing the whole TLB on every switch as
+// the kernel would have to without them.
+// =============================================================================
+
+use super::{spawn_named, wait_for_exit, Priority};
+use alloc::sync::Arc;
+use aprk_arch_arm64::mmu::{self, AddressSpace};
+use aprk_arch_arm64::timer::Timer;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Switches still to do, counting down
+static REMAINING: AtomicUsize = AtomicUsize::new(0);
+/// Whose turn it is (0 = ping, 1 = pong)
+static TURN: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn ping() {
+    play(0);
+}
+
+extern "C" fn pong() {
+    play(1);
+}
+
+fn play(me: usize) {
+    match AddressSpace::new() {
+        Some(space) => super::set_current_space(Arc::new(space)),
+        None => crate::log_warn!("sched", "No address space for the switch benchmark"),
+    }
+    while REMAINING.load(Ordering::Relaxed) != 0 {
+        if TURN.load(Ordering::Relaxed) == me {
+            REMAINING.fetch_sub(1, Ordering::Relaxed);
+            TURN.store(1 - me, Ordering::Relaxed);
+        }
+        super::schedule();
+    }
+}
+
+/// Time `switches` ping-pong switches, returning the average cost in ns.
+fn run(switches: usize) -> Option<u64> {
+    REMAINING.store(switches, Ordering::Relaxed);
+    TURN.store(0, Ordering::Relaxed);
+    let start = Timer::counter();
+    let ping = spawn_named(ping, "ping", Priority::Normal)?;
+    let Some(pong) = spawn_named(pong, "pong", Priority::Normal) else {
+        REMAINING.store(0, Ordering::Relaxed);
+        wait_for_exit(ping);
+        return None;
+    };
+    wait_for_exit(ping);
+    wait_for_exit(pong);
+    let elapsed = Timer::ticks_to_duration(Timer::counter() - start);
+    Some(elapsed.as_nanos() as u64 / switches as u64)
+}
+
+/// Measure the cost of a task switch between address spaces, with ASIDs
+/// and with a full TLB flush per switch.
+pub fn switch_benchmark(switches: usize) {
+    let switches = switches.max(1);
+    for (label, flush) in [("ASID tagged:     ", false), ("full TLB flush:  ", true)] {
+        mmu::set_flush_on_switch(flush);
+        let result = run(switches);
+        mmu::set_flush_on_switch(false);
+        match result {
+            Some(ns) => crate::println!("{} {:>6} ns/switch ({} switches)", label, ns, switches),
+            None => crate::println!("{} failed to start the tasks", label),
+        }
+    }
+}
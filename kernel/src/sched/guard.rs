@@ -0,0 +1,104 @@
+// =============================================================================
+// APRK OS - Stack Guard Pages
+// =============================================================================
+// Every task stack has unmapped memory directly below it: kernel stacks
+// sit at the top of their slot in the kernel stack region, user stacks
+// get one unmapped page. Running off the bottom of a stack then faults
+// straight away instead of silently overwriting whatever lies next to it.
+//
+// The guard ranges are recorded here so the fault handler can tell a
+// stack overflow from any other bad access and name the task at fault.
+// =============================================================================
+
+use aprk_arch_arm64::cpu;
+use aprk_arch_arm64::mmu::PAGE_SIZE;
+use crate::mm::kstack::KernelStack;
+use crate::mm::user::UserStack;
+use spin::Mutex;
+
+/// Which of a task's stacks a guard page protects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackKind {
+    Kernel,
+    User,
+}
+
+/// A registered guard range
+#[derive(Clone, Copy)]
+struct Guard {
+    pid: usize,
+    kind: StackKind,
+    /// Unmapped range `[start, end)`
+    start: usize,
+    end: usize,
+}
+
+/// Every task has at most a kernel and a user stack
+const MAX_GUARDS: usize = super::MAX_TASKS * 2;
+
+static GUARDS: Mutex<[Option<Guard>; MAX_GUARDS]> = Mutex::new([None; MAX_GUARDS]);
+
+/// Allocate a kernel stack of `size` bytes for task `pid` and register
+/// the hole beneath it.
+///
+/// Returns `None` if out of memory or stack slots.
+pub fn kernel_stack(pid: usize, size: usize) -> Option<KernelStack> {
+    let stack = KernelStack::new(size)?;
+    let (start, end) = stack.guard();
+    if !register(Guard { pid, kind: StackKind::Kernel, start, end }) {
+        crate::log_warn!("sched", "Guard registry full, task {} kernel stack unchecked", pid);
+    }
+    Some(stack)
+}
+
+/// Allocate a user stack of `size` bytes for task `pid`, with an
+/// unmapped guard page directly beneath it.
+///
+/// Returns `None` if out of memory or stack slots.
+pub fn user_stack(pid: usize, size: usize) -> Option<UserStack> {
+    let stack = crate::mm::user::alloc_stack(size)?;
+    let start = stack.guard();
+    if !register(Guard { pid, kind: StackKind::User, start, end: start + PAGE_SIZE }) {
+        crate::log_warn!("sched", "Guard registry full, task {} user stack unchecked", pid);
+    }
+    Some(stack)
+}
+
+fn register(guard: Guard) -> bool {
+    let daif = cpu::save_and_disable_interrupts();
+    let mut guards = GUARDS.lock();
+    let ok = match guards.iter_mut().find(|g| g.is_none()) {
+        Some(slot) => {
+            *slot = Some(guard);
+            true
+        }
+        None => false,
+    };
+    drop(guards);
+    cpu::restore_interrupts(daif);
+    ok
+}
+
+/// Forget the guard range starting at `start`, before the stack above it
+/// is freed.
+pub fn unregister(start: usize) {
+    let daif = cpu::save_and_disable_interrupts();
+    for slot in GUARDS.lock().iter_mut() {
+        if slot.is_some_and(|g| g.start == start) {
+            *slot = None;
+        }
+    }
+    cpu::restore_interrupts(daif);
+}
+
+/// Find the guard range containing `addr`.
+///
+/// Returns the owning task's PID and which of its stacks overflowed.
+pub fn find(addr: usize) -> Option<(usize, StackKind)> {
+    let daif = cpu::save_and_disable_interrupts();
+    let found = GUARDS.lock().iter().flatten()
+        .find(|g| (g.start..g.end).contains(&addr))
+        .map(|g| (g.pid, g.kind));
+    cpu::restore_interrupts(daif);
+    found
+}
@@ -5,6 +5,23 @@
 // Uses fixed-size arrays for stability during interrupt context.
 // =============================================================================
 
+use aprk_arch_arm64::gic;
+use aprk_arch_arm64::timer::TICK_MS;
+use aprk_arch_arm64::uart;
+use crate::fd::{self, FdTable, MAX_FDS};
+use crate::mm::pmm::PageTag;
+use crate::mm::user::{self, UserStack};
+use alloc::sync::Arc;
+use core::ops::Range;
+use aprk_arch_arm64::mmu::AddressSpace;
+
+pub mod bench;
+pub mod guard;
+pub mod mutex;
+pub mod wait;
+
+use crate::mm::kstack::KernelStack;
+
 /// Maximum number of tasks supported
 const MAX_TASKS: usize = 16;
 
@@ -55,6 +72,32 @@ pub struct Task {
     pub priority: Priority,     // Scheduling priority
     pub remaining_slices: usize, // Time slices remaining before preemption
     pub name: [u8; 16],         // Task name (fixed size for safety)
+    pub ticks_run: u64,         // Timer ticks spent running (CPU accounting)
+    pub cpu_limit_ticks: Option<u64>, // CPU watchdog limit (None = unlimited)
+    pub cpu_limit_hard: bool,   // Terminate outright at the limit instead of sending Kill
+    pub pending_signal: Option<Signal>, // Delivered on the task's next tick
+    pub fds: FdTable,           // Open file descriptors
+    pub heap_start: usize,      // Start of the sbrk heap (0 for kernel tasks)
+    pub brk: usize,             // Current program break
+    pub heap_pages: usize,      // Heap pages faulted in so far
+    pub image: Range<usize>,    // Pages of the loaded program (empty for kernel tasks)
+    pub space: Option<Arc<AddressSpace>>, // User address space (None = keep the active one)
+    pub kstack: Option<KernelStack>, // Kernel stack (None = the boot stack)
+    pub ustack: Option<UserStack>, // User stack (None for kernel tasks)
+    pub charge: Option<usize>,  // Task kernel heap allocations are charged to (see mm::quota)
+    pub locks_held: usize,      // KMutexes held; the task isn't killed until it's 0
+}
+
+/// Signals that can be sent to a task.
+///
+/// There are no user-space handlers yet, so every signal terminates the
+/// target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// Keyboard interrupt (Ctrl-C)
+    Interrupt,
+    /// Unconditional termination
+    Kill,
 }
 
 impl Task {
@@ -66,6 +109,20 @@ impl Task {
             priority: Priority::Idle,
             remaining_slices: 0,
             name: [0u8; 16],
+            ticks_run: 0,
+            cpu_limit_ticks: None,
+            cpu_limit_hard: false,
+            pending_signal: None,
+            fds: [None; MAX_FDS],
+            heap_start: 0,
+            brk: 0,
+            heap_pages: 0,
+            image: 0..0,
+            space: None,
+            kstack: None,
+            ustack: None,
+            charge: None,
+            locks_held: 0,
         }
     }
     
@@ -109,11 +166,40 @@ pub fn init() {
             priority: Priority::Idle,
             remaining_slices: 1,
             name: *b"idle\0\0\0\0\0\0\0\0\0\0\0\0",
+            ticks_run: 0,
+            cpu_limit_ticks: None,
+            cpu_limit_hard: false,
+            pending_signal: None,
+            fds: fd::stdio(0),
+            heap_start: 0,
+            brk: 0,
+            heap_pages: 0,
+            image: 0..0,
+            space: None,
+            kstack: None,
+            ustack: None,
+            charge: None,
+            locks_held: 0,
         };
         TASK_COUNT = 1;
         NEXT_PID = 1;
         SCHEDULER_ENABLED = false;
     }
+
+    gic::register_handler(gic::SGI_RESCHEDULE, reschedule_irq);
+    gic::enable_irq(gic::SGI_RESCHEDULE);
+}
+
+/// Handler for the RESCHEDULE SGI. It is EOI'd before we get here, so it
+/// is safe to switch tasks.
+fn reschedule_irq(_irq: u32) {
+    schedule();
+}
+
+/// Ask for `schedule()` to run once this CPU next unmasks IRQs, e.g. on
+/// return from a syscall, instead of switching tasks right now.
+pub fn request_reschedule() {
+    gic::send_sgi(gic::SGI_RESCHEDULE, gic::SgiTarget::Current);
 }
 
 /// Enable preemptive scheduling (call after initial setup)
@@ -122,7 +208,6 @@ pub fn enable() {
 }
 
 /// Check if scheduler is enabled
-#[allow(dead_code)]
 pub fn is_enabled() -> bool {
     unsafe { SCHEDULER_ENABLED }
 }
@@ -134,21 +219,25 @@ pub fn spawn(entry: extern "C" fn()) {
 }
 
 /// Spawn a new task with a name and priority (Kernel Thread)
-pub fn spawn_named(entry: extern "C" fn(), name: &str, priority: Priority) {
+///
+/// Returns the PID of the new task, or `None` if it couldn't be created.
+pub fn spawn_named(entry: extern "C" fn(), name: &str, priority: Priority) -> Option<usize> {
     unsafe {
         if TASK_COUNT >= MAX_TASKS {
-            crate::println!("[sched] ERROR: Max tasks ({}) reached!", MAX_TASKS);
-            return;
+            crate::log_error!("sched", "Max tasks ({}) reached!", MAX_TASKS);
+            return None;
         }
         
         let slot = TASK_COUNT;
         let id = NEXT_PID;
-        NEXT_PID += 1;
         
         // Allocate 16KB kernel stack
-        let stack_layout = core::alloc::Layout::from_size_align(16 * 1024, 16).unwrap();
-        let stack_ptr = alloc::alloc::alloc(stack_layout);
-        let mut stack_top = stack_ptr.add(16 * 1024) as u64;
+        let Some(kstack) = guard::kernel_stack(id, 16 * 1024) else {
+            crate::log_error!("sched", "Out of memory for task '{}' stack", name);
+            return None;
+        };
+        NEXT_PID += 1;
+        let mut stack_top = kstack.top() as u64;
         
         // Setup initial context on stack (Sync with context.S: 112 bytes = 14 u64s)
         let sp = (stack_top as *mut u64).sub(14);
@@ -176,37 +265,57 @@ pub fn spawn_named(entry: extern "C" fn(), name: &str, priority: Priority) {
         TASKS[slot].priority = priority;
         TASKS[slot].set_name(name);
         TASKS[slot].reset_time_slice();
+        TASKS[slot].ticks_run = 0;
+        TASKS[slot].cpu_limit_ticks = None;
+        TASKS[slot].cpu_limit_hard = false;
+        TASKS[slot].pending_signal = None;
+        TASKS[slot].fds = fd::stdio(uart::console_port());
+        TASKS[slot].heap_start = 0;
+        TASKS[slot].brk = 0;
+        TASKS[slot].heap_pages = 0;
+        TASKS[slot].image = 0..0;
+        TASKS[slot].space = None;
+        TASKS[slot].kstack = Some(kstack);
+        TASKS[slot].ustack = None;
+        TASKS[slot].charge = None;
+        TASKS[slot].locks_held = 0;
         
         TASK_COUNT += 1;
         
-        crate::println!("[sched] Task {} '{}' spawned (priority: {:?})", id, name, priority);
+        crate::log_info!("sched", "Task {} '{}' spawned (priority: {:?})", id, name, priority);
+        Some(id)
     }
 }
 
-/// Spawn a new User Task (EL0)
-pub fn spawn_user(entry_addr: u64, name: &str) {
+/// Spawn a new User Task (EL0) running the program loaded at `image`,
+/// whose pages it takes over, with open files `fds`.
+///
+/// Returns the PID of the new task, or `None` if the task table is full.
+pub fn spawn_user(entry_addr: u64, image: Range<usize>, name: &str, fds: FdTable) -> Option<usize> {
     unsafe {
         if TASK_COUNT >= MAX_TASKS {
-            crate::println!("[sched] ERROR: Max tasks reached!");
-            return;
+            crate::log_error!("sched", "Max tasks reached!");
+            return None;
         }
 
         let slot = TASK_COUNT;
         let id = NEXT_PID;
-        NEXT_PID += 1;
 
         // 1. Allocate Kernel Stack (16KB)
-        let kstack_layout = core::alloc::Layout::from_size_align(16 * 1024, 16).unwrap();
-        let kstack_ptr = alloc::alloc::alloc(kstack_layout);
-        let mut kstack_top = kstack_ptr.add(16 * 1024) as u64;
-
-        // 2. Allocate User Stack (64KB, EL0 Accessible)
-        // Access permissions handled by paging (Heap is EL0 RW)
-        let ustack_layout = core::alloc::Layout::from_size_align(64 * 1024, 16).unwrap();
-        let ustack_ptr = alloc::alloc::alloc(ustack_layout);
-        // Zero the stack (security/debug)
-        core::ptr::write_bytes(ustack_ptr, 0, 64 * 1024);
-        let ustack_top = ustack_ptr.add(64 * 1024) as u64;
+        let Some(kstack) = guard::kernel_stack(id, 16 * 1024) else {
+            crate::log_error!("sched", "Out of memory for task '{}' stack", name);
+            return None;
+        };
+        let mut kstack_top = kstack.top() as u64;
+
+        // 2. Allocate User Stack (64KB, EL0 Accessible, already zeroed)
+        let Some(ustack) = guard::user_stack(id, 64 * 1024) else {
+            crate::log_error!("sched", "Out of memory for task '{}' stack", name);
+            release_stack(kstack);
+            return None;
+        };
+        NEXT_PID += 1;
+        let ustack_top = ustack.top() as u64;
 
         // 3. Setup Context on Kernel Stack (112 bytes)
         let sp = (kstack_top as *mut u64).sub(14);
@@ -230,9 +339,24 @@ pub fn spawn_user(entry_addr: u64, name: &str) {
         TASKS[slot].priority = Priority::Normal; // Default user priority
         TASKS[slot].set_name(name);
         TASKS[slot].reset_time_slice();
+        TASKS[slot].ticks_run = 0;
+        TASKS[slot].cpu_limit_ticks = None;
+        TASKS[slot].cpu_limit_hard = false;
+        TASKS[slot].pending_signal = None;
+        TASKS[slot].fds = fds;
+        TASKS[slot].heap_start = user::brk_base(slot);
+        TASKS[slot].brk = user::brk_base(slot);
+        TASKS[slot].heap_pages = 0;
+        TASKS[slot].image = image;
+        TASKS[slot].space = user::space();
+        TASKS[slot].kstack = Some(kstack);
+        TASKS[slot].ustack = Some(ustack);
+        TASKS[slot].charge = None;
+        TASKS[slot].locks_held = 0;
 
         TASK_COUNT += 1;
-        crate::println!("[sched] User Task {} '{}' spawned.", id, name);
+        crate::log_info!("sched", "User Task {} '{}' spawned.", id, name);
+        Some(id)
     }
 }
 
@@ -261,7 +385,7 @@ extern "C" fn user_trampoline() {
         core::arch::asm!("mov {}, x19", out(reg) entry);
         core::arch::asm!("mov {}, x20", out(reg) stack);
         
-        crate::println!("[sched] Dropping to User Mode: Entry={:#x}, Stack={:#x}", entry, stack);
+        crate::log_debug!("sched", "Dropping to User Mode: Entry={:#x}, Stack={:#x}", entry, stack);
 
         // Enable interupts? 
         // enter_user_mode will mask them first, then eret will unmask (via SPSR).
@@ -278,27 +402,332 @@ pub fn exit_current_task() -> ! {
     unsafe {
         let id = TASKS[CURRENT_TASK].id;
         let name = TASKS[CURRENT_TASK].get_name();
-        crate::println!("[sched] Task {} '{}' exited.", id, name);
-        TASKS[CURRENT_TASK].state = TaskState::Dead;
+        crate::log_info!("sched", "Task {} '{}' exited.", id, name);
+        mark_dead(CURRENT_TASK);
         schedule();
         loop { aprk_arch_arm64::cpu::halt(); }
     }
 }
 
+/// A fault hit `addr`: if it is a stack guard page, report the overflow
+/// and terminate the current task.
+///
+/// Returns `false` if `addr` isn't in a guard page.
+pub fn stack_fault(addr: usize) -> bool {
+    let Some((pid, kind)) = guard::find(addr) else {
+        return false;
+    };
+    unsafe {
+        let current = &TASKS[CURRENT_TASK];
+        if current.id != pid {
+            // Only the owner runs on a stack; anything else is a wild access
+            crate::log_error!("sched", "Task {} '{}' hit the {:?} stack guard of task {} at {:#x}",
+                current.id, current.get_name(), kind, pid, addr);
+        } else {
+            crate::log_error!("sched", "Stack overflow in task {} '{}' ({:?} stack, fault at {:#x})",
+                pid, current.get_name(), kind, addr);
+        }
+    }
+    exit_current_task();
+}
+
+/// Kill the current (user) task after an exception it caused, such as a
+/// write to its own read-only text.
+pub fn user_exception(esr: u64, elr: u64, far: u64) -> ! {
+    let reason = match (esr >> 26) & 0x3F {
+        0x20 => "instruction abort",
+        0x24 if esr & 0x3C == 0x0C => "permission fault",
+        0x24 => "data abort",
+        0x22 | 0x26 => "misaligned PC/SP",
+        _ => "unhandled exception",
+    };
+    unsafe {
+        let current = &TASKS[CURRENT_TASK];
+        crate::log_error!("sched", "Task {} '{}' killed: {} at {:#x} (PC {:#x}, ESR {:#x})",
+            current.id, current.get_name(), reason, far, elr, esr);
+    }
+    exit_current_task();
+}
+
+/// Move the current task's program break by `increment` bytes.
+///
+/// Only the break is recorded; pages are mapped when first touched (see
+/// `heap_fault`), and pages wholly above a lowered break are freed.
+/// Returns the old break, or `None` for a kernel task or a break outside
+/// the task's heap window.
+pub fn sbrk(increment: isize) -> Option<usize> {
+    let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+    let result = unsafe {
+        let task = &mut TASKS[CURRENT_TASK];
+        let old = task.brk;
+        match old.checked_add_signed(increment) {
+            Some(new) if task.heap_start != 0
+                && new >= task.heap_start
+                && new - task.heap_start <= user::BRK_WINDOW =>
+            {
+                if new < old {
+                    task.heap_pages -= user::release(new, old, task.heap_pages, PageTag::UserHeap);
+                }
+                task.brk = new;
+                Some(old)
+            }
+            _ => None,
+        }
+    };
+    aprk_arch_arm64::cpu::restore_interrupts(daif);
+    result
+}
+
+/// A translation fault hit user address `addr`: if it lies below the
+/// current task's break, map a zeroed page there so the access can be
+/// retried.
+///
+/// Returns `false` if `addr` isn't in the heap or memory ran out.
+pub fn heap_fault(addr: usize) -> bool {
+    unsafe {
+        let task = &mut TASKS[CURRENT_TASK];
+        if addr < task.heap_start || addr >= task.brk {
+            return false;
+        }
+        if !user::fault_in(addr) {
+            crate::log_error!("sched", "Out of memory paging in heap of task {} '{}' at {:#x}",
+                task.id, task.get_name(), addr);
+            return false;
+        }
+        task.heap_pages += 1;
+        true
+    }
+}
+
+/// Give the current task its own user address space and switch to it.
+pub fn set_current_space(space: Arc<AddressSpace>) {
+    let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+    unsafe {
+        space.activate();
+        TASKS[CURRENT_TASK].space = Some(space);
+    }
+    aprk_arch_arm64::cpu::restore_interrupts(daif);
+}
+
 /// Get the current task ID
 pub fn current_task_id() -> usize {
     unsafe { TASKS[CURRENT_TASK].id }
 }
 
+/// Task the current task's kernel heap allocations are charged to, if
+/// any (see `mm::quota`).
+pub fn charge_target() -> Option<usize> {
+    unsafe { TASKS[CURRENT_TASK].charge }
+}
+
+/// Charge the current task's kernel heap allocations to `target` from now
+/// on, returning the previous target. Use `mm::quota::with_task_charge`.
+pub fn swap_charge(target: Option<usize>) -> Option<usize> {
+    unsafe { core::mem::replace(&mut TASKS[CURRENT_TASK].charge, target) }
+}
+
+/// Kill a task by ID.
+///
+/// Returns `false` if no live task with that ID exists. Killing the
+/// current task does not return. Files the task had open are flushed and
+/// closed, here or by whoever waits for it if it can't die straight away.
+pub fn kill_task(pid: usize) -> bool {
+    if pid == current_task_id() {
+        fd::close_all();
+        exit_current_task();
+    }
+    let found = send_signal(pid, Signal::Kill);
+    fd::close_dead();
+    found
+}
+
+/// Send a signal to a task.
+///
+/// A running task can't be torn down from interrupt context, so the
+/// signal is left pending and delivered on its next timer tick, as it is
+/// for a task holding a `KMutex`. Other tasks are terminated immediately. Safe to call from IRQ context.
+pub fn send_signal(pid: usize, signal: Signal) -> bool {
+    unsafe {
+        for i in 1..TASK_COUNT {
+            if TASKS[i].id == pid && TASKS[i].state != TaskState::Dead {
+                if i == CURRENT_TASK || TASKS[i].locks_held > 0 {
+                    TASKS[i].pending_signal = Some(signal);
+                } else {
+                    crate::log_info!("sched", "Task {} '{}' terminated by {:?}.", pid, TASKS[i].get_name(), signal);
+                    mark_dead(i);
+                }
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Run `f` on the current task's file descriptor table.
+pub fn with_current_fds<R>(f: impl FnOnce(&mut FdTable) -> R) -> R {
+    unsafe { f(&mut TASKS[CURRENT_TASK].fds) }
+}
+
+/// Check whether a task with this ID exists and hasn't exited.
+pub fn is_alive(pid: usize) -> bool {
+    unsafe {
+        (0..TASK_COUNT).any(|i| TASKS[i].id == pid && TASKS[i].state != TaskState::Dead)
+    }
+}
+
+/// Block the current task until the task `pid` has exited.
+pub fn wait_for_exit(pid: usize) {
+    loop {
+        let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+        if !is_alive(pid) {
+            aprk_arch_arm64::cpu::restore_interrupts(daif);
+            // Close what it left open if it was killed
+            fd::close_dead();
+            return;
+        }
+        wait::TASK_EXIT.sleep();
+        aprk_arch_arm64::cpu::restore_interrupts(daif);
+    }
+}
+
+/// Mark a task slot dead and wake anyone waiting for it to exit.
+unsafe fn mark_dead(slot: usize) {
+    TASKS[slot].state = TaskState::Dead;
+    let task = &mut TASKS[slot];
+    if task.heap_pages != 0 || !task.image.is_empty() {
+        // The heap and program are mapped in the task's own address space
+        if let Some(space) = &task.space {
+            space.activate();
+        }
+        if task.heap_pages != 0 {
+            let freed = user::release(task.heap_start, task.brk, task.heap_pages, PageTag::UserHeap);
+            task.heap_pages -= freed;
+            crate::log_info!("sched", "Task {} '{}' freed {} heap pages.", task.id, task.get_name(), freed);
+        }
+        let image = core::mem::replace(&mut task.image, 0..0);
+        user::release(image.start, image.end, usize::MAX, PageTag::Anonymous);
+        if let Some(space) = &TASKS[CURRENT_TASK].space {
+            space.activate();
+        }
+    }
+    // Its stacks and address space are freed by `reap_dead` once nothing
+    // runs on them
+    crate::console::task_exited(TASKS[slot].id);
+    crate::mm::quota::release(TASKS[slot].id);
+    wait::TASK_EXIT.wake_all();
+}
+
+/// Free the stacks and address spaces of dead tasks other than the current
+/// one, which may still be running on its kernel stack.
+///
+/// # Safety
+/// Must be called with IRQs masked.
+unsafe fn reap_dead() {
+    for i in 0..TASK_COUNT {
+        if i == CURRENT_TASK || TASKS[i].state != TaskState::Dead {
+            continue;
+        }
+        if let Some(kstack) = TASKS[i].kstack.take() {
+            release_stack(kstack);
+        }
+        if let Some(ustack) = TASKS[i].ustack.take() {
+            // Mapped in the task's own address space
+            if let Some(space) = &TASKS[i].space {
+                space.activate();
+            }
+            guard::unregister(ustack.guard());
+            user::free_stack(ustack);
+            if let Some(space) = &TASKS[CURRENT_TASK].space {
+                space.activate();
+            }
+        }
+        TASKS[i].space = None;
+    }
+}
+
+/// Unregister a kernel stack's guard and free it.
+fn release_stack(kstack: KernelStack) {
+    guard::unregister(kstack.guard().0);
+    drop(kstack);
+}
+
+/// Set the CPU time limit for a task in milliseconds (0 removes the limit).
+///
+/// Once the task's accumulated run time exceeds the limit, the scheduler
+/// sends it `Signal::Kill`, or for a `hard` limit terminates it outright.
+pub fn set_cpu_limit(pid: usize, ms: u64, hard: bool) -> bool {
+    let limit = if ms == 0 {
+        None
+    } else {
+        Some(ms.div_ceil(TICK_MS))
+    };
+
+    unsafe {
+        for i in 1..TASK_COUNT {
+            if TASKS[i].id == pid && TASKS[i].state != TaskState::Dead {
+                TASKS[i].cpu_limit_ticks = limit;
+                TASKS[i].cpu_limit_hard = hard && limit.is_some();
+                return true;
+            }
+        }
+        false
+    }
+}
+
 /// Print all active tasks
 pub fn print_tasks() {
     unsafe {
-        crate::println!("PID  STATE     PRIORITY  NAME");
-        crate::println!("---  -----     --------  ----");
+        crate::println!("PID  STATE     PRIORITY  TIME(ms)  LIMIT(ms)  STACK               USED(KB)  NAME");
+        crate::println!("---  -----     --------  --------  ---------  -----               --------  ----");
         for i in 0..TASK_COUNT {
             let task = &TASKS[i];
-            crate::println!("{: <3}  {: <9?} {: <9?} {}", 
-                task.id, task.state, task.priority, task.get_name());
+            let time_ms = task.ticks_run * TICK_MS;
+            let limit = match task.cpu_limit_ticks {
+                Some(limit) if task.cpu_limit_hard => alloc::format!("{} hard", limit * TICK_MS),
+                Some(limit) => alloc::format!("{}", limit * TICK_MS),
+                None => alloc::string::String::from("-"),
+            };
+            // A dead task's stack may be reaped by a reschedule, so look
+            // at it with IRQs masked
+            let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+            let (stack, used) = match &task.kstack {
+                Some(stack) => (alloc::format!("{:#018x}", stack.base()),
+                    alloc::format!("{}/{}", stack.peak_usage() / 1024, stack.size() / 1024)),
+                None if i == 0 => ("boot".into(), "-".into()),
+                None => ("-".into(), "-".into()),
+            };
+            aprk_arch_arm64::cpu::restore_interrupts(daif);
+            crate::println!("{: <3}  {: <9?} {: <9?} {: <9} {: <10} {: <18}  {: <9} {}",
+                task.id, task.state, task.priority, time_ms, limit, stack, used, task.get_name());
+        }
+    }
+}
+
+/// Print the sbrk heap of every live user task
+pub fn print_heaps() {
+    unsafe {
+        crate::println!("PID  BREAK               SIZE(KB)  RESIDENT(KB)  NAME");
+        for task in TASKS[..TASK_COUNT].iter() {
+            if task.heap_start == 0 || task.state == TaskState::Dead {
+                continue;
+            }
+            crate::println!("{: <3}  {:#018x}  {: <8}  {: <12}  {}", task.id, task.brk,
+                (task.brk - task.heap_start) / 1024, task.heap_pages * 4, task.get_name());
+        }
+    }
+}
+
+/// Print the kernel heap charged to every live task
+pub fn print_memory() {
+    unsafe {
+        crate::println!("PID  KHEAP(KB)  PEAK(KB)  QUOTA(KB)  REFUSED  NAME");
+        for task in TASKS[..TASK_COUNT].iter() {
+            if task.state == TaskState::Dead {
+                continue;
+            }
+            let usage = crate::mm::quota::usage(task.id);
+            crate::println!("{: <3}  {: <9}  {: <8}  {: <9}  {: <7}  {}", task.id, usage.charged / 1024,
+                usage.peak / 1024, usage.quota / 1024, usage.refused, task.get_name());
         }
     }
 }
@@ -309,8 +738,19 @@ pub fn task_count() -> usize {
     unsafe { TASK_COUNT }
 }
 
+/// Note that the current task took a `KMutex`, or has something else
+/// in progress it mustn't be torn down in the middle of, such as a disk
+/// request into its memory.
+pub fn lock_taken() {
+    unsafe { TASKS[CURRENT_TASK].locks_held += 1; }
+}
+
+/// Note that the current task is done with what `lock_taken` noted.
+pub fn lock_released() {
+    unsafe { TASKS[CURRENT_TASK].locks_held -= 1; }
+}
+
 /// Block the current task (e.g., waiting for I/O)
-#[allow(dead_code)]
 pub fn block_current_task() {
     unsafe {
         TASKS[CURRENT_TASK].state = TaskState::Blocked;
@@ -319,7 +759,6 @@ pub fn block_current_task() {
 }
 
 /// Wake up a blocked task by ID
-#[allow(dead_code)]
 pub fn wake_task(pid: usize) {
     unsafe {
         for i in 0..TASK_COUNT {
@@ -341,6 +780,41 @@ pub fn tick() {
         
 
         
+        // CPU accounting
+        TASKS[CURRENT_TASK].ticks_run += 1;
+
+        // A task is only torn down once it holds no KMutex
+        let killable = TASKS[CURRENT_TASK].locks_held == 0;
+
+        // CPU watchdog: past its limit a task is sent Kill, delivered
+        // below, or for a hard limit terminated outright
+        if let Some(limit) = TASKS[CURRENT_TASK].cpu_limit_ticks {
+            let task = &TASKS[CURRENT_TASK];
+            if task.ticks_run >= limit {
+                if task.cpu_limit_hard && killable {
+                    crate::log_warn!("sched", "Task {} '{}' exceeded hard CPU limit ({} ms), terminating.",
+                        task.id, task.get_name(), limit * TICK_MS);
+                    mark_dead(CURRENT_TASK);
+                    schedule();
+                    return;
+                }
+                if !task.cpu_limit_hard && task.pending_signal != Some(Signal::Kill) {
+                    crate::log_warn!("sched", "Task {} '{}' exceeded CPU limit ({} ms), sending Kill.",
+                        task.id, task.get_name(), limit * TICK_MS);
+                    send_signal(task.id, Signal::Kill);
+                }
+            }
+        }
+
+        // Deliver signals sent while the task was running
+        if let Some(signal) = TASKS[CURRENT_TASK].pending_signal.take_if(|_| killable) {
+            crate::log_info!("sched", "Task {} '{}' terminated by {:?}.",
+                TASKS[CURRENT_TASK].id, TASKS[CURRENT_TASK].get_name(), signal);
+            mark_dead(CURRENT_TASK);
+            schedule();
+            return;
+        }
+        
         // Decrement time slice for current task
         if TASKS[CURRENT_TASK].remaining_slices > 0 {
             TASKS[CURRENT_TASK].remaining_slices -= 1;
@@ -354,8 +828,23 @@ pub fn tick() {
 }
 
 /// Priority-aware round-robin scheduler
+///
+/// IRQs are masked while the task table is manipulated. The caller's
+/// interrupt state is restored when this task is switched back in, since
+/// the task that switched to us may have been running with IRQs masked.
 pub fn schedule() {
-    unsafe {
+    let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+    unsafe { switch_to_next(); }
+    aprk_arch_arm64::cpu::restore_interrupts(daif);
+}
+
+/// Pick the next runnable task and switch to it.
+///
+/// # Safety
+/// Must be called with IRQs masked.
+unsafe fn switch_to_next() {
+    reap_dead();
+    {
         let count = TASK_COUNT;
         if count <= 1 || !SCHEDULER_ENABLED { return; }
         
@@ -405,7 +894,7 @@ pub fn schedule() {
                     aprk_arch_arm64::context::context_switch(prev_sp, next_sp);
                 }
                 // If idle isn't ready either, halt
-                crate::println!("[sched] FATAL: No runnable tasks!");
+                crate::log_error!("sched", "No runnable tasks!");
                 loop { aprk_arch_arm64::cpu::halt(); }
             }
             return;
@@ -426,6 +915,9 @@ pub fn schedule() {
         TASKS[best_idx].state = TaskState::Running;
         TASKS[best_idx].reset_time_slice();
         CURRENT_TASK = best_idx;
+        if let Some(space) = &TASKS[best_idx].space {
+            space.activate();
+        }
         
         // Perform Context Switch
         let prev_sp = &mut TASKS[current_idx].stack_top as *mut u64;
@@ -0,0 +1,105 @@
+// =============================================================================
+// APRK OS - Blocking Mutex
+// =============================================================================
+// A lock for data held across slow operations such as disk I/O. A task
+// that finds it taken sleeps on the lock's wait queue instead of spinning
+// out its time slice, and is woken (and run next) when it's released.
+//
+// A task holding one isn't torn down: signals and the CPU watchdog wait
+// until it has released all of them, so a killed task can't leave the
+// lock taken forever. It may sleep, so it must never be taken from IRQ
+// context; before the scheduler runs it just spins.
+// =============================================================================
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use super::wait::WaitQueue;
+
+/// `owner` of a lock no task holds
+const NO_OWNER: usize = usize::MAX;
+
+/// A mutual exclusion lock whose waiters sleep.
+pub struct KMutex<T> {
+    locked: AtomicBool,
+    /// Task holding the lock, to catch a task taking it twice
+    owner: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: The data is only reached through a guard, one at a time
+unsafe impl<T: Send> Sync for KMutex<T> {}
+unsafe impl<T: Send> Send for KMutex<T> {}
+
+impl<T> KMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: AtomicUsize::new(NO_OWNER),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Take the data out of a lock no longer shared.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// Take the lock, sleeping until it's free.
+    pub fn lock(&self) -> KMutexGuard<'_, T> {
+        let pid = super::current_task_id();
+        loop {
+            // Masked so the release can't slip in between the check and
+            // going to sleep
+            let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+            if !self.locked.swap(true, Ordering::Acquire) {
+                self.owner.store(pid, Ordering::Relaxed);
+                super::lock_taken();
+                aprk_arch_arm64::cpu::restore_interrupts(daif);
+                return KMutexGuard { mutex: self };
+            }
+            if self.owner.load(Ordering::Relaxed) == pid {
+                panic!("KMutex: task {} took a lock it already holds", pid);
+            }
+            if super::is_enabled() {
+                self.waiters.sleep();
+            }
+            aprk_arch_arm64::cpu::restore_interrupts(daif);
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Access to the data of a locked `KMutex`. Releases it when dropped.
+pub struct KMutexGuard<'a, T> {
+    mutex: &'a KMutex<T>,
+}
+
+impl<T> Deref for KMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for KMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for KMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.mutex.locked.store(false, Ordering::Release);
+        super::lock_released();
+        // Let a waiter in now, or a task taking the lock in a loop would
+        // get it straight back every time
+        if self.mutex.waiters.wake_all() {
+            super::schedule();
+        }
+    }
+}
@@ -0,0 +1,106 @@
+// =============================================================================
+// APRK OS - Wait Queues
+// =============================================================================
+// Lets tasks sleep until an event (e.g. console input) wakes them.
+// Waiters are kept on a list of nodes from a slab cache, which never
+// touches the heap, so wakeups are safe from IRQ context.
+// =============================================================================
+
+use aprk_arch_arm64::uart::MAX_PORTS;
+use core::ptr::NonNull;
+use crate::mm::slab::Cache;
+use spin::Mutex;
+
+/// A task sleeping on a queue
+struct Waiter {
+    pid: usize,
+    next: Option<NonNull<Waiter>>,
+}
+
+static WAITERS: Cache<Waiter> = Cache::new("wait-queue");
+
+/// The head of a queue's waiter list
+struct Waiters(Option<NonNull<Waiter>>);
+
+// SAFETY: The nodes are only reached through the queue's lock
+unsafe impl Send for Waiters {}
+
+impl Waiters {
+    fn contains(&self, pid: usize) -> bool {
+        let mut node = self.0;
+        while let Some(waiter) = node.map(|n| unsafe { n.as_ref() }) {
+            if waiter.pid == pid {
+                return true;
+            }
+            node = waiter.next;
+        }
+        false
+    }
+}
+
+/// A queue of tasks blocked on the same event.
+pub struct WaitQueue {
+    waiters: Mutex<Waiters>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { waiters: Mutex::new(Waiters(None)) }
+    }
+
+    /// Block the current task until the queue is woken.
+    ///
+    /// Callers must check their wait condition and call this with IRQs
+    /// masked, otherwise a wakeup arriving in between is lost.
+    pub fn sleep(&self) {
+        let pid = super::current_task_id();
+        {
+            let mut waiters = self.waiters.lock();
+            if !waiters.contains(pid) {
+                match WAITERS.alloc(Waiter { pid, next: waiters.0 }) {
+                    Some(node) => waiters.0 = Some(node),
+                    None => return, // Out of memory: caller will simply poll again
+                }
+            }
+        }
+        super::block_current_task();
+    }
+
+    /// Wake every task sleeping on this queue. Returns whether any was.
+    /// Safe from IRQ context.
+    pub fn wake_all(&self) -> bool {
+        // Mask IRQs so an interrupt handler waking the same queue can't
+        // spin on the lock we hold.
+        let daif = aprk_arch_arm64::cpu::save_and_disable_interrupts();
+        let woken = {
+            let mut node = self.waiters.lock().0.take();
+            let woken = node.is_some();
+            while let Some(waiter) = node {
+                let (pid, next) = unsafe { (waiter.as_ref().pid, waiter.as_ref().next) };
+                super::wake_task(pid);
+                unsafe { WAITERS.free(waiter); }
+                node = next;
+            }
+            woken
+        };
+        aprk_arch_arm64::cpu::restore_interrupts(daif);
+        woken
+    }
+}
+
+/// Tasks waiting for input on each UART port (see `uart::wait_for_input`)
+pub static TTY_INPUT: [WaitQueue; MAX_PORTS] = [const { WaitQueue::new() }; MAX_PORTS];
+
+/// Tasks waiting for another task to exit (see `sched::wait_for_exit`)
+pub static TASK_EXIT: WaitQueue = WaitQueue::new();
+
+/// Block the current task until input arrives on a UART port.
+///
+/// Falls back to waiting for the next interrupt before the scheduler runs.
+pub fn wait_for_tty_input(port: usize) {
+    if super::is_enabled() {
+        TTY_INPUT[port].sleep();
+    } else {
+        unsafe { core::arch::asm!("wfi"); }
+    }
+}
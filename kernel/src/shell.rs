@@ -1,12 +1,25 @@
 // =============================================================================
 // APRK OS - Interactive Shell (Premium)
 // =============================================================================
+// Before the first prompt, the commands in `/etc/rc` (if there is one) are
+// run as if typed, so the system can be set up at boot without rebuilding
+// the kernel.
+// =============================================================================
 
-use aprk_arch_arm64::{print, println, uart};
+use aprk_arch_arm64::{log, print, println, uart};
+use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use crate::console;
 use crate::sched;
 
+/// Script run at boot
+const RC_PATH: &str = "/etc/rc";
+/// Most scripts running inside each other (`source` in a script)
+const MAX_SCRIPT_DEPTH: usize = 8;
+
+static SCRIPT_DEPTH: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
 fn print_fetch() {
     let task_count = sched::task_count();
     let current_el = aprk_arch_arm64::cpu::current_el();
@@ -29,50 +42,134 @@ pub extern "C" fn shell_task() {
     println!("Welcome! Type 'help' for available commands.");
     println!();
 
-    let mut buffer = String::new();
-    let mut history: Vec<String> = Vec::new();
+    // A disk without one is fine
+    if crate::fs::stat(RC_PATH).is_ok() {
+        run_script(RC_PATH);
+    }
 
-    // Initial prompt
-    print_prompt();
+    let mut history: Vec<String> = Vec::new();
+    let mut rx_dropped = uart::rx_dropped();
 
     loop {
-        if let Some(c) = uart::get_char() {
-            match c {
-                b'\n' | b'\r' => {
-                    println!();
-                    let cmd_line = buffer.trim().to_string();
-                    if !cmd_line.is_empty() {
-                         if history.len() >= 10 { history.remove(0); }
-                         history.push(cmd_line.clone());
-                         execute_command(&cmd_line);
-                    }
-                    buffer.clear();
-                    print_prompt();
-                }
-                b'\x08' | 127 => { // Backspace
-                    if !buffer.is_empty() {
-                         buffer.pop();
-                         print!("\x08 \x08");
-                    }
-                }
-                _ => {
-                    buffer.push(c as char);
-                    print!("{}", c as char);
-                }
-            }
-        } else {
-             sched::schedule();
-             core::hint::spin_loop();
+        print_prompt();
+        let line = console::read_line();
+
+        // Report input lost to RX ring buffer overflow
+        let dropped = uart::rx_dropped();
+        if dropped != rx_dropped {
+            println!("[shell] Warning: {} input bytes dropped (RX buffer full)", dropped - rx_dropped);
+            rx_dropped = dropped;
+        }
+
+        let cmd_line = line.trim();
+        if !cmd_line.is_empty() {
+            if history.len() >= 10 { history.remove(0); }
+            history.push(cmd_line.to_string());
+            execute_command(cmd_line);
         }
     }
 }
 
+/// Run each line of the script at `path` as a command, printing it first.
+/// Blank lines and lines starting with `#` are skipped. A failing command
+/// doesn't stop the rest.
+fn run_script(path: &str) {
+    use core::sync::atomic::Ordering;
+    if SCRIPT_DEPTH.load(Ordering::Relaxed) == MAX_SCRIPT_DEPTH {
+        println!("[shell] Error: {}: scripts nested too deep", path);
+        return;
+    }
+    let script = match crate::fs::read_file(path) {
+        Ok(script) => script,
+        Err(e) => {
+            println!("[shell] Error: {}: {}", path, e);
+            return;
+        }
+    };
+    for line in String::from_utf8_lossy(&script).lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        println!("[{}] {}", path, line);
+        SCRIPT_DEPTH.fetch_add(1, Ordering::Relaxed);
+        execute_command(line);
+        SCRIPT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 fn print_prompt() {
     print!("\x1b[1;32mroot@aprk\x1b[0m:\x1b[1;34m/\x1b[0m$ ");
 }
 
+/// Split a command line into arguments at whitespace. Single or double
+/// quotes keep spaces in an argument (`cat "my file.txt"`), and can be
+/// mixed with unquoted text in one argument.
+fn tokenize(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => arg.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(core::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err("Unterminated quote");
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+/// An output redirection: the file, and whether to append to it.
+type Redirect<'a> = (&'a str, bool);
+
+/// Take an output redirection (`> file` to replace it, `>> file` to append
+/// to it; the file name may follow without a space) off the end of a
+/// command's arguments. Returns `None` for a redirection without a file or
+/// anything after it.
+fn split_redirect<'a>(parts: &[&'a str]) -> Option<(Vec<&'a str>, Option<Redirect<'a>>)> {
+    let Some(at) = parts.iter().position(|p| p.starts_with('>')) else {
+        return Some((parts.to_vec(), None));
+    };
+    let (append, attached) = match parts[at].strip_prefix(">>") {
+        Some(rest) => (true, rest),
+        None => (false, &parts[at][1..]),
+    };
+    let (path, used) = if attached.is_empty() { (*parts.get(at + 1)?, 2) } else { (attached, 1) };
+    if at + used != parts.len() {
+        return None;
+    }
+    Some((parts[..at].to_vec(), Some((path, append))))
+}
+
 fn execute_command(cmd_line: &str) {
-    let parts: Vec<&str> = cmd_line.split_whitespace().collect();
+    let args = match tokenize(cmd_line) {
+        Ok(args) => args,
+        Err(e) => {
+            println!("[shell] Error: {}", e);
+            return;
+        }
+    };
+    let parts: Vec<&str> = args.iter().map(String::as_str).collect();
     if parts.is_empty() { return; }
     
     match parts[0] {
@@ -81,11 +178,65 @@ fn execute_command(cmd_line: &str) {
             println!("  help      - Show this help message");
             println!("  fetch     - Show Arch-inspired system info");
             println!("  version   - Show OS version info");
-            println!("  ls        - List files on disk");
-            println!("  cat <f>   - Print file content");
-            println!("  exec <f>  - Execute an ELF binary");
-            println!("  ps        - List running tasks");
+            println!("  ls [-lh] [dir] - List files on disk (-l: sizes and dates, -h: K/M sizes)");
+            println!("  cat [-v] <f> - Print file content (-v shows binary bytes escaped, a page at a time)");
+            println!("  hexdump <f> [offset] [len] - Dump file bytes in hex, a page at a time");
+            println!("  sha256sum <f> - Print the SHA-256 digest of a file");
+            println!("  write <f> <text> - Replace a file's content with text (creates it)");
+            println!("  touch <f> - Create an empty file");
+            println!("  mkdir <d> - Create a directory");
+            println!("  rm <path> - Delete a file or empty directory");
+            println!("  mv <old> <new> - Rename a file or directory");
+            println!("  cp <src> <dst> - Copy a file");
+            println!("  fstest    - Check nested directories, file names, concurrent reads and hashing on the disk");
+            println!("  sync      - Flush written data to the disk");
+            println!("  source <f> - Run the commands in a file (/etc/rc runs at boot)");
+            println!("  mount [<dev> <dir>] - List mounted filesystems, or mount block device dev (blk1, blk1p1, ram0) on dir");
+            println!("  umount <dir> - Unmount the filesystem mounted on dir");
+            println!("  lsblk [-s] - List the block devices: disks, their partitions and RAM disks (-s: error counters)");
+            println!("  lsvirtio - List the devices found on the virtio-mmio bus, with their interrupt counts");
+            println!("  ramdisk <kb> - Add a zeroed RAM disk of kb KB (ram0, ram1, ...)");
+            println!("  ifconfig [<ip>/<prefix> [gateway]] - Show the network interface, or give it a static address");
+            println!("  udpecho [port] - Send UDP datagrams on port (default 7) back to their sender, any key stops");
+            println!("  df [-h]   - Show size and free space of mounted filesystems");
+            println!("  fsck [path] - Check the filesystem for lost and cross-linked clusters (read-only)");
+            println!("  fscache [size <n> | readahead <n> | bench <f>] - Show block cache stats, resize it, set blocks read ahead (0: off), or time reading f twice");
+            println!("  exec <f> [ms [hard]] [> out | >> out] - Run an ELF binary in the foreground (optional CPU limit, Ctrl-C stops)");
+            println!("  ps [-m]   - List running tasks (-m: kernel heap charged to each)");
+            println!("  dmesg [-f] - Show the kernel log (-f: follow, any key stops)");
+            println!("  loglevel [tag] <level> - Set log level (error/warn/info/debug, 'reset' clears a tag)");
+            println!("  kill <pid> - Terminate a task");
+            println!("  limit <pid> <ms> [hard] - Set a task's CPU time limit (0 = none; hard: terminate, don't signal)");
+            println!("  quota [pid|default] [kb] - Show or set kernel heap quotas");
+            println!("  serial [baud] [8N1] - Show or change serial line settings");
+            println!("  serial flow <on|off> - Toggle XON/XOFF flow control");
+            println!("  console <n> - Send kernel console output to ttyS<n>");
+            println!("  console virtio [on|off] - Send kernel console output to the virtio console too, or stop");
+            println!("  irqdump   - Show interrupt controller state");
+            println!("  vmmap     - Show the kernel address space layout");
+            println!("  ptdump    - Dump the active page tables with decoded attributes");
+            println!("  ptwrite   - Try to write the kernel's root page table (should fault)");
+            println!("  meminfo   - Show physical memory, kernel heap and user heaps (-v: by page tag)");
+            println!("  fbbench   - Time filling the screen through each framebuffer mapping");
+            println!("  mousetest - Print where the pointer is clicked, any key stops");
+            println!("  gputext <msg> - Draw msg at the top left of the screen (\\n starts a new line)");
+            println!("  gputest - Check the drawing routines against reference output");
+            println!("  gpudemo - Draw a test pattern of lines, circles, triangles and frames");
+            println!("  view <file.bmp> - Show a 24 or 32-bit BMP image centered on the screen");
+            println!("  diskbench [kb] [dev] [raw] - Time sequential reads and writes and random 4 KB reads over kb KB (default 1024) of dev (blk0); raw writes to the device, not a file");
+            println!("  blkfault <n|off> - Make every nth block device request fail, to test error handling");
+            println!("  blkbench [mb] - Read mb MB (default 16) from blk0 in the background, timing how long the shell waits");
+            println!("  ctxbench [n] - Time task switches between address spaces (ASIDs vs TLB flush)");
+            println!("  pmmstress [n] - Allocate and free pages from several tasks at once, n rounds each");
+            println!("  pmmbench [n] - Time n mixed-order page block alloc/free cycles");
+            println!("  slabbench [n] - Time n small-object alloc/free cycles, slab cache vs heap");
+            println!("  leaks [secs] - List heap allocations older than secs (default 10) by call site");
+            println!("  heapoob   - Write one byte past a Vec's buffer (redzone test, debug-alloc only)");
+            println!("  overflow  - Spawn a task that overflows its stack (guard page test)");
+            println!("  irqstat [-l | storm <n>] - Show interrupt counts (-l: latency; storm: set masking threshold)");
             println!("  clear     - Clear the screen");
+            println!("  poweroff  - Power off the machine");
+            println!("  reboot    - Reboot the machine");
         },
         "fetch" => {
             print_fetch();
@@ -94,53 +245,1145 @@ fn execute_command(cmd_line: &str) {
             println!("APRK OS v1.0 (FAT32 Enabled)");
         },
         "ls" => {
-            crate::fs::list_root();
+            let (flags, paths): (Vec<&str>, Vec<&str>) = parts[1..].iter().partition(|p| p.starts_with('-'));
+            let long = flags.iter().any(|f| f.contains('l'));
+            let human = flags.iter().any(|f| f.contains('h'));
+            let path = paths.first().copied().unwrap_or("/");
+            match crate::fs::list_dir(path) {
+                Ok(entries) => list(&entries, long, human),
+                Err(e) => println!("[shell] Error: {}: {}", path, e),
+            }
         },
         "ps" => {
-            sched::print_tasks();
+            if parts.get(1) == Some(&"-m") {
+                sched::print_memory();
+            } else {
+                sched::print_tasks();
+            }
+        },
+        "dmesg" => {
+            dmesg(parts.get(1) == Some(&"-f"));
+        },
+        "loglevel" => {
+            match (parts.get(1), parts.get(2)) {
+                (None, _) => {
+                    println!("global: {}", log::level().name());
+                    log::for_each_override(|tag, level| println!("{}: {}", tag, level.name()));
+                }
+                (Some(level), None) => match log::Level::parse(level) {
+                    Some(level) => log::set_level(level),
+                    None => println!("[shell] Error: Unknown level: {}", level),
+                },
+                (Some(tag), Some(&"reset")) => {
+                    log::set_tag_level(tag, None);
+                }
+                (Some(tag), Some(level)) => match log::Level::parse(level) {
+                    Some(level) => {
+                        if !log::set_tag_level(tag, Some(level)) {
+                            println!("[shell] Error: Too many log overrides");
+                        }
+                    }
+                    None => println!("[shell] Error: Unknown level: {}", level),
+                },
+            }
+        },
+        "cat" => match (parts.get(1), parts.get(2)) {
+            (Some(&"-v"), Some(path)) => cat_escaped(path),
+            (Some(path), None) if *path != "-v" => cat(path),
+            _ => println!("Usage: cat [-v] <filename>"),
+        },
+        "hexdump" => {
+            let number = |s: &&str| match s.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => s.parse::<u64>().ok(),
+            };
+            let offset = parts.get(2).map(number);
+            let len = parts.get(3).map(number);
+            match (parts.get(1), offset.unwrap_or(Some(0)), len.unwrap_or(Some(u64::MAX))) {
+                (Some(path), Some(offset), Some(len)) if parts.len() <= 4 => hexdump(path, offset, len),
+                _ => println!("Usage: hexdump <file> [offset] [len]"),
+            }
+        },
+        "write" => {
+            if parts.len() < 3 {
+                println!("Usage: write <filename> <text>");
+            } else {
+                let text = parts[2..].join(" ");
+                if let Err(e) = crate::fs::write_file(parts[1], text.as_bytes()) {
+                    println!("[shell] Error: {}: {}", parts[1], e);
+                }
+            }
+        },
+        "touch" => match parts.get(1) {
+            // Touching an existing file leaves it alone
+            Some(path) => match crate::fs::create_file(path) {
+                Ok(()) | Err(crate::fs::FsError::AlreadyExists) => {}
+                Err(e) => println!("[shell] Error: {}: {}", path, e),
+            },
+            None => println!("Usage: touch <filename>"),
+        },
+        "mkdir" => match parts.get(1) {
+            Some(path) => {
+                if let Err(e) = crate::fs::mkdir(path) {
+                    println!("[shell] Error: {}: {}", path, e);
+                }
+            }
+            None => println!("Usage: mkdir <dirname>"),
+        },
+        "rm" => match parts.get(1) {
+            Some(path) => {
+                if let Err(e) = crate::fs::remove(path) {
+                    println!("[shell] Error: {}: {}", path, e);
+                }
+            }
+            None => println!("Usage: rm <path>"),
+        },
+        "mv" => match (parts.get(1), parts.get(2)) {
+            (Some(old), Some(new)) => {
+                if let Err(e) = crate::fs::rename(old, new) {
+                    println!("[shell] Error: {}: {}", old, e);
+                }
+            }
+            _ => println!("Usage: mv <old> <new>"),
+        },
+        "cp" => match (parts.get(1), parts.get(2)) {
+            (Some(src), Some(dst)) => {
+                // Progress for big files, every 64 KB
+                let mut shown = 0;
+                let result = crate::fs::copy_with_progress(src, dst, |copied| {
+                    if copied / 65536 > shown {
+                        shown = copied / 65536;
+                        print!("\r[cp] {} KB copied", copied / 1024);
+                    }
+                });
+                if shown > 0 {
+                    println!();
+                }
+                match result {
+                    Ok(bytes) if shown > 0 => println!("[cp] {} bytes copied", bytes),
+                    Ok(_) => {}
+                    Err(e) => println!("[shell] Error: {}: {}", src, e),
+                }
+            }
+            _ => println!("Usage: cp <src> <dst>"),
+        },
+        "mount" => match parts.len() {
+            1 => {
+                for mount in crate::fs::vfs::mounts() {
+                    println!("{} on {} type {}", mount.source, mount.point, mount.fs);
+                }
+            }
+            3 => {
+                if let Err(e) = crate::fs::mount(parts[1], parts[2]) {
+                    println!("[shell] Error: mount {}: {}", parts[1], e);
+                }
+            }
+            _ => println!("Usage: mount [<dev> <dir>]"),
+        },
+        "umount" => match parts.get(1) {
+            Some(point) => {
+                if let Err(e) = crate::fs::umount(point) {
+                    println!("[shell] Error: umount {}: {}", point, e);
+                }
+            }
+            None => println!("Usage: umount <dir>"),
+        },
+        "df" => {
+            let human = parts.get(1) == Some(&"-h");
+            let size = |bytes: u64| if human { human_size(bytes) } else { format!("{}", bytes) };
+            println!("{:<10} {:<11} {:>12} {:>12} {:>12} {:>4}  Mounted on", "Type", "Label", "Size", "Used", "Free", "Use%");
+            for mount in crate::fs::vfs::mounts() {
+                match crate::fs::statfs(&mount.point) {
+                    Ok(stats) => {
+                        let percent = stats.used_bytes() * 100 / stats.total_bytes().max(1);
+                        println!("{:<10} {:<11} {:>12} {:>12} {:>12} {:>3}%  {}", mount.fs, stats.label,
+                            size(stats.total_bytes()), size(stats.used_bytes()), size(stats.free_bytes()), percent, mount.point);
+                    }
+                    Err(e) => println!("[shell] Error: {}: {}", mount.point, e),
+                }
+            }
+        },
+        "fsck" => {
+            let point = parts.get(1).copied().unwrap_or("/");
+            println!("[fsck] Checking {}...", point);
+            use aprk_arch_arm64::timer::Timer;
+            let start = Timer::uptime();
+            match crate::fs::check(point) {
+                Ok(report) => {
+                    let mut pager = Pager::new();
+                    for problem in &report.problems {
+                        if !pager.line(format_args!("  {}", problem)) {
+                            break;
+                        }
+                    }
+                    println!("[fsck] {} directories, {} files, {}/{} clusters used ({} ms)", report.dirs, report.files,
+                        report.used_clusters, report.clusters, (Timer::uptime() - start).as_millis());
+                    for (kind, count) in crate::fs::fsck::PROBLEM_KINDS.iter().zip(report.counts()) {
+                        println!("  {:<22} {}", kind, count);
+                    }
+                    match report.problems.len() {
+                        0 => println!("[fsck] Filesystem is clean"),
+                        n => println!("[fsck] {} problem(s) found, nothing changed", n),
+                    }
+                }
+                Err(e) => println!("[shell] Error: {}: {}", point, e),
+            }
+        },
+        "lsblk" => {
+            use crate::block::BlockDevice;
+            let devices = crate::block::devices();
+            if devices.is_empty() {
+                println!("[shell] Error: No block device");
+            } else if parts.get(1) == Some(&"-s") {
+                println!("NAME     RETRIES  FAILURES");
+                for dev in devices {
+                    let stats = dev.stats();
+                    println!("{:<7} {:>8} {:>9}", dev.name, stats.retries, stats.failures);
+                }
+            } else {
+                let mounts = crate::fs::vfs::mounts();
+                let mounted = |name: &str| {
+                    mounts.iter().find(|m| m.source == name).map_or(String::new(), |m| format!("  on {}", m.point))
+                };
+                println!("NAME        START     BLOCKS    SIZE  TYPE");
+                for dev in devices {
+                    let kind = match crate::fs::partition::find(&dev.name) {
+                        Some(p) => format!("{} ({:#04x}){}", p.kind_name(), p.kind, if p.bootable { " boot" } else { "" }),
+                        None => String::from("disk"),
+                    };
+                    let start = if dev.is_partition() { dev.start } else { 0 };
+                    println!("{:<7} {:>9} {:>10} {:>7}  {}{}{}", dev.name, start, dev.num_blocks(), human_size(dev.size()),
+                        kind, if dev.readonly() { " ro" } else { "" }, mounted(&dev.name));
+                }
+            }
         },
-        "cat" => {
+        "lsvirtio" => {
+            let devices = crate::drivers::virtio::devices();
+            if devices.is_empty() {
+                println!("[shell] Error: No virtio device");
+            } else {
+                println!("SLOT  BASE        TYPE          VERSION  IRQ  INTERRUPTS      IDLE");
+                for dev in devices {
+                    let (interrupts, idle) = dev.interrupts();
+                    println!("{:>4}  {:#010x}  {:<12} {:>8} {:>4} {:>11} {:>9}", dev.slot, dev.base, format!("{:?}", dev.device_type),
+                        dev.version, dev.irq, interrupts, idle);
+                }
+            }
+        },
+        "ifconfig" => match parts.get(1) {
+            None => print_interface(),
+            Some(arg) => {
+                let (addr, prefix) = arg.split_once('/').unwrap_or((arg, "24"));
+                let addr = crate::net::Ipv4Addr::parse(addr);
+                let prefix = prefix.parse::<u8>().ok().filter(|&p| p <= 32);
+                let gateway = parts.get(2).map(|gw| crate::net::Ipv4Addr::parse(gw));
+                match (addr, prefix, gateway) {
+                    (Some(addr), Some(prefix), None | Some(Some(_))) => {
+                        let config = crate::net::Config { addr, prefix, gateway: gateway.flatten() };
+                        if let Err(e) = crate::net::configure(config) {
+                            println!("[shell] Error: {}", e);
+                        }
+                    }
+                    _ => println!("Usage: ifconfig [<ip>/<prefix> [gateway]]"),
+                }
+            }
+        },
+        "mousetest" => mouse_test(),
+        "gputest" => crate::drivers::gpu::self_test(),
+        "gpudemo" => crate::drivers::gpu::demo(),
+        "view" => match parts.get(1) {
+            Some(path) if parts.len() == 2 => view(path),
+            _ => println!("Usage: view <file.bmp>"),
+        },
+        "gputext" => {
             if parts.len() < 2 {
-                println!("Usage: cat <filename>");
+                println!("Usage: gputext <msg>");
+            } else if crate::drivers::gpu::resolution().is_none() {
+                println!("[shell] Error: No framebuffer");
             } else {
-                let filename = parts[1];
-                if let Some(content) = crate::fs::read_file(filename) {
-                    if let Ok(s) = core::str::from_utf8(&content) {
-                        println!("{}", s);
-                    } else {
-                        println!("[shell] Error: File is binary or invalid UTF-8");
+                let text = parts[1..].join(" ").replace("\\n", "\n");
+                crate::drivers::gpu::draw_text(8, 8, &text, (255, 255, 255), (0, 0, 0));
+            }
+        },
+        "udpecho" => match parts.get(1).map_or(Ok(7), |s| s.parse::<u16>()) {
+            Ok(port) => udp_echo(port),
+            Err(_) => println!("Usage: udpecho [port]"),
+        },
+        "ramdisk" => match parts.get(1).map(|s| s.parse::<u64>()) {
+            Some(Ok(kb)) if kb > 0 => match crate::block::ram::create(kb) {
+                Some(dev) => println!("{}: {} KB", dev.name, kb),
+                None => println!("[shell] Error: ramdisk: Not enough memory for {} KB", kb),
+            },
+            _ => println!("Usage: ramdisk <kb>"),
+        },
+        "source" => match parts.get(1) {
+            Some(path) => run_script(path),
+            None => println!("Usage: source <file>"),
+        },
+        "sync" => {
+            if let Err(e) = crate::fs::sync() {
+                println!("[shell] Error: sync: {}", e);
+            }
+        },
+        "fscache" => match (parts.get(1), parts.get(2)) {
+            (Some(&"size"), Some(n)) => match n.parse::<usize>() {
+                Ok(blocks) => {
+                    if let Err(e) = crate::fs::cache::resize(blocks) {
+                        println!("[shell] Error: fscache: {}", e);
                     }
-                } else {
-                    println!("[shell] Error: File not found");
                 }
+                Err(_) => println!("Usage: fscache size <blocks>"),
+            },
+            (Some(&"readahead"), Some(n)) => match n.parse::<usize>() {
+                Ok(blocks) => crate::fs::readahead::set_window(blocks),
+                Err(_) => println!("Usage: fscache readahead <blocks>"),
+            },
+            (Some(&"bench"), Some(file)) => cache_bench(file),
+            (None, _) => {
+                let stats = crate::fs::cache::stats();
+                let lookups = stats.hits + stats.misses;
+                println!("Block cache: {} of {} blocks ({} KB), {} dirty", stats.cached, stats.capacity,
+                    stats.capacity * crate::fs::cache::BLOCK_SIZE / 1024, stats.dirty);
+                println!("  hits {}  misses {}  hit rate {}%", stats.hits, stats.misses,
+                    (stats.hits * 100).checked_div(lookups).unwrap_or(0));
+                println!("  evictions {}  blocks written {}  read past the cache {}", stats.evictions, stats.writes, stats.direct);
+                println!("  written back {} blocks in {} requests, discarded {}", stats.written_back, stats.write_requests, stats.discarded);
+                println!("  read ahead {} blocks ({} at a time), {} used", stats.prefetched,
+                    crate::fs::readahead::window(), stats.prefetch_hits);
             }
+            _ => println!("Usage: fscache [size <blocks> | readahead <blocks> | bench <file>]"),
+        },
+        "fstest" => {
+            crate::fs::selftest::nested_dirs();
+            crate::fs::selftest::names();
+            crate::fs::selftest::concurrent_reads();
+            crate::crypto::sha256::self_test();
+            crate::fs::selftest::hashing();
+        },
+        "sha256sum" => match parts.get(1) {
+            Some(path) => match crate::fs::hash_file(path) {
+                Ok(digest) => println!("{}  {}", crate::crypto::sha256::to_hex(&digest), path),
+                Err(e) => println!("[shell] Error: {}: {}", path, e),
+            },
+            None => println!("Usage: sha256sum <file>"),
         },
         "exec" => {
+            let Some((parts, redirect)) = split_redirect(&parts) else {
+                println!("Usage: exec <binary_name> [cpu_limit_ms [hard]] [> file | >> file]");
+                return;
+            };
             if parts.len() < 2 {
-                println!("Usage: exec <binary_name>");
+                println!("Usage: exec <binary_name> [cpu_limit_ms [hard]] [> file | >> file]");
             } else {
                 let binary_name = parts[1];
+                let limit_ms = parts.get(2).and_then(|s| s.parse::<u64>().ok());
+                let hard = parts.get(3) == Some(&"hard");
                 println!("[shell] Executing {}...", binary_name);
-                
-                if let Some(elf_data) = crate::fs::read_file(binary_name) {
+
+                // Standard output to a file instead of the terminal
+                let mut fds = crate::fd::stdio(uart::console_port());
+                if let Some((path, append)) = redirect {
+                    match crate::fd::open_redirect(path, append) {
+                        Ok(file) => fds[1] = Some(file),
+                        Err(e) => {
+                            println!("[shell] Error: {}: {}", path, e);
+                            return;
+                        }
+                    }
+                }
+                let stdout = redirect.and(fds[1]);
+
+                let opened = crate::fs::open(binary_name);
+                if let Ok(mut file) = opened {
                     unsafe {
-                        if let Some(entry_point) = crate::loader::load_elf(&elf_data) {
-                            println!("[shell] Starting process at {:#x}", entry_point);
-                            sched::spawn_user(entry_point, binary_name);
+                        if let Some(image) = crate::loader::load_elf(&mut file) {
+                            println!("[shell] Starting process at {:#x}", image.entry);
+                            let pages = image.start..image.end;
+                            if let Some(pid) = sched::spawn_user(image.entry, pages, binary_name, fds) {
+                                if let Some(file) = stdout {
+                                    crate::fd::give(file, pid);
+                                }
+                                if let Some(ms) = limit_ms {
+                                    sched::set_cpu_limit(pid, ms, hard);
+                                    println!("[shell] CPU limit for {} set to {} ms{}", pid, ms, if hard { " (hard)" } else { "" });
+                                }
+
+                                // Run in the foreground until it exits (Ctrl-C interrupts)
+                                console::set_foreground(pid);
+                                sched::wait_for_exit(pid);
+                                console::clear_foreground();
+                                return;
+                            }
+                            crate::loader::unload(&image);
                         } else {
                             println!("[shell] Error: Failed to load ELF");
                         }
                     }
+                } else if let Err(e) = opened {
+                    println!("[shell] Error: {}: {}", binary_name, e);
+                }
+                // Nothing was started to take the output file
+                if let Some(file) = stdout {
+                    crate::fd::release(file);
+                }
+            }
+        },
+        "kill" => {
+            match parts.get(1).and_then(|s| s.parse::<usize>().ok()) {
+                Some(0) => println!("[shell] Error: Cannot kill the idle task"),
+                Some(pid) => {
+                    if !sched::kill_task(pid) {
+                        println!("[shell] Error: No such task: {}", pid);
+                    }
+                }
+                None => println!("Usage: kill <pid>"),
+            }
+        },
+        "quota" => {
+            let kb = parts.get(2).and_then(|s| s.parse::<usize>().ok());
+            match (parts.get(1), kb) {
+                (None, _) => println!("Default kernel heap quota: {} KB", crate::mm::quota::default_quota() / 1024),
+                (Some(&"default"), Some(kb)) => crate::mm::quota::set_default_quota(kb * 1024),
+                (Some(pid), kb) => match pid.parse::<usize>() {
+                    Ok(pid) if !sched::is_alive(pid) => println!("[shell] Error: No such task: {}", pid),
+                    Ok(pid) => match kb {
+                        Some(kb) => {
+                            if !crate::mm::quota::set_quota(pid, kb * 1024) {
+                                println!("[shell] Error: Too many quota accounts");
+                            }
+                        }
+                        None => {
+                            let usage = crate::mm::quota::usage(pid);
+                            println!("Task {}: {} of {} KB charged (peak {} KB, {} refused)", pid,
+                                usage.charged / 1024, usage.quota / 1024, usage.peak / 1024, usage.refused);
+                        }
+                    },
+                    Err(_) => println!("Usage: quota [pid|default] [kb]"),
+                },
+            }
+        },
+        "limit" => {
+            let pid = parts.get(1).and_then(|s| s.parse::<usize>().ok());
+            let ms = parts.get(2).and_then(|s| s.parse::<u64>().ok());
+            let hard = parts.get(3) == Some(&"hard");
+            match (pid, ms) {
+                (Some(pid), Some(ms)) => {
+                    if sched::set_cpu_limit(pid, ms, hard) {
+                        println!("[shell] CPU limit for {} set to {} ms{}", pid, ms, if hard { " (hard)" } else { "" });
+                    } else {
+                        println!("[shell] Error: No such task: {}", pid);
+                    }
+                }
+                _ => println!("Usage: limit <pid> <ms> [hard]"),
+            }
+        },
+        "serial" => {
+            match parts.get(1) {
+                Some(&"flow") => {
+                    let port = uart::console_port();
+                    match parts.get(2) {
+                        Some(&"on") => { uart::set_flow_control(port, true); }
+                        Some(&"off") => { uart::set_flow_control(port, false); }
+                        _ => println!("Usage: serial flow <on|off>"),
+                    }
+                }
+                Some(_) => serial_configure(&parts[1..]),
+                None => {}
+            }
+            print_serial();
+        },
+        "console" => {
+            use crate::drivers::virtio_console;
+            match &parts[1..] {
+                [] => println!("Console: ttyS{}{}", uart::console_port(),
+                    if virtio_console::is_selected() { " and virtio console" } else { "" }),
+                ["virtio", on @ ..] if matches!(on, [] | ["on"] | ["off"]) => {
+                    if !virtio_console::select(on != ["off"]) {
+                        println!("[shell] Error: No virtio console");
+                    }
+                }
+                [port] if port.parse::<usize>().is_ok() => {
+                    let port = port.parse::<usize>().unwrap_or(0);
+                    if !uart::init_port(port) || !uart::set_console(port) {
+                        println!("[shell] Error: No such serial port: {}", port);
+                    }
+                }
+                _ => println!("Usage: console [<n> | virtio [on|off]]"),
+            }
+        },
+        "diskbench" => {
+            let (mut kb, mut dev, mut raw) = (1024, "blk0", false);
+            let mut usage = false;
+            for arg in &parts[1..] {
+                match (*arg, arg.parse::<usize>()) {
+                    ("raw", _) => raw = true,
+                    (_, Ok(n)) if n > 0 => kb = n,
+                    (_, Ok(_)) => usage = true,
+                    (name, Err(_)) => dev = name,
+                }
+            }
+            if usage {
+                println!("Usage: diskbench [kb] [dev] [raw]");
+            } else {
+                crate::block::bench::run(dev, kb, raw);
+            }
+        },
+        "blkfault" => match parts.get(1).map(|s| (*s, s.parse::<usize>())) {
+            Some(("off", _)) => crate::block::set_fault_interval(0),
+            Some((_, Ok(n))) if n > 0 => crate::block::set_fault_interval(n),
+            None => match crate::block::fault_interval() {
+                0 => println!("Fault injection off"),
+                n => println!("Failing one block request in {}", n),
+            },
+            _ => println!("Usage: blkfault <n|off>"),
+        },
+        "blkbench" => match parts.get(1).map(|s| s.parse::<usize>()) {
+            None => crate::drivers::virtio_blk::responsiveness_bench(16),
+            Some(Ok(mb)) if mb > 0 => crate::drivers::virtio_blk::responsiveness_bench(mb),
+            _ => println!("Usage: blkbench [mb]"),
+        },
+        "fbbench" => {
+            crate::drivers::gpu::fill_benchmark();
+        },
+        "pmmstress" => {
+            let rounds = parts.get(1).and_then(|s| s.parse::<usize>().ok()).unwrap_or(1000);
+            crate::mm::stress::pmm_stress(rounds);
+        },
+        "leaks" => {
+            #[cfg(feature = "debug-alloc")]
+            crate::mm::track::report(parts.get(1).and_then(|s| s.parse::<u64>().ok()).unwrap_or(10));
+            #[cfg(not(feature = "debug-alloc"))]
+            println!("Allocation tracking is off: build with KERNEL_FEATURES=debug-alloc");
+        },
+        "slabbench" => {
+            let cycles = parts.get(1).and_then(|s| s.parse::<usize>().ok()).unwrap_or(10_000);
+            crate::mm::stress::slab_bench(cycles);
+        },
+        "pmmbench" => {
+            let cycles = parts.get(1).and_then(|s| s.parse::<usize>().ok()).unwrap_or(10_000);
+            crate::mm::stress::pmm_bench(cycles);
+        },
+        "ctxbench" => {
+            let switches = parts.get(1).and_then(|s| s.parse::<usize>().ok()).unwrap_or(10_000);
+            sched::bench::switch_benchmark(switches);
+        },
+        "heapoob" => {
+            #[cfg(feature = "debug-alloc")]
+            heap_overrun();
+            #[cfg(not(feature = "debug-alloc"))]
+            println!("Refusing to corrupt the heap without redzones: build with KERNEL_FEATURES=debug-alloc");
+        },
+        "overflow" => {
+            sched::spawn_named(overflow_task, "overflow", sched::Priority::Normal);
+        },
+        "irqdump" => {
+            aprk_arch_arm64::gic::dump();
+        },
+        "vmmap" => {
+            crate::mm::vmmap();
+        },
+        "ptdump" => {
+            crate::mm::ptdump();
+        },
+        "ptwrite" => {
+            if aprk_arch_arm64::mmu::probe_table_write() {
+                println!("[shell] Write to the kernel L1 table succeeded: tables are NOT protected");
+            } else {
+                println!("[shell] Write to the kernel L1 table faulted: tables are read-only");
+            }
+        },
+        "meminfo" => {
+            crate::mm::meminfo(parts.get(1) == Some(&"-v"));
+        },
+        "irqstat" => {
+            use aprk_arch_arm64::exception;
+            if parts.len() == 3 && parts[1] == "storm" {
+                match parts[2].parse::<u32>() {
+                    Ok(n) => {
+                        exception::set_storm_threshold(n);
+                        println!("Storm threshold set to {} unhandled/s (0 = never mask)", n);
+                    }
+                    Err(_) => println!("Usage: irqstat [storm <count>]"),
+                }
+                return;
+            }
+            if parts.get(1) == Some(&"-l") {
+                let t = exception::timer_latency();
+                println!("Timer tick latency ({} samples): min {} us, avg {} us, max {} us",
+                    t.samples, t.min.as_micros(), t.avg.as_micros(), t.max.as_micros());
+                // One row per interrupt: tick latency for the timer,
+                // handler run time for the rest
+                println!("Latency histogram (us):");
+                print!("  IRQ");
+                for upper in exception::LATENCY_BUCKETS_US {
+                    print!("  {:>7}", format!("<{}", upper));
+                }
+                println!("  {:>7}", format!(">={}", exception::LATENCY_BUCKETS_US[exception::LATENCY_BUCKETS_US.len() - 1]));
+                exception::for_each_irq_stat(|s| {
+                    if s.histogram.iter().any(|&count| count > 0) {
+                        print!("  {:>3}", s.irq);
+                        for count in s.histogram {
+                            print!("  {:>7}", count);
+                        }
+                        println!();
+                    }
+                });
+                println!("Handler run time:");
+                println!("  IRQ  SAMPLES     MIN us  AVG us  MAX us");
+                exception::for_each_irq_stat(|s| {
+                    let h = s.handler_time;
+                    if h.samples > 0 {
+                        println!("  {:>3}  {:<10}  {:<6}  {:<6}  {}",
+                            s.irq, h.samples, h.min.as_micros(), h.avg.as_micros(), h.max.as_micros());
+                    }
+                });
+                return;
+            }
+
+            let stats = exception::stats();
+            println!("Exceptions: {} sync, {} svc, {} irq, {} spurious",
+                stats.sync, stats.svc, stats.irq, stats.spurious);
+            println!("  IRQ  COUNT       UNHANDLED   STATE");
+            exception::for_each_irq_stat(|s| {
+                let state = if s.masked {
+                    "masked (storm)"
+                } else if s.has_handler {
+                    "handled"
                 } else {
-                    println!("[shell] Error: Binary not found");
+                    "no handler"
+                };
+                println!("  {:>3}  {:<10}  {:<10}  {}", s.irq, s.count, s.unhandled, state);
+            });
+            println!("Storm threshold: {} unhandled/s", exception::storm_threshold());
+            for id in 0..uart::MAX_PORTS {
+                if let Some(st) = uart::status(id) {
+                    println!("ttyS{}: {} dropped, {} overruns, {} line errors",
+                        id, st.dropped, st.overruns, st.line_errors);
                 }
             }
         },
         "clear" => {
             print!("\x1b[2J\x1b[H"); 
         },
+        "poweroff" => {
+            println!("[shell] Powering off...");
+            let _ = crate::fs::sync();
+            aprk_arch_arm64::cpu::poweroff();
+        },
+        "reboot" => {
+            println!("[shell] Rebooting...");
+            let _ = crate::fs::sync();
+            aprk_arch_arm64::cpu::reboot();
+        },
         _ => {
             println!("Unknown command: {}", parts[0]);
         }
     }
 }
+
+/// Recurse until the stack runs into its guard page. The task should be
+/// killed with a stack overflow report.
+extern "C" fn overflow_task() {
+    #[allow(unconditional_recursion)]
+    fn recurse(depth: usize) -> usize {
+        let frame = core::hint::black_box([depth as u8; 256]);
+        recurse(depth + 1) + frame[0] as usize
+    }
+    recurse(0);
+}
+
+/// Print the file at `path` a piece at a time, so files bigger than the
+/// heap can be shown.
+fn cat(path: &str) {
+    let Some(mut file) = open_file(path) else {
+        return;
+    };
+    let mut buf = [0u8; 4096];
+    // Bytes of a UTF-8 character split across two reads wait at the front
+    let mut carried = 0;
+    loop {
+        let n = match file.read(&mut buf[carried..]) {
+            Ok(0) if carried == 0 => break,
+            Ok(0) => {
+                println!();
+                println!("[shell] Error: File is binary or invalid UTF-8 (try cat -v)");
+                return;
+            }
+            Ok(n) => n,
+            Err(e) => {
+                println!();
+                println!("[shell] Error: {}: {}", path, e);
+                return;
+            }
+        };
+        let len = carried + n;
+        let valid = match core::str::from_utf8(&buf[..len]) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => {
+                println!();
+                println!("[shell] Error: File is binary or invalid UTF-8 (try cat -v)");
+                return;
+            }
+        };
+        // Checked just above
+        print!("{}", unsafe { core::str::from_utf8_unchecked(&buf[..valid]) });
+        buf.copy_within(valid..len, 0);
+        carried = len - valid;
+    }
+    println!();
+}
+
+/// Holds output to a screenful at a time: after every `PAGE_LINES` lines
+/// it waits for a key. q, Esc or Ctrl-C stops the output.
+struct Pager {
+    lines: usize,
+}
+
+impl Pager {
+    const PAGE_LINES: usize = 24;
+
+    fn new() -> Self {
+        Self { lines: 0 }
+    }
+
+    /// Print one line. Returns `false` once the user has asked to stop.
+    fn line(&mut self, args: core::fmt::Arguments) -> bool {
+        if self.lines == Self::PAGE_LINES {
+            print!("\x1b[7m-- More -- (q to quit)\x1b[0m");
+            let key = console::wait_key();
+            print!("\r\x1b[K");
+            if matches!(key, console::Key::Char(b'q' | b'Q' | 0x1b) | console::Key::Ctrl('c')) {
+                return false;
+            }
+            self.lines = 0;
+        }
+        println!("{}", args);
+        self.lines += 1;
+        true
+    }
+}
+
+/// Open `path` for the shell, reporting failure.
+fn open_file(path: &str) -> Option<crate::fs::FileHandle> {
+    match crate::fs::open(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            println!("[shell] Error: {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Show the BMP image at `path` centered on the screen, scaled down if it
+/// doesn't fit.
+fn view(path: &str) {
+    use crate::drivers::gpu;
+    let Some((width, height)) = gpu::resolution() else {
+        println!("[shell] Error: No framebuffer");
+        return;
+    };
+    let Some(mut file) = open_file(path) else {
+        return;
+    };
+    let mut data = alloc::vec::Vec::new();
+    if data.try_reserve_exact(file.size() as usize).is_err() {
+        println!("[shell] Error: {}: Out of memory", path);
+        return;
+    }
+    let mut chunk = [0u8; 4096];
+    loop {
+        match file.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) if data.try_reserve(n).is_ok() => data.extend_from_slice(&chunk[..n]),
+            Ok(_) => {
+                println!("[shell] Error: {}: Out of memory", path);
+                return;
+            }
+            Err(e) => {
+                println!("[shell] Error: {}: {}", path, e);
+                return;
+            }
+        }
+    }
+    drop(file);
+    let image = match gpu::bmp::decode(&data) {
+        Ok(image) => image,
+        Err(e) => {
+            println!("[shell] Error: {}: {}", path, e);
+            return;
+        }
+    };
+    drop(data);
+    let (w, h) = image.fit(width, height);
+    if let Some(fb) = gpu::FB_CONFIG.lock().as_mut() {
+        fb.clear((0, 0, 0));
+        fb.blit_image(&image, ((width - w) / 2) as i32, ((height - h) / 2) as i32, Some((width, height)));
+    }
+    gpu::present();
+    println!("{}: {}x{}, shown at {}x{}", path, image.width(), image.height(), w, h);
+}
+
+/// Print the file at `path` with bytes that aren't printable text shown
+/// as `^X` (control characters) and `M-` (bytes above 0x7f), like
+/// `cat -v`, a page at a time.
+fn cat_escaped(path: &str) {
+    let Some(mut file) = open_file(path) else {
+        return;
+    };
+    let mut pager = Pager::new();
+    let mut line = String::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                println!("[shell] Error: {}: {}", path, e);
+                return;
+            }
+        };
+        for &byte in &buf[..n] {
+            if byte == b'\n' {
+                if !pager.line(format_args!("{}", line)) {
+                    return;
+                }
+                line.clear();
+                continue;
+            }
+            let low = if byte >= 0x80 {
+                line.push_str("M-");
+                byte & 0x7f
+            } else {
+                byte
+            };
+            match low {
+                b'\t' if byte == low => line.push('\t'),
+                0x20..=0x7e => line.push(low as char),
+                0x7f => line.push_str("^?"),
+                _ => {
+                    line.push('^');
+                    line.push((low + b'@') as char);
+                }
+            }
+        }
+    }
+    if !line.is_empty() {
+        pager.line(format_args!("{}", line));
+    }
+}
+
+/// Print `len` bytes of the file at `path` from `offset` as hex and ASCII,
+/// 16 bytes a line (like `hexdump -C`), a page at a time. Runs of
+/// identical lines are shown as one `*`.
+fn hexdump(path: &str, offset: u64, len: u64) {
+    let Some(mut file) = open_file(path) else {
+        return;
+    };
+    if offset >= file.size() {
+        if offset > 0 {
+            println!("[shell] Offset {:#x} is past the end of {} ({} bytes)", offset, path, file.size());
+        }
+        return;
+    }
+    if let Err(e) = file.seek(crate::fs::SeekFrom::Start(offset)) {
+        println!("[shell] Error: {}: {}", path, e);
+        return;
+    }
+    let mut pager = Pager::new();
+    let mut pos = offset;
+    let end = offset.saturating_add(len).min(file.size());
+    let mut previous: Option<[u8; 16]> = None;
+    let mut skipping = false;
+    let mut buf = [0u8; 4096];
+    while pos < end {
+        let want = (end - pos).min(buf.len() as u64) as usize;
+        let n = match file.read(&mut buf[..want]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                println!("[shell] Error: {}: {}", path, e);
+                return;
+            }
+        };
+        for row in buf[..n].chunks(16) {
+            let full: Option<[u8; 16]> = row.try_into().ok();
+            if full.is_some() && full == previous {
+                if !skipping && !pager.line(format_args!("*")) {
+                    return;
+                }
+                skipping = true;
+                pos += 16;
+                continue;
+            }
+            skipping = false;
+            previous = full;
+
+            let mut hex = String::new();
+            let mut ascii = String::new();
+            for i in 0..16 {
+                if i == 8 {
+                    hex.push(' ');
+                }
+                match row.get(i) {
+                    Some(&b) => {
+                        hex.push_str(&format!("{:02x} ", b));
+                        ascii.push(if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' });
+                    }
+                    None => hex.push_str("   "),
+                }
+            }
+            if !pager.line(format_args!("{:08x}  {} |{}|", pos, hex, ascii)) {
+                return;
+            }
+            pos += row.len() as u64;
+        }
+    }
+    pager.line(format_args!("{:08x}", pos));
+}
+
+/// Read `file` twice, timing each read and counting block cache hits.
+fn cache_bench(file: &str) {
+    use aprk_arch_arm64::timer::Timer;
+    for pass in ["first", "second"] {
+        let before = crate::fs::cache::stats();
+        let start = Timer::counter();
+        let mut handle = match crate::fs::open(file) {
+            Ok(handle) => handle,
+            Err(e) => {
+                println!("[shell] Error: {}: {}", file, e);
+                return;
+            }
+        };
+        let mut buf = [0u8; 4096];
+        let mut total = 0;
+        loop {
+            match handle.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => total += n,
+                Err(e) => {
+                    println!("[shell] Error: {}: {}", file, e);
+                    return;
+                }
+            }
+        }
+        let us = Timer::ticks_to_duration(Timer::counter() - start).as_micros();
+        let after = crate::fs::cache::stats();
+        println!("{} read of {} bytes: {} us, {} hits, {} misses, {} read-ahead hits", pass, total, us,
+            after.hits - before.hits, after.misses - before.misses, after.prefetch_hits - before.prefetch_hits);
+    }
+}
+
+/// Print a directory listing, one line per entry.
+fn list(entries: &[crate::fs::DirEntryInfo], long: bool, human: bool) {
+    for entry in entries {
+        let name = if entry.is_dir { format!("{}/", entry.name) } else { entry.name.clone() };
+        if !long {
+            println!("  {}", name);
+            continue;
+        }
+        let size = match (entry.is_dir, human) {
+            (true, _) => String::from("-"),
+            (false, true) => human_size(entry.size),
+            (false, false) => format!("{}", entry.size),
+        };
+        let (date, time) = (entry.modified.date, entry.modified.time);
+        println!("  {} {:>10}  {:04}-{:02}-{:02} {:02}:{:02}  {}", if entry.is_dir { 'd' } else { '-' },
+            size, date.year, date.month, date.day, time.hour, time.min, name);
+    }
+}
+
+/// `bytes` in K or M with one decimal, or as is below 1K.
+fn human_size(bytes: u64) -> String {
+    let (unit, scale) = match bytes {
+        0..1024 => return format!("{}", bytes),
+        1024..1048576 => ('K', 1024),
+        _ => ('M', 1024 * 1024),
+    };
+    let tenths = bytes * 10 / scale;
+    format!("{}.{}{}", tenths / 10, tenths % 10, unit)
+}
+
+/// Write one byte past the end of a Vec's buffer. Freeing it should
+/// report the overwritten redzone and where the Vec was allocated.
+#[cfg(feature = "debug-alloc")]
+fn heap_overrun() {
+    let mut buf: Vec<u8> = Vec::with_capacity(32);
+    unsafe { buf.as_mut_ptr().add(buf.capacity()).write_volatile(0x42); }
+    drop(buf);
+}
+
+/// Replay the kernel log, then with `follow` keep printing new lines until
+/// a key is pressed. Output goes straight to the UART so replaying doesn't
+/// append to the log being read.
+fn dmesg(follow: bool) {
+    let lost = log::lost();
+    if lost > 0 {
+        uart::puts(&format!("[dmesg] {} older lines lost\n", lost));
+    }
+
+    let end = log::next_seq();
+    let mut seq = 0;
+    loop {
+        while let Some(line) = log::read_since(seq) {
+            if !follow && line.seq >= end {
+                return;
+            }
+            let ts = line.timestamp;
+            uart::puts(&format!("[{:5}.{:03}] {}\n", ts.as_secs(), ts.subsec_millis(), line.text()));
+            seq = line.seq + 1;
+        }
+        if !follow || console::read_key().is_some() {
+            return;
+        }
+        sched::schedule();
+    }
+}
+
+fn print_interface() {
+    let Some(mac) = crate::drivers::virtio_net::mac() else {
+        println!("[shell] Error: No network device");
+        return;
+    };
+    println!("eth0: MAC {}", mac);
+    match crate::net::config() {
+        Some(config) => {
+            print!("  inet {}/{}  broadcast {}", config.addr, config.prefix, config.broadcast());
+            match config.gateway {
+                Some(gateway) => println!("  gateway {}", gateway),
+                None => println!(),
+            }
+        }
+        None => println!("  no address"),
+    }
+    let stats = crate::drivers::virtio_net::stats();
+    println!("  RX {} frames ({} dropped)  TX {} frames  {}", stats.rx_frames, stats.dropped, stats.tx_frames,
+        if stats.irq { "interrupts" } else { "polled" });
+    for (ip, mac) in crate::net::arp::entries() {
+        println!("  arp {} at {}", ip, mac);
+    }
+}
+
+/// Print each click and release of the pointer, until a key is pressed.
+fn mouse_test() {
+    use crate::input::PointerEventKind;
+    if crate::drivers::virtio_input::count() == 0 {
+        println!("[shell] Error: No pointing device");
+        return;
+    }
+    println!("Click in the QEMU window (any key stops)");
+    crate::input::clear_events();
+    loop {
+        while let Some(event) = crate::input::next_event() {
+            match event.kind {
+                PointerEventKind::Pressed(buttons) => println!("pressed {:#05b} at ({}, {}), down {:#05b}",
+                    buttons, event.x, event.y, event.buttons),
+                PointerEventKind::Released(buttons) => println!("released {:#05b} at ({}, {}), down {:#05b}",
+                    buttons, event.x, event.y, event.buttons),
+                PointerEventKind::Moved => {}
+            }
+        }
+        if console::read_key().is_some() {
+            break;
+        }
+        sched::schedule();
+    }
+}
+
+/// Send datagrams arriving on UDP `port` back where they came from, until
+/// a key is pressed.
+fn udp_echo(port: u16) {
+    if crate::net::config().is_none() {
+        println!("[shell] Error: {}", crate::net::NetError::NotConfigured);
+        return;
+    }
+    let socket = match crate::net::udp::Socket::bind(port) {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("[shell] Error: port {}: {}", port, e);
+            return;
+        }
+    };
+    println!("Echoing UDP port {} (any key stops)", socket.port());
+    let mut echoed = 0;
+    loop {
+        while let Some(datagram) = socket.recv() {
+            match socket.send_to(datagram.src, datagram.src_port, &datagram.data) {
+                Ok(()) => echoed += 1,
+                Err(e) => println!("[shell] Error: {}:{}: {}", datagram.src, datagram.src_port, e),
+            }
+        }
+        if console::read_key().is_some() {
+            break;
+        }
+        sched::schedule();
+    }
+    println!("Echoed {} datagrams", echoed);
+}
+
+fn print_serial() {
+    for port in 0..uart::MAX_PORTS {
+        if let (Some(config), Some(status)) = (uart::config(port), uart::status(port)) {
+            print_serial_port(port, &config, &status);
+        }
+    }
+}
+
+fn print_serial_port(port: usize, config: &uart::UartConfig, status: &uart::UartStatus) {
+    let parity = match config.parity {
+        uart::Parity::None => 'N',
+        uart::Parity::Even => 'E',
+        uart::Parity::Odd => 'O',
+    };
+    println!("ttyS{}: {} baud, {}{}{} (clock {} Hz){}",
+        port, config.baud, config.data_bits, parity, config.stop_bits, config.clock_hz,
+        if port == uart::console_port() { " [console]" } else { "" });
+
+    println!("  TX FIFO: {}{}, RX FIFO: {}{}{}",
+        if status.tx_fifo_empty { "empty" } else { "pending" },
+        if status.tx_fifo_full { " (full)" } else { "" },
+        if status.rx_fifo_empty { "empty" } else { "pending" },
+        if status.rx_fifo_full { " (full)" } else { "" },
+        if status.busy { ", busy" } else { "" });
+    println!("  RX buffer: {} bytes, flow control: {}{}",
+        status.rx_buffered,
+        if status.flow_control { "xon/xoff" } else { "off" },
+        if status.xoff_sent { " (XOFF sent)" } else { "" });
+    println!("  Errors: {} overrun, {} framing/parity/break, {} dropped (RSR={:#x})",
+        status.overruns, status.line_errors, status.dropped, status.rsr);
+}
+
+/// Apply `serial <baud> [<data><parity><stop>]` to the console port,
+/// e.g. `serial 9600 7E1`.
+fn serial_configure(args: &[&str]) {
+    let port = uart::console_port();
+    let Some(current) = uart::config(port) else { return; };
+    let Ok(baud) = args[0].parse::<u32>() else {
+        println!("Usage: serial [baud] [8N1]");
+        return;
+    };
+
+    let (mut data_bits, mut parity, mut stop_bits) = (current.data_bits, current.parity, current.stop_bits);
+    if let Some(frame) = args.get(1) {
+        let bytes = frame.as_bytes();
+        if bytes.len() != 3 {
+            println!("Usage: serial [baud] [8N1]");
+            return;
+        }
+        data_bits = bytes[0].wrapping_sub(b'0');
+        stop_bits = bytes[2].wrapping_sub(b'0');
+        parity = match bytes[1].to_ascii_uppercase() {
+            b'N' => uart::Parity::None,
+            b'E' => uart::Parity::Even,
+            b'O' => uart::Parity::Odd,
+            _ => {
+                println!("[shell] Error: Parity must be N, E or O");
+                return;
+            }
+        };
+    }
+
+    if !uart::configure(port, baud, data_bits, parity, stop_bits) {
+        println!("[shell] Error: Unsupported serial settings");
+    }
+}
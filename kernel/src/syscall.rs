@@ -1,21 +1,37 @@
-use aprk_arch_arm64::{print, println};
+use aprk_arch_arm64::{log_debug, log_warn, print};
+use alloc::vec::Vec;
+use crate::console;
+use crate::fd;
+use crate::fs::FsError;
+use crate::mm;
 use crate::sched;
+use aprk_arch_arm64::mmu;
+
+/// console_ioctl commands
+const CONSOLE_GET_MODE: u64 = 0;
+const CONSOLE_SET_MODE: u64 = 1;
+/// Most bytes a read or write moves through the kernel at a time
+const USER_CHUNK: usize = 4096;
 
 pub fn handle_syscall(id: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
     match id {
         0 => { // print(ptr, len)
-            let ptr = arg0 as *const u8;
-            let len = arg1 as usize;
-            if !ptr.is_null() && len > 0 {
-                let s = unsafe { 
-                    let slice = core::slice::from_raw_parts(ptr, len);
-                    core::str::from_utf8(slice).unwrap_or("<?>")
-                };
-                print!("{}", s);
+            // Standard output redirected to a file or another port
+            if !fd::is_console(1) {
+                return status(write_user(1, arg0, arg1).map(|_| 0));
+            }
+            let text = match copy_in(arg0, arg1) {
+                Ok(text) => text,
+                Err(e) => return status(Err(e)),
+            };
+            if !text.is_empty() {
+                print!("{}", core::str::from_utf8(&text).unwrap_or("<?>"));
             }
             0
         },
         1 => { // exit()
+            // Flush files the task left open
+            fd::close_all();
             sched::exit_current_task();
             0
         },
@@ -36,8 +52,15 @@ pub fn handle_syscall(id: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
             let align = arg1 as usize;
             match core::alloc::Layout::from_size_align(size, align) {
                 Ok(layout) => {
-                    let ptr = unsafe { alloc::alloc::alloc(layout) as u64 };
-                    if ptr == 0 { 0 } else { ptr }
+                    // Null on failure, as GlobalAlloc expects: the task
+                    // sees an allocation error, the kernel carries on
+                    let ptr = mm::user::alloc(layout) as u64;
+                    log_debug!("syscall", "alloc(size={}, align={}) -> {:#x}", size, align, ptr);
+                    if ptr == 0 {
+                        log_warn!("syscall", "alloc of {} bytes by task {} failed: out of memory",
+                            size, sched::current_task_id());
+                    }
+                    ptr
                 },
                 Err(_) => 0,
             }
@@ -46,16 +69,140 @@ pub fn handle_syscall(id: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
             let ptr = arg0 as *mut u8;
             let size = arg1 as usize;
             let align = arg2 as usize;
-            if let Ok(layout) = core::alloc::Layout::from_size_align(size, align) {
-                unsafe { alloc::alloc::dealloc(ptr, layout); }
-                0
-            } else {
-                1
+            match core::alloc::Layout::from_size_align(size, align) {
+                Ok(layout) if unsafe { mm::user::dealloc(ptr, layout) } => 0,
+                _ => 1,
+            }
+        },
+        7 => { // open(path_ptr, path_len, flags) -> fd
+            let path = match copy_in(arg0, arg1) {
+                Ok(path) => path,
+                Err(e) => return status(Err(e)),
+            };
+            match core::str::from_utf8(&path) {
+                Ok(path) => status(fd::open(path, arg2).map(|fd| fd as u64)),
+                Err(_) => status(Err(FsError::InvalidName)),
+            }
+        },
+        8 => { // read(fd, buf_ptr, len) -> bytes read
+            status(read_user(arg0 as usize, arg1, arg2))
+        },
+        9 => { // write(fd, buf_ptr, len) -> bytes written
+            status(write_user(arg0 as usize, arg1, arg2))
+        },
+        10 => { // close(fd)
+            status(fd::close(arg0 as usize).map(|_| 0))
+        },
+        11 => { // console_ioctl(cmd, arg)
+            match arg0 {
+                CONSOLE_GET_MODE => console::mode() as u64,
+                CONSOLE_SET_MODE => match arg1 {
+                    0 => { console::set_mode(console::Mode::Cooked); 0 }
+                    1 => { console::set_mode(console::Mode::Raw); 0 }
+                    _ => u64::MAX,
+                },
+                _ => u64::MAX,
+            }
+        },
+        12 => { // yield_deferred()
+            // Exercises the RESCHEDULE SGI: it stays pending until IRQs
+            // are unmasked on return to user mode, then switches tasks.
+            sched::request_reschedule();
+            0
+        },
+        13 => { // sbrk(increment) -> old break
+            sched::sbrk(arg0 as i64 as isize).map_or(u64::MAX, |brk| brk as u64)
+        },
+        14 => { // lseek(fd, offset, whence) -> new position
+            status(fd::lseek(arg0 as usize, arg1 as i64, arg2))
+        },
+        15 => { // ftruncate(fd, len)
+            status(fd::ftruncate(arg0 as usize, arg1).map(|_| 0))
+        },
+        16 => { // pointer_state() -> x | y << 16 | buttons << 32
+            if crate::drivers::virtio_input::count() == 0 {
+                return u64::MAX;
             }
+            let (x, y, buttons) = crate::input::pointer_state();
+            x as u64 & 0xffff | (y as u64 & 0xffff) << 16 | (buttons as u64) << 32
         },
         _ => {
-            println!("[syscall] Unknown syscall: {}", id);
+            log_warn!("syscall", "Unknown syscall: {}", id);
             u64::MAX
         }
     }
 }
+
+/// Return value of a file syscall: the result, or the error's errno
+/// negated (so errors are the top 4095 values, -1 to -4095).
+fn status(result: Result<u64, FsError>) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(e) => e.errno().wrapping_neg(),
+    }
+}
+
+/// A kernel buffer of `len` bytes, charged to the task, or `OutOfMemory`
+/// if it's over its quota or the heap is full.
+fn bounce(len: usize) -> Result<Vec<u8>, FsError> {
+    if !mm::quota::allows(len) {
+        return Err(FsError::OutOfMemory);
+    }
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len).map_err(|_| FsError::OutOfMemory)?;
+    buf.resize(len, 0);
+    Ok(buf)
+}
+
+/// Copy the user buffer of `len` bytes at `ptr` into the kernel. Empty
+/// for `len` 0; `BadAddress` if it isn't all mapped readable.
+fn copy_in(ptr: u64, len: u64) -> Result<Vec<u8>, FsError> {
+    if !mmu::user_range_ok(ptr as usize, len as usize) {
+        return Err(FsError::BadAddress);
+    }
+    let mut buf = bounce(len as usize)?;
+    if !mmu::copy_from_user(&mut buf, ptr as usize) {
+        return Err(FsError::BadAddress);
+    }
+    Ok(buf)
+}
+
+/// read(fd, ptr, len): read up to `USER_CHUNK` bytes into the user buffer.
+/// Like any read it may return fewer bytes than asked for.
+fn read_user(fd: usize, ptr: u64, len: u64) -> Result<u64, FsError> {
+    if !mmu::user_range_ok(ptr as usize, len as usize) {
+        return Err(FsError::BadAddress);
+    }
+    let mut buf = bounce((len as usize).min(USER_CHUNK))?;
+    let n = fd::read(fd, &mut buf)?;
+    if !mmu::copy_to_user(ptr as usize, &buf[..n]) {
+        return Err(FsError::BadAddress);
+    }
+    Ok(n as u64)
+}
+
+/// write(fd, ptr, len): write the user buffer a chunk at a time. Returns
+/// the bytes written; `BadAddress` only if not even the first chunk could
+/// be read.
+fn write_user(fd: usize, ptr: u64, len: u64) -> Result<u64, FsError> {
+    if !mmu::user_range_ok(ptr as usize, len as usize) {
+        return Err(FsError::BadAddress);
+    }
+    let mut buf = bounce((len as usize).min(USER_CHUNK))?;
+    if len == 0 {
+        return fd::write(fd, &buf).map(|n| n as u64);
+    }
+    let mut done = 0;
+    while done < len {
+        let chunk = &mut buf[..(len - done).min(USER_CHUNK as u64) as usize];
+        if !mmu::copy_from_user(chunk, (ptr + done) as usize) {
+            return if done == 0 { Err(FsError::BadAddress) } else { Ok(done) };
+        }
+        let n = fd::write(fd, chunk)?;
+        done += n as u64;
+        if n < chunk.len() {
+            break;
+        }
+    }
+    Ok(done)
+}
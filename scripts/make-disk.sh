@@ -2,11 +2,15 @@
 # =============================================================================
 # APRK OS - Disk Image Creation Script (Absolute Isolation)
 # =============================================================================
+# Usage: ./scripts/make-disk.sh [source-dir] [image] [volume-name]
+#        (defaults: disk_root, disk.img, APRK)
+# =============================================================================
 set -e
 
 PROJECT_ROOT="$(cd "$(dirname "${BASH_SOURCE[0]}")/.." && pwd)"
-SOURCE_DIR="$PROJECT_ROOT/disk_root"
-DEST_IMG="$PROJECT_ROOT/disk.img"
+SOURCE_DIR="${1:-$PROJECT_ROOT/disk_root}"
+DEST_IMG="${2:-$PROJECT_ROOT/disk.img}"
+VOLNAME="${3:-APRK}"
 
 # Safe names without spaces
 SAFE_TMP="/tmp/aprk_build_$(date +%s)"
@@ -20,7 +24,7 @@ echo "Syncing files to safe location..."
 cp -r "$SOURCE_DIR/" "$SAFE_SRC/"
 
 echo "Creating raw FAT32 volume in isolated environment..."
-hdiutil create -fs MS-DOS -volname "APRK" -srcfolder "$SAFE_SRC" -layout NONE -ov "$SAFE_DMG" > /dev/null
+hdiutil create -fs MS-DOS -volname "$VOLNAME" -srcfolder "$SAFE_SRC" -layout NONE -ov "$SAFE_DMG" > /dev/null
 
 echo "Converting to raw format..."
 hdiutil convert "$SAFE_DMG" -format UDTO -o "$SAFE_RAW" -ov > /dev/null
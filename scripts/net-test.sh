@@ -0,0 +1,84 @@
+#!/bin/bash
+# =============================================================================
+# APRK OS - Network Test
+# =============================================================================
+# Boots a copy of disk.img with a virtio-net NIC on QEMU's user-mode
+# network. /etc/rc gives it the usual user-mode address and runs udpecho;
+# a datagram sent to the forwarded host port must come back unchanged.
+# Usage: ./scripts/net-test.sh [kernel-binary]
+# =============================================================================
+
+set -e
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+PROJECT_ROOT="$(dirname "$SCRIPT_DIR")"
+KERNEL="${1:-$PROJECT_ROOT/target/aarch64-unknown-none/debug/aprk-kernel}"
+QEMU="qemu-system-aarch64"
+PORT="${PORT:-5556}"
+
+for tool in $QEMU mcopy python3; do
+    if ! command -v $tool &> /dev/null; then
+        echo "Error: $tool not found (mtools: brew install mtools / apt install mtools)"
+        exit 1
+    fi
+done
+
+WORK="$(mktemp -d)"
+trap 'kill $QEMU_PID 2> /dev/null || true; rm -rf "$WORK"' EXIT
+IMG="$WORK/disk.img"
+LOG="$WORK/serial.log"
+MARKER="net-test-$$-$(date +%s)"
+
+cp "$PROJECT_ROOT/disk.img" "$IMG"
+printf 'ifconfig 10.0.2.15/24 10.0.2.2\nudpecho\n' > "$WORK/rc"
+mcopy -o -i "$IMG" "$WORK/rc" ::/etc/rc
+
+$QEMU \
+    -machine virt,gic-version=2 \
+    -cpu cortex-a72 \
+    -m 512M \
+    -display none \
+    -drive file="$IMG",if=none,format=raw,id=drive0 \
+    -device virtio-blk-device,drive=drive0 \
+    -netdev user,id=net0,hostfwd=udp::$PORT-:7 \
+    -device virtio-net-device,netdev=net0 \
+    -kernel "$KERNEL" \
+    -serial file:"$LOG" &
+QEMU_PID=$!
+
+for _ in $(seq 60); do
+    grep -q 'Echoing UDP port 7' "$LOG" 2> /dev/null && break
+    sleep 1
+done
+if ! grep -q 'Echoing UDP port 7' "$LOG"; then
+    echo "FAILED: udpecho didn't start"
+    tail -20 "$LOG"
+    exit 1
+fi
+
+# The first datagram may go while the guest is still asking for the
+# gateway's MAC address: try a few times
+REPLY="$(python3 - "$PORT" "$MARKER" <<'PY'
+import socket, sys
+port, marker = int(sys.argv[1]), sys.argv[2].encode()
+s = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+s.settimeout(2)
+for _ in range(5):
+    s.sendto(marker, ("127.0.0.1", port))
+    try:
+        print(s.recvfrom(2048)[0].decode())
+        break
+    except socket.timeout:
+        pass
+PY
+)"
+kill -9 $QEMU_PID 2> /dev/null || true
+wait $QEMU_PID 2> /dev/null || true
+
+if [ "$REPLY" = "$MARKER" ]; then
+    echo "PASSED: datagram echoed back"
+else
+    echo "FAILED: no echo (got '$REPLY')"
+    tail -20 "$LOG"
+    exit 1
+fi
@@ -4,6 +4,9 @@
 # =============================================================================
 # Runs APRK OS kernel on QEMU ARM64 virt machine.
 # Usage: ./scripts/qemu-run.sh [kernel-binary]
+#        MEM=2G ./scripts/qemu-run.sh  (RAM size, default 512M)
+#        NET=tap ./scripts/qemu-run.sh (NIC on host tap0 instead of user mode)
+#        VCONSOLE=/tmp/aprk.sock ./scripts/qemu-run.sh (virtio console on a socket)
 # =============================================================================
 
 set -e
@@ -18,6 +21,9 @@ KERNEL="${1:-$PROJECT_ROOT/target/aarch64-unknown-none/debug/aprk-kernel}"
 # QEMU binary
 QEMU="qemu-system-aarch64"
 
+# RAM size (the kernel sizes its memory map from the device tree)
+MEM="${MEM:-512M}"
+
 # Check if QEMU is installed
 if ! command -v $QEMU &> /dev/null; then
     echo "Error: $QEMU not found. Please install QEMU."
@@ -44,16 +50,52 @@ echo
 # Run QEMU with the following configuration:
 # -machine virt     : ARM virt machine (similar to real hardware)
 # -cpu cortex-a72   : Cortex-A72 CPU (good ARM64 core)
-# -m 512M           : 512MB RAM
+# -m $MEM           : RAM size (512MB unless MEM is set)
 # -nographic        : No graphical output, use serial console
 # -kernel           : Load our kernel binary
 # -serial mon:stdio : Connect serial port to terminal
+# -serial pty       : Second serial port (ttyS1) on a host pty
+# virtio-tablet-device moves the cursor with the host mouse (mousetest)
+# disk2.img, if there (make disk2), is attached as a second disk (blk1)
+# disk.tar, if there, is loaded as the ramdisk at 0x48000000 (-initrd is
+# ignored for ELF kernels), mounted at /initrd
+DISK2=()
+if [ -f disk2.img ]; then
+    DISK2=(-drive file=disk2.img,if=none,format=raw,discard=unmap,id=drive1 -device virtio-blk-device,drive=drive1)
+fi
+# The NIC is on QEMU's user-mode network (guest 10.0.2.15/24, gateway
+# 10.0.2.2) with host UDP port 5555 forwarded to guest port 7 (udpecho).
+# User mode doesn't pass pings from the host: NET=tap puts the NIC on an
+# existing tap0 instead, for `ping` to the address given with ifconfig.
+if [ "${NET:-user}" = tap ]; then
+    NETDEV=(-netdev tap,id=net0,ifname=tap0,script=no,downscript=no)
+else
+    NETDEV=(-netdev user,id=net0,hostfwd=udp::5555-:7)
+fi
+# VCONSOLE adds a virtio console on a Unix socket (socat - UNIX:$VCONSOLE),
+# carrying kernel output too (console=virtio; `console virtio off` stops it)
+VCON=()
+if [ -n "$VCONSOLE" ]; then
+    VCON=(-device virtio-serial-device -chardev socket,id=vcon0,path="$VCONSOLE",server=on,wait=off
+          -device virtconsole,chardev=vcon0 -append console=virtio)
+fi
+RAMDISK=()
+if [ -f disk.tar ]; then
+    RAMDISK=(-device loader,file=disk.tar,addr=0x48000000,force-raw=on)
+fi
 $QEMU \
     -machine virt,gic-version=2 \
     -cpu cortex-a72 \
-    -m 512M \
+    -m "$MEM" \
     -device virtio-gpu-device \
-    -drive file=disk.img,if=none,format=raw,id=drive0 \
+    -drive file=disk.img,if=none,format=raw,discard=unmap,id=drive0 \
     -device virtio-blk-device,drive=drive0 \
+    "${DISK2[@]}" \
+    "${NETDEV[@]}" \
+    -device virtio-net-device,netdev=net0 \
+    -device virtio-tablet-device \
+    "${VCON[@]}" \
+    "${RAMDISK[@]}" \
     -kernel "$KERNEL" \
-    -serial mon:stdio
+    -serial mon:stdio \
+    -serial pty
@@ -0,0 +1,64 @@
+#!/bin/bash
+# =============================================================================
+# APRK OS - Sync Test
+# =============================================================================
+# Boots a copy of disk.img whose /etc/rc writes a file and runs `sync`,
+# kills QEMU as soon as the sync is done (no clean shutdown to write
+# anything back), then checks the file on the host with mtools.
+# Usage: ./scripts/sync-test.sh [kernel-binary]
+# =============================================================================
+
+set -e
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+PROJECT_ROOT="$(dirname "$SCRIPT_DIR")"
+KERNEL="${1:-$PROJECT_ROOT/target/aarch64-unknown-none/debug/aprk-kernel}"
+QEMU="qemu-system-aarch64"
+
+for tool in $QEMU mcopy mtype; do
+    if ! command -v $tool &> /dev/null; then
+        echo "Error: $tool not found (mtools: brew install mtools / apt install mtools)"
+        exit 1
+    fi
+done
+
+WORK="$(mktemp -d)"
+trap 'kill $QEMU_PID 2> /dev/null || true; rm -rf "$WORK"' EXIT
+IMG="$WORK/disk.img"
+LOG="$WORK/serial.log"
+MARKER="sync-test-$$-$(date +%s)"
+
+cp "$PROJECT_ROOT/disk.img" "$IMG"
+# `version` only runs once `sync` has returned: seeing it is the cue
+printf 'write /synctest.txt %s\nsync\nversion\n' "$MARKER" > "$WORK/rc"
+mcopy -o -i "$IMG" "$WORK/rc" ::/etc/rc
+
+$QEMU \
+    -machine virt,gic-version=2 \
+    -cpu cortex-a72 \
+    -m 512M \
+    -display none \
+    -drive file="$IMG",if=none,format=raw,id=drive0 \
+    -device virtio-blk-device,drive=drive0 \
+    -kernel "$KERNEL" \
+    -serial file:"$LOG" &
+QEMU_PID=$!
+
+for _ in $(seq 60); do
+    grep -q '\[/etc/rc\] version' "$LOG" 2> /dev/null && break
+    sleep 1
+done
+kill -9 $QEMU_PID 2> /dev/null || true
+wait $QEMU_PID 2> /dev/null || true
+
+if ! grep -q '\[/etc/rc\] version' "$LOG"; then
+    echo "FAILED: the boot script didn't get past sync"
+    tail -20 "$LOG"
+    exit 1
+fi
+if [ "$(mtype -i "$IMG" ::/synctest.txt 2> /dev/null)" = "$MARKER" ]; then
+    echo "PASSED: /synctest.txt is on the disk image after sync"
+else
+    echo "FAILED: /synctest.txt missing or wrong on the disk image after sync"
+    exit 1
+fi
@@ -0,0 +1,81 @@
+#!/bin/bash
+# =============================================================================
+# APRK OS - Two Disk Test
+# =============================================================================
+# Boots a copy of disk.img with a fresh FAT32 image as a second disk
+# (blk1). /etc/rc mounts blk1 at /mnt and copies a file each way; QEMU is
+# killed once that's synced, and mtools checks both images on the host.
+# Usage: ./scripts/two-disk-test.sh [kernel-binary]
+# =============================================================================
+
+set -e
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+PROJECT_ROOT="$(dirname "$SCRIPT_DIR")"
+KERNEL="${1:-$PROJECT_ROOT/target/aarch64-unknown-none/debug/aprk-kernel}"
+QEMU="qemu-system-aarch64"
+
+for tool in $QEMU mcopy mtype mformat; do
+    if ! command -v $tool &> /dev/null; then
+        echo "Error: $tool not found (mtools: brew install mtools / apt install mtools)"
+        exit 1
+    fi
+done
+
+WORK="$(mktemp -d)"
+trap 'kill $QEMU_PID 2> /dev/null || true; rm -rf "$WORK"' EXIT
+IMG="$WORK/disk.img"
+IMG2="$WORK/disk2.img"
+LOG="$WORK/serial.log"
+MARKER="two-disk-test-$$-$(date +%s)"
+
+cp "$PROJECT_ROOT/disk.img" "$IMG"
+dd if=/dev/zero of="$IMG2" bs=1M count=40 2> /dev/null
+mformat -i "$IMG2" -F -v APRK2 ::
+echo "$MARKER" > "$WORK/from2.txt"
+mcopy -i "$IMG2" "$WORK/from2.txt" ::/from2.txt
+echo "$MARKER" > "$WORK/from1.txt"
+mcopy -o -i "$IMG" "$WORK/from1.txt" ::/from1.txt
+# `version` only runs once `sync` has returned: seeing it is the cue
+printf 'mkdir /mnt\nmount blk1 /mnt\ncp /mnt/from2.txt /copied.txt\ncp /from1.txt /mnt/copied.txt\nsync\nversion\n' > "$WORK/rc"
+mcopy -o -i "$IMG" "$WORK/rc" ::/etc/rc
+
+$QEMU \
+    -machine virt,gic-version=2 \
+    -cpu cortex-a72 \
+    -m 512M \
+    -display none \
+    -drive file="$IMG",if=none,format=raw,id=drive0 \
+    -device virtio-blk-device,drive=drive0 \
+    -drive file="$IMG2",if=none,format=raw,id=drive1 \
+    -device virtio-blk-device,drive=drive1 \
+    -kernel "$KERNEL" \
+    -serial file:"$LOG" &
+QEMU_PID=$!
+
+for _ in $(seq 60); do
+    grep -q '\[/etc/rc\] version' "$LOG" 2> /dev/null && break
+    sleep 1
+done
+kill -9 $QEMU_PID 2> /dev/null || true
+wait $QEMU_PID 2> /dev/null || true
+
+if ! grep -q '\[/etc/rc\] version' "$LOG"; then
+    echo "FAILED: the boot script didn't get past sync"
+    tail -20 "$LOG"
+    exit 1
+fi
+failed=0
+if [ "$(mtype -i "$IMG" ::/copied.txt 2> /dev/null)" = "$MARKER" ]; then
+    echo "PASSED: blk1:/from2.txt copied to blk0:/copied.txt"
+else
+    echo "FAILED: blk0:/copied.txt missing or wrong"
+    failed=1
+fi
+if [ "$(mtype -i "$IMG2" ::/copied.txt 2> /dev/null)" = "$MARKER" ]; then
+    echo "PASSED: blk0:/from1.txt copied to blk1:/copied.txt"
+else
+    echo "FAILED: blk1:/copied.txt missing or wrong"
+    failed=1
+fi
+exit $failed
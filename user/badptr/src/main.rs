@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+
+// =============================================================================
+// APRK OS - Bad Pointer Test
+// =============================================================================
+// Hands the kernel buffers it can't use: a write from an unmapped page, a
+// read into the task's own read-only text, a path reaching into the kernel
+// half. Each must fail with EFAULT; the kernel must not fault on them.
+// =============================================================================
+
+use aprk_user_lib::{close, exit, lseek, open_with, print, println, write, Errno, Whence, OPEN_TRUNCATE, OPEN_WRITE};
+
+const SCRATCH: &str = "/badptr.tmp";
+
+/// Raw syscall `id` with three arguments, as the kernel returns it.
+fn syscall(id: u64, a0: u64, a1: u64, a2: u64) -> u64 {
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov x8, {id}",
+            "svc #0",
+            id = in(reg) id,
+            inlateout("x0") a0 => ret,
+            in("x1") a1,
+            in("x2") a2,
+            clobber_abi("C")
+        );
+    }
+    ret
+}
+
+/// Check that `ret` is -EFAULT.
+fn expect_efault(what: &str, ret: u64) -> bool {
+    if ret == Errno::EFAULT.0.wrapping_neg() {
+        println!("[badptr] {}: EFAULT, ok", what);
+        true
+    } else {
+        println!("[badptr] FAILED: {} returned {:#x}", what, ret);
+        false
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    print("[badptr] Passing bad buffers to syscalls...\n");
+    let mut ok = expect_efault("write from unmapped page", syscall(9, 1, 0x1000, 1));
+    ok &= expect_efault("print from kernel half", syscall(0, 0xffff_0000_0000_0000, 16, 0));
+    ok &= expect_efault("open path in kernel half", syscall(7, 0xffff_0000_0000_0000, 8, 0));
+
+    // A file with something in it, to read into .text
+    match open_with(SCRATCH, OPEN_WRITE | OPEN_TRUNCATE) {
+        Ok(fd) => {
+            write(fd, b"x");
+            lseek(fd, 0, Whence::Start);
+            let text = _start as *const () as u64;
+            ok &= expect_efault("read into .text", syscall(8, fd as u64, text, 1));
+            close(fd);
+        }
+        Err(e) => {
+            println!("[badptr] FAILED: {}: {}", SCRATCH, e);
+            ok = false;
+        }
+    }
+    print(if ok { "[badptr] PASSED\n" } else { "[badptr] FAILED\n" });
+    exit();
+}
@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+// =============================================================================
+// APRK OS - Large Allocation Test
+// =============================================================================
+// Asks the kernel for 1GB in one allocation. That can never fit, so the
+// alloc syscall must fail cleanly (return null) rather than bring the
+// kernel down, and a small allocation afterwards must still work.
+// =============================================================================
+
+extern crate alloc;
+
+use aprk_user_lib::{exit, print, println};
+use core::alloc::Layout;
+
+/// Size of the allocation that must fail
+const HUGE: usize = 1024 * 1024 * 1024;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let layout = Layout::from_size_align(HUGE, 16).unwrap();
+    // Through the allocator directly: `Box`/`Vec` would treat null as fatal
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    if !ptr.is_null() {
+        print("[bigalloc] FAILED: 1GB allocation succeeded\n");
+        unsafe { alloc::alloc::dealloc(ptr, layout) };
+        exit();
+    }
+    println!("[bigalloc] 1GB allocation refused");
+
+    let small = alloc::vec![0x5Au8; 4096];
+    let ok = small.iter().all(|&b| b == 0x5A);
+    println!("[bigalloc] {}: small allocation after the refusal {}",
+        if ok { "PASSED" } else { "FAILED" }, if ok { "works" } else { "is corrupted" });
+    exit();
+}
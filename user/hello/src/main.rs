@@ -6,7 +6,7 @@
 
 extern crate alloc;
 use alloc::alloc::{alloc, Layout};
-use aprk_user_lib::{print, yield_cpu};
+use aprk_user_lib::{print, yield_cpu, yield_deferred};
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
@@ -29,6 +29,12 @@ pub extern "C" fn _start() -> ! {
         }
     }
 
+    // Yield through the RESCHEDULE SGI once; getting here again means the
+    // deferred switch came back to us
+    print("[TEST] Deferred yield... ");
+    yield_deferred();
+    print("OK\n");
+
     print("Done.\n");
     
     loop {
@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+// =============================================================================
+// APRK OS - Key Test
+// =============================================================================
+// Puts the console in raw mode and prints the bytes each key press sends,
+// so escape sequences and control keys can be inspected. Press 'q' to
+// quit; the console returns to cooked mode when the program exits.
+// =============================================================================
+
+use aprk_user_lib::{console_set_mode, exit, print, println, read, ConsoleMode};
+
+/// stdin
+const STDIN: usize = 0;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    console_set_mode(ConsoleMode::Raw);
+    print("[keytest] Press keys to see their bytes, 'q' quits\n");
+
+    let mut buf = [0u8; 16];
+    loop {
+        let Some(n) = read(STDIN, &mut buf) else { break };
+        for &c in &buf[..n] {
+            match c {
+                0x20..=0x7E => print!("{:#04x} '{}' ", c, c as char),
+                _ => print!("{:#04x} ", c),
+            }
+        }
+        println!();
+        if buf[..n].contains(&b'q') {
+            break;
+        }
+    }
+
+    exit();
+}
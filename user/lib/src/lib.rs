@@ -9,7 +9,7 @@ use core::panic::PanicInfo;
 // System call wrappers for user programs.
 // =============================================================================
 
-/// Print a string to the console.
+/// Print a string to standard output (the console, unless redirected).
 /// Syscall 0: print(ptr, len)
 pub fn print(s: &str) {
     unsafe {
@@ -62,6 +62,19 @@ pub fn yield_cpu() {
     }
 }
 
+/// Yield the CPU on return from the kernel, via a self-targeted
+/// reschedule interrupt rather than a direct switch.
+/// Syscall 12: yield_deferred()
+pub fn yield_deferred() {
+    unsafe {
+        core::arch::asm!(
+            "mov x8, #12", // Syscall ID: YIELD_DEFERRED
+            "svc #0",
+            clobber_abi("C")
+        );
+    }
+}
+
 /// Sleep for the specified number of milliseconds.
 /// Syscall 4: sleep(ms)
 /// Note: Currently just yields, proper timing not yet implemented.
@@ -76,6 +89,247 @@ pub fn sleep(_ms: u64) {
     }
 }
 
+/// Error from a file syscall: a POSIX errno value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u64);
+
+impl Errno {
+    pub const ENOENT: Errno = Errno(2);
+    pub const EIO: Errno = Errno(5);
+    pub const EBADF: Errno = Errno(9);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EEXIST: Errno = Errno(17);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const ENOSPC: Errno = Errno(28);
+}
+
+impl core::fmt::Display for Errno {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let name = match self.0 {
+            2 => "No such file or directory",
+            5 => "I/O error",
+            6 => "No such device",
+            9 => "Bad file descriptor",
+            12 => "Out of memory",
+            14 => "Bad address",
+            17 => "File exists",
+            20 => "Not a directory",
+            21 => "Is a directory",
+            22 => "Invalid argument",
+            24 => "Too many open files",
+            28 => "No space left on device",
+            30 => "Read-only file system",
+            36 => "File name too long",
+            39 => "Directory not empty",
+            95 => "Operation not supported",
+            117 => "Filesystem is corrupt",
+            n => return write!(f, "Error {}", n),
+        };
+        f.write_str(name)
+    }
+}
+
+/// Split a syscall return value into the result or the error: errors come
+/// back as the errno negated.
+fn check(ret: u64) -> Result<u64, Errno> {
+    if ret > (-4096i64) as u64 { Err(Errno(ret.wrapping_neg())) } else { Ok(ret) }
+}
+
+/// `open_with` flag: open for writing too, creating the file if missing
+pub const OPEN_WRITE: u64 = 1 << 0;
+/// `open_with` flag: empty the file (with `OPEN_WRITE`)
+pub const OPEN_TRUNCATE: u64 = 1 << 1;
+/// `open_with` flag: every write goes to the end (with `OPEN_WRITE`)
+pub const OPEN_APPEND: u64 = 1 << 2;
+
+/// Open a device (e.g. "/dev/ttyS1") or a file, read-only.
+/// Syscall 7: open(ptr, len, 0) -> fd
+pub fn open(path: &str) -> Option<usize> {
+    open_with(path, 0).ok()
+}
+
+/// Open a device or a file with `OPEN_*` flags.
+/// Syscall 7: open(ptr, len, flags) -> fd
+pub fn open_with(path: &str, flags: u64) -> Result<usize, Errno> {
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov x8, #7", // Syscall ID: OPEN
+            "svc #0",
+            inlateout("x0") path.as_ptr() as u64 => ret,
+            in("x1") path.len(),
+            in("x2") flags,
+            clobber_abi("C")
+        );
+    }
+    check(ret).map(|fd| fd as usize)
+}
+
+/// Read from a file descriptor, blocking until data is available.
+/// Syscall 8: read(fd, ptr, len) -> bytes read
+pub fn read(fd: usize, buf: &mut [u8]) -> Option<usize> {
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov x8, #8", // Syscall ID: READ
+            "svc #0",
+            inlateout("x0") fd as u64 => ret,
+            in("x1") buf.as_mut_ptr(),
+            in("x2") buf.len(),
+            clobber_abi("C")
+        );
+    }
+    check(ret).ok().map(|n| n as usize)
+}
+
+/// Write to a file descriptor.
+/// Syscall 9: write(fd, ptr, len) -> bytes written
+pub fn write(fd: usize, buf: &[u8]) -> Option<usize> {
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov x8, #9", // Syscall ID: WRITE
+            "svc #0",
+            inlateout("x0") fd as u64 => ret,
+            in("x1") buf.as_ptr(),
+            in("x2") buf.len(),
+            clobber_abi("C")
+        );
+    }
+    check(ret).ok().map(|n| n as usize)
+}
+
+/// Close a file descriptor.
+/// Syscall 10: close(fd)
+pub fn close(fd: usize) {
+    unsafe {
+        core::arch::asm!(
+            "mov x8, #10", // Syscall ID: CLOSE
+            "svc #0",
+            in("x0") fd,
+            clobber_abi("C")
+        );
+    }
+}
+
+/// Where `lseek` measures its offset from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Start = 0,
+    Current = 1,
+    End = 2,
+}
+
+/// Move the position of a file fd. Returns the new position.
+/// Syscall 14: lseek(fd, offset, whence) -> position
+pub fn lseek(fd: usize, offset: i64, whence: Whence) -> Option<u64> {
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov x8, #14", // Syscall ID: LSEEK
+            "svc #0",
+            inlateout("x0") fd as u64 => ret,
+            in("x1") offset,
+            in("x2") whence as u64,
+            clobber_abi("C")
+        );
+    }
+    check(ret).ok()
+}
+
+/// Cut a file fd opened for writing to `len` bytes, or extend it with
+/// zeros.
+/// Syscall 15: ftruncate(fd, len)
+pub fn ftruncate(fd: usize, len: u64) -> bool {
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov x8, #15", // Syscall ID: FTRUNCATE
+            "svc #0",
+            inlateout("x0") fd as u64 => ret,
+            in("x1") len,
+            clobber_abi("C")
+        );
+    }
+    check(ret).is_ok()
+}
+
+/// Move the program break by `increment` bytes (negative shrinks the
+/// heap). Pages are only backed by memory once touched.
+/// Syscall 13: sbrk(increment) -> old break
+pub fn sbrk(increment: isize) -> Option<*mut u8> {
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov x8, #13", // Syscall ID: SBRK
+            "svc #0",
+            inlateout("x0") increment as u64 => ret,
+            clobber_abi("C")
+        );
+    }
+    if ret == u64::MAX { None } else { Some(ret as *mut u8) }
+}
+
+/// Where the pointer is on the screen and which buttons are down (bit 0
+/// left, 1 right, 2 middle), or `None` without a pointing device.
+/// Syscall 16: pointer_state() -> x | y << 16 | buttons << 32
+pub fn pointer_state() -> Option<(u32, u32, u8)> {
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov x8, #16", // Syscall ID: POINTER_STATE
+            "svc #0",
+            out("x0") ret,
+            clobber_abi("C")
+        );
+    }
+    if ret == u64::MAX {
+        None
+    } else {
+        Some((ret as u32 & 0xffff, (ret >> 16) as u32 & 0xffff, (ret >> 32) as u8))
+    }
+}
+
+/// Console input mode (see `console_set_mode`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleMode {
+    /// Line-buffered with echo (default)
+    Cooked = 0,
+    /// Keystrokes delivered immediately, without echo
+    Raw = 1,
+}
+
+fn console_ioctl(cmd: u64, arg: u64) -> u64 {
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov x8, #11", // Syscall ID: CONSOLE_IOCTL
+            "svc #0",
+            inlateout("x0") cmd => ret,
+            in("x1") arg,
+            clobber_abi("C")
+        );
+    }
+    ret
+}
+
+/// Get the console input mode.
+/// Syscall 11: console_ioctl(0)
+pub fn console_mode() -> ConsoleMode {
+    match console_ioctl(0, 0) {
+        1 => ConsoleMode::Raw,
+        _ => ConsoleMode::Cooked,
+    }
+}
+
+/// Set the console input mode. Raw mode is reset when the task exits.
+/// Syscall 11: console_ioctl(1, mode)
+pub fn console_set_mode(mode: ConsoleMode) {
+    console_ioctl(1, mode as u64);
+}
+
 // Convenience macros for printing
 #[macro_export]
 macro_rules! print {
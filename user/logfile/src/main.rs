@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+
+// =============================================================================
+// APRK OS - Log File Test
+// =============================================================================
+// Appends a "run N" line to /log.txt, N counting the lines already there,
+// so running it again after a reboot must show the count going up: the
+// log survives on the disk. It exits without closing the file, leaving the
+// kernel to flush it. Then checks lseek and ftruncate on a scratch file.
+// =============================================================================
+
+extern crate alloc;
+
+use aprk_user_lib::{
+    close, exit, ftruncate, lseek, open_with, print, println, read, write, Errno, Whence,
+    OPEN_TRUNCATE, OPEN_WRITE,
+};
+
+const LOG: &str = "/log.txt";
+const SCRATCH: &str = "/logtest.tmp";
+
+/// Read the rest of `fd` into `buf`. Returns the bytes read.
+fn read_all(fd: usize, buf: &mut [u8]) -> usize {
+    let mut done = 0;
+    while done < buf.len() {
+        match read(fd, &mut buf[done..]) {
+            Some(0) | None => break,
+            Some(n) => done += n,
+        }
+    }
+    done
+}
+
+/// Check `lseek` and `ftruncate` on a scratch file. Returns what failed.
+fn seek_and_truncate() -> Result<(), &'static str> {
+    let fd = open_with(SCRATCH, OPEN_WRITE | OPEN_TRUNCATE).map_err(|_| "open scratch file")?;
+    let mut buf = [0u8; 16];
+    let result = (|| {
+        write(fd, b"hello world").ok_or("write")?;
+        if !ftruncate(fd, 5) {
+            return Err("ftruncate to 5 bytes");
+        }
+        lseek(fd, 0, Whence::Start).ok_or("lseek to the start")?;
+        if read_all(fd, &mut buf) != 5 || &buf[..5] != b"hello" {
+            return Err("read after shrinking");
+        }
+        if !ftruncate(fd, 8) || lseek(fd, 0, Whence::End) != Some(8) {
+            return Err("ftruncate to 8 bytes");
+        }
+        lseek(fd, -3, Whence::Current).ok_or("lseek back")?;
+        if read_all(fd, &mut buf) != 3 || buf[..3] != [0, 0, 0] {
+            return Err("extension isn't zeros");
+        }
+        Ok(())
+    })();
+    close(fd);
+    result
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let fd = match open_with(LOG, OPEN_WRITE) {
+        Ok(fd) => fd,
+        Err(e) => {
+            println!("[logfile] FAILED: cannot open {} for writing: {}", LOG, e);
+            exit();
+        }
+    };
+
+    let mut runs = 0;
+    let mut buf = [0u8; 512];
+    loop {
+        match read(fd, &mut buf) {
+            Some(0) | None => break,
+            Some(n) => runs += buf[..n].iter().filter(|&&b| b == b'\n').count(),
+        }
+    }
+
+    let line = alloc::format!("run {}\n", runs + 1);
+    if lseek(fd, 0, Whence::End).is_none() || write(fd, line.as_bytes()) != Some(line.len()) {
+        print("[logfile] FAILED: cannot append to /log.txt\n");
+        exit();
+    }
+    println!("[logfile] Logged run {} to {} (reboot and run again: the count should go on)", runs + 1, LOG);
+
+    match seek_and_truncate() {
+        Ok(()) => print("[logfile] PASSED: lseek and ftruncate\n"),
+        Err(step) => println!("[logfile] FAILED: {}", step),
+    }
+    match open_with("/", 0) {
+        Err(e) if e == Errno::EISDIR => print("[logfile] PASSED: opening a directory fails with EISDIR\n"),
+        Err(e) => println!("[logfile] FAILED: opening a directory: {}", e),
+        Ok(_) => print("[logfile] FAILED: opened a directory as a file\n"),
+    }
+    // /log.txt is left open on purpose
+    exit();
+}
@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+// =============================================================================
+// APRK OS - Demand Paging Test
+// =============================================================================
+// Grows the heap by 64MB with sbrk but only touches the first 1MB of it.
+// The kernel pages the heap in on first touch, so the task should only
+// ever hold 256 heap pages, which the kernel logs as freed when it exits.
+// =============================================================================
+
+use aprk_user_lib::{exit, print, println, sbrk};
+
+const PAGE_SIZE: usize = 4096;
+/// Heap reserved with sbrk
+const RESERVE: usize = 64 * 1024 * 1024;
+/// Part of the heap actually touched
+const TOUCH: usize = 1024 * 1024;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let Some(heap) = sbrk(RESERVE as isize) else {
+        print("[sbrktest] sbrk failed\n");
+        exit();
+    };
+    println!("[sbrktest] Reserved {} MB at {:p}", RESERVE / 1024 / 1024, heap);
+
+    // Fresh heap pages must read as zero; write a pattern to each page
+    let mut ok = true;
+    for offset in (0..TOUCH).step_by(PAGE_SIZE) {
+        unsafe {
+            let p = heap.add(offset);
+            ok &= p.read_volatile() == 0;
+            p.write_volatile(0xA5);
+            ok &= p.read_volatile() == 0xA5;
+        }
+    }
+    println!("[sbrktest] Touched {} of {} pages: {}", TOUCH / PAGE_SIZE, RESERVE / PAGE_SIZE,
+        if ok { "OK" } else { "FAILED" });
+    exit();
+}
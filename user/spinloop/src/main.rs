@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+// =============================================================================
+// APRK OS - CPU Spin Test
+// =============================================================================
+// Busy-loops forever without yielding. Used to test the CPU watchdog:
+//   exec spinloop 500
+// should reap the task after roughly 500ms of CPU time.
+// =============================================================================
+
+use aprk_user_lib::print;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    print("\n[spinloop] Spinning without yielding...\n");
+
+    loop {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+// =============================================================================
+// APRK OS - Text Protection Test
+// =============================================================================
+// Tries to overwrite its own entry point. The loader maps .text read-only
+// and executable, so the store faults and the kernel kills the task: the
+// "still running" line must never be printed.
+// =============================================================================
+
+use aprk_user_lib::{exit, print};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    print("[textwrite] Writing to .text, expecting to be killed...\n");
+    let text = _start as *const () as *mut u32;
+    unsafe { text.write_volatile(0xD503_201F); } // NOP
+    print("[textwrite] FAILED: still running after writing .text\n");
+    exit();
+}
@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+// =============================================================================
+// APRK OS - Serial Echo
+// =============================================================================
+// Opens the second serial port and echoes everything typed on it, while
+// the kernel console stays on the first. Run QEMU with
+//   -serial mon:stdio -serial pty
+// then `exec ttyecho` and connect to the reported pty.
+// =============================================================================
+
+use aprk_user_lib::{exit, open, print, read, write};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let Some(tty) = open("/dev/ttyS1") else {
+        print("[ttyecho] Cannot open /dev/ttyS1\n");
+        exit();
+    };
+
+    print("[ttyecho] Echoing on /dev/ttyS1 (Ctrl-D on that port quits)\n");
+    write(tty, b"ttyecho ready\n");
+
+    let mut buf = [0u8; 64];
+    loop {
+        let Some(n) = read(tty, &mut buf) else { break };
+        if buf[..n].contains(&0x04) {
+            break;
+        }
+        for &c in &buf[..n] {
+            match c {
+                b'\r' => { write(tty, b"\n"); }
+                _ => { write(tty, &[c]); }
+            }
+        }
+    }
+
+    write(tty, b"\nbye\n");
+    exit();
+}